@@ -0,0 +1,196 @@
+//! Measures how quickly, and with how many allocations, lines received from
+//! the server are decoded into messages.
+//!
+//! Run with `cargo bench --bench decode`. The traffic is a mix of the kinds
+//! of lines a busy bouncer or relay sees. The first two runs feed it to the
+//! transport in chunks the size of a TCP segment, so that lines often arrive
+//! split across reads. The last feeds long lines a few bytes at a time, as a
+//! slow link would, which is where searching each line for its end only once
+//! rather than on every read pays off.
+//!
+//! Decoding still copies each line once, as a pircolate `Message` owns its
+//! text, so the runs show where the time and allocations go rather than a
+//! line decoded without copying.
+//!
+//! The allocations counted include those of the transport acting on each
+//! message, such as tracking the hostmasks of users, as well as those of
+//! parsing it.
+
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_irc_client;
+
+use futures::{Async, Future, Poll, Stream};
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use tokio_irc_client::Client;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+// Counts the allocations made while decoding.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const LINES: usize = 200_000;
+const SEGMENT: usize = 1460;
+
+// The length of the long lines, and the size of the reads they arrive in.
+const LONG_LINES: usize = 20_000;
+const LONG_LINE: usize = 500;
+const TRICKLE: usize = 16;
+
+const TRAFFIC: &[&str] = &[
+    ":alice!alice@example.com PRIVMSG #rust :has anyone tried the new release yet?",
+    "@time=2024-01-01T12:00:00.000Z;msgid=abc123 :bob!bob@example.org PRIVMSG #rust :yes",
+    ":carol!carol@example.net JOIN #rust",
+    ":dave!dave@example.com PRIVMSG #rust :\x01ACTION waves\x01",
+    ":irc.example.com 372 client :- Welcome to the message of the day, which is long",
+    ":erin!erin@example.org NOTICE client :a notice sent straight to the client",
+];
+
+// A connection whose reads are served from memory, a segment at a time.
+struct Replayed {
+    data: Vec<u8>,
+    position: usize,
+    segment: usize,
+}
+
+impl Read for Replayed {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.data.len() - self.position;
+
+        if remaining == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let length = cmp::min(cmp::min(remaining, buffer.len()), self.segment);
+
+        buffer[..length].copy_from_slice(&self.data[self.position..self.position + length]);
+        self.position += length;
+
+        Ok(length)
+    }
+}
+
+impl Write for Replayed {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Replayed {}
+
+impl AsyncWrite for Replayed {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+fn traffic() -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for line in TRAFFIC.iter().cycle().take(LINES) {
+        data.extend_from_slice(line.as_bytes());
+        data.extend_from_slice(b"\r\n");
+    }
+
+    data
+}
+
+// Messages just short of the longest line allowed without tags.
+fn long_traffic() -> Vec<u8> {
+    let prefix = ":alice!alice@example.com PRIVMSG #rust :";
+    let text = "a".repeat(LONG_LINE - prefix.len() - 2);
+    let mut data = Vec::new();
+
+    for _ in 0..LONG_LINES {
+        data.extend_from_slice(prefix.as_bytes());
+        data.extend_from_slice(text.as_bytes());
+        data.extend_from_slice(b"\r\n");
+    }
+
+    data
+}
+
+fn bench<S, F>(name: &str, data: Vec<u8>, segment: usize, lines: usize, connect: F)
+where
+    S: Stream,
+    S::Error: ::std::fmt::Debug,
+    F: FnOnce(Replayed) -> S,
+{
+    let mut stream = connect(Replayed {
+        data: data,
+        position: 0,
+        segment: segment,
+    });
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut decoded = 0;
+
+    // Reads from memory never block, so every poll is ready until the
+    // traffic runs out.
+    futures::future::poll_fn(|| -> Poll<(), ()> {
+        while let Async::Ready(Some(_)) = stream.poll().expect("decoding failed") {
+            decoded += 1;
+        }
+
+        Ok(Async::Ready(()))
+    }).wait()
+        .expect("decoding failed");
+
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated;
+
+    assert_eq!(decoded, lines);
+
+    println!(
+        "{:<10} {:>8.0} ns/line {:>8.2} allocations/line {:>8.0} bytes/line",
+        name,
+        elapsed.as_secs_f64() * 1e9 / lines as f64,
+        allocations as f64 / lines as f64,
+        allocated as f64 / lines as f64,
+    );
+}
+
+fn main() {
+    let client = Client::new(([127, 0, 0, 1], 6667));
+
+    bench("transport", traffic(), SEGMENT, LINES, |io| {
+        client.connect_stream(io).unwrap()
+    });
+    bench("raw", traffic(), SEGMENT, LINES, |io| {
+        client.connect_stream(io).unwrap().raw()
+    });
+    bench("trickled", long_traffic(), TRICKLE, LONG_LINES, |io| {
+        client.connect_stream(io).unwrap()
+    });
+}
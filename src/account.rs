@@ -0,0 +1,240 @@
+//! The account module contains support for the IRCv3
+//! `draft/account-registration` extension, which lets clients register an
+//! account with the network and verify it, without messaging its services.
+//!
+//! Accounts are registered with `Requester::register_account`, and verified
+//! with the code the network sends, such as by email, using
+//! `Requester::verify_account`. When the capability is offered but wasn't
+//! requested during registration, such as with
+//! `Registration::account_registration`, it's requested first.
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate tokio_irc_client;
+//! # use futures::Future;
+//! # use tokio_irc_client::request::Requester;
+//! # fn example(requester: Requester) {
+//! let registered = requester
+//!     .register_account("*", Some("me@example.org"), "hunter2")
+//!     .map(|registered| if registered.verification_required {
+//!         println!("Check your email: {}", registered.message);
+//!     });
+//! # }
+//! # fn main() {}
+//! ```
+
+use error::{ErrorKind, Result};
+use request::Pending;
+use state::State;
+
+use futures::sync::oneshot::Sender;
+
+use pircolate::Message;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// The name of the capability allowing accounts to be registered.
+pub const CAPABILITY: &str = "draft/account-registration";
+
+/// An account the server has registered or verified.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisteredAccount {
+    /// The name of the account.
+    pub account: String,
+    /// Whether the account must be verified, with the code the network sent,
+    /// before it can be used.
+    pub verification_required: bool,
+    /// The message the server gave, such as where the code was sent.
+    pub message: String,
+}
+
+/// Why the server refused to register or verify an account, as given by the
+/// code of its FAIL reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccountFailure {
+    /// An account with the name already exists.
+    AccountExists,
+    /// The account name isn't valid.
+    BadAccountName,
+    /// The account name must be the client's current nickname.
+    AccountNameMustBeNick,
+    /// The client must have a nickname before registering.
+    NeedNick,
+    /// The client is already logged in to an account.
+    AlreadyAuthenticated,
+    /// The password is too weak.
+    WeakPassword,
+    /// The password isn't acceptable, such as for containing a space.
+    UnacceptablePassword,
+    /// The email address isn't valid, or one is required but wasn't given.
+    InvalidEmail,
+    /// The email address isn't acceptable, such as for its domain.
+    UnacceptableEmail,
+    /// The verification code is wrong.
+    InvalidCode,
+    /// Registration is temporarily unavailable, and may be tried later.
+    TemporarilyUnavailable,
+    /// Any other code.
+    Other(String),
+}
+
+impl AccountFailure {
+    /// The `AccountFailure` described by the code of a FAIL reply.
+    pub fn from_code(code: &str) -> AccountFailure {
+        match code {
+            "ACCOUNT_EXISTS" => AccountFailure::AccountExists,
+            "BAD_ACCOUNT_NAME" => AccountFailure::BadAccountName,
+            "ACCOUNT_NAME_MUST_BE_NICK" => AccountFailure::AccountNameMustBeNick,
+            "NEED_NICK" => AccountFailure::NeedNick,
+            "ALREADY_AUTHENTICATED" => AccountFailure::AlreadyAuthenticated,
+            "WEAK_PASSWORD" => AccountFailure::WeakPassword,
+            "UNACCEPTABLE_PASSWORD" => AccountFailure::UnacceptablePassword,
+            "INVALID_EMAIL" => AccountFailure::InvalidEmail,
+            "UNACCEPTABLE_EMAIL" => AccountFailure::UnacceptableEmail,
+            "INVALID_CODE" => AccountFailure::InvalidCode,
+            "TEMPORARILY_UNAVAILABLE" => AccountFailure::TemporarilyUnavailable,
+            code => AccountFailure::Other(code.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for AccountFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            AccountFailure::AccountExists => "ACCOUNT_EXISTS",
+            AccountFailure::BadAccountName => "BAD_ACCOUNT_NAME",
+            AccountFailure::AccountNameMustBeNick => "ACCOUNT_NAME_MUST_BE_NICK",
+            AccountFailure::NeedNick => "NEED_NICK",
+            AccountFailure::AlreadyAuthenticated => "ALREADY_AUTHENTICATED",
+            AccountFailure::WeakPassword => "WEAK_PASSWORD",
+            AccountFailure::UnacceptablePassword => "UNACCEPTABLE_PASSWORD",
+            AccountFailure::InvalidEmail => "INVALID_EMAIL",
+            AccountFailure::UnacceptableEmail => "UNACCEPTABLE_EMAIL",
+            AccountFailure::InvalidCode => "INVALID_CODE",
+            AccountFailure::TemporarilyUnavailable => "TEMPORARILY_UNAVAILABLE",
+            AccountFailure::Other(ref code) => code,
+        })
+    }
+}
+
+// A REGISTER or VERIFY waiting for the server's answer, and first for the
+// capability to be enabled if it had to be requested.
+pub(crate) struct AccountRequest {
+    command: &'static str,
+    // The command, held back until the capability has been acknowledged.
+    deferred: Option<Message>,
+    resend: Option<Message>,
+    // The account waiting to be verified, shared by the requester's
+    // registrations and verifications.
+    unverified: Arc<Mutex<Option<String>>>,
+    sender: Option<Sender<Result<RegisteredAccount>>>,
+}
+
+impl AccountRequest {
+    pub(crate) fn new(
+        command: Message,
+        requesting_capability: bool,
+        unverified: Arc<Mutex<Option<String>>>,
+        sender: Sender<Result<RegisteredAccount>>,
+    ) -> AccountRequest {
+        let name = if command.raw_command() == "VERIFY" { "VERIFY" } else { "REGISTER" };
+
+        AccountRequest {
+            command: name,
+            deferred: if requesting_capability { Some(command) } else { None },
+            resend: None,
+            unverified: unverified,
+            sender: Some(sender),
+        }
+    }
+
+    fn complete(&mut self, result: Result<RegisteredAccount>) -> bool {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(result);
+        }
+
+        true
+    }
+
+    // Handles the answer to the capability request.
+    fn handle_cap(&mut self, args: &[&str]) -> bool {
+        let acknowledged = match args.get(1) {
+            Some(&"ACK") => true,
+            Some(&"NAK") => false,
+            _ => return false,
+        };
+
+        let concerns = args
+            .last()
+            .is_some_and(|caps| caps.split_whitespace().any(|cap| cap == CAPABILITY));
+
+        if !concerns {
+            return false;
+        }
+
+        if !acknowledged {
+            let err = ErrorKind::CapabilityNotEnabled(CAPABILITY.to_owned());
+            return self.complete(Err(err.into()));
+        }
+
+        self.resend = self.deferred.take();
+
+        false
+    }
+}
+
+impl Pending for AccountRequest {
+    fn handle(&mut self, message: &Message, _state: &State) -> bool {
+        let args: Vec<&str> = message.raw_args().collect();
+
+        if self.deferred.is_some() {
+            return message.raw_command() == "CAP" && self.handle_cap(&args);
+        }
+
+        match message.raw_command() {
+            // "REGISTER SUCCESS <account> :<message>",
+            // "REGISTER VERIFICATION_REQUIRED <account> :<message>" and
+            // "VERIFY SUCCESS <account> :<message>"
+            command if command == self.command && args.len() >= 3 => {
+                let verification_required = args[0] == "VERIFICATION_REQUIRED";
+
+                if args[0] != "SUCCESS" && !verification_required {
+                    return false;
+                }
+
+                *self.unverified.lock().expect("Account lock poisoned") = if verification_required {
+                    Some(args[1].to_owned())
+                } else {
+                    None
+                };
+
+                self.complete(Ok(RegisteredAccount {
+                    account: args[1].to_owned(),
+                    verification_required: verification_required,
+                    message: args[args.len() - 1].to_owned(),
+                }))
+            }
+
+            // "FAIL <command> <code> [<context>...] :<description>"
+            "FAIL" if args.len() >= 3 && args[0] == self.command => {
+                let failure = AccountFailure::from_code(args[1]);
+                let reason = args[args.len() - 1].to_owned();
+
+                self.complete(Err(ErrorKind::AccountRegistrationFailed(failure, reason).into()))
+            }
+
+            // ERR_UNKNOWNCOMMAND
+            "421" if args.get(1) == Some(&self.command) => {
+                let err = ErrorKind::CapabilityNotEnabled(CAPABILITY.to_owned());
+                self.complete(Err(err.into()))
+            }
+
+            _ => false,
+        }
+    }
+
+    fn resend(&mut self) -> Vec<Message> {
+        self.resend.take().into_iter().collect()
+    }
+}
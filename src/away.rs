@@ -0,0 +1,127 @@
+//! The away module contains the types used to control how messages sent to
+//! users who are marked as away are handled.
+//!
+//! Bots sending reminders or notifications to a user that's away will often
+//! want to hold on to those messages until the user returns. Setting an
+//! `AwayPolicy` on a `Client` makes the `IrcTransport` take care of this for
+//! every PRIVMSG and NOTICE sent directly to a user, based on the away status
+//! tracked by its `State`.
+
+use state::State;
+
+use pircolate::Message;
+
+use std::collections::{HashMap, VecDeque};
+
+/// How messages sent directly to users who are away are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AwayPolicy {
+    /// Messages are sent regardless of whether the user is away.
+    #[default]
+    Deliver,
+    /// Messages sent to users who are away are silently dropped.
+    Suppress,
+    /// Messages sent to users who are away are held until the user returns,
+    /// keeping at most the given number of the most recent messages per user.
+    /// Held messages are discarded if the user quits.
+    Queue(usize),
+}
+
+// Applies an `AwayPolicy` to the messages sent on a connection.
+pub(crate) struct AwayFilter {
+    policy: AwayPolicy,
+    // Messages held for each away user, keyed by their folded nickname.
+    held: HashMap<String, VecDeque<Message>>,
+}
+
+impl AwayFilter {
+    pub fn new(policy: AwayPolicy) -> AwayFilter {
+        AwayFilter {
+            policy: policy,
+            held: HashMap::new(),
+        }
+    }
+
+    // Returns the message if it should be sent now.
+    pub fn filter(&mut self, message: Message, state: &State) -> Option<Message> {
+        if self.policy == AwayPolicy::Deliver {
+            return Some(message);
+        }
+
+        let target = match message.raw_command() {
+            "PRIVMSG" | "NOTICE" => match message.raw_args().next() {
+                Some(target) if !state.is_channel(target) && state.is_away(target) => {
+                    state.fold_name(target)
+                }
+                _ => return Some(message),
+            },
+            _ => return Some(message),
+        };
+
+        if let AwayPolicy::Queue(limit) = self.policy {
+            let held = self.held.entry(target).or_default();
+
+            held.push_back(message);
+
+            while held.len() > limit {
+                held.pop_front();
+            }
+        }
+
+        None
+    }
+
+    // Handles a message received from the server, which has already been
+    // applied to `state`, releasing any messages held for users who returned.
+    pub fn handle_incoming(
+        &mut self,
+        message: &Message,
+        state: &State,
+        outgoing: &mut VecDeque<Message>,
+    ) {
+        if self.held.is_empty() {
+            return;
+        }
+
+        let source = match message.prefix() {
+            Some((nick, _, _)) => state.fold_name(nick),
+            None => String::new(),
+        };
+
+        match message.raw_command() {
+            "QUIT" => {
+                self.held.remove(&source);
+            }
+
+            // Held messages follow the user to their new nickname.
+            "NICK" => if let Some(nick) = message.raw_args().next() {
+                if let Some(held) = self.held.remove(&source) {
+                    let held = held.iter().filter_map(|message| retarget(message, nick));
+
+                    self.held.insert(state.fold_name(nick), held.collect());
+                }
+            },
+
+            _ => (),
+        }
+
+        let returned: Vec<String> = self.held
+            .keys()
+            .filter(|nick| !state.is_away(nick))
+            .cloned()
+            .collect();
+
+        for nick in returned {
+            if let Some(held) = self.held.remove(&nick) {
+                outgoing.extend(held);
+            }
+        }
+    }
+}
+
+// Rebuilds a PRIVMSG or NOTICE so that it's sent to `target` instead.
+fn retarget(message: &Message, target: &str) -> Option<Message> {
+    let text = message.raw_args().nth(1).unwrap_or_default();
+
+    Message::try_from(format!("{} {} :{}", message.raw_command(), target, text)).ok()
+}
@@ -0,0 +1,566 @@
+//! The bot module contains `Bot`, a command dispatcher which turns a
+//! connection into a bot answering commands such as `!weather London`.
+//!
+//! Each `Command` is registered with a handler, which is called with an
+//! `Invocation` describing who used the command, where, and with which
+//! arguments. Handlers return a future, so they can wait on requests to the
+//! server or on anything else before replying. Replies sent with
+//! `Invocation::reply` are routed back to where the command was used: the
+//! channel, or the user for commands sent in a private message.
+//!
+//! Commands start with one of the bot's prefixes, `!` by default, or with
+//! the bot's nickname when `Bot::addressed` is enabled, as in
+//! `mybot: weather London`. In private messages the prefix may be left out.
+//! Commands can be restricted to some channels, to private messages, and to
+//! the users allowed by a `Permission`. A `CommandLimiter` given to the bot
+//! is checked before each permitted use of a command, and its cooldown
+//! responses are sent as replies.
+//!
+//! A `Bot` is given to `Client::bot`, which has each connection made by the
+//! client dispatch the commands it receives and drive the handlers while
+//! it's being polled, across reconnects. Handlers that fail are reported
+//! with `Event::AutomationFailed`.
+//!
+//! ```no_run
+//! # extern crate tokio_irc_client;
+//! use tokio_irc_client::Client;
+//! use tokio_irc_client::bot::{Bot, Command, Invocation, Permission};
+//!
+//! # fn main() {
+//! let bot = Bot::new()
+//!     .command(Command::new("echo", |invocation: Invocation| {
+//!         invocation.reply(&invocation.text)
+//!     }).usage("{prefix}{command} <text>").min_args(1))
+//!     .command(Command::new("quit", |invocation: Invocation| {
+//!         invocation.reply("Bye!")
+//!     }).permission(Permission::Hostmask(vec!["admin!*@example.org".to_owned()])));
+//!
+//! let client = Client::new(([127, 0, 0, 1], 6667)).bot(bot);
+//! # }
+//! ```
+//!
+//! This module is only available with the `bot` feature.
+
+use error::{Error, Result};
+use event::{Automation, EventBus};
+use limit::{CommandLimiter, Decision};
+use request::Requester;
+use state::State;
+use template::{Template, Values};
+
+use futures::{Async, Future, IntoFuture};
+
+use pircolate::Message;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+type Handler = dyn Fn(Invocation) -> Box<dyn Future<Item = (), Error = Error> + Send> + Send + Sync;
+
+/// Who may use a command.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Permission {
+    /// Anyone may use the command.
+    #[default]
+    Anyone,
+    /// Only users whose `nick!user@host` matches one of the masks, which may
+    /// contain `*` and `?` wildcards, may use the command.
+    Hostmask(Vec<String>),
+    /// Only users holding the given membership prefix, such as `@` for
+    /// operators, or a higher one in the channel the command is used in may
+    /// use the command. It can't be used in private messages.
+    Prefix(char),
+}
+
+impl Permission {
+    fn allows(&self, message: &Message, channel: Option<&str>, state: &State) -> bool {
+        match *self {
+            Permission::Anyone => true,
+            Permission::Hostmask(ref masks) => {
+                let sender = message.raw_prefix().unwrap_or_default();
+
+                masks.iter().any(|mask| wildcard_match(mask, sender))
+            }
+            Permission::Prefix(required) => {
+                let (nick, channel) = match (message.prefix(), channel) {
+                    (Some((nick, _, _)), Some(channel)) => (nick, channel),
+                    _ => return false,
+                };
+
+                // Prefixes are advertised from the highest to the lowest.
+                let symbols = state.channel_mode_types().prefix_symbols().to_owned();
+                let rank = |symbol| symbols.chars().position(|c| c == symbol);
+
+                let required = match rank(required) {
+                    Some(required) => required,
+                    None => return false,
+                };
+
+                match state.channel(channel) {
+                    Some(channel) => channel.members.iter().any(|(member, prefixes)| {
+                        state.same_name(member, nick)
+                            && prefixes.chars().filter_map(rank).any(|held| held <= required)
+                    }),
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+/// A command the bot answers, along with the handler called when it's used.
+pub struct Command {
+    name: String,
+    aliases: Vec<String>,
+    handler: Arc<Handler>,
+    channels: Option<Vec<String>>,
+    private: bool,
+    permission: Permission,
+    denied: Option<Template>,
+    usage: Option<Template>,
+    min_args: usize,
+}
+
+impl Command {
+    /// Create a new `Command` with the given name, which `handler` answers.
+    /// By default the command may be used by anyone, in any channel and in
+    /// private messages.
+    pub fn new<N, F, R>(name: N, handler: F) -> Command
+    where
+        N: Into<String>,
+        F: Fn(Invocation) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = (), Error = Error>,
+        R::Future: Send + 'static,
+    {
+        Command {
+            name: name.into(),
+            aliases: Vec::new(),
+            handler: Arc::new(move |invocation| Box::new(handler(invocation).into_future())),
+            channels: None,
+            private: true,
+            permission: Permission::Anyone,
+            denied: None,
+            usage: None,
+            min_args: 0,
+        }
+    }
+
+    /// Also answer the command when it's used under the given name.
+    pub fn alias<A: Into<String>>(mut self, alias: A) -> Command {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    /// Only answer the command in the given channels, and in private
+    /// messages unless disabled with `private`. Channel names are compared
+    /// using the server's case mapping.
+    pub fn channels<C: Into<String>>(mut self, channels: Vec<C>) -> Command {
+        self.channels = Some(channels.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Whether the command is answered in private messages. This is enabled
+    /// by default.
+    pub fn private(mut self, private: bool) -> Command {
+        self.private = private;
+        self
+    }
+
+    /// Only answer the command for the users allowed by `permission`.
+    pub fn permission(mut self, permission: Permission) -> Command {
+        self.permission = permission;
+        self
+    }
+
+    /// The reply given to users who aren't allowed to use the command, which
+    /// are otherwise ignored. The template may refer to `{nick}`, `{prefix}`
+    /// and `{command}`.
+    pub fn denied(mut self, denied: &str) -> Command {
+        self.denied = Some(Template::new(denied));
+        self
+    }
+
+    /// The reply given when the command is used with fewer than `min_args`
+    /// arguments, such as `Usage: {prefix}{command} <city>`. The template
+    /// may refer to `{nick}`, `{prefix}` and `{command}`.
+    pub fn usage(mut self, usage: &str) -> Command {
+        self.usage = Some(Template::new(usage));
+        self
+    }
+
+    /// The fewest arguments the command may be used with. Uses with fewer
+    /// aren't given to the handler, and are answered with the `usage` reply
+    /// if there is one.
+    pub fn min_args(mut self, min_args: usize) -> Command {
+        self.min_args = min_args;
+        self
+    }
+
+    fn answers(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+
+    fn available_in(&self, channel: Option<&str>, state: &State) -> bool {
+        match (channel, self.channels.as_ref()) {
+            (None, _) => self.private,
+            (Some(_), None) => true,
+            (Some(channel), Some(channels)) => channels
+                .iter()
+                .any(|allowed| state.same_name(allowed, channel)),
+        }
+    }
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Command")
+            .field("name", &self.name)
+            .field("aliases", &self.aliases)
+            .field("channels", &self.channels)
+            .field("private", &self.private)
+            .field("permission", &self.permission)
+            .field("min_args", &self.min_args)
+            .finish()
+    }
+}
+
+/// A use of a command, given to its handler.
+#[derive(Clone)]
+pub struct Invocation {
+    /// The nickname of the user who used the command.
+    pub nick: String,
+    /// The channel the command was used in, or `None` if it was sent in a
+    /// private message.
+    pub channel: Option<String>,
+    /// The name of the command, as it was registered rather than as the
+    /// alias or capitalization used.
+    pub command: String,
+    /// The arguments given to the command, split on whitespace.
+    pub args: Vec<String>,
+    /// The text following the name of the command, with surrounding
+    /// whitespace removed.
+    pub text: String,
+    /// The PRIVMSG holding the command.
+    pub message: Message,
+    requester: Requester,
+    state: State,
+}
+
+impl Invocation {
+    /// Reply to the command where it was used: in its channel, or to the
+    /// user in a private message. Each line of the text is sent as a
+    /// separate message.
+    pub fn reply(&self, text: &str) -> Result<()> {
+        self.send("PRIVMSG", self.reply_target(), text)
+    }
+
+    /// Reply to the user who used the command with a private NOTICE, even
+    /// if it was used in a channel.
+    pub fn notice(&self, text: &str) -> Result<()> {
+        self.send("NOTICE", &self.nick, text)
+    }
+
+    /// Where replies to the command are sent.
+    pub fn reply_target(&self) -> &str {
+        self.channel.as_ref().unwrap_or(&self.nick)
+    }
+
+    /// The handle for sending requests on the connection the command was
+    /// received on.
+    pub fn requester(&self) -> &Requester {
+        &self.requester
+    }
+
+    /// The state of the connection the command was received on.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    fn send(&self, command: &str, target: &str, text: &str) -> Result<()> {
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let raw = format!("{} {} :{}", command, target, line);
+
+            self.requester.send(Message::try_from(raw)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Invocation")
+            .field("nick", &self.nick)
+            .field("channel", &self.channel)
+            .field("command", &self.command)
+            .field("args", &self.args)
+            .field("message", &self.message)
+            .finish()
+    }
+}
+
+/// A set of commands, along with how they're recognized. See the module
+/// documentation for details.
+pub struct Bot {
+    prefixes: Vec<String>,
+    addressed: bool,
+    commands: Vec<Command>,
+    limiter: Option<Mutex<CommandLimiter>>,
+}
+
+impl Default for Bot {
+    fn default() -> Bot {
+        Bot::new()
+    }
+}
+
+impl Bot {
+    /// Create a new `Bot` without any commands, which recognizes commands
+    /// starting with `!`.
+    pub fn new() -> Bot {
+        Bot {
+            prefixes: vec!["!".to_owned()],
+            addressed: false,
+            commands: Vec::new(),
+            limiter: None,
+        }
+    }
+
+    /// Recognize commands starting with the given prefix, instead of `!`.
+    /// Calling this again adds further prefixes.
+    pub fn prefix<P: Into<String>>(mut self, prefix: P) -> Bot {
+        if self.prefixes == ["!"] {
+            self.prefixes.clear();
+        }
+
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    /// Whether to also recognize commands addressed to the bot by its
+    /// current nickname, such as `mybot: help` or `mybot, help`. This is
+    /// disabled by default.
+    pub fn addressed(mut self, addressed: bool) -> Bot {
+        self.addressed = addressed;
+        self
+    }
+
+    /// Answer the given command.
+    pub fn command(mut self, command: Command) -> Bot {
+        self.commands.push(command);
+        self
+    }
+
+    /// Check each permitted use of a command against the limits of
+    /// `limiter`, ignoring the uses it limits.
+    pub fn limiter(mut self, limiter: CommandLimiter) -> Bot {
+        self.limiter = Some(Mutex::new(limiter));
+        self
+    }
+
+    // The prefix used and the text following it, if the message text holds
+    // a command.
+    fn strip_prefix<'a>(
+        &'a self,
+        text: &'a str,
+        private: bool,
+        state: &State,
+    ) -> Option<(&'a str, &'a str)> {
+        for prefix in &self.prefixes {
+            if let Some(rest) = text.strip_prefix(prefix.as_str()) {
+                return Some((prefix, rest));
+            }
+        }
+
+        if self.addressed {
+            if let Some(nick) = state.nick() {
+                let addressed = text
+                    .split_at_checked(nick.len())
+                    .filter(|&(start, _)| state.same_name(start, &nick))
+                    .and_then(|(_, rest)| rest.strip_prefix(&[':', ','][..]));
+
+                if let Some(rest) = addressed {
+                    return Some(("", rest.trim_start()));
+                }
+            }
+        }
+
+        if private {
+            return Some(("", text));
+        }
+
+        None
+    }
+}
+
+impl fmt::Debug for Bot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bot")
+            .field("prefixes", &self.prefixes)
+            .field("addressed", &self.addressed)
+            .field("commands", &self.commands)
+            .finish()
+    }
+}
+
+// Dispatches the commands received on a single connection to the handlers
+// of a `Bot`, and drives the handlers.
+pub(crate) struct Dispatcher {
+    bot: Arc<Bot>,
+    running: Vec<Box<dyn Future<Item = (), Error = Error> + Send>>,
+}
+
+impl Dispatcher {
+    pub fn new(bot: Arc<Bot>) -> Dispatcher {
+        Dispatcher {
+            bot: bot,
+            running: Vec::new(),
+        }
+    }
+
+    // Handles a message received from the server, starting the handler of
+    // the command it holds, if any. Replies are queued with `requester`.
+    pub fn handle_incoming(
+        &mut self,
+        message: &Message,
+        state: &State,
+        requester: &Requester,
+        events: &EventBus,
+    ) {
+        if let Some((handler, invocation)) = self.invocation(message, state, requester, events) {
+            self.running.push(handler(invocation));
+            self.poll(events);
+        }
+    }
+
+    // Drives the running handlers, reporting those that fail.
+    pub fn poll(&mut self, events: &EventBus) {
+        self.running.retain_mut(|handler| match handler.poll() {
+            Ok(Async::NotReady) => true,
+            Ok(Async::Ready(())) => false,
+            Err(err) => {
+                events.emit_failure(Automation::Bot, err);
+                false
+            }
+        });
+    }
+
+    // The use of a command held by a message, along with the command's
+    // handler, if it holds one the sender is allowed to use. Uses that are
+    // refused are answered here.
+    fn invocation(
+        &self,
+        message: &Message,
+        state: &State,
+        requester: &Requester,
+        events: &EventBus,
+    ) -> Option<(Arc<Handler>, Invocation)> {
+        if message.raw_command() != "PRIVMSG" {
+            return None;
+        }
+
+        let (nick, _, _) = message.prefix()?;
+
+        // The bot's own messages, such as those echoed by a bouncer, are
+        // never commands.
+        if state.is_self(nick) {
+            return None;
+        }
+
+        let mut args = message.raw_args();
+        let (target, text) = (args.next()?, args.next()?);
+        let channel = if state.is_channel(target) { Some(target) } else { None };
+
+        let (prefix, rest) = self.bot.strip_prefix(text, channel.is_none(), state)?;
+        let mut words = rest.split_whitespace();
+        let name = words.next()?;
+
+        let command = self.bot.commands.iter().find(|command| command.answers(name))?;
+
+        if !command.available_in(channel, state) {
+            return None;
+        }
+
+        let invocation = Invocation {
+            nick: nick.to_owned(),
+            channel: channel.map(|channel| channel.to_owned()),
+            command: command.name.clone(),
+            args: words.map(|word| word.to_owned()).collect(),
+            text: rest.trim_start()[name.len()..].trim().to_owned(),
+            message: message.clone(),
+            requester: requester.clone(),
+            state: state.clone(),
+        };
+
+        let values = Values::new()
+            .set("nick", nick)
+            .set("prefix", prefix)
+            .set("command", command.name.as_str());
+
+        let refusal = if !command.permission.allows(message, channel, state) {
+            Some(command.denied.as_ref().map(|denied| denied.render(&values)))
+        } else if invocation.args.len() < command.min_args {
+            Some(command.usage.as_ref().map(|usage| usage.render(&values)))
+        } else if let Some(ref limiter) = self.bot.limiter {
+            let decision = limiter
+                .lock()
+                .expect("Bot lock poisoned")
+                .check_on(state, &command.name, nick, channel);
+
+            match decision {
+                Decision::Allow => None,
+                Decision::Limited { response, .. } => Some(response),
+            }
+        } else {
+            None
+        };
+
+        match refusal {
+            None => Some((command.handler.clone(), invocation)),
+            Some(reply) => {
+                if let Some(reply) = reply {
+                    if let Err(err) = invocation.reply(&reply) {
+                        events.emit_failure(Automation::Bot, err);
+                    }
+                }
+
+                None
+            }
+        }
+    }
+}
+
+// Matches `text` against a pattern in which `*` matches any run of
+// characters and `?` any single character, ignoring ASCII case.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let text: Vec<char> = text.chars().map(|c| c.to_ascii_lowercase()).collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was seen, and the text it has matched up to.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
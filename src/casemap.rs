@@ -0,0 +1,127 @@
+//! The casemap module contains the types used to compare nicknames and
+//! channel names the way the server does.
+//!
+//! Names on IRC are case-insensitive, but which characters are considered to
+//! differ only by case depends on the server, which advertises it with the
+//! `CASEMAPPING` ISUPPORT token. Under the traditional `rfc1459` mapping,
+//! `[`, `]`, `\` and `~` are the upper case forms of `{`, `}`, `|` and `^`,
+//! so `Nick[away]` and `nick{away}` are the same user.
+//!
+//! The mapping of a connection is available from `State::casemapping`, and
+//! `IrcStr` wraps a name so that it can be compared and hashed according to
+//! it, such as when names are used as the keys of a `HashMap`.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// How the case of nicknames and channel names is folded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaseMapping {
+    /// Only the ASCII letters differ by case.
+    Ascii,
+    /// The ASCII letters differ by case, as do `[]\~` and `{}|^`. This is
+    /// assumed when the server doesn't advertise a mapping.
+    #[default]
+    Rfc1459,
+    /// The ASCII letters differ by case, as do `[]\` and `{}|`.
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    /// The mapping named by the value of the `CASEMAPPING` ISUPPORT token,
+    /// which is `None` when the server didn't advertise one. Mappings that
+    /// aren't known are treated as `Ascii`.
+    pub fn from_isupport(value: Option<&str>) -> CaseMapping {
+        match value {
+            None | Some("rfc1459") => CaseMapping::Rfc1459,
+            Some("strict-rfc1459") => CaseMapping::StrictRfc1459,
+            Some(_) => CaseMapping::Ascii,
+        }
+    }
+
+    /// Folds the case of the given name, so that names differing only by
+    /// case are equal once folded.
+    pub fn fold(&self, name: &str) -> String {
+        name.chars().map(|c| self.fold_char(c)).collect()
+    }
+
+    /// Returns true if the two names differ only by case.
+    pub fn equal(&self, a: &str, b: &str) -> bool {
+        a.len() == b.len()
+            && a.chars()
+                .zip(b.chars())
+                .all(|(a, b)| self.fold_char(a) == self.fold_char(b))
+    }
+
+    fn fold_char(&self, c: char) -> char {
+        match (*self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Rfc1459, '~') => '^',
+            (CaseMapping::Rfc1459, '[') | (CaseMapping::StrictRfc1459, '[') => '{',
+            (CaseMapping::Rfc1459, ']') | (CaseMapping::StrictRfc1459, ']') => '}',
+            (CaseMapping::Rfc1459, '\\') | (CaseMapping::StrictRfc1459, '\\') => '|',
+            _ => c,
+        }
+    }
+}
+
+/// A nickname or channel name which is compared and hashed according to a
+/// `CaseMapping`, while keeping the case it was given in.
+#[derive(Clone, Debug)]
+pub struct IrcStr {
+    name: String,
+    folded: String,
+}
+
+impl IrcStr {
+    /// Wrap the given name, comparing it according to `mapping`.
+    pub fn new<S: Into<String>>(name: S, mapping: CaseMapping) -> IrcStr {
+        let name = name.into();
+
+        IrcStr {
+            folded: mapping.fold(&name),
+            name: name,
+        }
+    }
+
+    /// The name, in the case it was given in.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// The name with its case folded.
+    pub fn folded(&self) -> &str {
+        &self.folded
+    }
+
+    /// Unwrap the name, in the case it was given in.
+    pub fn into_string(self) -> String {
+        self.name
+    }
+}
+
+impl PartialEq for IrcStr {
+    fn eq(&self, other: &IrcStr) -> bool {
+        self.folded == other.folded
+    }
+}
+
+impl Eq for IrcStr {}
+
+impl Hash for IrcStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.folded.hash(state);
+    }
+}
+
+impl fmt::Display for IrcStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl AsRef<str> for IrcStr {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
@@ -0,0 +1,298 @@
+//! The chathistory module contains support for the IRCv3
+//! `draft/chathistory` extension, which lets clients fetch the messages sent
+//! to a channel or user while they weren't connected.
+//!
+//! Requesting the capability, along with `server-time` so each message
+//! carries the time it was originally sent, is enabled with
+//! `Registration::chathistory`. History is then requested with
+//! `Requester::latest_history`, `Requester::history_before` and
+//! `Requester::history_after`, whose futures resolve to the messages of the
+//! batch the server answers with, oldest first.
+//!
+//! The messages of the batch are also yielded by the transport as usual, and
+//! can be told apart from live traffic with `State::is_history`.
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate tokio_irc_client;
+//! # use futures::Future;
+//! # use tokio_irc_client::request::Requester;
+//! use tokio_irc_client::chathistory::Reference;
+//!
+//! # fn example(requester: Requester) {
+//! let missed = requester
+//!     .latest_history("#rust", Some(Reference::MsgId("abc123".to_owned())), 100)
+//!     .map(|messages| for historical in messages {
+//!         println!("{:?} {}", historical.time, historical.message.raw_message());
+//!     });
+//! # }
+//! # fn main() {}
+//! ```
+
+use error::{ErrorKind, Result};
+use request::Pending;
+use state::State;
+
+use futures::sync::oneshot::Sender;
+
+use pircolate::Message;
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the capability allowing history to be requested.
+pub const CAPABILITY: &str = "draft/chathistory";
+
+/// The type of the batches holding the messages sent in response to a
+/// history request.
+pub const BATCH: &str = "chathistory";
+
+/// A point in the history of a conversation, from which messages are
+/// requested.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reference {
+    /// The message with the given id, as given by its `msgid` tag.
+    MsgId(String),
+    /// The given time.
+    Timestamp(SystemTime),
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Reference::MsgId(ref msgid) => write!(f, "msgid={}", msgid),
+            Reference::Timestamp(time) => write!(f, "timestamp={}", format_time(time)),
+        }
+    }
+}
+
+/// A message returned by a history request.
+#[derive(Clone, Debug)]
+pub struct HistoricalMessage {
+    /// When the message was originally sent, if the server said.
+    pub time: Option<SystemTime>,
+    /// The message itself.
+    pub message: Message,
+}
+
+/// The time a message was sent, as given by its `time` tag when the
+/// `server-time` capability is enabled.
+pub fn server_time(message: &Message) -> Option<SystemTime> {
+    message
+        .raw_tags()
+        .find(|&(key, _)| key == "time")
+        .and_then(|(_, time)| time)
+        .and_then(parse_time)
+}
+
+// Parses a time given as "YYYY-MM-DDThh:mm:ss[.sss]Z", in UTC.
+fn parse_time(time: &str) -> Option<SystemTime> {
+    let time = time.strip_suffix('Z')?;
+    let (date, clock) = time.split_at(time.find('T')?);
+    let (clock, fraction) = match clock[1..].find('.') {
+        Some(dot) => (&clock[1..dot + 1], &clock[dot + 2..]),
+        None => (&clock[1..], ""),
+    };
+
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let clock: Vec<u64> = clock.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+
+    if date.len() != 3 || clock.len() != 3 || fraction.len() > 9 {
+        return None;
+    }
+
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        let digits: u32 = fraction.parse().ok()?;
+        digits * 10u32.pow(9 - fraction.len() as u32)
+    };
+
+    let days = days_from_civil(date[0], date[1], date[2]);
+
+    if days < 0 {
+        return None;
+    }
+
+    let seconds = days as u64 * 86_400 + clock[0] * 3600 + clock[1] * 60 + clock[2];
+
+    Some(UNIX_EPOCH + Duration::new(seconds, nanos))
+}
+
+// Formats a time as "YYYY-MM-DDThh:mm:ss.sssZ", in UTC. Times before the
+// Unix epoch are given as the epoch itself.
+pub(crate) fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds % 86_400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// The number of days between the Unix epoch and the given date of the
+// proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The date of the proleptic Gregorian calendar the given number of days
+// after the Unix epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400;
+
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// A history request waiting for the batch holding its messages.
+pub(crate) struct HistoryRequest {
+    target: String,
+    // The reference tags of the batch holding the messages and of the
+    // batches nested inside it, once the server has opened it.
+    batches: Vec<String>,
+    messages: Vec<HistoricalMessage>,
+    sender: Option<Sender<Result<Vec<HistoricalMessage>>>>,
+}
+
+impl HistoryRequest {
+    pub(crate) fn new(
+        target: &str,
+        sender: Sender<Result<Vec<HistoricalMessage>>>,
+    ) -> HistoryRequest {
+        HistoryRequest {
+            target: target.to_owned(),
+            batches: Vec::new(),
+            messages: Vec::new(),
+            sender: Some(sender),
+        }
+    }
+
+    fn complete(&mut self, result: Result<Vec<HistoricalMessage>>) -> bool {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(result);
+        }
+
+        true
+    }
+
+    fn fail(&mut self, reason: String) -> bool {
+        let err = ErrorKind::HistoryUnavailable(self.target.clone(), reason);
+
+        self.complete(Err(err.into()))
+    }
+
+    // Handles a message received before the server opened the batch, which
+    // may open it or refuse the request.
+    fn handle_unopened(&mut self, message: &Message, state: &State) -> bool {
+        let args: Vec<&str> = message.raw_args().collect();
+
+        match message.raw_command() {
+            // "BATCH +<reference> chathistory <target>"
+            "BATCH" if args.len() > 2 && args[1] == BATCH => {
+                match args[0].strip_prefix('+') {
+                    Some(opened) if state.same_name(args[2], &self.target) => {
+                        self.batches.push(opened.to_owned());
+                    }
+                    _ => (),
+                }
+
+                false
+            }
+
+            // "FAIL CHATHISTORY <code> [<context>...] :<description>", where
+            // the context names the target when the failure concerns one.
+            "FAIL" if args.len() > 2 && args[0] == "CHATHISTORY" => {
+                let context = &args[2..args.len() - 1];
+
+                let concerns_target = context.iter().any(|arg| state.same_name(arg, &self.target));
+
+                if !context.is_empty() && !concerns_target {
+                    return false;
+                }
+
+                self.fail(format!("{}: {}", args[1], args[args.len() - 1]))
+            }
+
+            // ERR_UNKNOWNCOMMAND
+            "421" if args.get(1) == Some(&"CHATHISTORY") => {
+                self.fail(args.get(2).cloned().unwrap_or_default().to_owned())
+            }
+
+            _ => false,
+        }
+    }
+}
+
+impl Pending for HistoryRequest {
+    fn handle(&mut self, message: &Message, state: &State) -> bool {
+        if self.batches.is_empty() {
+            return self.handle_unopened(message, state);
+        }
+
+        let in_batch = message
+            .raw_tags()
+            .find(|&(key, _)| key == "batch")
+            .and_then(|(_, reference)| reference)
+            .is_some_and(|reference| self.batches.iter().any(|batch| batch == reference));
+
+        if message.raw_command() != "BATCH" {
+            if in_batch {
+                self.messages.push(HistoricalMessage {
+                    time: server_time(message),
+                    message: message.clone(),
+                });
+            }
+
+            return false;
+        }
+
+        let reference = message.raw_args().next().unwrap_or_default();
+
+        if let Some(opened) = reference.strip_prefix('+') {
+            // Batches nested inside the history, such as netsplits, hold
+            // historical messages too.
+            if in_batch {
+                self.batches.push(opened.to_owned());
+            }
+
+            return false;
+        }
+
+        if reference.get(1..) != Some(self.batches[0].as_str()) {
+            return false;
+        }
+
+        let mut messages = ::std::mem::take(&mut self.messages);
+
+        // Servers send history oldest first, but sorting keeps the order
+        // right when they don't, as long as every message has a time.
+        if messages.iter().all(|historical| historical.time.is_some()) {
+            messages.sort_by_key(|historical| historical.time);
+        }
+
+        self.complete(Ok(messages))
+    }
+}
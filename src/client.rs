@@ -1,30 +1,84 @@
 //! The client module contains all types needed to make a connection
 //! to a remote IRC host.
 
+use away::{AwayFilter, AwayPolicy};
+#[cfg(feature = "bot")]
+use bot::{Bot, Dispatcher};
+use clock::{self, Clock};
 use codec;
-use error::{Error, ErrorKind};
+#[cfg(not(target_arch = "wasm32"))]
+use connect::{SocketOptions, TcpConnect};
+use conversation::Conversation;
+use ctcp::{AutoResponder, Responder as CtcpResponder};
+use error::{Error, ErrorKind, Result};
+use event::{self, Automation, Event, EventBus, Events};
+use invite::{InviteHandler, InvitePolicy};
+use query::{Queries, Query};
+use ready::OnReady;
+use clock::Timer;
+use reconnect::{Backoff, Connecting, DisconnectPolicy, Reconnect};
+use registration::{Registrar, Registration};
+use replay::Replay;
+use request::{Requester, Response, Topic};
+#[cfg(feature = "rules")]
+use rules::Rules;
+use srv;
+use state::State;
+use stats::ChannelStats;
+use traffic::TrafficLog;
+use transform::{ErrorPolicy, Pipeline, Transform, Transforming};
+use watchdog::{StallAction, Verdict, Watch, Watchdog};
+use who::{Listing, Name, WhoEntry};
 
-use futures::{Async, Future, Poll, Sink, StartSend, Stream};
+use bytes::Bytes;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
 
-use pircolate::Message;
 use pircolate::message;
+use pircolate::Message;
 
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_core::net::TcpStream;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio_core::reactor::Handle;
-use tokio_core::net::{TcpStream, TcpStreamNew};
 
-use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
 
-#[cfg(feature = "tls")]
-use tokio_tls::{ConnectAsync, TlsConnectorExt, TlsStream};
 #[cfg(feature = "tls")]
 use native_tls::TlsConnector;
+#[cfg(feature = "websocket")]
+use std::io;
+#[cfg(feature = "tls")]
+use tls::{self, CertificateVerifier, WeakTlsPolicy};
+#[cfg(feature = "tls")]
+use tokio_tls::{ConnectAsync, TlsConnectorExt, TlsStream};
+#[cfg(feature = "websocket")]
+use websocket::WebSocketStream;
 
-use std::net::SocketAddr;
-use std::time;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
 
 const PING_TIMEOUT_IN_SECONDS: u64 = 10 * 60;
 
+// The longest line accepted by default, including its CRLF, as set by RFC
+// 1459. Once `message-tags` is enabled tags may take up to 8191 more bytes.
+const DEFAULT_MAX_LINE_LENGTH: usize = 512;
+
+/// What happens when the server sends a line longer than the limit set
+/// with `Client::max_line_length`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OversizedLinePolicy {
+    /// Skip the line, reporting it with `Event::LineDiscarded`.
+    #[default]
+    Discard,
+    /// Fail the stream with `LineTooLong`. The rest of the line is still
+    /// skipped if the stream is polled again.
+    Fail,
+}
+
 /// A light-weight client type for establishing connections to remote servers.
 /// This type consumes a given `SocketAddr` and provides several methods for
 /// establishing connections to a remote server.  Currently these methods
@@ -34,15 +88,409 @@ const PING_TIMEOUT_IN_SECONDS: u64 = 10 * 60;
 /// Each of the connection methods will return a future, that when successfully
 /// resolved, will provide a `Stream` that allows for communication with the
 /// remote server.
+#[derive(Clone)]
 pub struct Client {
-    host: SocketAddr,
+    // Only connected to where there's TCP, rather than on wasm32.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    addrs: Vec<SocketAddr>,
+    // The host names of the addresses located by `from_network`.
+    hosts: Vec<(SocketAddr, String)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    local_addr: Option<SocketAddr>,
+    #[cfg(not(target_arch = "wasm32"))]
+    socket_options: SocketOptions,
+    tls: bool,
+    backoff: Backoff,
+    restore_session: bool,
+    disconnect_policy: DisconnectPolicy,
+    config: TransportConfig,
+    #[cfg(feature = "tls")]
+    verifier: Option<Arc<dyn CertificateVerifier>>,
+    #[cfg(feature = "tls")]
+    weak_tls: WeakTlsPolicy,
+}
+
+// The configuration handed from a `Client` to each `IrcTransport` it creates.
+#[derive(Clone)]
+struct TransportConfig {
+    registration: Option<Registration>,
+    events: EventBus,
+    away_policy: AwayPolicy,
+    invite_policy: InvitePolicy,
+    ctcp: Option<CtcpResponder>,
+    watchdog: Option<Watchdog>,
+    clock: Arc<dyn Clock>,
+    inbound: Pipeline,
+    outbound: Pipeline,
+    preflight: bool,
+    replay_capacity: usize,
+    stats_window: Duration,
+    max_line_length: usize,
+    oversized_lines: OversizedLinePolicy,
+    traffic_log: Option<TrafficLog>,
+    on_ready: Option<OnReady>,
+    #[cfg(feature = "rules")]
+    responder: Option<Arc<Rules>>,
+    #[cfg(feature = "bot")]
+    bot: Option<Arc<Bot>>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> TransportConfig {
+        TransportConfig {
+            registration: None,
+            events: EventBus::default(),
+            away_policy: AwayPolicy::default(),
+            invite_policy: InvitePolicy::default(),
+            ctcp: None,
+            watchdog: None,
+            clock: clock::system(),
+            inbound: Pipeline::default(),
+            outbound: Pipeline::outbound(),
+            preflight: false,
+            replay_capacity: 0,
+            stats_window: Duration::from_secs(0),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            oversized_lines: OversizedLinePolicy::default(),
+            traffic_log: None,
+            on_ready: None,
+            #[cfg(feature = "rules")]
+            responder: None,
+            #[cfg(feature = "bot")]
+            bot: None,
+        }
+    }
 }
 
 impl Client {
     /// Create a new instance of `Client` that provides the ability to establish
     /// remote server connections with the specified host.
     pub fn new<H: Into<SocketAddr>>(host: H) -> Client {
-        Client { host: host.into() }
+        Client::with_addrs(Some(host.into()))
+    }
+
+    /// Create a new instance of `Client` that connects to whichever of the
+    /// given addresses of a server answers first. Attempts to each address
+    /// are raced as described by RFC 8305 ("Happy Eyeballs"), alternating
+    /// between IPv6 and IPv4 addresses and starting with IPv6.
+    pub fn with_addrs<I>(addrs: I) -> Client
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        Client {
+            addrs: addrs.into_iter().collect(),
+            hosts: Vec::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            local_addr: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            socket_options: SocketOptions::default(),
+            tls: false,
+            backoff: Backoff::default(),
+            restore_session: true,
+            disconnect_policy: DisconnectPolicy::default(),
+            config: TransportConfig::default(),
+            #[cfg(feature = "tls")]
+            verifier: None,
+            #[cfg(feature = "tls")]
+            weak_tls: WeakTlsPolicy::default(),
+        }
+    }
+
+    /// Create a new instance of `Client` that connects to the addresses the
+    /// given host name, such as `("irc.example.org", 6667)`, resolves to,
+    /// as with `Client::with_addrs`.
+    ///
+    /// The host name is resolved by the operating system, which blocks the
+    /// current thread until it completes.
+    pub fn resolve<A: ToSocketAddrs>(host: A) -> Result<Client> {
+        Ok(Client::with_addrs(host.to_socket_addrs()?))
+    }
+
+    /// Create a new instance of `Client` that connects to the servers of
+    /// the network at `domain`, located from the DNS SRV records it
+    /// publishes, as with `Client::with_addrs`. See the `srv` module for
+    /// details.
+    ///
+    /// When the servers found are for IRC over TLS, as `Client::tls` tells,
+    /// connect with `connect_tls`. The certificate of the server connected
+    /// to is then validated against the host name its address was found
+    /// at, such as the target of its SRV record, which is also sent with
+    /// SNI, rather than the domain given to `connect_tls`.
+    ///
+    /// The lookups block the current thread until they complete.
+    pub fn from_network(domain: &str) -> Result<Client> {
+        let service = srv::locate(domain)?;
+        let mut client = Client::with_addrs(service.servers.iter().map(|server| server.addr));
+
+        client.hosts = service
+            .servers
+            .into_iter()
+            .map(|server| (server.addr, server.host))
+            .collect();
+        client.tls = service.tls;
+        Ok(client)
+    }
+
+    /// Perform the given actions once a connection made by this client has
+    /// registered. See the `ready` module for details.
+    pub fn on_ready(mut self, on_ready: OnReady) -> Client {
+        self.config.on_ready = Some(on_ready);
+        self
+    }
+
+    /// Reply to the messages received by each connection made by this client
+    /// according to the given rules, sending the replies and relayed messages
+    /// of the rules that match. Webhook and ignore outcomes are left to the
+    /// application. See the `rules` module for details.
+    #[cfg(feature = "rules")]
+    pub fn responder(mut self, rules: Rules) -> Client {
+        self.config.responder = Some(Arc::new(rules));
+        self
+    }
+
+    /// Answer the commands received by each connection made by this client
+    /// with the handlers of the given bot, which are driven while the
+    /// connection is being polled. See the `bot` module for details.
+    #[cfg(feature = "bot")]
+    pub fn bot(mut self, bot: Bot) -> Client {
+        self.config.bot = Some(Arc::new(bot));
+        self
+    }
+
+    /// Bind the socket of each connection made by this client to the given
+    /// local address before connecting, choosing the address the connection
+    /// comes from on hosts with several. A port of 0 lets the operating
+    /// system choose the port.
+    ///
+    /// Only the server's addresses of the same family as the local address
+    /// are connected to.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn local_addr<A: Into<SocketAddr>>(mut self, addr: A) -> Client {
+        self.local_addr = Some(addr.into());
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the socket of each connection, sending messages
+    /// as soon as they're written rather than coalescing small writes.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn nodelay(mut self, nodelay: bool) -> Client {
+        self.socket_options.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enable the operating system's TCP keepalive on the socket of each
+    /// connection, probing the server once the connection has been idle for
+    /// `keepalive`, or disable it with `None`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> Client {
+        self.socket_options.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Set the time-to-live of the packets sent on each connection.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn ttl(mut self, ttl: u32) -> Client {
+        self.socket_options.ttl = Some(ttl);
+        self
+    }
+
+    /// Wait between attempts to reconnect as given by `backoff`, for the
+    /// connections made by `connect_reconnecting` and
+    /// `connect_tls_reconnecting`.
+    pub fn reconnect_backoff(mut self, backoff: Backoff) -> Client {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Whether the connections made by `connect_reconnecting` and
+    /// `connect_tls_reconnecting` restore the channels, away status and user
+    /// modes of the connection that was lost once they register. This is
+    /// enabled by default. See the `reconnect` module for details.
+    pub fn restore_session(mut self, restore: bool) -> Client {
+        self.restore_session = restore;
+        self
+    }
+
+    /// Handle the messages sent to the connections made by
+    /// `connect_reconnecting` and `connect_tls_reconnecting` while they're
+    /// disconnected as given by `policy`. By default sending fails.
+    pub fn disconnect_policy(mut self, policy: DisconnectPolicy) -> Client {
+        self.disconnect_policy = policy;
+        self
+    }
+
+    /// Register each connection made by this client with the given
+    /// `Registration`.  The registration sequence is sent as soon as the
+    /// connection is established, before any messages sent to the transport.
+    pub fn registration(mut self, registration: Registration) -> Client {
+        self.config.registration = Some(registration);
+        self
+    }
+
+    /// Checks the certificates presented to `connect_tls` with the given
+    /// `CertificateVerifier`, in addition to or instead of the default
+    /// validation.  See the `tls` module for the details of what the
+    /// verifier is responsible for.
+    ///
+    /// This isn't available on macOS, iOS or Windows, where the presented
+    /// certificates can't be retrieved.
+    #[cfg(all(
+        feature = "tls",
+        not(any(target_os = "macos", target_os = "windows", target_os = "ios"))
+    ))]
+    pub fn certificate_verifier<V>(mut self, verifier: V) -> Client
+    where
+        V: CertificateVerifier + 'static,
+    {
+        self.verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Set how connections made by `connect_tls` that negotiate an obsolete
+    /// protocol version or a weak cipher suite are handled. By default they
+    /// are reported with `Event::WeakTls`. See the `tls` module for details.
+    #[cfg(feature = "tls")]
+    pub fn weak_tls_policy(mut self, policy: WeakTlsPolicy) -> Client {
+        self.weak_tls = policy;
+        self
+    }
+
+    /// Set how PRIVMSG and NOTICE messages sent directly to users who are
+    /// marked as away are handled. By default they are always sent.
+    pub fn away_policy(mut self, policy: AwayPolicy) -> Client {
+        self.config.away_policy = policy;
+        self
+    }
+
+    /// Set how invitations of the client to channels are handled. By default
+    /// they are only reported with `Event::Invited`.
+    pub fn invite_policy(mut self, policy: InvitePolicy) -> Client {
+        self.config.invite_policy = policy;
+        self
+    }
+
+    /// Answer the CTCP VERSION, PING and TIME queries received by each
+    /// connection made by this client as set by `responder`. By default
+    /// CTCP queries are left to the application. See the `ctcp` module for
+    /// details.
+    pub fn ctcp_responder(mut self, responder: CtcpResponder) -> Client {
+        self.config.ctcp = Some(responder);
+        self
+    }
+
+    /// Watch each connection made by this client for going quiet, acting as
+    /// the `Watchdog` says once nothing has been received for a while. By
+    /// default only the PING timeout is applied. See the `watchdog` module
+    /// for details.
+    pub fn watchdog(mut self, watchdog: Watchdog) -> Client {
+        self.config.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Use the given `Clock` as the source of time for each connection made
+    /// by this client, instead of the system's monotonic clock.
+    pub fn clock<C>(mut self, clock: C) -> Client
+    where
+        C: Clock + 'static,
+    {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    /// Apply the given `Transform` to the text of each PRIVMSG and NOTICE
+    /// received from the server, after any previously installed inbound
+    /// transforms. See the `transform` module for details.
+    pub fn inbound_transform<X>(mut self, transform: X) -> Client
+    where
+        X: Transform + 'static,
+    {
+        self.config.inbound.push(Arc::new(transform));
+        self
+    }
+
+    /// Apply the given `Transform` to the text of each PRIVMSG and NOTICE
+    /// sent to the server, after any previously installed outbound
+    /// transforms.
+    pub fn outbound_transform<X>(mut self, transform: X) -> Client
+    where
+        X: Transform + 'static,
+    {
+        self.config.outbound.push(Arc::new(transform));
+        self
+    }
+
+    /// Set what happens to messages whose transforms fail. By default the
+    /// error is returned from the transport.
+    pub fn transform_error_policy(mut self, policy: ErrorPolicy) -> Client {
+        self.config.inbound.set_policy(policy);
+        self.config.outbound.set_policy(policy);
+        self
+    }
+
+    /// When enabled, PRIVMSG and NOTICE messages sent to a channel whose
+    /// tracked modes would prevent the client from sending to it fail with
+    /// `CannotSendToChannel` instead of being sent. These are +n when the
+    /// client isn't in the channel, +m when it doesn't have voice, and +R
+    /// when it isn't identified. The modes of each channel are queried with
+    /// MODE as soon as it's joined. Messages sent by handles, such as a
+    /// `Query`, are refused with `Event::AutomationFailed` instead.
+    pub fn send_preflight(mut self, enabled: bool) -> Client {
+        self.config.preflight = enabled;
+        self
+    }
+
+    /// Keep the last `capacity` messages received on each connection made by
+    /// this client, which can be read through `IrcTransport::replay`. By
+    /// default no messages are kept.
+    pub fn replay_buffer(mut self, capacity: usize) -> Client {
+        self.config.replay_capacity = capacity;
+        self
+    }
+
+    /// Keep statistics about the messages sent to each channel during the
+    /// given window of time on each connection made by this client, which
+    /// can be read through `IrcTransport::stats`. By default no statistics
+    /// are kept.
+    pub fn channel_stats(mut self, window: Duration) -> Client {
+        self.config.stats_window = window;
+        self
+    }
+
+    /// Accept lines of up to `length` bytes from the server, including their
+    /// CRLF, plus up to 8191 bytes of tags once `message-tags` is enabled.
+    /// Longer lines are handled according to the `OversizedLinePolicy`, and
+    /// are never buffered whole. By default the limit is 512 bytes.
+    pub fn max_line_length(mut self, length: usize) -> Client {
+        self.config.max_line_length = length;
+        self
+    }
+
+    /// Set what happens when the server sends a line longer than the limit.
+    /// By default the line is discarded.
+    pub fn oversized_lines(mut self, policy: OversizedLinePolicy) -> Client {
+        self.config.oversized_lines = policy;
+        self
+    }
+
+    /// Record the raw lines sent and received on each connection made by
+    /// this client to the given `TrafficLog`. See the `traffic` module for
+    /// details.
+    pub fn traffic_log(mut self, log: TrafficLog) -> Client {
+        self.config.traffic_log = Some(log);
+        self
+    }
+
+    /// Returns a `Stream` of the events observed on every connection made by
+    /// this client.
+    pub fn events(&self) -> Events {
+        self.config.events.subscribe()
+    }
+
+    /// Whether the servers of this client are for IRC over TLS, as found by
+    /// `Client::from_network`, and so should be connected to with
+    /// `connect_tls`.
+    pub fn tls(&self) -> bool {
+        self.tls
     }
 
     /// Returns a future, that when resolved provides an unecrypted `Stream`
@@ -52,10 +500,17 @@ impl Client {
     /// The resulting `Stream` can be `split` into a separate `Stream` for
     /// receiving `Message` from the server and a `Sink` for sending `Message`
     /// to the server.
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn connect(&self, handle: &Handle) -> ClientConnectFuture {
-        let tcp_stream = TcpStream::connect(&self.host, handle);
+        self.config.events.emit(Event::Connecting);
 
-        ClientConnectFuture { inner: tcp_stream }
+        let tcp_stream = TcpConnect::new(&self.addrs, self.local_addr, self.socket_options, handle)
+            .preamble(self.preamble());
+
+        ClientConnectFuture {
+            inner: tcp_stream,
+            config: self.config.clone(),
+        }
     }
 
     /// Returns a future, that when resolved provides a TLS encrypted `Stream`
@@ -67,47 +522,158 @@ impl Client {
     /// to the server.
     ///
     /// `domain` is the domain name of the remote server being connected to.
-    /// it is required to validate the security of the connection.
+    /// it is required to validate the security of the connection. Clients
+    /// made by `Client::from_network` use the host name of the server
+    /// connected to instead.
     #[cfg(feature = "tls")]
     pub fn connect_tls<D: Into<String>>(
         &self,
         handle: &Handle,
         domain: D,
     ) -> ClientConnectTlsFuture {
-        use self::ClientConnectTlsFuture::*;
+        self.config.events.emit(Event::Connecting);
 
-        let tls_connector = match TlsConnector::builder() {
-            Ok(tls_builder) => match tls_builder.build() {
-                Ok(connector) => connector,
-                Err(err) => {
-                    return TlsErr(ErrorKind::Tls(err).into());
-                }
-            },
-            Err(err) => {
-                return TlsErr(ErrorKind::Tls(err).into());
+        let state = match tls::connector(self.verifier.as_deref()) {
+            Ok(connector) => {
+                let tcp_stream =
+                    TcpConnect::new(&self.addrs, self.local_addr, self.socket_options, handle)
+                        .preamble(self.preamble());
+
+                TlsConnectState::TcpConnecting(tcp_stream, connector)
             }
+            Err(err) => TlsConnectState::Error(ErrorKind::Tls(err).into()),
         };
 
-        let tcp_stream = TcpStream::connect(&self.host, handle);
+        ClientConnectTlsFuture {
+            state: state,
+            domain: domain.into(),
+            hosts: self.hosts.clone(),
+            verifier: self.verifier.clone(),
+            weak_tls: self.weak_tls,
+            config: self.config.clone(),
+        }
+    }
 
-        TcpConnecting(tcp_stream, tls_connector, domain.into())
+    /// Returns a `Reconnect`, which connects to the server as with `connect`
+    /// and reconnects whenever the connection is lost. See the `reconnect`
+    /// module for details.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn connect_reconnecting(&self, handle: &Handle) -> Reconnect<TcpStream> {
+        let client = self.clone();
+        let connect_handle = handle.clone();
+
+        self.reconnecting_with(handle, move || Box::new(client.connect(&connect_handle)))
+    }
+
+    /// Returns a `Reconnect`, which connects to the server as with
+    /// `connect_tls` and reconnects whenever the connection is lost.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls_reconnecting<D: Into<String>>(
+        &self,
+        handle: &Handle,
+        domain: D,
+    ) -> Reconnect<TlsStream<TcpStream>> {
+        let client = self.clone();
+        let connect_handle = handle.clone();
+        let domain = domain.into();
+
+        self.reconnecting_with(handle, move || {
+            Box::new(client.connect_tls(&connect_handle, domain.clone()))
+        })
+    }
+
+    /// Returns a `Reconnect`, which connects with the futures `connect`
+    /// returns and reconnects whenever the connection is lost, following
+    /// this client's settings. A new future is made for each attempt, so
+    /// connections made another way, such as with `connect_websocket`, can
+    /// be kept open too.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn reconnecting_with<F, T>(&self, handle: &Handle, connect: F) -> Reconnect<T>
+    where
+        F: FnMut() -> Connecting<T> + 'static,
+        T: AsyncRead + AsyncWrite,
+    {
+        self.reconnecting_with_timer(handle.clone(), connect)
+    }
+
+    /// Returns a `Reconnect` like `reconnecting_with`, waiting between
+    /// attempts with the given `Timer` rather than tokio's reactor, for
+    /// targets such as `wasm32` which don't have one.
+    pub fn reconnecting_with_timer<F, T, M>(&self, timer: M, connect: F) -> Reconnect<T>
+    where
+        F: FnMut() -> Connecting<T> + 'static,
+        T: AsyncRead + AsyncWrite,
+        M: Timer + 'static,
+    {
+        Reconnect::new(
+            connect,
+            self.backoff,
+            self.config.clock.clone(),
+            timer,
+            self.config.events.clone(),
+            self.restore_session,
+            self.disconnect_policy,
+        )
+    }
+}
+
+impl Client {
+    /// Returns a `Stream` that can be used to receive `Message` from the
+    /// server and send `Message` to the server over the given connection,
+    /// which has already been established. The address given to
+    /// `Client::new` isn't used.
+    ///
+    /// This allows connections that have been wrapped, such as with the
+    /// `FaultInjector` of the `fault` module, to be used.
+    pub fn connect_stream<T>(&self, stream: T) -> Result<IrcTransport<T>>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        IrcTransport::new(stream, self.config.clone())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn preamble(&self) -> Option<Vec<u8>> {
+        self.config
+            .registration
+            .as_ref()
+            .and_then(Registration::preamble)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Client {
+    /// Returns a `Stream` that can be used to receive `Message` from the
+    /// server and send `Message` to the server over the given connected
+    /// WebSocket. The address given to `Client::new` isn't used.
+    ///
+    /// See the `websocket` module for how the socket is represented.
+    pub fn connect_websocket<S>(&self, socket: S) -> Result<IrcTransport<WebSocketStream<S>>>
+    where
+        S: Stream<Item = Vec<u8>, Error = io::Error>
+            + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+    {
+        self.connect_stream(WebSocketStream::new(socket))
     }
 }
 
 /// Represents a future, that when resolved provides an unecrypted `Stream`
 /// that can be used to receive `Message` from the server and send `Message`
 /// to the server.
+#[cfg(not(target_arch = "wasm32"))]
 pub struct ClientConnectFuture {
-    inner: TcpStreamNew,
+    inner: TcpConnect,
+    config: TransportConfig,
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl Future for ClientConnectFuture {
     type Item = IrcTransport<TcpStream>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let framed = try_ready!(self.inner.poll()).framed(codec::IrcCodec);
-        let irc_transport = IrcTransport::new(framed);
+        let tcp_stream = try_ready!(self.inner.poll());
+        let irc_transport = IrcTransport::new(tcp_stream, self.config.clone())?;
 
         Ok(Async::Ready(irc_transport))
     }
@@ -117,12 +683,19 @@ impl Future for ClientConnectFuture {
 /// that can be used to receive `Message` from the server and send `Message`
 /// to the server.
 #[cfg(feature = "tls")]
-pub enum ClientConnectTlsFuture {
-    #[doc(hidden)]
-    TlsErr(Error),
-    #[doc(hidden)]
-    TcpConnecting(TcpStreamNew, TlsConnector, String),
-    #[doc(hidden)]
+pub struct ClientConnectTlsFuture {
+    state: TlsConnectState,
+    domain: String,
+    hosts: Vec<(SocketAddr, String)>,
+    verifier: Option<Arc<dyn CertificateVerifier>>,
+    weak_tls: WeakTlsPolicy,
+    config: TransportConfig,
+}
+
+#[cfg(feature = "tls")]
+enum TlsConnectState {
+    Error(Error),
+    TcpConnecting(TcpConnect, TlsConnector),
     TlsHandshake(ConnectAsync<TcpStream>),
 }
 
@@ -141,35 +714,83 @@ pub enum ClientConnectTlsFuture {
 // `TlsConnector` to be created, an operation that can possibly fail, this
 // future may start in an error state and will immediately resolve with that
 // error on the next call to `poll`.
+//
+// When a `CertificateVerifier` has been supplied, it's consulted with the
+// presented certificates once the handshake completes. The handshake is
+// performed without the TLS library's own validation only if the verifier
+// replaces it, and the domain is sent with SNI either way. For a client made
+// by `from_network`, the domain is the host name of the address connected to.
+//
+// The negotiated protocol version and cipher suite are then checked against
+// the `WeakTlsPolicy`.
 #[cfg(feature = "tls")]
 impl Future for ClientConnectTlsFuture {
     type Item = IrcTransport<TlsStream<TcpStream>>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        use self::ClientConnectTlsFuture::*;
+        use self::TlsConnectState::*;
 
-        let connect_async = match *self {
-            TlsErr(ref mut error) => {
+        let connect_async = match self.state {
+            Error(ref mut error) => {
                 let error = ::std::mem::replace(error, ErrorKind::Unexpected.into());
                 return Err(error);
             }
 
             TlsHandshake(ref mut tls_connect_future) => {
-                let framed = try_ready!(tls_connect_future.poll()).framed(codec::IrcCodec);
-                let irc_transport = IrcTransport::new(framed);
+                let tls_stream = try_ready!(tls_connect_future.poll());
+
+                if let Some(ref verifier) = self.verifier {
+                    let chain = tls::peer_certificates(&tls_stream)?;
+
+                    if !verifier.verify(&chain, &self.domain) {
+                        return Err(ErrorKind::CertificateRejected(self.domain.clone()).into());
+                    }
+                }
+
+                self.config.events.emit(Event::TlsHandshakeComplete);
+
+                if self.weak_tls != WeakTlsPolicy::Allow {
+                    let weakness = tls::negotiated(&tls_stream).and_then(|negotiated| {
+                        negotiated.weakness().map(|reason| (negotiated, reason))
+                    });
+
+                    if let Some((negotiated, reason)) = weakness {
+                        self.config.events.emit(Event::WeakTls {
+                            protocol: negotiated.protocol,
+                            cipher: negotiated.cipher,
+                            reason: reason.clone(),
+                        });
+
+                        if self.weak_tls == WeakTlsPolicy::Refuse {
+                            return Err(ErrorKind::WeakTls(reason).into());
+                        }
+                    }
+                }
+
+                let irc_transport = IrcTransport::new(tls_stream, self.config.clone())?;
 
                 return Ok(Async::Ready(irc_transport));
             }
 
-            TcpConnecting(ref mut tcp_connect_future, ref mut tls_connector, ref domain) => {
-
+            TcpConnecting(ref mut tcp_connect_future, ref mut tls_connector) => {
                 let tcp_stream = try_ready!(tcp_connect_future.poll());
-                tls_connector.connect_async(&domain, tcp_stream)
+                let peer = tcp_stream.peer_addr().ok();
+                let host = self
+                    .hosts
+                    .iter()
+                    .find(|&&(addr, _)| Some(addr) == peer)
+                    .map(|(_, host)| host.clone());
+
+                if let Some(host) = host {
+                    self.domain = host;
+                }
+
+                tls_connector.connect_async(&self.domain, tcp_stream)
             }
         };
 
-        *self = ClientConnectTlsFuture::TlsHandshake(connect_async);
+        self.state = TlsHandshake(connect_async);
 
         Ok(Async::NotReady)
     }
@@ -182,23 +803,587 @@ impl Future for ClientConnectTlsFuture {
 ///
 /// It is possible to split `IrcTransport` into `Stream` and `Sink` via the
 /// the `split` method.
+///
+/// The transport also keeps track of the client's own nickname, user modes
+/// and hostmask as they're observed, which can be queried through the
+/// `State` handle returned by the `state` method.
 pub struct IrcTransport<T>
 where
     T: AsyncRead + AsyncWrite,
 {
     inner: Framed<T, codec::IrcCodec>,
-    last_ping: time::Instant,
+    clock: Arc<dyn Clock>,
+    last_ping: Duration,
+    state: State,
+    registrar: Option<Registrar>,
+    events: EventBus,
+    away: AwayFilter,
+    invites: InviteHandler,
+    ctcp: Option<AutoResponder>,
+    watch: Option<Watch>,
+    // Messages generated by the transport itself to run the protocol, such
+    // as PONG replies, registration and CTCP replies, which are sent ahead
+    // of any messages given to the `Sink` and aren't checked or transformed.
+    outgoing: VecDeque<Message>,
+    // Messages queued by handles such as a `Query`, and by an `OnReady` or
+    // a responder, which are checked and transformed like those given to
+    // the `Sink` before they're sent, along with the automation reporting
+    // their failures.
+    queued: VecDeque<(Message, Automation)>,
+    // The reason given by the server in an ERROR message, reported once the
+    // connection is closed.
+    closing_reason: Option<String>,
+    disconnected: bool,
+    inbound: Pipeline,
+    outbound: Pipeline,
+    // The messages currently being transformed. Only one message travelling
+    // in each direction is transformed at a time, keeping them in order.
+    receiving: Option<(Bytes, Transforming)>,
+    // A message sent is queued with the automation reporting its failures,
+    // unless it was given to the `Sink`.
+    sending: Option<(Transforming, Option<Automation>)>,
+    requester: Requester,
+    queries: Queries,
+    preflight: bool,
+    replay: Replay,
+    stats: ChannelStats,
+    on_ready: Option<OnReady>,
+    #[cfg(feature = "rules")]
+    responder: Option<Arc<Rules>>,
+    #[cfg(feature = "bot")]
+    bot: Option<Dispatcher>,
 }
 
 impl<T> IrcTransport<T>
 where
     T: AsyncRead + AsyncWrite,
 {
-    fn new(inner: Framed<T, codec::IrcCodec>) -> IrcTransport<T> {
-        IrcTransport {
-            inner: inner,
-            last_ping: time::Instant::now(),
+    fn new(stream: T, config: TransportConfig) -> Result<IrcTransport<T>> {
+        let state = State::default();
+        let codec = codec::IrcCodec::new(
+            config.max_line_length,
+            config.oversized_lines,
+            state.clone(),
+            config.events.clone(),
+            config.traffic_log.clone(),
+        );
+
+        let now = config.clock.now();
+        let mut irc_transport = IrcTransport {
+            inner: stream.framed(codec),
+            last_ping: now,
+            stats: ChannelStats::new(config.stats_window, config.clock.clone(), state.clone()),
+            clock: config.clock,
+            state: state.clone(),
+            registrar: config.registration.map(Registrar::new),
+            events: config.events,
+            away: AwayFilter::new(config.away_policy),
+            invites: InviteHandler::new(config.invite_policy),
+            ctcp: config.ctcp.map(AutoResponder::new),
+            watch: config.watchdog.map(|watchdog| Watch::new(watchdog, now)),
+            outgoing: VecDeque::new(),
+            queued: VecDeque::new(),
+            closing_reason: None,
+            disconnected: false,
+            inbound: config.inbound,
+            outbound: config.outbound,
+            receiving: None,
+            sending: None,
+            requester: Requester::new(state),
+            queries: Queries::default(),
+            preflight: config.preflight,
+            replay: Replay::new(config.replay_capacity),
+            on_ready: config.on_ready,
+            #[cfg(feature = "rules")]
+            responder: config.responder,
+            #[cfg(feature = "bot")]
+            bot: config.bot.map(Dispatcher::new),
+        };
+
+        if let Some(ref mut registrar) = irc_transport.registrar {
+            registrar.start(&mut irc_transport.outgoing)?;
         }
+
+        Ok(irc_transport)
+    }
+
+    /// Returns a handle to the state tracked for this connection. The handle
+    /// remains valid after the transport has been `split`.
+    pub fn state(&self) -> State {
+        self.state.clone()
+    }
+
+    /// Returns a `Stream` of the events observed on this connection.
+    pub fn events(&self) -> Events {
+        self.events.subscribe()
+    }
+
+    /// Returns a handle to the buffer of messages recently received on this
+    /// connection, which is empty unless enabled with `Client::replay_buffer`.
+    /// The handle remains valid after the transport has been dropped.
+    pub fn replay(&self) -> Replay {
+        self.replay.clone()
+    }
+
+    /// Returns a handle to the statistics kept about the channels of this
+    /// connection, which are empty unless enabled with
+    /// `Client::channel_stats`. The handle remains valid after the transport
+    /// has been dropped.
+    pub fn stats(&self) -> ChannelStats {
+        self.stats.clone()
+    }
+
+    /// Returns a handle for sending requests on this connection, which
+    /// remains valid after the transport has been `split`.
+    pub fn requester(&self) -> Requester {
+        self.requester.clone()
+    }
+
+    /// Open a private conversation with the user `nick`. See the `query`
+    /// module for details.
+    pub fn query(&self, nick: &str) -> Query {
+        self.queries.open(nick, self.requester.clone())
+    }
+
+    /// Open a conversation in the channel, or with the user, named `target`.
+    /// See the `conversation` module for details.
+    pub fn conversation(&self, target: &str) -> Conversation {
+        Conversation::new(
+            target,
+            &self.queries,
+            self.requester.clone(),
+            self.state.clone(),
+            self.replay.clone(),
+            self.events.clone(),
+        )
+    }
+
+    /// Join `channel`, returning a future that resolves once the join has
+    /// been confirmed. See `Requester::join` for details.
+    pub fn join(&self, channel: &str, key: Option<&str>) -> Response<()> {
+        self.requester.join(channel, key)
+    }
+
+    /// Leave `channel`, returning a future that resolves once the server has
+    /// confirmed it. See `Requester::part` for details.
+    pub fn part(&self, channel: &str, reason: Option<&str>) -> Response<()> {
+        self.requester.part(channel, reason)
+    }
+
+    /// List the members of `channel`. See `Requester::names` for details.
+    pub fn names(&self, channel: &str) -> Response<Listing<Name>> {
+        self.requester.names(channel)
+    }
+
+    /// Query the users matching `mask` with WHO. See `Requester::who` for
+    /// details.
+    pub fn who(&self, mask: &str) -> Response<Listing<WhoEntry>> {
+        self.requester.who(mask)
+    }
+
+    /// Query the given fields of the users matching `mask` with WHOX. See
+    /// `Requester::whox` for details.
+    pub fn whox(&self, mask: &str, fields: &str) -> Response<Listing<WhoEntry>> {
+        self.requester.whox(mask, fields)
+    }
+
+    /// Query the topic of `channel`. See `Requester::topic` for details.
+    pub fn topic(&self, channel: &str) -> Response<Topic> {
+        self.requester.topic(channel)
+    }
+
+    /// Set the topic of `channel`, returning a future that resolves once the
+    /// change has been confirmed. See `Requester::set_topic` for details.
+    pub fn set_topic(&self, channel: &str, text: &str) -> Response<()> {
+        self.requester.set_topic(channel, text)
+    }
+
+    /// Mark the client as away. See `Requester::set_away` for details.
+    pub fn set_away(&self, reason: &str) -> Response<()> {
+        self.requester.set_away(reason)
+    }
+
+    /// Mark the client as no longer away. See `Requester::set_back` for
+    /// details.
+    pub fn set_back(&self) -> Response<()> {
+        self.requester.set_back()
+    }
+
+    // Attempts to send all of the messages generated by the transport, and
+    // those queued by handles.
+    fn poll_outgoing(&mut self) -> Poll<(), Error> {
+        let queued = self.requester.take_queued();
+
+        self.queued
+            .extend(queued.into_iter().map(|message| (message, Automation::Queued)));
+
+        // Queued messages are transformed one at a time, while the messages
+        // generated by the transport are sent regardless.
+        while self.poll_sending()?.is_ready() {
+            let (message, action) = match self.queued.pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+
+            match self.prepare(message) {
+                Ok(Some(sending)) => self.sending = Some((sending, Some(action))),
+                Ok(None) => (),
+                Err(err) => self.events.emit_failure(action, err),
+            }
+        }
+
+        while let Some(message) = self.outgoing.pop_front() {
+            self.state.handle_outgoing(&message);
+
+            if let AsyncSink::NotReady(message) = self.inner.start_send(message)? {
+                self.outgoing.push_front(message);
+                return Ok(Async::NotReady);
+            }
+        }
+
+        Ok(self.inner.poll_complete()?)
+    }
+
+    // Checks a message sent by the user and starts transforming it, or else
+    // queues it to be sent unless it's held for an away user.
+    fn prepare(&mut self, message: Message) -> Result<Option<Transforming>> {
+        codec::check_line(message.raw_message().as_bytes())?;
+
+        if self.preflight {
+            self.check_restrictions(&message)?;
+        }
+
+        let sending = {
+            let peer = message.raw_args().next().unwrap_or_default();
+            self.outbound.start(&message, peer, &self.state)
+        };
+
+        if sending.is_none() {
+            if let Some(message) = self.away.filter(message, &self.state) {
+                self.outgoing.push_back(message);
+            }
+        }
+
+        Ok(sending)
+    }
+
+    // Drives the transforms of the message being sent, queueing it to be
+    // sent once they complete.
+    fn poll_sending(&mut self) -> Poll<(), Error> {
+        let transformed = match self.sending {
+            Some((ref mut sending, _)) => sending.poll(),
+            None => return Ok(Async::Ready(())),
+        };
+
+        let transformed = match transformed {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(transformed)) => Ok(transformed),
+            Err(err) => Err(err),
+        };
+
+        let transformed = match (transformed, self.sending.take()) {
+            (Ok(transformed), _) => transformed,
+            // Failures of queued messages are reported, as whatever queued
+            // them has already moved on.
+            (Err(err), Some((_, Some(action)))) => {
+                self.events.emit_failure(action, err);
+                Vec::new()
+            }
+            (Err(err), _) => return Err(err),
+        };
+
+        for message in transformed {
+            if let Some(message) = self.away.filter(message, &self.state) {
+                self.outgoing.push_back(message);
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    // Drives the transforms of the message being received, returning it once
+    // they complete unless it was dropped.
+    fn poll_receiving(&mut self) -> Poll<Option<codec::Line>, Error> {
+        let transformed = match self.receiving {
+            Some((_, ref mut receiving)) => receiving.poll(),
+            None => return Ok(Async::Ready(None)),
+        };
+
+        if let Ok(Async::NotReady) = transformed {
+            return Ok(Async::NotReady);
+        }
+
+        let raw = match self.receiving.take() {
+            Some((raw, _)) => raw,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        // Incoming text isn't split, so there's at most one message.
+        match transformed? {
+            Async::Ready(mut messages) if !messages.is_empty() => {
+                Ok(Async::Ready(Some(codec::Line {
+                    raw: raw,
+                    message: Ok(messages.remove(0)),
+                })))
+            }
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+
+    /// Converts this transport into a `RawIrcTransport`, which provides the
+    /// raw line received from the server alongside each parsed `Message`.
+    pub fn raw(self) -> RawIrcTransport<T> {
+        RawIrcTransport { inner: self }
+    }
+
+    // Receives the next line from the server, handling any PING requests
+    // and keeping track of the state of the connection.
+    fn poll_line(&mut self) -> Poll<Option<codec::Line>, Error> {
+        let since_ping = self.clock.now().saturating_sub(self.last_ping);
+
+        if since_ping.as_secs() >= PING_TIMEOUT_IN_SECONDS {
+            let error: Error = ErrorKind::PingTimeout.into();
+
+            self.events.emit(Event::PingTimeout);
+            self.disconnect(error.to_string());
+            self.close()?;
+
+            return Err(error);
+        }
+
+        self.poll_watchdog()?;
+        self.requester.register();
+
+        #[cfg(feature = "bot")]
+        {
+            if let Some(ref mut bot) = self.bot {
+                bot.poll(&self.events);
+            }
+        }
+
+        self.poll_outgoing()?;
+
+        loop {
+            if let Some(line) = try_ready!(self.poll_receiving()) {
+                if let Ok(ref message) = line.message {
+                    self.handle_received(message)?;
+                }
+
+                return Ok(Async::Ready(Some(line)));
+            }
+
+            let line = match self.inner.poll() {
+                Ok(Async::Ready(Some(line))) => {
+                    if let Some(ref mut watch) = self.watch {
+                        watch.received(self.clock.now());
+                    }
+
+                    line
+                }
+                Ok(Async::Ready(None)) => {
+                    let reason = self
+                        .closing_reason
+                        .take()
+                        .unwrap_or_else(|| ErrorKind::ConnectionReset.to_string());
+
+                    self.disconnect(reason);
+                    return Ok(Async::Ready(None));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    self.disconnect(err.to_string());
+                    return Err(err);
+                }
+            };
+
+            if let Ok(ref message) = line.message {
+                if message.raw_command() == "PING" {
+                    self.last_ping = self.clock.now();
+
+                    if let Some(host) = message.raw_args().next() {
+                        match message::client::pong(host) {
+                            Ok(pong) => self.outgoing.push_back(pong),
+                            Err(err) => self.events.emit_failure(Automation::Pong, err),
+                        }
+
+                        self.poll_outgoing()?;
+                    }
+
+                    continue;
+                }
+
+                // The rest of the transport only sees the message once
+                // it's been transformed, and not at all if it's dropped.
+                let peer = inbound_peer(message, &self.state);
+
+                if let Some(receiving) = self.inbound.start(message, peer, &self.state) {
+                    self.receiving = Some((line.raw, receiving));
+                    continue;
+                }
+
+                self.handle_received(message)?;
+            }
+
+            return Ok(Async::Ready(Some(line)));
+        }
+    }
+
+    // Records and acts on a message received from the server, once any
+    // inbound transforms have been applied to it.
+    fn handle_received(&mut self, message: &Message) -> Result<()> {
+        self.replay.record(message);
+        self.handle_incoming(message)?;
+        self.poll_outgoing()?;
+
+        Ok(())
+    }
+
+    // Acts on the watchdog if nothing has been received for too long,
+    // failing when the connection is given up on.
+    fn poll_watchdog(&mut self) -> Result<()> {
+        let verdict = match self.watch {
+            Some(ref mut watch) => watch.check(self.clock.now()),
+            None => return Ok(()),
+        };
+
+        match verdict {
+            Verdict::Fine => return Ok(()),
+            Verdict::Stalled(idle, action) => {
+                self.events.emit(Event::Stalled { idle: idle });
+
+                match action {
+                    StallAction::Probe => {
+                        match Message::try_from("PING :watchdog".to_owned()) {
+                            Ok(ping) => self.outgoing.push_back(ping),
+                            Err(err) => self.events.emit_failure(Automation::Watchdog, err),
+                        }
+
+                        self.poll_outgoing()?;
+                        return Ok(());
+                    }
+                    StallAction::Report => return Ok(()),
+                    StallAction::Reconnect => (),
+                }
+            }
+            Verdict::Unanswered => (),
+        }
+
+        let error: Error = ErrorKind::Stalled.into();
+
+        self.disconnect(error.to_string());
+        self.close()?;
+
+        Err(error)
+    }
+
+    // How long until the watchdog is next due, if it will be before more
+    // traffic is received.
+    pub(crate) fn watchdog_due(&self) -> Option<Duration> {
+        let deadline = self.watch.as_ref()?.deadline()?;
+
+        Some(deadline.saturating_sub(self.clock.now()))
+    }
+
+    // Fails if the tracked modes of the channel a message is sent to would
+    // prevent it from being delivered.
+    fn check_restrictions(&self, message: &Message) -> Result<()> {
+        let channel = match message.raw_command() {
+            "PRIVMSG" | "NOTICE" => match message.raw_args().next() {
+                Some(target) if self.state.is_channel(target) => target,
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        match self.state.send_restriction(channel) {
+            Some(mode) => Err(ErrorKind::CannotSendToChannel(channel.to_owned(), mode).into()),
+            None => Ok(()),
+        }
+    }
+
+    // Reports that the connection was lost, at most once.
+    fn disconnect(&mut self, reason: String) {
+        if !self.disconnected {
+            self.disconnected = true;
+            self.requester.abort();
+            self.events.emit(Event::Disconnected { reason: reason });
+        }
+    }
+
+    fn handle_incoming(&mut self, message: &Message) -> Result<()> {
+        self.state.handle_incoming(message);
+
+        // The modes of a channel aren't sent when joining it, so they have
+        // to be asked for.
+        if self.preflight && message.raw_command() == "JOIN" {
+            let from_self = match message.prefix() {
+                Some((nick, _, _)) => self.state.is_self(nick),
+                None => false,
+            };
+
+            if let (true, Some(channel)) = (from_self, message.raw_args().next()) {
+                self.outgoing
+                    .push_back(Message::try_from(format!("MODE {}", channel))?);
+            }
+        }
+
+        if message.raw_command() == "ERROR" {
+            self.closing_reason = message.raw_args().next().map(|reason| reason.to_owned());
+        }
+
+        if let Some(ref mut registrar) = self.registrar {
+            registrar.handle_incoming(message, &self.state, &mut self.outgoing, &self.events)?;
+        }
+
+        self.away
+            .handle_incoming(message, &self.state, &mut self.outgoing);
+
+        if let Some(ref mut ctcp) = self.ctcp {
+            let now = self.clock.now();
+
+            ctcp.handle_incoming(message, &self.state, now, &mut self.outgoing);
+        }
+
+        // The messages of an `OnReady` and a responder are the user's own,
+        // so they're queued like those of handles.
+        if let Some(ref on_ready) = self.on_ready {
+            let mut messages = VecDeque::new();
+
+            on_ready.handle_incoming(message, &self.state, &mut messages, &self.events);
+            self.queued
+                .extend(messages.into_iter().map(|message| (message, Automation::OnReady)));
+        }
+
+        #[cfg(feature = "rules")]
+        {
+            if let Some(ref responder) = self.responder {
+                let mut messages = VecDeque::new();
+
+                responder.handle_incoming(message, &self.state, &mut messages, &self.events);
+                self.queued
+                    .extend(messages.into_iter().map(|message| (message, Automation::Responder)));
+            }
+        }
+
+        self.requester.handle_incoming(message, &self.state);
+
+        #[cfg(feature = "bot")]
+        {
+            if let Some(ref mut bot) = self.bot {
+                bot.handle_incoming(message, &self.state, &self.requester, &self.events);
+            }
+        }
+
+        self.queries.handle_incoming(message, &self.state);
+        self.invites
+            .handle_incoming(message, &self.state, &self.requester, &self.events);
+        self.stats.record(message);
+
+        if let Some(event) = event::from_message(message, &self.state) {
+            self.events.emit(event);
+        }
+
+        Ok(())
     }
 }
 
@@ -210,31 +1395,158 @@ where
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        if self.last_ping.elapsed().as_secs() >= PING_TIMEOUT_IN_SECONDS {
-            self.close()?;
-            return Err(ErrorKind::ConnectionReset.into());
+        match try_ready!(self.poll_line()) {
+            Some(line) => Ok(Async::Ready(Some(line.message?))),
+            None => Ok(Async::Ready(None)),
         }
+    }
+}
 
-        loop {
-            match try_ready!(self.inner.poll()) {
-                Some(ref message) if message.raw_command() == "PING" => {
-                    self.last_ping = time::Instant::now();
+impl<T> Sink for IrcTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type SinkItem = Message;
+    type SinkError = Error;
 
-                    if let Some(host) = message.raw_args().next() {
-                        let result = self.inner.start_send(message::client::pong(host)?)?;
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        // Messages generated by the transport, such as the registration
+        // sequence, and those queued by handles must reach the server first.
+        if self.poll_outgoing()?.is_not_ready() || self.sending.is_some() {
+            return Ok(AsyncSink::NotReady(item));
+        }
 
-                        assert!(result.is_ready());
+        if let Some(sending) = self.prepare(item)? {
+            self.sending = Some((sending, None));
+        }
 
-                        self.inner.poll_complete()?;
-                    }
-                }
-                message => return Ok(Async::Ready(message)),
-            }
+        self.poll_outgoing()?;
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_outgoing());
+
+        // The message being transformed was polled by `poll_outgoing`.
+        if self.sending.is_some() {
+            return Ok(Async::NotReady);
         }
+
+        Ok(Async::Ready(()))
     }
 }
 
-impl<T> Sink for IrcTransport<T>
+// The other end of the conversation a message received from the server
+// belongs to, which is either the channel it was sent to or its sender.
+fn inbound_peer<'a>(message: &'a Message, state: &State) -> &'a str {
+    match message.raw_args().next() {
+        Some(target) if state.is_channel(target) => target,
+        _ => match message.prefix() {
+            Some((nick, _, _)) => nick,
+            None => "",
+        },
+    }
+}
+
+/// A line received from the server by a `RawIrcTransport`.
+#[derive(Debug)]
+pub enum RawMessage {
+    /// A line that was parsed into a `Message`, along with the exact bytes
+    /// of the line, excluding the trailing CRLF.
+    Parsed(Message, Bytes),
+    /// A line that couldn't be parsed, such as one that isn't valid UTF-8,
+    /// excluding the trailing CRLF.
+    Unparsed(Bytes),
+}
+
+impl RawMessage {
+    /// The exact bytes of the line, excluding the trailing CRLF.
+    pub fn raw(&self) -> &Bytes {
+        match *self {
+            RawMessage::Parsed(_, ref raw) | RawMessage::Unparsed(ref raw) => raw,
+        }
+    }
+
+    /// The parsed `Message`, if the line could be parsed.
+    pub fn message(&self) -> Option<&Message> {
+        match *self {
+            RawMessage::Parsed(ref message, _) => Some(message),
+            RawMessage::Unparsed(_) => None,
+        }
+    }
+}
+
+/// `RawIrcTransport` is an `IrcTransport` that yields each line received from
+/// the server as a `RawMessage`, which carries the raw line alongside the
+/// parsed `Message`. Lines that can't be parsed are yielded as
+/// `RawMessage::Unparsed` rather than causing the stream to error.
+///
+/// PING requests are still answered by the transport and aren't yielded, and
+/// messages are sent through the `Sink` in the same way as with
+/// `IrcTransport`.
+pub struct RawIrcTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    inner: IrcTransport<T>,
+}
+
+impl<T> RawIrcTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    /// Returns a handle to the state tracked for this connection.
+    pub fn state(&self) -> State {
+        self.inner.state()
+    }
+
+    /// Returns a `Stream` of the events observed on this connection.
+    pub fn events(&self) -> Events {
+        self.inner.events()
+    }
+
+    /// Returns a handle to the buffer of messages recently received on this
+    /// connection.
+    pub fn replay(&self) -> Replay {
+        self.inner.replay()
+    }
+
+    /// Returns a handle to the statistics kept about the channels of this
+    /// connection.
+    pub fn stats(&self) -> ChannelStats {
+        self.inner.stats()
+    }
+
+    /// Returns a handle for sending requests on this connection.
+    pub fn requester(&self) -> Requester {
+        self.inner.requester()
+    }
+}
+
+impl<T> Stream for RawIrcTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type Item = RawMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let line = match try_ready!(self.inner.poll_line()) {
+            Some(line) => line,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let raw_message = match line.message {
+            Ok(message) => RawMessage::Parsed(message, line.raw),
+            Err(_) => RawMessage::Unparsed(line.raw),
+        };
+
+        Ok(Async::Ready(Some(raw_message)))
+    }
+}
+
+impl<T> Sink for RawIrcTransport<T>
 where
     T: AsyncRead + AsyncWrite,
 {
@@ -242,10 +1554,10 @@ where
     type SinkError = Error;
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        Ok(self.inner.start_send(item)?)
+        self.inner.start_send(item)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        Ok(self.inner.poll_complete()?)
+        self.inner.poll_complete()
     }
 }
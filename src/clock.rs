@@ -0,0 +1,112 @@
+//! The clock module contains the traits used to abstract over the sources of
+//! time used by this crate, so that its protocol handling can run on targets
+//! where `std::time::Instant` and tokio's timers aren't available, such as
+//! `wasm32` in a browser.
+//!
+//! A `Clock` tells the time, which is used for things like noticing when the
+//! server has stopped sending PINGs and for rate limiting. A `Timer` creates
+//! futures that resolve after a delay. By default the system's monotonic
+//! clock is used; other targets can provide their own implementations, for
+//! example on top of `performance.now()` and `setTimeout`.
+//!
+//! `wasm32` has neither, so there a `Clock` must be given to everything that
+//! tells the time, such as with `Client::clock`, and a `Timer` to
+//! `Client::reconnecting_with_timer`. Using the default clock there panics.
+
+use error::Error;
+#[cfg(not(target_arch = "wasm32"))]
+use error::ErrorKind;
+
+use futures::Future;
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_core::reactor::{Handle, Timeout};
+
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+/// A monotonic source of time.
+pub trait Clock: Send + Sync {
+    /// The time elapsed since some fixed point chosen by the clock, such as
+    /// when it was created. The value must never decrease.
+    fn now(&self) -> Duration;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> Duration + Send + Sync,
+{
+    fn now(&self) -> Duration {
+        self()
+    }
+}
+
+/// A `Clock` based on the system's monotonic clock, measuring the time since
+/// the clock was created.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SystemClock {
+    /// Create a new `SystemClock` starting from the current instant.
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A future which resolves once a delay has passed.
+pub type Delay = Box<dyn Future<Item = (), Error = Error>>;
+
+/// A source of futures which resolve after a delay.
+pub trait Timer {
+    /// Returns a future which resolves once `duration` has passed.
+    fn delay(&self, duration: Duration) -> Delay;
+}
+
+// Timers are provided by tokio's reactor by default, where it's available.
+#[cfg(not(target_arch = "wasm32"))]
+impl Timer for Handle {
+    fn delay(&self, duration: Duration) -> Delay {
+        match Timeout::new(duration, self) {
+            Ok(timeout) => Box::new(timeout.map_err(|err| ErrorKind::Io(err).into())),
+            Err(err) => Box::new(::futures::future::err(ErrorKind::Io(err).into())),
+        }
+    }
+}
+
+// The clock used when none has been configured.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock::new())
+}
+
+// There's no system clock to fall back to on wasm32, so rather than letting
+// time stand still, which would stall rate limits, using the default fails
+// loudly.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn system() -> Arc<dyn Clock> {
+    Arc::new(|| -> Duration {
+        panic!("wasm32 has no system clock, so a Clock must be configured")
+    })
+}
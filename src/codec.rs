@@ -1,40 +1,454 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use tokio_io::codec::{Decoder, Encoder};
 
 use pircolate::Message;
 
-use super::error::{Error, Result};
+use super::client::OversizedLinePolicy;
+use super::error::{Error, ErrorKind, Result};
+use super::event::{Automation, Event, EventBus};
+use super::state::State;
+use super::traffic::{Direction, TrafficLog};
 
-const DELIMETER_LENGTH: usize = 2;
+// The most bytes the tags of a message may take up once `message-tags` is
+// enabled, including the leading '@' and the trailing space.
+const MAX_TAGS_LENGTH: usize = 8191;
 
-pub struct IrcCodec;
+// CTCP's low-level quoting character. Within CTCP messages, NUL, CR, LF and
+// the quoting character itself are escaped so they can't break the framing
+// of the message they're carried in.
+const M_QUOTE: u8 = 0x10;
+
+pub struct IrcCodec {
+    // The longest line accepted, including its CRLF, before any tags.
+    max_line_length: usize,
+    policy: OversizedLinePolicy,
+    state: State,
+    events: EventBus,
+    log: Option<TrafficLog>,
+    // The length of the oversized line being skipped, if there is one.
+    discarding: Option<usize>,
+    // How much of the buffer is known not to contain the end of a line, so
+    // that a line arriving over several reads is only searched once.
+    searched: usize,
+}
+
+impl IrcCodec {
+    pub fn new(
+        max_line_length: usize,
+        policy: OversizedLinePolicy,
+        state: State,
+        events: EventBus,
+        log: Option<TrafficLog>,
+    ) -> IrcCodec {
+        IrcCodec {
+            max_line_length: max_line_length,
+            policy: policy,
+            state: state,
+            events: events,
+            log: log,
+            discarding: None,
+            searched: 0,
+        }
+    }
+
+    // Tags are allowed on top of the line's length once they're enabled.
+    fn limit(&self) -> usize {
+        if self.state.has_capability("message-tags") {
+            self.max_line_length + MAX_TAGS_LENGTH
+        } else {
+            self.max_line_length
+        }
+    }
+
+    fn record(&self, direction: Direction, line: &[u8]) {
+        if let Some(ref log) = self.log {
+            if let Err(err) = log.record(direction, line) {
+                self.events.emit_failure(Automation::TrafficLog, err);
+            }
+        }
+    }
+
+    // Skips the oversized line at the start of `buffer`, of which `length`
+    // bytes have arrived, including its end if `ended`.
+    fn oversized(&mut self, buffer: &mut BytesMut, length: usize, ended: bool) -> Result<()> {
+        buffer.split_to(length);
+        self.discarding = Some(length);
+        self.searched = 0;
+
+        if ended {
+            self.discarded();
+        }
+
+        match self.policy {
+            OversizedLinePolicy::Discard => Ok(()),
+            OversizedLinePolicy::Fail => Err(ErrorKind::LineTooLong(self.limit()).into()),
+        }
+    }
+
+    // Reports the oversized line that has been skipped.
+    fn discarded(&mut self) {
+        if let Some(length) = self.discarding.take() {
+            if self.policy == OversizedLinePolicy::Discard {
+                self.events.emit(Event::LineDiscarded { length: length });
+            }
+        }
+    }
+}
+
+// A line received from the server, along with the result of parsing it.
+// Lines that can't be parsed don't fail the stream, so that the transport
+// can decide whether to pass them on.
+pub struct Line {
+    pub raw: Bytes,
+    pub message: Result<Message>,
+}
 
 impl Decoder for IrcCodec {
-    type Item = Message;
+    type Item = Line;
     type Error = Error;
 
+    // Lines longer than the limit are skipped as they arrive, rather than
+    // buffered until their end, so a server can't exhaust the client's memory
+    // by never ending a line.
     fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>> {
-        if let Some(index) = buffer.iter().position(|&b| b == b'\n') {
-            let command = buffer.split_to(index - 1);
-            buffer.split_to(DELIMETER_LENGTH);
+        loop {
+            let newline = buffer[self.searched..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map(|index| self.searched + index);
 
-            Ok(Some(
-                Message::try_from(String::from_utf8(command.to_vec())?)?,
-            ))
-        } else {
-            Ok(None)
+            self.searched = match newline {
+                Some(_) => 0,
+                None => buffer.len(),
+            };
+
+            if let Some(length) = self.discarding {
+                match newline {
+                    Some(index) => {
+                        buffer.split_to(index + 1);
+                        self.discarding = Some(length + index + 1);
+                        self.discarded();
+                        continue;
+                    }
+                    None => {
+                        self.discarding = Some(length + buffer.len());
+                        self.searched = 0;
+                        buffer.clear();
+                        return Ok(None);
+                    }
+                }
+            }
+
+            let limit = self.limit();
+
+            match newline {
+                Some(index) if index < limit => {
+                    let mut raw = buffer.split_to(index + 1);
+
+                    // Lines are meant to end with CRLF, but some servers
+                    // only send LF.
+                    let end = if raw.ends_with(b"\r\n") { index - 1 } else { index };
+                    raw.truncate(end);
+
+                    let raw = raw.freeze();
+                    self.record(Direction::Received, &raw);
+
+                    return Ok(Some(Line {
+                        message: parse(&raw),
+                        raw: raw,
+                    }));
+                }
+                Some(index) => self.oversized(buffer, index + 1, true)?,
+                None if buffer.len() >= limit => {
+                    let length = buffer.len();
+
+                    self.oversized(buffer, length, false)?;
+                    return Ok(None);
+                }
+                None => return Ok(None),
+            }
         }
     }
 }
 
+// A `Message` owns its text, so parsing copies the line once, with CTCP
+// dequoting making the copy rather than adding another, and the copy is
+// checked for UTF-8 where it is. pircolate then allocates once more for the
+// positions of the arguments, and again for tags. The raw line given out
+// alongside the message is a slice of the receive buffer, which isn't copied.
+pub(crate) fn parse(raw: &[u8]) -> Result<Message> {
+    let command = if is_ctcp(raw) {
+        low_level_dequote(raw)
+    } else {
+        raw.to_vec()
+    };
+
+    Ok(Message::try_from(String::from_utf8(command)?)?)
+}
+
 impl Encoder for IrcCodec {
     type Item = Message;
     type Error = Error;
 
     fn encode(&mut self, message: Self::Item, buffer: &mut BytesMut) -> Result<()> {
-        buffer.extend(message.raw_message().as_bytes());
+        let raw_message = message.raw_message().as_bytes();
+
+        check_line(raw_message)?;
+        self.record(Direction::Sent, raw_message);
+
+        if is_ctcp(raw_message) {
+            buffer.extend(low_level_quote(raw_message));
+        } else {
+            buffer.extend(raw_message);
+        }
+
         buffer.extend(b"\r\n");
 
         Ok(())
     }
 }
+
+// Fails for a line which would end early on the wire, letting whoever built
+// it send more lines. CTCP messages are exempt, as quoting them escapes
+// these characters.
+pub(crate) fn check_line(line: &[u8]) -> Result<()> {
+    if is_ctcp(line) || !line.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+        return Ok(());
+    }
+
+    let line = String::from_utf8_lossy(line).into_owned();
+
+    Err(ErrorKind::InvalidParameter(line, "it contains CR, LF or NUL".to_owned()).into())
+}
+
+// Determines whether a raw line carries a CTCP message, which begins its
+// trailing parameter with the \x01 delimiter.
+fn is_ctcp(line: &[u8]) -> bool {
+    line.windows(3).any(|window| window == b" :\x01")
+}
+
+fn low_level_quote(line: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(line.len());
+
+    for &byte in line {
+        match byte {
+            b'\0' => quoted.extend_from_slice(&[M_QUOTE, b'0']),
+            b'\n' => quoted.extend_from_slice(&[M_QUOTE, b'n']),
+            b'\r' => quoted.extend_from_slice(&[M_QUOTE, b'r']),
+            M_QUOTE => quoted.extend_from_slice(&[M_QUOTE, M_QUOTE]),
+            byte => quoted.push(byte),
+        }
+    }
+
+    quoted
+}
+
+fn low_level_dequote(line: &[u8]) -> Vec<u8> {
+    let mut dequoted = Vec::with_capacity(line.len());
+    let mut bytes = line.iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte != M_QUOTE {
+            dequoted.push(byte);
+            continue;
+        }
+
+        // Unknown escapes drop the quoting character, per the CTCP spec.
+        match bytes.next() {
+            Some(&b'0') => dequoted.push(b'\0'),
+            Some(&b'n') => dequoted.push(b'\n'),
+            Some(&b'r') => dequoted.push(b'\r'),
+            Some(&other) => dequoted.push(other),
+            None => (),
+        }
+    }
+
+    dequoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use event::Events;
+
+    use futures::{future, Async, Future, Poll, Stream};
+
+    fn codec(max_line_length: usize, policy: OversizedLinePolicy) -> (IrcCodec, Events) {
+        let events = EventBus::default();
+        let subscription = events.subscribe();
+        let codec = IrcCodec::new(max_line_length, policy, State::default(), events, None);
+
+        (codec, subscription)
+    }
+
+    fn decode(codec: &mut IrcCodec, buffer: &mut BytesMut) -> Option<String> {
+        codec.decode(buffer).unwrap().map(|line| {
+            assert!(line.message.is_ok());
+            String::from_utf8(line.raw.to_vec()).unwrap()
+        })
+    }
+
+    fn discarded(events: &mut Events) -> Vec<usize> {
+        let mut lengths = Vec::new();
+
+        future::poll_fn(|| -> Poll<(), Error> {
+            while let Async::Ready(Some(event)) = events.poll()? {
+                if let Event::LineDiscarded { length } = event {
+                    lengths.push(length);
+                }
+            }
+
+            Ok(Async::Ready(()))
+        })
+        .wait()
+        .unwrap();
+
+        lengths
+    }
+
+    #[test]
+    fn lines_split_across_reads() {
+        let (mut codec, _) = codec(512, OversizedLinePolicy::Discard);
+        let mut buffer = BytesMut::from(&b"PRIVMSG #rust :hel"[..]);
+
+        assert_eq!(decode(&mut codec, &mut buffer), None);
+
+        buffer.extend(b"lo\r");
+        assert_eq!(decode(&mut codec, &mut buffer), None);
+
+        buffer.extend(b"\nPING");
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PRIVMSG #rust :hello".to_owned()));
+        assert_eq!(decode(&mut codec, &mut buffer), None);
+
+        buffer.extend(b" :irc.example.org\r\n");
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PING :irc.example.org".to_owned()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn lines_ending_with_only_lf() {
+        let (mut codec, _) = codec(512, OversizedLinePolicy::Discard);
+        let mut buffer = BytesMut::from(&b"PING :a\nPING :b\r\nPING :c\n"[..]);
+
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PING :a".to_owned()));
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PING :b".to_owned()));
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PING :c".to_owned()));
+        assert_eq!(decode(&mut codec, &mut buffer), None);
+    }
+
+    #[test]
+    fn lines_at_the_limit_are_accepted() {
+        let (mut codec, mut events) = codec(16, OversizedLinePolicy::Discard);
+        // 14 bytes and the CRLF.
+        let mut buffer = BytesMut::from(&b"PRIVMSG #a :ok\r\n"[..]);
+
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PRIVMSG #a :ok".to_owned()));
+        assert!(discarded(&mut events).is_empty());
+    }
+
+    #[test]
+    fn lines_over_the_limit_are_discarded() {
+        let (mut codec, mut events) = codec(16, OversizedLinePolicy::Discard);
+        let mut buffer = BytesMut::from(&b"PRIVMSG #a :ok!\r\nPING :x\r\n"[..]);
+
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PING :x".to_owned()));
+        assert_eq!(discarded(&mut events), vec![17]);
+    }
+
+    #[test]
+    fn lines_over_the_limit_are_discarded_across_reads() {
+        let (mut codec, mut events) = codec(16, OversizedLinePolicy::Discard);
+        let mut buffer = BytesMut::from(&b"PRIVMSG #rust :a long"[..]);
+
+        // The line is skipped as it arrives, rather than buffered.
+        assert_eq!(decode(&mut codec, &mut buffer), None);
+        assert!(buffer.is_empty());
+
+        buffer.extend(b" line that goes");
+        assert_eq!(decode(&mut codec, &mut buffer), None);
+        assert!(buffer.is_empty());
+        assert!(discarded(&mut events).is_empty());
+
+        buffer.extend(b" on\r\nPING :x\r\n");
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PING :x".to_owned()));
+        assert_eq!(discarded(&mut events), vec![41]);
+    }
+
+    #[test]
+    fn lines_over_the_limit_fail_the_stream() {
+        let (mut codec, mut events) = codec(16, OversizedLinePolicy::Fail);
+        let mut buffer = BytesMut::from(&b"PRIVMSG #rust :a long"[..]);
+
+        match codec.decode(&mut buffer) {
+            Err(Error(ErrorKind::LineTooLong(16), _)) => (),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("the line was accepted"),
+        }
+
+        // The rest of the line is still skipped.
+        buffer.extend(b" line\r\nPING :x\r\n");
+        assert_eq!(decode(&mut codec, &mut buffer), Some("PING :x".to_owned()));
+        assert!(discarded(&mut events).is_empty());
+    }
+
+    #[test]
+    fn ctcp_quoting_round_trips() {
+        let line = b"PRIVMSG #rust :\x01PING a\0b\rc\nd\x10e\x01";
+        let quoted = low_level_quote(line);
+
+        assert_eq!(&quoted[..], &b"PRIVMSG #rust :\x01PING a\x100b\x10rc\x10nd\x10\x10e\x01"[..]);
+        assert!(!quoted.iter().any(|&b| b == b'\0' || b == b'\r' || b == b'\n'));
+        assert_eq!(low_level_dequote(&quoted), &line[..]);
+    }
+
+    #[test]
+    fn ctcp_messages_are_quoted_on_the_wire() {
+        let (mut codec, _) = codec(512, OversizedLinePolicy::Discard);
+        let message = Message::try_from("PRIVMSG #rust :\x01PING a\x10b\x01".to_owned()).unwrap();
+        let mut buffer = BytesMut::new();
+
+        codec.encode(message.clone(), &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &b"PRIVMSG #rust :\x01PING a\x10\x10b\x01\r\n"[..]);
+
+        let line = codec.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(line.message.unwrap().raw_message(), message.raw_message());
+    }
+
+    #[test]
+    fn unknown_ctcp_escapes_drop_the_quoting_character() {
+        assert_eq!(low_level_dequote(b":\x01A\x10x\x10"), &b":\x01Ax"[..]);
+    }
+
+    #[test]
+    fn lines_without_ctcp_are_not_dequoted() {
+        let message = parse(b"PRIVMSG #rust :a\x10nb").unwrap();
+
+        assert_eq!(message.raw_message(), "PRIVMSG #rust :a\x10nb");
+    }
+
+    #[test]
+    fn lines_which_would_inject_another_are_refused() {
+        let (mut codec, _) = codec(512, OversizedLinePolicy::Discard);
+        let mut buffer = BytesMut::new();
+
+        for raw in &["PRIVMSG #rust :hi\r\nQUIT :bye", "PRIVMSG #rust :hi\nQUIT", "PART #a\0b"] {
+            let message = Message::try_from((*raw).to_owned()).unwrap();
+
+            assert!(codec.encode(message, &mut buffer).is_err());
+        }
+
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn line_breaks_in_ctcp_messages_are_quoted_instead() {
+        let (mut codec, _) = codec(512, OversizedLinePolicy::Discard);
+        let raw = "PRIVMSG #rust :\x01PING a\r\nQUIT\x01".to_owned();
+        let message = Message::try_from(raw).unwrap();
+        let mut buffer = BytesMut::new();
+
+        codec.encode(message, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &b"PRIVMSG #rust :\x01PING a\x10r\x10nQUIT\x01\r\n"[..]);
+    }
+}
@@ -0,0 +1,22 @@
+//! The command module contains strongly typed representations of commands
+//! that aren't provided by `pircolate`, for use with `Message::command`.
+
+command! {
+    /// Represents an IRCv3 CHGHOST command, sent when a user's username or
+    /// host changes. The first element is the new username and the second
+    /// element is the new host.
+    ("CHGHOST" => ChgHost(user, host))
+}
+
+command! {
+    /// Represents an IRCv3 SETNAME command, sent when a user's real name
+    /// changes. The first element is the new real name.
+    ("SETNAME" => SetName(real_name))
+}
+
+command! {
+    /// Represents an INVITE command, sent when a user invites another to a
+    /// channel. The first element is the nickname of the invited user and the
+    /// second element is the channel.
+    ("INVITE" => Invite(target, channel))
+}
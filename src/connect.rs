@@ -0,0 +1,231 @@
+// Establishes TCP connections to a server with several addresses, racing
+// attempts to each of them as described by RFC 8305 ("Happy Eyeballs").
+//
+// The addresses are interleaved by family, starting with IPv6, and attempted
+// in that order. A new attempt is started whenever the previous one fails, or
+// once it has gone unanswered for a short delay, while the earlier attempts
+// are left running. The first attempt to succeed is used and the others are
+// dropped.
+//
+// When a local address is given, every socket is bound to it before
+// connecting, so only addresses of the same family as it are attempted.
+//
+// The socket options are applied to the stream of the attempt that succeeds,
+// and any preamble, such as a PROXY protocol header, is then written to it
+// raw, before it's handed to TLS or the codec.
+
+use clock::{Delay, Timer};
+use error::{Error, ErrorKind};
+
+use futures::{Async, Future, Poll};
+
+use socket2::{Domain, Socket, Type};
+
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+use tokio_io::io::{write_all, WriteAll};
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// How long an attempt is given before the next one is started, as
+// recommended by RFC 8305.
+const ATTEMPT_DELAY_IN_MILLISECONDS: u64 = 250;
+
+type Attempt = Box<dyn Future<Item = TcpStream, Error = io::Error> + Send>;
+
+// The options set on the socket of each connection. Those left unset keep the
+// operating system's defaults.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SocketOptions {
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<Option<Duration>>,
+    pub ttl: Option<u32>,
+}
+
+impl SocketOptions {
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+
+        if let Some(keepalive) = self.keepalive {
+            stream.set_keepalive(keepalive)?;
+        }
+
+        if let Some(ttl) = self.ttl {
+            stream.set_ttl(ttl)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) struct TcpConnect {
+    handle: Handle,
+    local: Option<SocketAddr>,
+    options: SocketOptions,
+    remaining: VecDeque<SocketAddr>,
+    attempts: Vec<Attempt>,
+    delay: Option<Delay>,
+    error: Option<io::Error>,
+    preamble: Option<Vec<u8>>,
+    writing: Option<Box<WriteAll<TcpStream, Vec<u8>>>>,
+}
+
+impl TcpConnect {
+    pub fn new(
+        addrs: &[SocketAddr],
+        local: Option<SocketAddr>,
+        options: SocketOptions,
+        handle: &Handle,
+    ) -> TcpConnect {
+        let mut remaining = interleave(addrs);
+
+        if let Some(local) = local {
+            remaining.retain(|addr| addr.is_ipv6() == local.is_ipv6());
+        }
+
+        let mut connect = TcpConnect {
+            handle: handle.clone(),
+            local: local,
+            options: options,
+            remaining: remaining,
+            attempts: Vec::new(),
+            delay: None,
+            error: None,
+            preamble: None,
+            writing: None,
+        };
+
+        connect.start_next();
+        connect
+    }
+
+    // Write the given bytes to the stream once it's connected.
+    pub fn preamble(mut self, preamble: Option<Vec<u8>>) -> TcpConnect {
+        self.preamble = preamble;
+        self
+    }
+
+    fn start_next(&mut self) {
+        self.delay = None;
+
+        if let Some(addr) = self.remaining.pop_front() {
+            let attempt = match self.local {
+                Some(local) => match bound_socket(local) {
+                    Ok(socket) => TcpStream::connect_stream(socket, &addr, &self.handle),
+                    Err(err) => Box::new(::futures::future::err(err)),
+                },
+                None => Box::new(TcpStream::connect(&addr, &self.handle)),
+            };
+
+            self.attempts.push(attempt);
+
+            if !self.remaining.is_empty() {
+                let delay = Duration::from_millis(ATTEMPT_DELAY_IN_MILLISECONDS);
+                self.delay = Some(self.handle.delay(delay));
+            }
+        }
+    }
+}
+
+impl Future for TcpConnect {
+    type Item = TcpStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(ref mut writing) = self.writing {
+                let (stream, _) = try_ready!(writing.poll().map_err(ErrorKind::Io));
+                return Ok(Async::Ready(stream));
+            }
+
+            let mut failed = false;
+            let mut i = 0;
+
+            while i < self.attempts.len() {
+                match self.attempts[i].poll() {
+                    Ok(Async::Ready(stream)) => {
+                        self.options.apply(&stream).map_err(ErrorKind::Io)?;
+
+                        match self.preamble.take() {
+                            Some(preamble) => {
+                                self.attempts.clear();
+                                self.remaining.clear();
+                                self.delay = None;
+                                self.writing = Some(Box::new(write_all(stream, preamble)));
+                                break;
+                            }
+                            None => return Ok(Async::Ready(stream)),
+                        }
+                    }
+                    Ok(Async::NotReady) => i += 1,
+                    Err(err) => {
+                        drop(self.attempts.swap_remove(i));
+                        self.error = Some(err);
+                        failed = true;
+                    }
+                }
+            }
+
+            if self.writing.is_some() {
+                continue;
+            }
+
+            // A failed attempt is followed by the next one straight away,
+            // rather than waiting out the delay.
+            if failed && !self.remaining.is_empty() {
+                self.start_next();
+                continue;
+            }
+
+            let delayed = match self.delay {
+                Some(ref mut delay) => delay.poll()?.is_ready(),
+                None => false,
+            };
+
+            if delayed {
+                self.start_next();
+                continue;
+            }
+
+            if self.attempts.is_empty() && self.remaining.is_empty() {
+                let error = match self.error.take() {
+                    Some(err) => err,
+                    None => io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to"),
+                };
+
+                return Err(ErrorKind::Io(error).into());
+            }
+
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+fn bound_socket(local: SocketAddr) -> io::Result<::std::net::TcpStream> {
+    let socket = Socket::new(Domain::for_address(local), Type::STREAM, None)?;
+
+    socket.bind(&local.into())?;
+    Ok(socket.into())
+}
+
+// Orders the addresses so that the two families alternate, starting with
+// IPv6, keeping the order of the addresses within each family.
+fn interleave(addrs: &[SocketAddr]) -> VecDeque<SocketAddr> {
+    let mut v6 = addrs.iter().filter(|addr| addr.is_ipv6());
+    let mut v4 = addrs.iter().filter(|addr| addr.is_ipv4());
+
+    let mut ordered = VecDeque::with_capacity(addrs.len());
+
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second).cloned()),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! The conversation module contains `Conversation`, a handle scoped to a
+//! single channel or user.
+//!
+//! A `Conversation` is obtained from `IrcTransport::conversation`, and
+//! gathers everything needed to take part in one conversation: as a
+//! `Stream` it yields the messages sent to the channel, or sent to the
+//! client by the user, and it has methods to send to, reply in and show
+//! typing in the conversation, to read its recent history, and to watch the
+//! events concerning it. A bot module handling a single channel can be
+//! given just its `Conversation`, which keeps it self-contained and easy to
+//! test.
+//!
+//! Conversations with users follow them through nickname changes, as with
+//! `Query`. Messages only reach a `Conversation` while the transport it was
+//! obtained from, or the `Stream` half of it, is being polled.
+//!
+//! Text containing CR, LF or NUL, which would end the message early and let
+//! the rest of the text be sent as another line, is refused with
+//! `InvalidParameter`.
+
+use error::{Error, Result};
+use event::{Event, EventBus, Events};
+use outgoing;
+use query::{self, Queries};
+use replay::Replay;
+use request::Requester;
+use state::State;
+use tagmsg;
+
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::UnboundedReceiver;
+
+use pircolate::Message;
+
+use std::sync::{Arc, Mutex};
+
+pub use tagmsg::Typing;
+
+/// A handle for taking part in a conversation in a channel or with a user.
+/// See the module documentation for details.
+pub struct Conversation {
+    target: Arc<Mutex<String>>,
+    is_channel: bool,
+    messages: UnboundedReceiver<Message>,
+    requester: Requester,
+    state: State,
+    replay: Replay,
+    events: EventBus,
+}
+
+impl Conversation {
+    pub(crate) fn new(
+        target: &str,
+        queries: &Queries,
+        requester: Requester,
+        state: State,
+        replay: Replay,
+        events: EventBus,
+    ) -> Conversation {
+        let is_channel = state.is_channel(target);
+        let (target, messages) = queries.subscribe(target, is_channel);
+
+        Conversation {
+            target: target,
+            is_channel: is_channel,
+            messages: messages,
+            requester: requester,
+            state: state,
+            replay: replay,
+            events: events,
+        }
+    }
+
+    /// The channel or the current nickname of the user the conversation is
+    /// with.
+    pub fn target(&self) -> String {
+        self.target.lock().expect("Conversation lock poisoned").clone()
+    }
+
+    /// Returns true if the conversation is in a channel.
+    pub fn is_channel(&self) -> bool {
+        self.is_channel
+    }
+
+    /// Send a PRIVMSG with the given text to the conversation.
+    pub fn send(&self, text: &str) -> Result<()> {
+        outgoing::check_trailing(text)?;
+        self.send_raw(format!("PRIVMSG {} :{}", self.target(), text))
+    }
+
+    /// Send a NOTICE with the given text to the conversation.
+    pub fn notice(&self, text: &str) -> Result<()> {
+        outgoing::check_trailing(text)?;
+        self.send_raw(format!("NOTICE {} :{}", self.target(), text))
+    }
+
+    /// Reply to a message of the conversation with the given text. When the
+    /// server supports `message-tags` and the message has an id, the reply is
+    /// tagged as one so clients can show which message it answers. Otherwise
+    /// replies in a channel are addressed to the sender by name.
+    pub fn reply(&self, to: &Message, text: &str) -> Result<()> {
+        outgoing::check_trailing(text)?;
+
+        let target = self.target();
+
+        let msgid = to.raw_tags()
+            .find(|&(key, _)| key == "msgid")
+            .and_then(|(_, msgid)| msgid);
+
+        match (msgid, to.prefix()) {
+            (Some(msgid), _) if self.state.has_capability("message-tags") => {
+                self.send_raw(format!("@+draft/reply={} PRIVMSG {} :{}", msgid, target, text))
+            }
+            (_, Some((nick, _, _))) if self.is_channel => {
+                self.send_raw(format!("PRIVMSG {} :{}: {}", target, nick, text))
+            }
+            _ => self.send_raw(format!("PRIVMSG {} :{}", target, text)),
+        }
+    }
+
+    /// Show other users whether the client is typing in the conversation.
+    /// This does nothing when the server doesn't support `message-tags`.
+    pub fn typing(&self, typing: Typing) -> Result<()> {
+        if !self.state.has_capability("message-tags") {
+            return Ok(());
+        }
+
+        self.requester.send(tagmsg::typing(&self.target(), typing)?)
+    }
+
+    /// The messages of the conversation still held by the connection's
+    /// replay buffer, oldest first. This is empty unless enabled with
+    /// `Client::replay_buffer`.
+    pub fn history(&self) -> Vec<Message> {
+        let target = self.target();
+
+        self.replay
+            .messages()
+            .into_iter()
+            .filter(|message| query::belongs(message, &target, self.is_channel, &self.state))
+            .collect()
+    }
+
+    /// Returns a `Stream` of the events concerning the conversation, such as
+    /// mode changes in its channel or the user it's with going away.
+    pub fn events(&self) -> ConversationEvents {
+        ConversationEvents {
+            inner: self.events.subscribe(),
+            target: self.target.clone(),
+            is_channel: self.is_channel,
+            state: self.state.clone(),
+        }
+    }
+
+    fn send_raw(&self, raw: String) -> Result<()> {
+        self.requester.send(Message::try_from(raw)?)
+    }
+}
+
+impl Stream for Conversation {
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.messages.poll() {
+            Ok(ready) => Ok(ready),
+            // Polling an unbounded receiver never fails.
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+/// A `Stream` of the events concerning a `Conversation`, returned by
+/// `Conversation::events`.
+pub struct ConversationEvents {
+    inner: Events,
+    target: Arc<Mutex<String>>,
+    is_channel: bool,
+    state: State,
+}
+
+impl ConversationEvents {
+    fn concerns(&self, event: &Event) -> bool {
+        let target = self.target.lock().expect("Conversation lock poisoned");
+        let is_target = |name: &str| self.state.same_name(name, &target);
+
+        // Events about a user concern the channels they're in.
+        let concerns_user = |nick: &str| {
+            if !self.is_channel {
+                return is_target(nick);
+            }
+
+            match self.state.channel(&target) {
+                Some(channel) => channel
+                    .members
+                    .iter()
+                    .any(|(member, _)| self.state.same_name(member, nick)),
+                None => false,
+            }
+        };
+
+        match *event {
+            Event::HostChanged { ref nick, .. }
+            | Event::RealNameChanged { ref nick, .. }
+            | Event::AwayChanged { ref nick, .. } => concerns_user(nick),
+            Event::Invited { ref channel, .. } => is_target(channel),
+            Event::ModeChanged { ref target, .. }
+            | Event::PlaybackStarted { ref target }
+            | Event::PlaybackFinished { ref target } => is_target(target),
+            _ => false,
+        }
+    }
+}
+
+impl Stream for ConversationEvents {
+    type Item = Event;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(event) => if self.concerns(&event) {
+                    return Ok(Async::Ready(Some(event)));
+                },
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
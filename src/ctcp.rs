@@ -0,0 +1,230 @@
+//! The ctcp module contains helpers for sending and receiving CTCP messages,
+//! which are carried inside PRIVMSG (for queries) and NOTICE (for replies)
+//! with their contents wrapped in `\x01` delimiters.
+//!
+//! The low-level quoting CTCP requires for NUL, CR and LF characters is
+//! handled by the transport, so the payloads given to and returned from
+//! these helpers are always unquoted.
+//!
+//! Setting a `Responder` on a `Client` makes the `IrcTransport` answer the
+//! VERSION, PING and TIME queries it receives. Replies are rate limited, both
+//! per user and overall, and queries over the limits are ignored, so a flood
+//! of queries can't make the client flood itself off the server.
+
+use chathistory;
+use error::Result;
+use limit::Counter;
+use state::State;
+
+use pircolate::Message;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+const DELIMITER: char = '\x01';
+
+/// The VERSION reply given by a `Responder` unless another is set.
+pub const DEFAULT_VERSION: &str = concat!("tokio-irc-client ", env!("CARGO_PKG_VERSION"));
+
+/// Which CTCP queries are answered automatically, and how often.
+#[derive(Clone, Debug)]
+pub struct Responder {
+    version: Option<String>,
+    ping: bool,
+    time: bool,
+    limit: (u32, Duration),
+    per_user: (u32, Duration),
+}
+
+impl Responder {
+    /// Create a new `Responder` answering VERSION with `DEFAULT_VERSION`,
+    /// PING with its parameters, and TIME with the current time in UTC. By
+    /// default at most 4 replies are sent every 10 seconds, and at most 2 of
+    /// them to the same user.
+    pub fn new() -> Responder {
+        Responder {
+            version: Some(DEFAULT_VERSION.to_owned()),
+            ping: true,
+            time: true,
+            limit: (4, Duration::from_secs(10)),
+            per_user: (2, Duration::from_secs(10)),
+        }
+    }
+
+    /// Answer VERSION with the given string, such as the name and version of
+    /// the bot, rather than `DEFAULT_VERSION`.
+    pub fn version<V: Into<String>>(mut self, version: V) -> Responder {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Whether VERSION is answered.
+    pub fn answer_version(mut self, answer: bool) -> Responder {
+        self.version = match (answer, self.version) {
+            (true, None) => Some(DEFAULT_VERSION.to_owned()),
+            (true, version) => version,
+            (false, _) => None,
+        };
+        self
+    }
+
+    /// Whether PING is answered.
+    pub fn answer_ping(mut self, answer: bool) -> Responder {
+        self.ping = answer;
+        self
+    }
+
+    /// Whether TIME is answered.
+    pub fn answer_time(mut self, answer: bool) -> Responder {
+        self.time = answer;
+        self
+    }
+
+    /// Send at most `count` replies per `window`, whoever the queries
+    /// come from.
+    pub fn limit(mut self, count: u32, window: Duration) -> Responder {
+        self.limit = (count, window);
+        self
+    }
+
+    /// Send at most `count` replies per `window` to each user.
+    pub fn per_user(mut self, count: u32, window: Duration) -> Responder {
+        self.per_user = (count, window);
+        self
+    }
+
+    // The answer to a query, if it's one that's answered.
+    fn answer(&self, command: &str, params: Option<&str>) -> Option<Option<String>> {
+        match command {
+            "VERSION" => self.version.clone().map(Some),
+            "PING" if self.ping => Some(params.map(|params| params.to_owned())),
+            "TIME" if self.time => Some(Some(chathistory::format_time(SystemTime::now()))),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Responder {
+    fn default() -> Responder {
+        Responder::new()
+    }
+}
+
+// Applies a `Responder` to the queries received on a connection.
+pub(crate) struct AutoResponder {
+    responder: Responder,
+    overall: Counter,
+    // The replies sent to each user, keyed by their folded nickname.
+    users: HashMap<String, Counter>,
+}
+
+impl AutoResponder {
+    pub fn new(responder: Responder) -> AutoResponder {
+        AutoResponder {
+            responder: responder,
+            overall: Counter::default(),
+            users: HashMap::new(),
+        }
+    }
+
+    // Handles a message received from the server, answering it if it's a
+    // query the responder answers and the limits allow. `now` is the time
+    // given by the transport's clock.
+    pub fn handle_incoming(
+        &mut self,
+        message: &Message,
+        state: &State,
+        now: Duration,
+        outgoing: &mut VecDeque<Message>,
+    ) {
+        if !is_query(message) {
+            return;
+        }
+
+        let nick = match message.prefix() {
+            Some((nick, _, _)) if !state.is_self(nick) => nick,
+            _ => return,
+        };
+
+        let (command, answer) = match parse(message) {
+            Some((command, params)) => match self.responder.answer(command, params) {
+                Some(answer) => (command, answer),
+                None => return,
+            },
+            None => return,
+        };
+
+        let (per_user, per_user_window) = self.responder.per_user;
+        let (limit, window) = self.responder.limit;
+
+        self.users
+            .retain(|_, counter| !counter.expired(per_user_window, now));
+
+        let user = self.users.entry(state.fold_name(nick)).or_default();
+
+        // Queries from users over their own limit are ignored without
+        // counting against the overall one.
+        if !user.hit(per_user, per_user_window, now) || !self.overall.hit(limit, window, now) {
+            return;
+        }
+
+        if let Ok(reply) = reply(nick, command, answer.as_deref()) {
+            outgoing.push_back(reply);
+        }
+    }
+}
+
+/// Constructs a message containing a CTCP query sent to `target`, such as
+/// `VERSION` or `PING` with its parameters.
+pub fn query(target: &str, command: &str, params: Option<&str>) -> Result<Message> {
+    build("PRIVMSG", target, command, params)
+}
+
+/// Constructs a message containing a CTCP reply sent to `target`.
+pub fn reply(target: &str, command: &str, params: Option<&str>) -> Result<Message> {
+    build("NOTICE", target, command, params)
+}
+
+/// Extracts the CTCP command and its parameters from a PRIVMSG or NOTICE,
+/// if it carries a CTCP message. The trailing delimiter is optional, as
+/// some clients omit it.
+pub fn parse(message: &Message) -> Option<(&str, Option<&str>)> {
+    match message.raw_command() {
+        "PRIVMSG" | "NOTICE" => (),
+        _ => return None,
+    }
+
+    let text = message.raw_args().nth(1)?;
+    let text = text.strip_prefix(DELIMITER)?;
+    let text = text.strip_suffix(DELIMITER).unwrap_or(text);
+
+    let mut parts = text.splitn(2, ' ');
+    let command = parts.next()?;
+
+    if command.is_empty() {
+        return None;
+    }
+
+    Some((command, parts.next()))
+}
+
+/// Returns true if the message is a CTCP query, rather than a reply.
+pub fn is_query(message: &Message) -> bool {
+    message.raw_command() == "PRIVMSG" && parse(message).is_some()
+}
+
+fn build(kind: &str, target: &str, command: &str, params: Option<&str>) -> Result<Message> {
+    let body = match params {
+        Some(params) => format!("{} {}", command, params),
+        None => command.to_owned(),
+    };
+
+    Ok(Message::try_from(format!(
+        "{} {} :{}{}{}",
+        kind,
+        target,
+        DELIMITER,
+        body,
+        DELIMITER
+    ))?)
+}
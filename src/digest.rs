@@ -0,0 +1,276 @@
+// The hash functions, and the HMAC and PBKDF2 constructions over them, used
+// by the SCRAM mechanisms of the sasl module and for certificate pinning.
+
+// The length of the blocks both SHA-1 and SHA-256 process at a time.
+const BLOCK_LENGTH: usize = 64;
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+}
+
+impl Algorithm {
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Sha1 => sha1(data),
+            Algorithm::Sha256 => sha256(data),
+        }
+    }
+
+    // HMAC, as defined by RFC 2104.
+    pub fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut key = if key.len() > BLOCK_LENGTH {
+            self.digest(key)
+        } else {
+            key.to_vec()
+        };
+
+        key.resize(BLOCK_LENGTH, 0);
+
+        let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+        inner.extend_from_slice(data);
+
+        let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+        outer.extend(self.digest(&inner));
+
+        self.digest(&outer)
+    }
+
+    // PBKDF2, as defined by RFC 8018, deriving a key as long as the digest,
+    // which is all SCRAM needs.
+    pub fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        let mut block = salt.to_vec();
+        block.extend_from_slice(&[0, 0, 0, 1]);
+
+        let mut u = self.hmac(password, &block);
+        let mut key = u.clone();
+
+        for _ in 1..iterations {
+            u = self.hmac(password, &u);
+
+            for (k, b) in key.iter_mut().zip(&u) {
+                *k ^= b;
+            }
+        }
+
+        key
+    }
+}
+
+// Pads a message to a whole number of blocks, ending with its length in bits.
+fn pad(data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    let bits = (data.len() as u64).wrapping_mul(8);
+
+    padded.push(0x80);
+
+    while padded.len() % BLOCK_LENGTH != BLOCK_LENGTH - 8 {
+        padded.push(0);
+    }
+
+    padded.extend_from_slice(&bits.to_be_bytes());
+    padded
+}
+
+// Reads the words of a block, most significant byte first.
+fn words(block: &[u8], schedule: &mut [u32]) {
+    for (word, bytes) in schedule.iter_mut().zip(block.chunks(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+}
+
+fn sha1(data: &[u8]) -> Vec<u8> {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut w = [0u32; 80];
+
+    for block in pad(data).chunks(BLOCK_LENGTH) {
+        words(block, &mut w[..16]);
+
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, v) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    h.iter().flat_map(|h| h.to_be_bytes().to_vec()).collect()
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut w = [0u32; 64];
+
+    for block in pad(data).chunks(BLOCK_LENGTH) {
+        words(block, &mut w[..16]);
+
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = h;
+
+        for (&k, &word) in SHA256_K.iter().zip(w.iter()) {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let temp1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let temp2 = s0.wrapping_add(maj);
+
+            v = [
+                temp1.wrapping_add(temp2),
+                v[0],
+                v[1],
+                v[2],
+                v[3].wrapping_add(temp1),
+                v[4],
+                v[5],
+                v[6],
+            ];
+        }
+
+        for (h, v) in h.iter_mut().zip(&v) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    h.iter().flat_map(|h| h.to_be_bytes().to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // The examples of FIPS 180-2.
+    #[test]
+    fn sha1_matches_fips_180() {
+        let million = vec![b'a'; 1_000_000];
+
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(hex(&sha1(&million)), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn sha256_matches_fips_180() {
+        let million = vec![b'a'; 1_000_000];
+
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            hex(&sha256(&million)),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    // Test cases 1 and 2 of RFC 2202.
+    #[test]
+    fn hmac_sha1_matches_rfc_2202() {
+        assert_eq!(
+            hex(&Algorithm::Sha1.hmac(&[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex(&Algorithm::Sha1.hmac(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+    }
+
+    // Test cases 1, 2 and 6 of RFC 4231, the last with a key longer than a
+    // block.
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        assert_eq!(
+            hex(&Algorithm::Sha256.hmac(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(&Algorithm::Sha256.hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&Algorithm::Sha256.hmac(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    // The test vectors of RFC 6070 deriving keys as long as the digest.
+    #[test]
+    fn pbkdf2_sha1_matches_rfc_6070() {
+        let pbkdf2 = |iterations| hex(&Algorithm::Sha1.pbkdf2(b"password", b"salt", iterations));
+
+        assert_eq!(pbkdf2(1), "0c60c80f961f0e71f3a9b524af6012062fe037a6");
+        assert_eq!(pbkdf2(2), "ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957");
+        assert_eq!(pbkdf2(4096), "4b007901b765489abead49d926f721d065a429c1");
+    }
+}
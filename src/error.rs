@@ -18,6 +18,141 @@ error_chain! {
             description("The connection was reset by the remote host.")
             display("The connection was reset by the remote host.")
         }
+
+        PingTimeout {
+            description("The remote host stopped sending PING requests.")
+            display("The remote host stopped sending PING requests.")
+        }
+
+        Stalled {
+            description("Nothing was received from the remote host for too long.")
+            display("Nothing was received from the remote host for too long.")
+        }
+
+        NicknameUnavailable(nick: String) {
+            description("No available nickname could be found.")
+            display("No available nickname could be found for '{}'.", nick)
+        }
+
+        PasswordMismatch {
+            description("The server password was rejected.")
+            display("The server password was rejected.")
+        }
+
+        Banned(reason: String) {
+            description("The client is banned from the server.")
+            display("The client is banned from the server: {}", reason)
+        }
+
+        SaslFailed(reason: String) {
+            description("SASL authentication failed.")
+            display("SASL authentication failed: {}", reason)
+        }
+
+        InvalidRules(reason: String) {
+            description("The rules configuration is invalid.")
+            display("The rules configuration is invalid: {}", reason)
+        }
+
+        UnknownNetwork(id: String) {
+            description("A message was sent to a network that doesn't exist.")
+            display("There is no network called '{}'.", id)
+        }
+
+        InvalidKey(peer: String) {
+            description("The encryption key is invalid.")
+            display("The encryption key for '{}' is invalid.", peer)
+        }
+
+        RequestAborted {
+            description("The connection closed before the request completed.")
+            display("The connection closed before the request completed.")
+        }
+
+        CannotJoin(channel: String, reason: String) {
+            description("The server refused to let the client join the channel.")
+            display("Cannot join '{}': {}", channel, reason)
+        }
+
+        CannotSendToChannel(channel: String, mode: char) {
+            description("The channel's modes prevent the client from sending to it.")
+            display("Cannot send to '{}', which is set +{}.", channel, mode)
+        }
+
+        NoSuchChannel(channel: String) {
+            description("The channel does not exist.")
+            display("The channel '{}' does not exist.", channel)
+        }
+
+        NotOnChannel(channel: String) {
+            description("The client is not in the channel.")
+            display("The client is not in '{}'.", channel)
+        }
+
+        ChanOpPrivsNeeded(channel: String) {
+            description("The client is not a channel operator.")
+            display("The client is not an operator of '{}'.", channel)
+        }
+
+        InvalidMode(mode: char) {
+            description("A mode that takes a parameter was given without one.")
+            display("The mode '{}' takes a parameter, but none was given.", mode)
+        }
+
+        UnsupportedCompression(format: String) {
+            description("The data is compressed in a format that isn't supported.")
+            display("The data is compressed with {}, which isn't supported.", format)
+        }
+
+        LineTooLong(limit: usize) {
+            description("The server sent a line longer than the limit.")
+            display("The server sent a line longer than {} bytes.", limit)
+        }
+
+        CapabilityNotEnabled(cap: String) {
+            description("The capability needed for the request isn't enabled.")
+            display("The '{}' capability isn't enabled.", cap)
+        }
+
+        HistoryUnavailable(target: String, reason: String) {
+            description("The server refused to send the history.")
+            display("Cannot fetch the history of '{}': {}", target, reason)
+        }
+
+        Dns(name: String, reason: String) {
+            description("The DNS lookup failed.")
+            display("Cannot look up '{}': {}", name, reason)
+        }
+
+        ServerBusy(command: String) {
+            description("The server is too busy to answer the command.")
+            display("The server kept asking for {} to be tried again later.", command)
+        }
+
+        InvalidTarget(target: String, reason: String) {
+            description("A message was given a target it can't be sent to.")
+            display("The target '{}' can't be used: {}.", target, reason)
+        }
+
+        InvalidParameter(param: String, reason: String) {
+            description("A message was given a parameter that can't be sent.")
+            display("The parameter {:?} can't be sent: {}.", param, reason)
+        }
+
+        TooManyTargets(command: String, limit: usize) {
+            description("A message was given more targets than the server allows.")
+            display("The {} has more targets than the server allows ({}).", command, limit)
+        }
+
+        TooManyModes(limit: usize) {
+            description("A MODE message was given more modes with parameters than allowed.")
+            display("The server allows at most {} modes with parameters in a MODE message.", limit)
+        }
+
+        AccountRegistrationFailed(failure: ::account::AccountFailure, reason: String) {
+            description("The server refused to register or verify the account.")
+            display("Account registration failed ({}): {}", failure, reason)
+        }
     }
 
     links {
@@ -43,9 +178,180 @@ error_chain! {
             description("The connection was reset by the remote host.")
             display("The connection was reset by the remote host.")
         }
+
+        PingTimeout {
+            description("The remote host stopped sending PING requests.")
+            display("The remote host stopped sending PING requests.")
+        }
+
+        Stalled {
+            description("Nothing was received from the remote host for too long.")
+            display("Nothing was received from the remote host for too long.")
+        }
+
+        NicknameUnavailable(nick: String) {
+            description("No available nickname could be found.")
+            display("No available nickname could be found for '{}'.", nick)
+        }
+
+        PasswordMismatch {
+            description("The server password was rejected.")
+            display("The server password was rejected.")
+        }
+
+        Banned(reason: String) {
+            description("The client is banned from the server.")
+            display("The client is banned from the server: {}", reason)
+        }
+
+        SaslFailed(reason: String) {
+            description("SASL authentication failed.")
+            display("SASL authentication failed: {}", reason)
+        }
+
+        InvalidRules(reason: String) {
+            description("The rules configuration is invalid.")
+            display("The rules configuration is invalid: {}", reason)
+        }
+
+        UnknownNetwork(id: String) {
+            description("A message was sent to a network that doesn't exist.")
+            display("There is no network called '{}'.", id)
+        }
+
+        InvalidKey(peer: String) {
+            description("The encryption key is invalid.")
+            display("The encryption key for '{}' is invalid.", peer)
+        }
+
+        RequestAborted {
+            description("The connection closed before the request completed.")
+            display("The connection closed before the request completed.")
+        }
+
+        CannotJoin(channel: String, reason: String) {
+            description("The server refused to let the client join the channel.")
+            display("Cannot join '{}': {}", channel, reason)
+        }
+
+        CannotSendToChannel(channel: String, mode: char) {
+            description("The channel's modes prevent the client from sending to it.")
+            display("Cannot send to '{}', which is set +{}.", channel, mode)
+        }
+
+        NoSuchChannel(channel: String) {
+            description("The channel does not exist.")
+            display("The channel '{}' does not exist.", channel)
+        }
+
+        NotOnChannel(channel: String) {
+            description("The client is not in the channel.")
+            display("The client is not in '{}'.", channel)
+        }
+
+        ChanOpPrivsNeeded(channel: String) {
+            description("The client is not a channel operator.")
+            display("The client is not an operator of '{}'.", channel)
+        }
+
+        InvalidMode(mode: char) {
+            description("A mode that takes a parameter was given without one.")
+            display("The mode '{}' takes a parameter, but none was given.", mode)
+        }
+
+        UnsupportedCompression(format: String) {
+            description("The data is compressed in a format that isn't supported.")
+            display("The data is compressed with {}, which isn't supported.", format)
+        }
+
+        LineTooLong(limit: usize) {
+            description("The server sent a line longer than the limit.")
+            display("The server sent a line longer than {} bytes.", limit)
+        }
+
+        CapabilityNotEnabled(cap: String) {
+            description("The capability needed for the request isn't enabled.")
+            display("The '{}' capability isn't enabled.", cap)
+        }
+
+        HistoryUnavailable(target: String, reason: String) {
+            description("The server refused to send the history.")
+            display("Cannot fetch the history of '{}': {}", target, reason)
+        }
+
+        Dns(name: String, reason: String) {
+            description("The DNS lookup failed.")
+            display("Cannot look up '{}': {}", name, reason)
+        }
+
+        ServerBusy(command: String) {
+            description("The server is too busy to answer the command.")
+            display("The server kept asking for {} to be tried again later.", command)
+        }
+
+        InvalidTarget(target: String, reason: String) {
+            description("A message was given a target it can't be sent to.")
+            display("The target '{}' can't be used: {}.", target, reason)
+        }
+
+        InvalidParameter(param: String, reason: String) {
+            description("A message was given a parameter that can't be sent.")
+            display("The parameter {:?} can't be sent: {}.", param, reason)
+        }
+
+        TooManyTargets(command: String, limit: usize) {
+            description("A message was given more targets than the server allows.")
+            display("The {} has more targets than the server allows ({}).", command, limit)
+        }
+
+        TooManyModes(limit: usize) {
+            description("A MODE message was given more modes with parameters than allowed.")
+            display("The server allows at most {} modes with parameters in a MODE message.", limit)
+        }
+
+        AccountRegistrationFailed(failure: ::account::AccountFailure, reason: String) {
+            description("The server refused to register or verify the account.")
+            display("Account registration failed ({}): {}", failure, reason)
+        }
+
+        CertificateRejected(domain: String) {
+            description("The certificate presented by the remote host was rejected.")
+            display("The certificate presented by '{}' was rejected.", domain)
+        }
+
+        CertificateUnavailable {
+            description("The certificate presented by the remote host could not be retrieved.")
+            display("The certificate presented by the remote host could not be retrieved.")
+        }
+
+        WeakTls(reason: String) {
+            description("The TLS connection negotiated obsolete or weak security.")
+            display("The TLS connection is weak: {}.", reason)
+        }
+
+        InvalidFingerprint(fingerprint: String) {
+            description("The certificate fingerprint is invalid.")
+            display("'{}' is not a valid SHA-256 fingerprint.", fingerprint)
+        }
     }
 
     links {
         Pircolate(::pircolate::error::Error, ::pircolate::error::ErrorKind);
     }
 }
+
+impl ErrorKind {
+    /// Whether the failure may not happen again if the connection is retried
+    /// with the same configuration, such as when the connection was lost,
+    /// as opposed to when the server rejected the client's credentials.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            *self,
+            ErrorKind::Io(_)
+                | ErrorKind::ConnectionReset
+                | ErrorKind::PingTimeout
+                | ErrorKind::Stalled
+                | ErrorKind::NicknameUnavailable(_)
+        )
+    }
+}
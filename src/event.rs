@@ -0,0 +1,438 @@
+//! The event module contains the `Event` type, which describes changes
+//! observed on a connection at a higher level than the raw messages that
+//! caused them.
+//!
+//! Events are delivered on a side-channel `Stream`, `Events`, which can be
+//! obtained from either a `Client` or an `IrcTransport`. Every subscriber
+//! receives its own copy of each event.
+
+use command::{ChgHost, Invite, SetName};
+use error::Error;
+use mode::{self, Mode};
+use pretty::escape;
+use state::State;
+use znc;
+
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use pircolate::Message;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// An event observed on a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A connection to the server is being established.
+    Connecting,
+
+    /// The TLS handshake with the server completed, and the certificate it
+    /// presented was accepted.
+    TlsHandshakeComplete,
+
+    /// The TLS connection negotiated an obsolete protocol version or a weak
+    /// cipher suite. See `tls::WeakTlsPolicy`.
+    WeakTls {
+        /// The protocol version that was negotiated.
+        protocol: String,
+        /// The cipher suite that was negotiated.
+        cipher: String,
+        /// Why the connection is considered weak.
+        reason: String,
+    },
+
+    /// The client was registered with the server, as reported by
+    /// RPL_WELCOME.
+    Registered,
+
+    /// The server stopped sending PING requests, and the connection is
+    /// being closed.
+    PingTimeout,
+
+    /// Nothing has been received from the server for the duration of the
+    /// `Watchdog`, which is acting as its `StallAction` says.
+    Stalled {
+        /// How long it has been since anything was received.
+        idle: Duration,
+    },
+
+    /// The connection to the server was lost.
+    Disconnected {
+        /// Why the connection was lost, which is the reason given by the
+        /// server when there is one.
+        reason: String,
+    },
+
+    /// An attempt is being made to reconnect to the server.
+    Reconnecting {
+        /// The number of the attempt, starting from 1.
+        attempt: usize,
+    },
+
+    /// IRCv3 capabilities were enabled or disabled, either as requested by
+    /// the client or because the server withdrew them with `cap-notify`.
+    CapabilitiesChanged {
+        /// The capabilities that were enabled.
+        enabled: Vec<String>,
+        /// The capabilities that were disabled or withdrawn.
+        disabled: Vec<String>,
+    },
+
+    /// The server sent a line longer than the limit set with
+    /// `Client::max_line_length`, which was skipped.
+    LineDiscarded {
+        /// The length of the line in bytes, including its line ending.
+        length: usize,
+    },
+
+    /// A user's username or host changed, as reported by CHGHOST.
+    HostChanged {
+        /// The nickname of the user.
+        nick: String,
+        /// The new username of the user.
+        user: String,
+        /// The new host of the user.
+        host: String,
+    },
+
+    /// A user's real name changed, as reported by SETNAME.
+    RealNameChanged {
+        /// The nickname of the user.
+        nick: String,
+        /// The new real name of the user.
+        real_name: String,
+    },
+
+    /// A user, or the client itself, was marked as away or returned. Other
+    /// users are only reported when the `away-notify` capability is enabled.
+    AwayChanged {
+        /// The nickname of the user.
+        nick: String,
+        /// The user's away message, or `None` if they returned.
+        message: Option<String>,
+    },
+
+    /// A user was invited to a channel. Invitations of other users are only
+    /// reported when the `invite-notify` capability is enabled.
+    Invited {
+        /// The nickname of the user who sent the invitation.
+        nick: String,
+        /// The nickname of the invited user.
+        target: String,
+        /// The channel the user was invited to.
+        channel: String,
+    },
+
+    /// The modes of a channel or user were changed.
+    ModeChanged {
+        /// The channel or nickname whose modes changed.
+        target: String,
+        /// The nickname of the user or the name of the server that changed
+        /// them.
+        set_by: String,
+        /// The changes that were made.
+        changes: Vec<Mode>,
+    },
+
+    /// ZNC started playing back the messages buffered for a channel or user,
+    /// as requested with `znc::play`.
+    PlaybackStarted {
+        /// The channel or nickname whose buffer is being played back.
+        target: String,
+    },
+
+    /// ZNC finished playing back a buffer.
+    PlaybackFinished {
+        /// The channel or nickname whose buffer was played back.
+        target: String,
+    },
+
+    /// An action performed automatically on behalf of the client failed.
+    AutomationFailed {
+        /// The action that failed.
+        action: Automation,
+        /// A description of why the action failed, which is the reason given
+        /// by the server when there is one.
+        error: String,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Event::Connecting => f.write_str("connecting"),
+            Event::TlsHandshakeComplete => f.write_str("TLS handshake complete"),
+            Event::WeakTls { ref reason, .. } => write!(f, "weak TLS connection: {}", reason),
+            Event::Registered => f.write_str("registered"),
+            Event::PingTimeout => f.write_str("ping timeout"),
+            Event::Stalled { idle } => write!(f, "stalled ({}s without traffic)", idle.as_secs()),
+            Event::Disconnected { ref reason } => write!(f, "disconnected: {}", escape(reason)),
+            Event::Reconnecting { attempt } => write!(f, "reconnecting (attempt {})", attempt),
+            Event::LineDiscarded { length } => write!(f, "discarded a line of {} bytes", length),
+            Event::CapabilitiesChanged {
+                ref enabled,
+                ref disabled,
+            } => {
+                f.write_str("capabilities changed:")?;
+
+                for cap in enabled {
+                    write!(f, " +{}", escape(cap))?;
+                }
+
+                for cap in disabled {
+                    write!(f, " -{}", escape(cap))?;
+                }
+
+                Ok(())
+            }
+            Event::HostChanged {
+                ref nick,
+                ref user,
+                ref host,
+            } => write!(f, "{} changed host to {}@{}", escape(nick), escape(user), escape(host)),
+            Event::RealNameChanged {
+                ref nick,
+                ref real_name,
+            } => write!(f, "{} changed real name to {}", escape(nick), escape(real_name)),
+            Event::AwayChanged {
+                ref nick,
+                message: Some(ref message),
+            } => write!(f, "{} is away: {}", escape(nick), escape(message)),
+            Event::AwayChanged {
+                ref nick,
+                message: None,
+            } => write!(f, "{} is back", escape(nick)),
+            Event::Invited {
+                ref nick,
+                ref target,
+                ref channel,
+            } => write!(f, "{} invited {} to {}", escape(nick), escape(target), escape(channel)),
+            Event::ModeChanged {
+                ref target,
+                ref set_by,
+                ref changes,
+            } => {
+                write!(f, "{} set modes on {}:", escape(set_by), escape(target))?;
+
+                for change in changes {
+                    write!(f, " {}", change)?;
+                }
+
+                Ok(())
+            }
+            Event::PlaybackStarted { ref target } => {
+                write!(f, "playback of {} started", escape(target))
+            }
+            Event::PlaybackFinished { ref target } => {
+                write!(f, "playback of {} finished", escape(target))
+            }
+            Event::AutomationFailed {
+                action,
+                ref error,
+            } => write!(f, "{:?} failed: {}", action, escape(error)),
+        }
+    }
+}
+
+/// The actions performed automatically by a connection, which are reported
+/// by `Event::AutomationFailed` when they fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Automation {
+    /// Replying to a PING request from the server.
+    Pong,
+    /// Taking back the preferred nickname once it's released.
+    NickReclaim,
+    /// Negotiating IRCv3 capabilities during registration.
+    CapabilityNegotiation,
+    /// Performing the actions of an `OnReady` once registered.
+    OnReady,
+    /// Joining a channel the client was invited to.
+    InviteJoin,
+    /// Replying to a message according to the rules of a responder.
+    Responder,
+    /// Answering a command of a `Bot`.
+    Bot,
+    /// Restoring the channels, away status and user modes of a lost
+    /// connection on the next one.
+    RestoreSession,
+    /// Sending a message held in the outbox of a `Reconnect` while it was
+    /// disconnected.
+    Outbox,
+    /// Sending the PING of a `Watchdog` probing a quiet connection.
+    Watchdog,
+    /// Writing a line to a `TrafficLog`.
+    TrafficLog,
+    /// Sending a message queued by a handle, such as a `Query`, a
+    /// `Conversation` or a `Bot` replying to a command, which was refused by
+    /// the send restrictions or failed to be transformed.
+    Queued,
+}
+
+/// A `Stream` of the events observed on a connection.
+///
+/// The stream ends when the `Client` or `IrcTransport` it was obtained
+/// from, along with any connections made by it, no longer exist.
+pub struct Events {
+    inner: UnboundedReceiver<Event>,
+}
+
+impl Stream for Events {
+    type Item = Event;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.inner.poll() {
+            Ok(ready) => Ok(ready),
+            // Polling an unbounded receiver never fails.
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+// Distributes events to every subscribed `Events` stream.
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Events {
+        let (sender, receiver) = mpsc::unbounded();
+
+        self.lock().push(sender);
+
+        Events { inner: receiver }
+    }
+
+    pub fn emit_failure<E: ToString>(&self, action: Automation, error: E) {
+        self.emit(Event::AutomationFailed {
+            action: action,
+            error: error.to_string(),
+        });
+    }
+
+    pub fn emit(&self, event: Event) {
+        // Subscribers that have been dropped are removed as they're found.
+        self.lock()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Vec<UnboundedSender<Event>>> {
+        self.subscribers.lock().expect("EventBus lock poisoned")
+    }
+}
+
+// Produces the event described by a message received from the server, if any,
+// using `state` to interpret it.
+pub(crate) fn from_message(message: &Message, state: &State) -> Option<Event> {
+    // RPL_WELCOME
+    if message.raw_command() == "001" {
+        return Some(Event::Registered);
+    }
+
+    // BATCH: "+<reference> <type> <params>..." or "-<reference>"
+    if message.raw_command() == "BATCH" {
+        let reference = message.raw_args().next()?;
+        let batch = state.batch(reference.get(1..)?)?;
+
+        if batch.kind != znc::PLAYBACK_BATCH {
+            return None;
+        }
+
+        let target = batch.params.into_iter().next().unwrap_or_default();
+
+        return match reference.chars().next() {
+            Some('+') => Some(Event::PlaybackStarted { target: target }),
+            Some('-') => Some(Event::PlaybackFinished { target: target }),
+            _ => None,
+        };
+    }
+
+    // "CAP <nick> ACK :<caps>" or, with cap-notify, "CAP <nick> DEL :<caps>"
+    if message.raw_command() == "CAP" {
+        let mut args = message.raw_args();
+        let subcommand = args.nth(1)?;
+        let caps = args.next_back().unwrap_or_default().split_whitespace();
+
+        let (enabled, disabled) = match subcommand {
+            "ACK" => caps.partition(|cap| !cap.starts_with('-')),
+            "DEL" => (Vec::new(), caps.collect()),
+            _ => return None,
+        };
+
+        return Some(Event::CapabilitiesChanged {
+            enabled: enabled.into_iter().map(str::to_owned).collect(),
+            disabled: disabled
+                .into_iter()
+                .map(|cap: &str| cap.trim_start_matches('-').to_owned())
+                .collect(),
+        });
+    }
+
+    // RPL_UNAWAY or RPL_NOWAWAY, confirming the client's own status.
+    if let "305" | "306" = message.raw_command() {
+        return Some(Event::AwayChanged {
+            nick: state.nick()?,
+            message: state.away(),
+        });
+    }
+
+    // Most messages don't describe an event, so the sender's nickname is
+    // only copied once one does.
+    let nick = match message.prefix() {
+        Some((nick, _, _)) => nick,
+        None => return None,
+    };
+
+    // With away-notify, "AWAY :<message>" or "AWAY" when returning.
+    if message.raw_command() == "AWAY" {
+        return Some(Event::AwayChanged {
+            nick: nick.to_owned(),
+            message: message.raw_args().next().map(str::to_owned),
+        });
+    }
+
+    if let Some(ChgHost(user, host)) = message.command::<ChgHost>() {
+        return Some(Event::HostChanged {
+            nick: nick.to_owned(),
+            user: user.to_owned(),
+            host: host.to_owned(),
+        });
+    }
+
+    if let Some(Invite(target, channel)) = message.command::<Invite>() {
+        return Some(Event::Invited {
+            nick: nick.to_owned(),
+            target: target.to_owned(),
+            channel: channel.to_owned(),
+        });
+    }
+
+    if message.raw_command() == "MODE" {
+        let args: Vec<&str> = message.raw_args().collect();
+        let (target, args) = args.split_first()?;
+
+        let changes = if state.is_channel(target) {
+            state.channel_mode_types().parse(args)
+        } else {
+            args.iter().flat_map(|modes| mode::parse_user_modes(modes)).collect()
+        };
+
+        return Some(Event::ModeChanged {
+            target: target.to_string(),
+            set_by: nick.to_owned(),
+            changes: changes,
+        });
+    }
+
+    if let Some(SetName(real_name)) = message.command::<SetName>() {
+        return Some(Event::RealNameChanged {
+            nick: nick.to_owned(),
+            real_name: real_name.to_owned(),
+        });
+    }
+
+    None
+}
@@ -0,0 +1,186 @@
+//! The fault module contains `FaultInjector`, which wraps a connection and
+//! simulates an unreliable network, for testing how an application copes
+//! with delays, partial writes, dropped connections and corrupted data.
+//!
+//! Faults are chosen by a pseudo-random generator seeded from the
+//! `Faults` configuration, so a failing run can be reproduced exactly by
+//! reusing its seed. A wrapped connection is turned into an `IrcTransport`
+//! with `Client::connect_stream`.
+//!
+//! This module is only available with the `testing` feature.
+
+use futures::{task, Poll};
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::io::{self, Read, Write};
+
+/// The faults injected into a connection, each given as the probability of
+/// it happening on any one read or write.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    seed: u64,
+    delay: f64,
+    partial_write: f64,
+    disconnect: f64,
+    corruption: f64,
+}
+
+impl Faults {
+    /// Create a new `Faults` using the given seed, which doesn't inject any
+    /// faults.
+    pub fn new(seed: u64) -> Faults {
+        Faults {
+            seed: seed,
+            delay: 0.0,
+            partial_write: 0.0,
+            disconnect: 0.0,
+            corruption: 0.0,
+        }
+    }
+
+    /// Make reads and writes not ready, as if waiting on the network. The
+    /// current task is woken straight away, so delayed operations are
+    /// retried on the next poll.
+    pub fn delays(mut self, probability: f64) -> Faults {
+        self.delay = probability;
+        self
+    }
+
+    /// Write only part of the data given to each write.
+    pub fn partial_writes(mut self, probability: f64) -> Faults {
+        self.partial_write = probability;
+        self
+    }
+
+    /// Drop the connection part way through the data being read, after
+    /// which reads see the end of the stream and writes fail.
+    pub fn disconnects(mut self, probability: f64) -> Faults {
+        self.disconnect = probability;
+        self
+    }
+
+    /// Corrupt a single byte of the data being read.
+    pub fn corruption(mut self, probability: f64) -> Faults {
+        self.corruption = probability;
+        self
+    }
+}
+
+/// Wraps a connection, injecting the configured `Faults` into its reads and
+/// writes.
+pub struct FaultInjector<T> {
+    inner: T,
+    faults: Faults,
+    rng: u64,
+    disconnected: bool,
+}
+
+impl<T> FaultInjector<T> {
+    /// Wrap the given connection.
+    pub fn new(inner: T, faults: Faults) -> FaultInjector<T> {
+        FaultInjector {
+            inner: inner,
+            rng: faults.seed,
+            faults: faults,
+            disconnected: false,
+        }
+    }
+
+    /// Returns true once a disconnect has been injected.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    // The next value of a SplitMix64 generator.
+    fn next(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut value = self.rng;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        value ^ (value >> 31)
+    }
+
+    fn happens(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    // A number in the range `0..bound`, which must not be empty.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn delay(&mut self) -> io::Result<()> {
+        if self.happens(self.faults.delay) {
+            task::current().notify();
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for FaultInjector<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.disconnected {
+            return Ok(0);
+        }
+
+        self.delay()?;
+
+        let mut length = self.inner.read(buffer)?;
+
+        if length > 0 && self.happens(self.faults.disconnect) {
+            length = self.below(length);
+            self.disconnected = true;
+        }
+
+        if length > 0 && self.happens(self.faults.corruption) {
+            let index = self.below(length);
+            let flip = (self.next() as u8).max(1);
+
+            buffer[index] ^= flip;
+        }
+
+        Ok(length)
+    }
+}
+
+impl<T: Write> Write for FaultInjector<T> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        if self.disconnected {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.delay()?;
+
+        let length = if buffer.len() > 1 && self.happens(self.faults.partial_write) {
+            1 + self.below(buffer.len() - 1)
+        } else {
+            buffer.len()
+        };
+
+        self.inner.write(&buffer[..length])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.disconnected {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for FaultInjector<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for FaultInjector<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
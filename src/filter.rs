@@ -0,0 +1,284 @@
+//! The filter module contains `MessageStreamExt`, which adds adapters to any
+//! `Stream` of `Message`, such as an `IrcTransport`, that pick out the
+//! messages a bot is usually interested in.
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate pircolate;
+//! # extern crate tokio_irc_client;
+//! # use futures::Stream;
+//! # use pircolate::Message;
+//! use tokio_irc_client::filter::MessageStreamExt;
+//!
+//! # fn example<S: Stream<Item = Message>>(stream: S) {
+//! let greetings = stream
+//!     .mentions("mybot")
+//!     .filter(|privmsg| privmsg.text.contains("hello"));
+//! # }
+//! # fn main() {}
+//! ```
+
+use casemap::CaseMapping;
+use tagmsg::TagMsg;
+
+use futures::{Async, Poll, Stream};
+
+use pircolate::Message;
+
+use std::ops::RangeInclusive;
+
+/// A PRIVMSG received from the server.
+#[derive(Clone, Debug)]
+pub struct Privmsg {
+    /// The nickname of the sender, if the message has a source.
+    pub nick: Option<String>,
+    /// The channel or nickname the message was sent to.
+    pub target: String,
+    /// The text of the message.
+    pub text: String,
+    /// The message itself.
+    pub message: Message,
+}
+
+impl Privmsg {
+    /// Returns the `Privmsg` held by `message`, if it is a PRIVMSG.
+    pub fn from_message(message: Message) -> Option<Privmsg> {
+        if message.raw_command() != "PRIVMSG" {
+            return None;
+        }
+
+        let (target, text) = {
+            let mut args = message.raw_args();
+
+            match (args.next(), args.next()) {
+                (Some(target), Some(text)) => (target.to_owned(), text.to_owned()),
+                _ => return None,
+            }
+        };
+
+        Some(Privmsg {
+            nick: message.prefix().map(|(nick, _, _)| nick.to_owned()),
+            target: target,
+            text: text,
+            message: message,
+        })
+    }
+}
+
+/// A numeric reply received from the server.
+#[derive(Clone, Debug)]
+pub struct Numeric {
+    /// The numeric code of the reply.
+    pub code: u16,
+    /// The message itself.
+    pub message: Message,
+}
+
+/// Adapters for a `Stream` of `Message`.
+pub trait MessageStreamExt: Stream<Item = Message> + Sized {
+    /// Yields only PRIVMSG messages.
+    fn privmsgs(self) -> Privmsgs<Self> {
+        Privmsgs { inner: self }
+    }
+
+    /// Yields only TAGMSG messages, such as typing notifications and
+    /// reactions.
+    fn tagmsgs(self) -> TagMsgs<Self> {
+        TagMsgs { inner: self }
+    }
+
+    /// Yields only the messages whose first argument is the given channel,
+    /// such as the PRIVMSG, JOIN and PART messages of the channel. Channel
+    /// names are compared using the `rfc1459` case mapping.
+    #[allow(clippy::wrong_self_convention)]
+    fn from_channel(self, channel: &str) -> FromChannel<Self> {
+        FromChannel {
+            inner: self,
+            channel: CaseMapping::default().fold(channel),
+        }
+    }
+
+    /// Yields only the numeric replies whose code is in `codes`, such as
+    /// `400..=599` for errors.
+    fn numerics(self, codes: RangeInclusive<u16>) -> Numerics<Self> {
+        Numerics {
+            inner: self,
+            codes: codes,
+        }
+    }
+
+    /// Yields only the PRIVMSG messages mentioning the given nickname as a
+    /// word of their text, such as `mybot: hello`. Nicknames are compared
+    /// using the `rfc1459` case mapping.
+    fn mentions(self, nick: &str) -> Mentions<Self> {
+        Mentions {
+            inner: self,
+            nick: nick.to_owned(),
+        }
+    }
+}
+
+impl<S> MessageStreamExt for S where S: Stream<Item = Message> {}
+
+/// A `Stream` of the PRIVMSG messages of another stream, returned by
+/// `MessageStreamExt::privmsgs`.
+pub struct Privmsgs<S> {
+    inner: S,
+}
+
+impl<S> Stream for Privmsgs<S>
+where
+    S: Stream<Item = Message>,
+{
+    type Item = Privmsg;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(message) => if let Some(privmsg) = Privmsg::from_message(message) {
+                    return Ok(Async::Ready(Some(privmsg)));
+                },
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// A `Stream` of the TAGMSG messages of another stream, returned by
+/// `MessageStreamExt::tagmsgs`.
+pub struct TagMsgs<S> {
+    inner: S,
+}
+
+impl<S> Stream for TagMsgs<S>
+where
+    S: Stream<Item = Message>,
+{
+    type Item = TagMsg;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(message) => if let Some(tagmsg) = TagMsg::from_message(message) {
+                    return Ok(Async::Ready(Some(tagmsg)));
+                },
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// A `Stream` of the messages of another stream concerning a channel,
+/// returned by `MessageStreamExt::from_channel`.
+pub struct FromChannel<S> {
+    inner: S,
+    channel: String,
+}
+
+impl<S> Stream for FromChannel<S>
+where
+    S: Stream<Item = Message>,
+{
+    type Item = Message;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(message) => {
+                    let matches = match message.raw_args().next() {
+                        Some(target) => CaseMapping::default().fold(target) == self.channel,
+                        None => false,
+                    };
+
+                    if matches {
+                        return Ok(Async::Ready(Some(message)));
+                    }
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// A `Stream` of the numeric replies of another stream, returned by
+/// `MessageStreamExt::numerics`.
+pub struct Numerics<S> {
+    inner: S,
+    codes: RangeInclusive<u16>,
+}
+
+impl<S> Stream for Numerics<S>
+where
+    S: Stream<Item = Message>,
+{
+    type Item = Numeric;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(message) => {
+                    let command = message.raw_command();
+
+                    // Numerics are always three digits.
+                    let code = match command.parse::<u16>() {
+                        Ok(code) if command.len() == 3 => code,
+                        _ => continue,
+                    };
+
+                    if self.codes.contains(&code) {
+                        return Ok(Async::Ready(Some(Numeric {
+                            code: code,
+                            message: message,
+                        })));
+                    }
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// A `Stream` of the PRIVMSG messages of another stream mentioning a
+/// nickname, returned by `MessageStreamExt::mentions`.
+pub struct Mentions<S> {
+    inner: S,
+    nick: String,
+}
+
+impl<S> Stream for Mentions<S>
+where
+    S: Stream<Item = Message>,
+{
+    type Item = Privmsg;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let privmsg = match try_ready!(self.inner.poll()) {
+                Some(message) => match Privmsg::from_message(message) {
+                    Some(privmsg) => privmsg,
+                    None => continue,
+                },
+                None => return Ok(Async::Ready(None)),
+            };
+
+            if mentions(&privmsg.text, &self.nick) {
+                return Ok(Async::Ready(Some(privmsg)));
+            }
+        }
+    }
+}
+
+// Returns true if `nick` appears in `text` as a word by itself, rather than
+// as part of a longer word or nickname.
+fn mentions(text: &str, nick: &str) -> bool {
+    let is_nick_char = |c: char| c.is_alphanumeric() || "-_[]{}\\|^`".contains(c);
+    let casemapping = CaseMapping::default();
+
+    text.split(|c: char| !is_nick_char(c))
+        .any(|word| casemapping.equal(word, nick))
+}
@@ -0,0 +1,386 @@
+//! The fish module implements FiSH (blowcrypt) message encryption on top of
+//! the hooks provided by the `transform` module.
+//!
+//! FiSH encrypts the text of each message with Blowfish in ECB mode using a
+//! key shared by everyone in a channel, or by the two users in a private
+//! conversation, and encodes it with its own variant of base64 behind an
+//! `+OK ` prefix. Keys are held by `FishKeys`, from which an inbound
+//! transform for decryption and an outbound transform for encryption are
+//! obtained:
+//!
+//! ```no_run
+//! # extern crate tokio_irc_client;
+//! # use tokio_irc_client::Client;
+//! # use tokio_irc_client::fish::FishKeys;
+//! # fn main() {
+//! # let addr = "127.0.0.1:6667".parse::<std::net::SocketAddr>().unwrap();
+//! let keys = FishKeys::new();
+//! keys.set_key("#secret", "hunter22").unwrap();
+//!
+//! let client = Client::new(addr)
+//!     .inbound_transform(keys.decrypt())
+//!     .outbound_transform(keys.encrypt());
+//! # }
+//! ```
+//!
+//! Both PRIVMSG and NOTICE messages are encrypted and decrypted, other than
+//! CTCP messages. Encrypted text takes up half as much room again as the
+//! text itself, so text that would make the message too long for the server
+//! to relay is split up and sent as several messages, each encrypted on its
+//! own. Messages exchanged with a peer that has no key are left
+//! untouched, as are incoming messages that aren't encrypted. Peers are
+//! matched to keys with the casemapping of the connection, so a key set for
+//! `#Secret[1]` is used for `#secret{1}` on servers using `rfc1459`. Key
+//! exchange, such as DH1080, and the CBC mode of newer FiSH implementations
+//! aren't supported.
+//!
+//! This module is only available with the `fish` feature.
+
+use casemap::CaseMapping;
+use error::{ErrorKind, Result};
+use state::State;
+use transform::{Transform, TransformFuture};
+
+use blowfish::Blowfish;
+use blowfish::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use blowfish::cipher::generic_array::GenericArray;
+
+use futures::future;
+
+use std::sync::{Arc, RwLock};
+
+// The alphabet of FiSH's base64 variant.
+const ALPHABET: &[u8] = b"./0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+const BLOCK_LENGTH: usize = 8;
+const ENCODED_BLOCK_LENGTH: usize = 12;
+
+// The prefixes marking encrypted text, the second of which is used by some
+// older clients.
+const PREFIXES: &[&str] = &["+OK ", "mcps "];
+
+/// The keys used to encrypt and decrypt the messages exchanged with each
+/// channel or user. `FishKeys` is a handle, so keys can be changed after
+/// its transforms have been installed on a `Client`.
+///
+/// The names given to `set_key`, `remove_key` and `has_key` are compared
+/// with the `rfc1459` casemapping, which is what servers assume unless they
+/// advertise another.
+#[derive(Clone, Default)]
+pub struct FishKeys {
+    keys: Arc<RwLock<Vec<(String, Blowfish)>>>,
+}
+
+impl FishKeys {
+    /// Create an empty set of keys.
+    pub fn new() -> FishKeys {
+        FishKeys::default()
+    }
+
+    /// Use `key` for messages exchanged with `peer`, a channel or nickname.
+    /// Keys must be between 4 and 56 bytes long.
+    pub fn set_key(&self, peer: &str, key: &str) -> Result<()> {
+        let cipher = Blowfish::new_from_slice(key.as_bytes())
+            .map_err(|_| ErrorKind::InvalidKey(peer.to_owned()))?;
+
+        let mut keys = self.keys.write().expect("FishKeys lock poisoned");
+
+        keys.retain(|(name, _)| !CaseMapping::default().equal(name, peer));
+        keys.push((peer.to_owned(), cipher));
+
+        Ok(())
+    }
+
+    /// Stop encrypting messages exchanged with `peer`.
+    pub fn remove_key(&self, peer: &str) {
+        self.keys
+            .write()
+            .expect("FishKeys lock poisoned")
+            .retain(|(name, _)| !CaseMapping::default().equal(name, peer));
+    }
+
+    /// Whether there's a key for messages exchanged with `peer`.
+    pub fn has_key(&self, peer: &str) -> bool {
+        self.with_cipher(CaseMapping::default(), peer, |_| ()).is_some()
+    }
+
+    /// Returns a `Transform` that decrypts incoming messages.
+    pub fn decrypt(&self) -> Decrypt {
+        Decrypt { keys: self.clone() }
+    }
+
+    /// Returns a `Transform` that encrypts outgoing messages.
+    pub fn encrypt(&self) -> Encrypt {
+        Encrypt { keys: self.clone() }
+    }
+
+    // Calls `f` with the cipher of the key for `peer`, if there is one,
+    // preferring a key set for exactly the same name.
+    fn with_cipher<F, R>(&self, casemapping: CaseMapping, peer: &str, f: F) -> Option<R>
+    where
+        F: FnOnce(&Blowfish) -> R,
+    {
+        let keys = self.keys.read().expect("FishKeys lock poisoned");
+
+        keys.iter()
+            .find(|(name, _)| name == peer)
+            .or_else(|| keys.iter().find(|(name, _)| casemapping.equal(name, peer)))
+            .map(|(_, cipher)| f(cipher))
+    }
+}
+
+/// A `Transform` decrypting FiSH encrypted messages, obtained from
+/// `FishKeys::decrypt`.
+pub struct Decrypt {
+    keys: FishKeys,
+}
+
+impl Decrypt {
+    fn decrypt(&self, casemapping: CaseMapping, peer: &str, text: String) -> TransformFuture {
+        let ciphertext = PREFIXES
+            .iter()
+            .filter_map(|prefix| text.strip_prefix(prefix))
+            .next();
+
+        let plaintext = match ciphertext {
+            Some(ciphertext) => self.keys
+                .with_cipher(casemapping, peer, |cipher| decrypt(cipher, ciphertext))
+                .and_then(|plaintext| plaintext),
+            None => None,
+        };
+
+        Box::new(future::ok(Some(plaintext.unwrap_or(text))))
+    }
+}
+
+impl Transform for Decrypt {
+    fn transform(&self, peer: &str, text: String) -> TransformFuture {
+        self.decrypt(CaseMapping::default(), peer, text)
+    }
+
+    fn transform_on(&self, state: &State, peer: &str, text: String) -> TransformFuture {
+        self.decrypt(state.casemapping(), peer, text)
+    }
+}
+
+/// A `Transform` encrypting messages with FiSH, obtained from
+/// `FishKeys::encrypt`.
+pub struct Encrypt {
+    keys: FishKeys,
+}
+
+impl Encrypt {
+    fn encrypt(&self, state: &State, peer: &str, text: String) -> TransformFuture {
+        let limit = state.message_length_limit("PRIVMSG", peer);
+        let ciphertext = self.keys.with_cipher(state.casemapping(), peer, |cipher| {
+            split(&text, plaintext_limit(limit))
+                .into_iter()
+                .map(|piece| encrypt(cipher, piece))
+                .collect::<Vec<_>>()
+                .join("\n")
+        });
+
+        Box::new(future::ok(Some(ciphertext.unwrap_or(text))))
+    }
+}
+
+impl Transform for Encrypt {
+    // Without the state of the connection, the longest possible prefix is
+    // assumed when splitting the text.
+    fn transform(&self, peer: &str, text: String) -> TransformFuture {
+        self.encrypt(&State::default(), peer, text)
+    }
+
+    fn transform_on(&self, state: &State, peer: &str, text: String) -> TransformFuture {
+        self.encrypt(state, peer, text)
+    }
+}
+
+// The most bytes of plaintext whose ciphertext fits in `limit` bytes, which
+// is at least a block so that long text is always making progress.
+fn plaintext_limit(limit: usize) -> usize {
+    let blocks = limit.saturating_sub(PREFIXES[0].len()) / ENCODED_BLOCK_LENGTH;
+
+    blocks.max(1) * BLOCK_LENGTH
+}
+
+// Splits the text into pieces of at most `limit` bytes, preferring to split
+// after a space and never splitting a character. The limit is at least a
+// block, which is longer than any character.
+fn split(text: &str, limit: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+
+    while rest.len() > limit {
+        let mut end = limit;
+
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        if let Some(space) = rest[..end].rfind(' ') {
+            end = space + 1;
+        }
+
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+
+    pieces.push(rest);
+    pieces
+}
+
+fn encrypt(cipher: &Blowfish, plaintext: &str) -> String {
+    let mut encoded = String::from(PREFIXES[0]);
+
+    // The final block is padded with NULs.
+    for chunk in plaintext.as_bytes().chunks(BLOCK_LENGTH) {
+        let mut block = GenericArray::clone_from_slice(&[0; BLOCK_LENGTH]);
+        block[..chunk.len()].copy_from_slice(chunk);
+
+        cipher.encrypt_block(&mut block);
+
+        let (left, right) = block.split_at(BLOCK_LENGTH / 2);
+
+        // Each half is encoded as six characters, least significant bits
+        // first, with the right half coming first.
+        for half in &[right, left] {
+            let mut value = u32::from_be_bytes([half[0], half[1], half[2], half[3]]);
+
+            for _ in 0..6 {
+                encoded.push(ALPHABET[(value & 0x3f) as usize] as char);
+                value >>= 6;
+            }
+        }
+    }
+
+    encoded
+}
+
+// Returns `None` if the ciphertext isn't valid.
+fn decrypt(cipher: &Blowfish, ciphertext: &str) -> Option<String> {
+    let ciphertext = ciphertext.trim_end().as_bytes();
+
+    let chunks = ciphertext.chunks_exact(ENCODED_BLOCK_LENGTH);
+
+    if ciphertext.is_empty() || !chunks.remainder().is_empty() {
+        return None;
+    }
+
+    let mut plaintext = Vec::with_capacity(ciphertext.len() / ENCODED_BLOCK_LENGTH * BLOCK_LENGTH);
+
+    for chunk in chunks {
+        let mut halves = [0u32; 2];
+
+        for (half, encoded) in halves.iter_mut().zip(chunk.chunks(6)) {
+            let mut value = 0u64;
+
+            for (i, &c) in encoded.iter().enumerate() {
+                let index = ALPHABET.iter().position(|&a| a == c)?;
+                value |= (index as u64) << (i * 6);
+            }
+
+            *half = value as u32;
+        }
+
+        let mut block = GenericArray::clone_from_slice(&[0; BLOCK_LENGTH]);
+        block[..4].copy_from_slice(&halves[1].to_be_bytes());
+        block[4..].copy_from_slice(&halves[0].to_be_bytes());
+
+        cipher.decrypt_block(&mut block);
+        plaintext.extend_from_slice(&block);
+    }
+
+    while plaintext.last() == Some(&0) {
+        plaintext.pop();
+    }
+
+    Some(String::from_utf8_lossy(&plaintext).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Future;
+
+    // Keys, plaintext and the ciphertext of the plaintext, as produced by a
+    // separate implementation of FiSH using the Blowfish of pyca/cryptography,
+    // which agrees with `openssl enc -bf-ecb`.
+    const VECTORS: &[(&str, &str, &str)] = &[
+        ("hunter22", "Hello, world!", "+OK CrB9u.QKj7d.O/rZM.7Pq4C/"),
+        ("0123456789abcdef", "hello wo", "+OK eM3Kz/9Hrhj0"),
+        (
+            "a much longer secret key",
+            "PRIVMSG text with ümlauts, 1234567",
+            "+OK 1ZX2p1Kzlv6/Ib5nq0WnkIu0sisSx1QcJwk/s9SJ6.YlNap.8QK4l.ffwLZ0",
+        ),
+    ];
+
+    fn cipher(key: &str) -> Blowfish {
+        Blowfish::new_from_slice(key.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn blowfish_matches_openssl() {
+        let mut block = GenericArray::clone_from_slice(b"hello wo");
+        cipher("0123456789abcdef").encrypt_block(&mut block);
+
+        assert_eq!(&block[..], &[0x95, 0x4d, 0xdb, 0x4b, 0x65, 0xc0, 0x5c, 0x90][..]);
+    }
+
+    #[test]
+    fn text_is_encrypted_as_other_implementations_do() {
+        for &(key, plaintext, ciphertext) in VECTORS {
+            assert_eq!(encrypt(&cipher(key), plaintext), ciphertext);
+        }
+    }
+
+    #[test]
+    fn text_encrypted_by_other_implementations_is_decrypted() {
+        for &(key, plaintext, ciphertext) in VECTORS {
+            let ciphertext = &ciphertext[PREFIXES[0].len()..];
+            assert_eq!(decrypt(&cipher(key), ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn invalid_ciphertext_is_left_alone() {
+        assert_eq!(decrypt(&cipher("hunter22"), "CrB9u.QKj7d"), None);
+        assert_eq!(decrypt(&cipher("hunter22"), "CrB9u.QKj7d!"), None);
+        assert_eq!(decrypt(&cipher("hunter22"), ""), None);
+    }
+
+    #[test]
+    fn long_text_is_split_into_lines_the_server_can_relay() {
+        let keys = FishKeys::new();
+        keys.set_key("#secret", "hunter22").unwrap();
+
+        let text = "ünïcödé wörds ".repeat(40);
+        let encrypted = keys.encrypt().transform("#secret", text.clone()).wait().unwrap().unwrap();
+        let lines: Vec<&str> = encrypted.split('\n').collect();
+
+        assert!(lines.len() > 1);
+
+        let limit = State::default().message_length_limit("PRIVMSG", "#secret");
+        let mut decrypted = String::new();
+
+        for line in lines {
+            assert!(line.len() <= limit, "{} bytes", line.len());
+
+            let ciphertext = line.strip_prefix(PREFIXES[0]).unwrap();
+            decrypted.push_str(&decrypt(&cipher("hunter22"), ciphertext).unwrap());
+        }
+
+        assert_eq!(decrypted, text);
+    }
+
+    #[test]
+    fn pieces_end_after_a_space_without_splitting_characters() {
+        assert_eq!(split("one two three", 8), vec!["one two ", "three"]);
+        assert_eq!(split("one two three", 7), vec!["one ", "two ", "three"]);
+        assert_eq!(split("ééééé", 8), vec!["éééé", "é"]);
+        assert_eq!(split("short", 8), vec!["short"]);
+    }
+}
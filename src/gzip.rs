@@ -0,0 +1,269 @@
+// A streaming gzip decoder, as described by RFC 1952, built on the DEFLATE
+// implementation of miniz_oxide. Files made of several concatenated members,
+// as produced by appending to a compressed log, are decoded as one.
+
+use miniz_oxide::inflate::stream::{self, InflateState};
+use miniz_oxide::{DataFormat, MZFlush, MZStatus};
+
+use std::io::{self, BufRead, Read};
+
+pub(crate) const MAGIC: &[u8] = &[0x1f, 0x8b];
+
+// The flags of the member header.
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+pub(crate) struct GzDecoder<R> {
+    inner: R,
+    inflate: Box<InflateState>,
+    // Whether a member header has been read and its data not yet finished.
+    in_member: bool,
+    crc: u32,
+    size: u32,
+}
+
+impl<R: BufRead> GzDecoder<R> {
+    pub fn new(inner: R) -> GzDecoder<R> {
+        GzDecoder {
+            inner: inner,
+            inflate: InflateState::new_boxed(DataFormat::Raw),
+            in_member: false,
+            crc: !0,
+            size: 0,
+        }
+    }
+
+    // Reads the header of the next member, returning false at the end of the
+    // input.
+    fn start_member(&mut self) -> io::Result<bool> {
+        if self.inner.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+
+        let mut header = [0; 10];
+        self.inner.read_exact(&mut header)?;
+
+        if &header[..2] != MAGIC || header[2] != 8 {
+            return Err(invalid("not a gzip member"));
+        }
+
+        let flags = header[3];
+
+        if flags & FEXTRA != 0 {
+            let mut length = [0; 2];
+            self.inner.read_exact(&mut length)?;
+
+            let length = u16::from_le_bytes(length) as u64;
+            io::copy(&mut (&mut self.inner).take(length), &mut io::sink())?;
+        }
+
+        for &flag in &[FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                let mut field = Vec::new();
+                self.inner.read_until(0, &mut field)?;
+            }
+        }
+
+        if flags & FHCRC != 0 {
+            self.inner.read_exact(&mut [0; 2])?;
+        }
+
+        self.inflate.reset(DataFormat::Raw);
+        self.in_member = true;
+        self.crc = !0;
+        self.size = 0;
+
+        Ok(true)
+    }
+
+    // Checks the trailer of the member that was just decoded.
+    fn finish_member(&mut self) -> io::Result<()> {
+        let mut trailer = [0; 8];
+        self.inner.read_exact(&mut trailer)?;
+
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+        if crc != !self.crc || size != self.size {
+            return Err(invalid("gzip member is corrupt"));
+        }
+
+        self.in_member = false;
+
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for GzDecoder<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if !self.in_member && !self.start_member()? {
+                return Ok(0);
+            }
+
+            let (consumed, written, status) = {
+                let input = self.inner.fill_buf()?;
+                let result = stream::inflate(&mut self.inflate, input, buffer, MZFlush::None);
+
+                if input.is_empty() && result.bytes_written == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                (result.bytes_consumed, result.bytes_written, result.status)
+            };
+
+            self.inner.consume(consumed);
+            self.crc = crc32(self.crc, &buffer[..written]);
+            self.size = self.size.wrapping_add(written as u32);
+
+            match status {
+                Ok(MZStatus::StreamEnd) => self.finish_member()?,
+                Ok(_) => (),
+                Err(_) => return Err(invalid("gzip data is corrupt")),
+            }
+
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+// Updates a CRC-32, as used by gzip, with the given bytes.
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    crc
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "PING :x\r\n", as compressed by Python's gzip module.
+    const PING: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x0b, 0xf0, 0xf4, 0x73, 0x57,
+        0xb0, 0xaa, 0xe0, 0xe5, 0x02, 0x00, 0x94, 0x21, 0x2e, 0xf5, 0x09, 0x00, 0x00, 0x00,
+    ];
+
+    // A line of a log named "log.txt", as compressed by Python's gzip module.
+    const WELCOME: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x6c, 0x6f, 0x67, 0x2e, 0x74,
+        0x78, 0x74, 0x00, 0xb3, 0xca, 0x2c, 0x4a, 0xd6, 0x4b, 0xad, 0x48, 0xcc, 0x2d, 0xc8, 0x49,
+        0xd5, 0xcb, 0x2f, 0x4a, 0x57, 0x30, 0x30, 0x30, 0x54, 0x48, 0xca, 0x2f, 0x51, 0xb0, 0x0a,
+        0x4f, 0xcd, 0x49, 0xce, 0xcf, 0x4d, 0xe5, 0xe5, 0x02, 0x00, 0x14, 0xc8, 0x0d, 0x63, 0x23,
+        0x00, 0x00, 0x00,
+    ];
+
+    // "PRIVMSG #a :hi\r\n" in a member with an extra field, a comment and a
+    // header checksum.
+    const PRIVMSG: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x04, 0x00, 0x61, 0x62, 0x00,
+        0x01, 0x6e, 0x6f, 0x74, 0x65, 0x00, 0x71, 0xec, 0x0b, 0x08, 0xf2, 0x0c, 0xf3, 0x0d, 0x76,
+        0x57, 0x50, 0x4e, 0x54, 0xb0, 0xca, 0xc8, 0xe4, 0xe5, 0x02, 0x00, 0x2b, 0x9a, 0x90, 0xaf,
+        0x10, 0x00, 0x00, 0x00,
+    ];
+
+    // An empty file, as compressed by Python's gzip module.
+    const EMPTY: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x03, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn decode(file: &[u8]) -> io::Result<String> {
+        let mut decoded = String::new();
+        GzDecoder::new(file).read_to_string(&mut decoded)?;
+
+        Ok(decoded)
+    }
+
+    #[test]
+    fn files_are_decoded() {
+        assert_eq!(decode(PING).unwrap(), "PING :x\r\n");
+        assert_eq!(
+            decode(WELCOME).unwrap(),
+            ":irc.example.org 001 bot :Welcome\r\n"
+        );
+        assert_eq!(decode(PRIVMSG).unwrap(), "PRIVMSG #a :hi\r\n");
+        assert_eq!(decode(EMPTY).unwrap(), "");
+    }
+
+    #[test]
+    fn concatenated_members_are_decoded_as_one() {
+        let file = [PING, EMPTY, PRIVMSG].concat();
+
+        assert_eq!(decode(&file).unwrap(), "PING :x\r\nPRIVMSG #a :hi\r\n");
+    }
+
+    #[test]
+    fn files_are_decoded_a_byte_at_a_time() {
+        let file = [WELCOME, PING].concat();
+        let mut decoder = GzDecoder::new(io::BufReader::with_capacity(1, &file[..]));
+        let mut decoded = Vec::new();
+        let mut byte = [0];
+
+        while decoder.read(&mut byte).unwrap() == 1 {
+            decoded.push(byte[0]);
+        }
+
+        assert_eq!(decoded, &b":irc.example.org 001 bot :Welcome\r\nPING :x\r\n"[..]);
+    }
+
+    #[test]
+    fn truncated_files_are_refused() {
+        for length in 1..PING.len() {
+            assert!(decode(&PING[..length]).is_err(), "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn corrupt_data_is_refused() {
+        // Not gzip at all.
+        let file = b":irc.example.org 001 bot :Welcome\r\n";
+        assert_eq!(decode(file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A DEFLATE block of the reserved type 3.
+        let mut file = PING.to_vec();
+        file[10] = 0x07;
+        assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A second member which isn't gzip.
+        let file = [PING, b"not a gzip member"].concat();
+        assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn checksum_and_size_mismatches_are_refused() {
+        let crc = PING.len() - 8;
+
+        let mut file = PING.to_vec();
+        file[crc] ^= 1;
+        assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut file = PING.to_vec();
+        file[crc + 4] += 1;
+        assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xCBF4_3926);
+    }
+}
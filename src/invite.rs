@@ -0,0 +1,133 @@
+//! The invite module contains the types used to control how invitations to
+//! channels are handled.
+//!
+//! Every INVITE is reported with `Event::Invited`. Setting an `InvitePolicy`
+//! on a `Client` also makes the `IrcTransport` join the channels it's invited
+//! to, either from a list of trusted nicknames or as decided by a closure.
+//! Invited channels are joined with `Requester::join`, and joins the server
+//! refuses are reported with `Event::AutomationFailed`.
+
+use command::Invite as InviteCommand;
+use event::{Automation, EventBus};
+use request::{Requester, Response};
+use state::State;
+
+use futures::{Async, Future};
+
+use pircolate::Message;
+
+use std::fmt;
+use std::sync::Arc;
+
+/// An invitation of the client to a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invite {
+    /// The nickname of the user who sent the invitation.
+    pub nick: String,
+    /// The channel the client was invited to.
+    pub channel: String,
+}
+
+type Decide = dyn Fn(&Invite, &State) -> bool + Send + Sync;
+
+/// How invitations of the client to channels are handled.
+#[derive(Clone, Default)]
+pub enum InvitePolicy {
+    /// Invitations are only reported.
+    #[default]
+    Ignore,
+    /// Invitations from the given nicknames are accepted by joining the
+    /// channel. Nicknames are compared using the server's case mapping.
+    AcceptFrom(Vec<String>),
+    /// Invitations for which the closure returns true are accepted by
+    /// joining the channel.
+    Decide(Arc<Decide>),
+}
+
+impl InvitePolicy {
+    /// Accept the invitations for which `f` returns true.
+    pub fn decide<F>(f: F) -> InvitePolicy
+    where
+        F: Fn(&Invite, &State) -> bool + Send + Sync + 'static,
+    {
+        InvitePolicy::Decide(Arc::new(f))
+    }
+
+    fn accepts(&self, invite: &Invite, state: &State) -> bool {
+        match *self {
+            InvitePolicy::Ignore => false,
+            InvitePolicy::AcceptFrom(ref nicks) => nicks
+                .iter()
+                .any(|nick| state.same_name(nick, &invite.nick)),
+            InvitePolicy::Decide(ref f) => f(invite, state),
+        }
+    }
+}
+
+impl fmt::Debug for InvitePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            InvitePolicy::Ignore => f.write_str("Ignore"),
+            InvitePolicy::AcceptFrom(ref nicks) => f.debug_tuple("AcceptFrom").field(nicks).finish(),
+            InvitePolicy::Decide(_) => f.write_str("Decide(..)"),
+        }
+    }
+}
+
+// Applies an `InvitePolicy` to the invitations received on a connection.
+pub(crate) struct InviteHandler {
+    policy: InvitePolicy,
+    // The joins of accepted invitations waiting to be confirmed.
+    joining: Vec<Response<()>>,
+}
+
+impl InviteHandler {
+    pub fn new(policy: InvitePolicy) -> InviteHandler {
+        InviteHandler {
+            policy: policy,
+            joining: Vec::new(),
+        }
+    }
+
+    // Handles a message received from the server, after it has been seen by
+    // `requester`, joining the channel of an accepted invitation and
+    // reporting the joins that failed.
+    pub fn handle_incoming(
+        &mut self,
+        message: &Message,
+        state: &State,
+        requester: &Requester,
+        events: &EventBus,
+    ) {
+        if let Some(invite) = invite(message, state) {
+            if self.policy.accepts(&invite, state) {
+                self.joining.push(requester.join(&invite.channel, None));
+            }
+        }
+
+        self.joining.retain_mut(|join| match join.poll() {
+            Ok(Async::NotReady) => true,
+            Ok(Async::Ready(())) => false,
+            Err(err) => {
+                events.emit_failure(Automation::InviteJoin, err);
+                false
+            }
+        });
+    }
+}
+
+// The invitation of the client held by `message`, if it's one. With the
+// `invite-notify` capability, invitations of other users are seen as well.
+fn invite(message: &Message, state: &State) -> Option<Invite> {
+    let InviteCommand(target, channel) = message.command::<InviteCommand>()?;
+    let (nick, _, _) = message.prefix()?;
+
+    if !state.is_self(target) {
+        return None;
+    }
+
+    Some(Invite {
+        nick: nick.to_owned(),
+        channel: channel.to_owned(),
+    })
+}
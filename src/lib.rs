@@ -27,26 +27,113 @@
 // TODO: **REALLY** improve the quality of the documentation in this library.
 // it's really bad. I'm not very good at writing it.
 #![deny(missing_docs)]
+// The error_chain! invocations have outgrown the default limit.
+#![recursion_limit = "256"]
 
 #[macro_use]
 extern crate futures;
 #[macro_use]
 extern crate error_chain;
+#[cfg(not(target_arch = "wasm32"))]
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate bytes;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate socket2;
+extern crate getrandom;
+#[macro_use]
 extern crate pircolate;
 
 #[cfg(feature = "tls")]
 extern crate tokio_tls;
 #[cfg(feature = "tls")]
 extern crate native_tls;
+#[cfg(all(
+    feature = "tls",
+    not(any(target_os = "macos", target_os = "windows", target_os = "ios"))
+))]
+extern crate openssl;
+
+#[cfg(feature = "fish")]
+extern crate blowfish;
+
+#[cfg(feature = "gzip")]
+extern crate miniz_oxide;
+
+#[cfg(feature = "rules")]
+extern crate regex;
+#[cfg(feature = "rules")]
+extern crate serde;
+#[cfg(feature = "rules")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "rules")]
+extern crate toml;
 
 mod codec;
+#[cfg(not(target_arch = "wasm32"))]
+mod connect;
+mod digest;
+pub mod account;
+pub mod away;
+#[cfg(feature = "bot")]
+pub mod bot;
+pub mod casemap;
+pub mod chathistory;
+pub mod clock;
 pub mod error;
 pub mod client;
+pub mod command;
+pub mod conversation;
+pub mod ctcp;
+pub mod event;
+#[cfg(feature = "testing")]
+pub mod fault;
+pub mod filter;
+#[cfg(feature = "fish")]
+pub mod fish;
+#[cfg(feature = "gzip")]
+mod gzip;
+pub mod invite;
+pub mod limit;
+pub mod logfile;
+pub mod mode;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod multi;
+pub mod outgoing;
+pub mod pool;
+pub mod pretty;
+pub mod query;
+pub mod ready;
+pub mod reconnect;
+pub mod registration;
+pub mod replay;
+pub mod request;
+#[cfg(feature = "rules")]
+pub mod rules;
+pub mod sasl;
+pub mod srv;
+pub mod state;
+pub mod stats;
+pub mod tagmsg;
+pub mod template;
+pub mod throttle;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod traffic;
+pub mod transform;
+pub mod watchdog;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod who;
+pub mod znc;
 
-pub use client::{Client, ClientConnectFuture};
+pub use client::{Client, OversizedLinePolicy};
+#[cfg(not(target_arch = "wasm32"))]
+pub use client::ClientConnectFuture;
 #[cfg(feature = "tls")]
 pub use client::ClientConnectTlsFuture;
 pub use error::Error;
+pub use event::{Automation, Event, Events};
+pub use registration::Registration;
+pub use state::State;
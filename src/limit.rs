@@ -0,0 +1,417 @@
+//! The limit module contains `CommandLimiter`, a counter based rate limiter
+//! for protecting bot commands from abuse.
+//!
+//! Each command can be given its own `Limit`, which caps how many times it
+//! may be used within a window of time by a single user and within a single
+//! channel. Users who keep going over a limit are blocked for exponentially
+//! longer periods, and are told about it at most once per block.
+//!
+//! Nicknames and channel names are compared the way the server compares
+//! them when checked with `CommandLimiter::check_on`, and with the `rfc1459`
+//! casemapping otherwise. Counters are forgotten once their window has
+//! passed, and offenders once they've been forgiven, so the limiter doesn't
+//! grow with every user it has ever seen.
+
+use casemap::CaseMapping;
+use clock::{self, Clock};
+use state::State;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often counters and offenders that no longer matter are forgotten.
+const PRUNE_INTERVAL_IN_SECONDS: u64 = 60;
+
+/// The limits applied to a single command.
+#[derive(Clone, Debug)]
+pub struct Limit {
+    per_user: Option<(u32, Duration)>,
+    per_channel: Option<(u32, Duration)>,
+    penalty: Duration,
+    max_penalty: Duration,
+    forgive_after: Duration,
+    response: Option<String>,
+}
+
+impl Limit {
+    /// Create a new `Limit` which doesn't restrict the command. By default
+    /// offenders are blocked for 30 seconds, doubling with each repeated
+    /// offense up to an hour, and forgiven after 10 minutes of good behaviour.
+    pub fn new() -> Limit {
+        Limit {
+            per_user: None,
+            per_channel: None,
+            penalty: Duration::from_secs(30),
+            max_penalty: Duration::from_secs(60 * 60),
+            forgive_after: Duration::from_secs(10 * 60),
+            response: None,
+        }
+    }
+
+    /// Allow each user at most `count` uses of the command per `window`.
+    pub fn per_user(mut self, count: u32, window: Duration) -> Limit {
+        self.per_user = Some((count, window));
+        self
+    }
+
+    /// Allow at most `count` uses of the command per `window` in each channel.
+    pub fn per_channel(mut self, count: u32, window: Duration) -> Limit {
+        self.per_channel = Some((count, window));
+        self
+    }
+
+    /// Block offenders for `penalty` on their first offense, doubling for
+    /// each repeated offense, up to `max_penalty`.
+    pub fn penalty(mut self, penalty: Duration, max_penalty: Duration) -> Limit {
+        self.penalty = penalty;
+        self.max_penalty = max_penalty;
+        self
+    }
+
+    /// Forget a user's previous offenses once they've gone `duration`
+    /// without committing another.
+    pub fn forgive_after(mut self, duration: Duration) -> Limit {
+        self.forgive_after = duration;
+        self
+    }
+
+    /// The response to give to a user when they're first blocked.
+    pub fn response<R: Into<String>>(mut self, response: R) -> Limit {
+        self.response = Some(response.into());
+        self
+    }
+}
+
+impl Default for Limit {
+    fn default() -> Limit {
+        Limit::new()
+    }
+}
+
+/// The outcome of checking a use of a command against its limits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The command may be run.
+    Allow,
+    /// The command must not be run.
+    Limited {
+        /// How long until the command may be used again.
+        retry_after: Duration,
+        /// The cooldown response to send, only given the first time a user
+        /// is limited during a block.
+        response: Option<String>,
+    },
+}
+
+#[derive(Default)]
+pub(crate) struct Counter {
+    window_start: Option<Duration>,
+    count: u32,
+}
+
+impl Counter {
+    // Counts a use, returning false if it goes over the limit.
+    pub(crate) fn hit(&mut self, count: u32, window: Duration, now: Duration) -> bool {
+        if self.expired(window, now) {
+            self.window_start = Some(now);
+            self.count = 0;
+        }
+
+        self.count += 1;
+        self.count <= count
+    }
+
+    // Whether the uses counted so far are too old to count against a limit.
+    pub(crate) fn expired(&self, window: Duration, now: Duration) -> bool {
+        match self.window_start {
+            Some(start) => now.saturating_sub(start) >= window,
+            None => true,
+        }
+    }
+
+    fn retry_after(&self, window: Duration, now: Duration) -> Duration {
+        match self.window_start {
+            Some(start) => (start + window).saturating_sub(now),
+            None => Duration::from_secs(0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Offender {
+    strikes: u32,
+    blocked_until: Option<Duration>,
+    last_offense: Option<Duration>,
+    notified: bool,
+}
+
+impl Offender {
+    // Whether the offender is neither blocked nor has offenses left to be
+    // forgiven.
+    fn forgiven(&self, forgive_after: Duration, now: Duration) -> bool {
+        let blocked = self.blocked_until.is_some_and(|until| now < until);
+        let remembered = self.last_offense
+            .is_some_and(|last_offense| now.saturating_sub(last_offense) < forgive_after);
+
+        !blocked && !remembered
+    }
+}
+
+/// Tracks the use of commands and decides whether further uses are allowed.
+pub struct CommandLimiter {
+    clock: Arc<dyn Clock>,
+    default: Limit,
+    limits: HashMap<String, Limit>,
+    users: HashMap<(String, String), Counter>,
+    channels: HashMap<(String, String), Counter>,
+    offenders: HashMap<(String, String), Offender>,
+    last_pruned: Duration,
+}
+
+impl Default for CommandLimiter {
+    fn default() -> CommandLimiter {
+        CommandLimiter::new(Limit::default())
+    }
+}
+
+impl CommandLimiter {
+    /// Create a new `CommandLimiter` that applies `default` to commands
+    /// without a limit of their own.
+    pub fn new(default: Limit) -> CommandLimiter {
+        CommandLimiter {
+            clock: clock::system(),
+            default: default,
+            limits: HashMap::new(),
+            users: HashMap::new(),
+            channels: HashMap::new(),
+            offenders: HashMap::new(),
+            last_pruned: Duration::from_secs(0),
+        }
+    }
+
+    /// Use the given `Clock` as the source of time, instead of the system's
+    /// monotonic clock.
+    pub fn clock<C>(mut self, clock: C) -> CommandLimiter
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Apply the given `Limit` to `command` instead of the default.
+    pub fn limit<C: Into<String>>(mut self, command: C, limit: Limit) -> CommandLimiter {
+        self.limits.insert(command.into(), limit);
+        self
+    }
+
+    /// Check a use of `command` by `nick`, in `channel` if it wasn't used in
+    /// a private message, counting it towards the command's limits.
+    pub fn check(&mut self, command: &str, nick: &str, channel: Option<&str>) -> Decision {
+        let now = self.clock.now();
+
+        self.check_at(command, nick, channel, now)
+    }
+
+    /// The same as `check`, but comparing `nick` and `channel` with those of
+    /// earlier uses the way the server tracked by `state` compares names.
+    pub fn check_on(
+        &mut self,
+        state: &State,
+        command: &str,
+        nick: &str,
+        channel: Option<&str>,
+    ) -> Decision {
+        let now = self.clock.now();
+
+        self.check_with(state.casemapping(), command, nick, channel, now)
+    }
+
+    /// The same as `check`, but as if it were performed at the time `now`,
+    /// as measured by the limiter's `Clock`.
+    pub fn check_at(
+        &mut self,
+        command: &str,
+        nick: &str,
+        channel: Option<&str>,
+        now: Duration,
+    ) -> Decision {
+        self.check_with(CaseMapping::default(), command, nick, channel, now)
+    }
+
+    fn check_with(
+        &mut self,
+        casemapping: CaseMapping,
+        command: &str,
+        nick: &str,
+        channel: Option<&str>,
+        now: Duration,
+    ) -> Decision {
+        if now.saturating_sub(self.last_pruned).as_secs() >= PRUNE_INTERVAL_IN_SECONDS {
+            self.prune(now);
+        }
+
+        let limit = self.limits.get(command).unwrap_or(&self.default);
+        let user_key = (command.to_owned(), casemapping.fold(nick));
+        let offender = self.offenders.entry(user_key.clone()).or_default();
+
+        if let Some(last_offense) = offender.last_offense {
+            if now.saturating_sub(last_offense) >= limit.forgive_after {
+                *offender = Offender::default();
+            }
+        }
+
+        // Users who are already blocked stay blocked, and only hear about
+        // it the first time.
+        if let Some(blocked_until) = offender.blocked_until {
+            if now < blocked_until {
+                let response = if offender.notified {
+                    None
+                } else {
+                    offender.notified = true;
+                    limit.response.clone()
+                };
+
+                return Decision::Limited {
+                    retry_after: blocked_until - now,
+                    response: response,
+                };
+            }
+
+            offender.blocked_until = None;
+        }
+
+        let mut retry_after = None;
+
+        if let Some((count, window)) = limit.per_user {
+            let counter = self.users.entry(user_key.clone()).or_default();
+
+            if !counter.hit(count, window, now) {
+                retry_after = Some(counter.retry_after(window, now));
+            }
+        }
+
+        if let (Some((count, window)), Some(channel)) = (limit.per_channel, channel) {
+            let channel_key = (command.to_owned(), casemapping.fold(channel));
+            let counter = self.channels.entry(channel_key).or_default();
+
+            // A busy channel isn't the fault of any one user, so going over
+            // the channel limit doesn't count as an offense.
+            if !counter.hit(count, window, now) && retry_after.is_none() {
+                return Decision::Limited {
+                    retry_after: counter.retry_after(window, now),
+                    response: None,
+                };
+            }
+        }
+
+        let retry_after = match retry_after {
+            Some(retry_after) => retry_after,
+            None => return Decision::Allow,
+        };
+
+        offender.strikes += 1;
+        offender.last_offense = Some(now);
+
+        // The first offense only has to wait out the window, repeat
+        // offenses are blocked for exponentially longer.
+        let block = if offender.strikes == 1 {
+            retry_after
+        } else {
+            let factor = 2u32.saturating_pow(offender.strikes - 2);
+
+            limit
+                .penalty
+                .checked_mul(factor)
+                .unwrap_or(limit.max_penalty)
+                .min(limit.max_penalty)
+                .max(retry_after)
+        };
+
+        offender.blocked_until = Some(now + block);
+        offender.notified = true;
+
+        Decision::Limited {
+            retry_after: block,
+            response: limit.response.clone(),
+        }
+    }
+
+    // Forgets the counters whose window has passed and the offenders who
+    // have been forgiven, neither of which affects any later decision.
+    fn prune(&mut self, now: Duration) {
+        let limits = &self.limits;
+        let default = &self.default;
+        let limit = |command: &str| limits.get(command).unwrap_or(default);
+
+        self.users.retain(|(command, _), counter| match limit(command).per_user {
+            Some((_, window)) => !counter.expired(window, now),
+            None => false,
+        });
+
+        self.channels.retain(|(command, _), counter| match limit(command).per_channel {
+            Some((_, window)) => !counter.expired(window, now),
+            None => false,
+        });
+
+        self.offenders.retain(|(command, _), offender| {
+            !offender.forgiven(limit(command).forgive_after, now)
+        });
+
+        self.last_pruned = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn names_are_compared_by_casemapping() {
+        let limit = Limit::new().per_user(1, secs(10)).per_channel(2, secs(10));
+        let mut limiter = CommandLimiter::new(limit);
+
+        assert_eq!(limiter.check_at("ping", "bot[1]", Some("#a"), secs(0)), Decision::Allow);
+        assert!(limiter.check_at("ping", "BOT{1}", Some("#b"), secs(1)) != Decision::Allow);
+
+        assert_eq!(limiter.check_at("ping", "x", Some("#chan^"), secs(1)), Decision::Allow);
+        assert_eq!(limiter.check_at("ping", "y", Some("#CHAN~"), secs(1)), Decision::Allow);
+        assert!(limiter.check_at("ping", "z", Some("#chan~"), secs(1)) != Decision::Allow);
+    }
+
+    #[test]
+    fn expired_counters_and_forgiven_offenders_are_forgotten() {
+        let limit = Limit::new()
+            .per_user(1, secs(10))
+            .per_channel(5, secs(10))
+            .forgive_after(secs(100));
+        let mut limiter = CommandLimiter::new(limit);
+
+        for user in 0..10 {
+            limiter.check_at("ping", &user.to_string(), Some("#a"), secs(0));
+        }
+
+        limiter.check_at("ping", "0", None, secs(1));
+
+        assert_eq!(limiter.users.len(), 10);
+        assert_eq!(limiter.channels.len(), 1);
+
+        // Only the offender is remembered once the windows have passed.
+        limiter.check_at("ping", "1", None, secs(60));
+
+        assert_eq!(limiter.users.len(), 1);
+        assert!(limiter.channels.is_empty());
+        assert_eq!(limiter.offenders.len(), 2);
+        assert_eq!(limiter.offenders[&("ping".to_owned(), "0".to_owned())].strikes, 1);
+
+        limiter.check_at("ping", "1", None, secs(120));
+
+        assert_eq!(limiter.users.len(), 1);
+        assert_eq!(limiter.offenders.len(), 1);
+    }
+}
@@ -0,0 +1,97 @@
+//! The logfile module contains `LogReader`, which reads the messages stored
+//! in a log of raw IRC protocol lines, such as one recorded from a
+//! connection, so they can be analyzed or replayed.
+//!
+//! Logs are often archived compressed. With the `gzip` feature, logs
+//! compressed with gzip are recognized and decompressed while they're read,
+//! so they don't have to be decompressed first. Logs compressed in a format
+//! that isn't supported fail with `UnsupportedCompression`.
+
+use codec;
+use error::{ErrorKind, Result};
+
+use pircolate::Message;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+// The magic numbers starting files in the compression formats that are
+// recognized, along with their names.
+const FORMATS: &[(&[u8], &str)] = &[
+    (&[0x1f, 0x8b], "gzip"),
+    (&[0x28, 0xb5, 0x2f, 0xfd], "zstd"),
+    (b"BZh", "bzip2"),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], "xz"),
+];
+
+/// An `Iterator` over the messages in a log, with each line of the log
+/// holding a single message.
+///
+/// Blank lines are skipped, and lines that can't be parsed are yielded as
+/// errors without ending the iteration.
+pub struct LogReader {
+    inner: Box<dyn BufRead + Send>,
+    line: Vec<u8>,
+}
+
+impl LogReader {
+    /// Create a new `LogReader` reading the log at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<LogReader> {
+        LogReader::new(File::open(path)?)
+    }
+
+    /// Create a new `LogReader` reading a log from `reader`, decompressing
+    /// it if it's compressed.
+    pub fn new<R>(reader: R) -> Result<LogReader>
+    where
+        R: Read + Send + 'static,
+    {
+        let mut reader = BufReader::new(reader);
+
+        let format = {
+            let start = reader.fill_buf()?;
+
+            FORMATS
+                .iter()
+                .find(|&&(magic, _)| start.starts_with(magic))
+                .map(|&(_, format)| format)
+        };
+
+        let inner: Box<dyn BufRead + Send> = match format {
+            None => Box::new(reader),
+            #[cfg(feature = "gzip")]
+            Some("gzip") => Box::new(BufReader::new(::gzip::GzDecoder::new(reader))),
+            Some(format) => return Err(ErrorKind::UnsupportedCompression(format.to_owned()).into()),
+        };
+
+        Ok(LogReader {
+            inner: inner,
+            line: Vec::new(),
+        })
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+
+            match self.inner.read_until(b'\n', &mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(err) => return Some(Err(err.into())),
+            }
+
+            while let Some(&(b'\r' | b'\n')) = self.line.last() {
+                self.line.pop();
+            }
+
+            if !self.line.is_empty() {
+                return Some(codec::parse(&self.line));
+            }
+        }
+    }
+}
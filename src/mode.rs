@@ -0,0 +1,350 @@
+//! The mode module contains types for working with MODE changes.
+//!
+//! Whether each channel mode takes a parameter depends on the server, which
+//! advertises this with the `CHANMODES` and `PREFIX` ISUPPORT tokens.
+//! `ChannelModes` uses them to split a MODE message into a list of `Mode`
+//! changes, each with its own parameter, and to build valid MODE messages
+//! from such a list. The `ChannelModes` for a connection is available from
+//! `State::channel_mode_types`.
+
+use error::{ErrorKind, Result};
+use pretty::escape;
+
+use pircolate::Message;
+
+use std::fmt;
+
+// The channel modes assumed when the server doesn't advertise CHANMODES.
+pub(crate) const DEFAULT_CHANMODES: &str = "beI,k,l,imnpst";
+
+// The membership prefixes assumed when the server doesn't advertise PREFIX.
+pub(crate) const DEFAULT_PREFIX: &str = "(ov)@+";
+
+// The number of modes with parameters assumed to be allowed in a single
+// MODE message when the server doesn't advertise MODES.
+const DEFAULT_MAX_PARAMS: usize = 3;
+
+/// A single mode being set or unset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mode {
+    /// Whether the mode is being set, rather than unset.
+    pub adding: bool,
+    /// The mode character.
+    pub mode: char,
+    /// The parameter of the mode, if it takes one.
+    pub param: Option<String>,
+}
+
+impl Mode {
+    /// A mode being set, with the given parameter if it takes one.
+    pub fn add(mode: char, param: Option<&str>) -> Mode {
+        Mode {
+            adding: true,
+            mode: mode,
+            param: param.map(str::to_owned),
+        }
+    }
+
+    /// A mode being unset, with the given parameter if it takes one.
+    pub fn remove(mode: char, param: Option<&str>) -> Mode {
+        Mode {
+            adding: false,
+            mode: mode,
+            param: param.map(str::to_owned),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", if self.adding { '+' } else { '-' }, self.mode)?;
+
+        if let Some(ref param) = self.param {
+            write!(f, " {}", escape(param))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How a channel mode is used, as advertised by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeKind {
+    /// A mode maintaining a list of masks, such as bans, which always takes
+    /// a parameter.
+    List,
+    /// A setting which always takes a parameter, such as the channel key.
+    Always,
+    /// A setting which takes a parameter only when set, such as the member
+    /// limit.
+    WhenSet,
+    /// A flag which never takes a parameter.
+    Never,
+    /// A mode granting a membership prefix to a user, such as operator
+    /// status, which takes the nickname of the user.
+    Prefix,
+}
+
+/// The channel modes supported by a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelModes {
+    list: String,
+    always: String,
+    when_set: String,
+    prefix_modes: String,
+    prefix_symbols: String,
+    max_params: usize,
+}
+
+impl Default for ChannelModes {
+    fn default() -> ChannelModes {
+        ChannelModes::new(DEFAULT_CHANMODES, DEFAULT_PREFIX, None)
+    }
+}
+
+impl ChannelModes {
+    /// Create a new `ChannelModes` from the values of the `CHANMODES`,
+    /// `PREFIX` and `MODES` ISUPPORT tokens.
+    pub fn new(chanmodes: &str, prefix: &str, modes: Option<&str>) -> ChannelModes {
+        let mut types = chanmodes.split(',');
+
+        let (prefix_modes, prefix_symbols) = match prefix.find(')') {
+            Some(index) if prefix.starts_with('(') => (&prefix[1..index], &prefix[index + 1..]),
+            _ => ("", prefix),
+        };
+
+        ChannelModes {
+            list: types.next().unwrap_or_default().to_owned(),
+            always: types.next().unwrap_or_default().to_owned(),
+            when_set: types.next().unwrap_or_default().to_owned(),
+            prefix_modes: prefix_modes.to_owned(),
+            prefix_symbols: prefix_symbols.to_owned(),
+            max_params: modes
+                .and_then(|modes| modes.parse().ok())
+                .unwrap_or(DEFAULT_MAX_PARAMS),
+        }
+    }
+
+    /// How the given mode is used. Modes the server didn't advertise are
+    /// assumed to be flags.
+    pub fn kind(&self, mode: char) -> ModeKind {
+        if self.prefix_modes.contains(mode) {
+            ModeKind::Prefix
+        } else if self.list.contains(mode) {
+            ModeKind::List
+        } else if self.always.contains(mode) {
+            ModeKind::Always
+        } else if self.when_set.contains(mode) {
+            ModeKind::WhenSet
+        } else {
+            ModeKind::Never
+        }
+    }
+
+    /// The membership prefix symbol granted by the given mode, such as `@`
+    /// for `o`.
+    pub fn prefix_symbol(&self, mode: char) -> Option<char> {
+        self.prefix_modes
+            .find(mode)
+            .and_then(|index| self.prefix_symbols.chars().nth(index))
+    }
+
+    /// The membership prefix symbols, from the highest ranked to the lowest.
+    pub fn prefix_symbols(&self) -> &str {
+        &self.prefix_symbols
+    }
+
+    /// The most modes with parameters the server allows in a single MODE
+    /// message, as advertised by the `MODES` ISUPPORT token.
+    pub fn max_params(&self) -> usize {
+        self.max_params
+    }
+
+    pub(crate) fn takes_param(&self, mode: &Mode) -> bool {
+        match self.kind(mode.mode) {
+            ModeKind::List | ModeKind::Always | ModeKind::Prefix => true,
+            ModeKind::WhenSet => mode.adding,
+            ModeKind::Never => false,
+        }
+    }
+
+    /// Parse the arguments of a channel MODE message following the target,
+    /// which are a mode string and its parameters, such as
+    /// `["+ov-l", "alice", "bob"]`.
+    ///
+    /// Only the first argument is the mode string. The rest are parameters,
+    /// even when they start with `+` or `-`, such as the key in
+    /// `["+k", "-secret"]`. List modes without a parameter, which are
+    /// requests for the list rather than changes to it, are given without
+    /// one.
+    pub fn parse(&self, args: &[&str]) -> Vec<Mode> {
+        let mut changes = Vec::new();
+
+        let (modes, mut params) = match args.split_first() {
+            Some((modes, params)) => (*modes, params.iter()),
+            None => return changes,
+        };
+
+        let mut adding = true;
+
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                mode => {
+                    let mut change = Mode {
+                        adding: adding,
+                        mode: mode,
+                        param: None,
+                    };
+
+                    if self.takes_param(&change) {
+                        change.param = params.next().map(|&param| param.to_owned());
+                    }
+
+                    changes.push(change);
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Build the MODE messages applying the given changes to `target`,
+    /// putting at most as many modes with parameters in each message as the
+    /// server allows.
+    ///
+    /// Fails with `InvalidMode` if a mode that takes a parameter is given
+    /// without one.
+    pub fn commands(&self, target: &str, changes: &[Mode]) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+
+        let mut batch: Vec<&Mode> = Vec::new();
+        let mut params = 0;
+
+        for change in changes {
+            let takes_param = self.takes_param(change);
+
+            if takes_param && change.param.is_none() {
+                return Err(ErrorKind::InvalidMode(change.mode).into());
+            }
+
+            if takes_param && params == self.max_params.max(1) {
+                messages.push(mode_command(target, &batch)?);
+                batch.clear();
+                params = 0;
+            }
+
+            if takes_param {
+                params += 1;
+            }
+
+            batch.push(change);
+        }
+
+        if !batch.is_empty() {
+            messages.push(mode_command(target, &batch)?);
+        }
+
+        Ok(messages)
+    }
+}
+
+/// Parse a user mode string, such as `+iw-x`, none of which take
+/// parameters.
+pub fn parse_user_modes(modes: &str) -> Vec<Mode> {
+    let mut changes = Vec::new();
+    let mut adding = true;
+
+    for mode in modes.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            mode => changes.push(Mode {
+                adding: adding,
+                mode: mode,
+                param: None,
+            }),
+        }
+    }
+
+    changes
+}
+
+fn mode_command(target: &str, changes: &[&Mode]) -> Result<Message> {
+    let (modes, params) = mode_args(changes);
+    let mut raw = format!("MODE {} {}", target, modes);
+
+    for param in params {
+        raw.push(' ');
+        raw.push_str(param);
+    }
+
+    Ok(Message::try_from(raw)?)
+}
+
+// The mode string and parameters applying the given changes, such as
+// `+ov-l` with `["alice", "bob"]`.
+pub(crate) fn mode_args<'a>(changes: &[&'a Mode]) -> (String, Vec<&'a str>) {
+    let mut modes = String::new();
+    let mut params = Vec::new();
+    let mut adding = None;
+
+    for change in changes {
+        if adding != Some(change.adding) {
+            modes.push(if change.adding { '+' } else { '-' });
+            adding = Some(change.adding);
+        }
+
+        modes.push(change.mode);
+
+        if let Some(ref param) = change.param {
+            params.push(param.as_str());
+        }
+    }
+
+    (modes, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(adding: bool, mode: char, param: Option<&str>) -> Mode {
+        Mode {
+            adding: adding,
+            mode: mode,
+            param: param.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn modes_are_parsed_with_their_parameters() {
+        let modes = ChannelModes::default();
+
+        assert_eq!(
+            modes.parse(&["+ov-l", "alice", "bob"]),
+            vec![
+                mode(true, 'o', Some("alice")),
+                mode(true, 'v', Some("bob")),
+                mode(false, 'l', None),
+            ]
+        );
+        assert_eq!(modes.parse(&["+b"]), vec![mode(true, 'b', None)]);
+        assert!(modes.parse(&[]).is_empty());
+    }
+
+    #[test]
+    fn parameters_starting_with_a_sign_are_not_mode_strings() {
+        let modes = ChannelModes::default();
+
+        assert_eq!(
+            modes.parse(&["+kb", "-secret", "+bad!*@*"]),
+            vec![mode(true, 'k', Some("-secret")), mode(true, 'b', Some("+bad!*@*"))]
+        );
+        assert_eq!(
+            modes.parse(&["-o", "+nick", "+t"]),
+            vec![mode(false, 'o', Some("+nick"))]
+        );
+    }
+}
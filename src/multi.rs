@@ -0,0 +1,437 @@
+//! The multi module contains `MultiClient`, which keeps connections to
+//! several networks open at once on a single reactor.
+//!
+//! Each network is added with the `Client` used to connect to it, under a
+//! `NetworkId` naming it, along with the `ReconnectPolicy` deciding what
+//! happens when its connection is lost. The messages received from every
+//! network are yielded by the `MultiClient`'s `Stream` tagged with the
+//! network they came from, and messages given to its `Sink` are sent to the
+//! network they're tagged with.
+//!
+//! Each network is kept connected by a `Reconnect`, which follows the
+//! network's `ReconnectPolicy` in place of the client's `Backoff`, restores
+//! the session on each new connection and handles messages sent while
+//! there's no connection as the client's `DisconnectPolicy` says. See the
+//! `reconnect` module for details. Losing a network doesn't end the stream:
+//! once its `Reconnect` gives up, or the failure is one that retrying can't
+//! fix, the network remains with `NetworkStatus::Failed` until it's removed.
+//! The events of each network, such as `Event::Disconnected`, are available
+//! from the `Client` it was added with.
+//!
+//! The connection of a network that's lost is only noticed while the
+//! `MultiClient` is polled as a `Stream`, so it should be polled for as
+//! long as messages are being sent, such as by splitting it and driving
+//! both halves.
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate tokio_core;
+//! # extern crate tokio_irc_client;
+//! # use std::net::SocketAddr;
+//! use futures::Stream;
+//! use tokio_core::reactor::Core;
+//! use tokio_irc_client::Client;
+//! use tokio_irc_client::multi::{MultiClient, ReconnectPolicy};
+//!
+//! # fn main() {
+//! # let libera: SocketAddr = "127.0.0.1:6667".parse().unwrap();
+//! # let oftc: SocketAddr = "127.0.0.1:6668".parse().unwrap();
+//! let mut core = Core::new().unwrap();
+//! let mut multi = MultiClient::new(&core.handle());
+//!
+//! multi.add("libera", Client::new(libera), ReconnectPolicy::default());
+//! multi.add("oftc", Client::new(oftc), ReconnectPolicy::never());
+//!
+//! let messages = multi.for_each(|(network, message)| {
+//!     println!("{}: {}", network, message.raw_message());
+//!     Ok(())
+//! });
+//!
+//! core.run(messages).unwrap();
+//! # }
+//! ```
+
+use client::Client;
+use error::{Error, ErrorKind};
+use reconnect::{Backoff, Reconnect};
+
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::task::{self, Task};
+
+use pircolate::Message;
+
+use tokio_core::reactor::Handle;
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::fmt;
+use std::time::Duration;
+
+// How long to wait before reconnecting by default.
+const DEFAULT_RECONNECT_DELAY_IN_SECONDS: u64 = 10;
+
+/// The name of a network in a `MultiClient`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NetworkId(String);
+
+impl NetworkId {
+    /// Create a new `NetworkId` with the given name.
+    pub fn new<S: Into<String>>(name: S) -> NetworkId {
+        NetworkId(name.into())
+    }
+
+    /// Returns the name of the network.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for NetworkId {
+    fn from(name: &'a str) -> NetworkId {
+        NetworkId::new(name)
+    }
+}
+
+impl From<String> for NetworkId {
+    fn from(name: String) -> NetworkId {
+        NetworkId::new(name)
+    }
+}
+
+impl fmt::Display for NetworkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What a `MultiClient` does when the connection to a network is lost, or
+/// can't be made, which is the `Backoff` followed by the network's
+/// `Reconnect`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    backoff: Backoff,
+}
+
+impl Default for ReconnectPolicy {
+    /// Reconnect after 10 seconds, however many attempts it takes.
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy::after(Duration::from_secs(DEFAULT_RECONNECT_DELAY_IN_SECONDS))
+    }
+}
+
+impl From<Backoff> for ReconnectPolicy {
+    fn from(backoff: Backoff) -> ReconnectPolicy {
+        ReconnectPolicy { backoff: backoff }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Reconnect after the given delay, however many attempts it takes.
+    pub fn after(delay: Duration) -> ReconnectPolicy {
+        ReconnectPolicy::from(Backoff::new(delay, delay))
+    }
+
+    /// Never reconnect, leaving the network failed once its connection is
+    /// lost.
+    pub fn never() -> ReconnectPolicy {
+        ReconnectPolicy::default().max_attempts(0)
+    }
+
+    /// Give up once the given number of attempts in a row have failed.
+    pub fn max_attempts(mut self, attempts: usize) -> ReconnectPolicy {
+        self.backoff = self.backoff.max_attempts(attempts);
+        self
+    }
+}
+
+/// The state of the connection to a network in a `MultiClient`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetworkStatus {
+    /// The connection is being made.
+    Connecting,
+    /// The network is connected.
+    Connected,
+    /// The connection was lost, and will be made again after the delay of
+    /// the network's `ReconnectPolicy`.
+    Waiting {
+        /// The number of the attempt that will be made, starting from 1.
+        attempt: usize,
+    },
+    /// The connection was lost and won't be made again, for the given
+    /// reason.
+    Failed(String),
+}
+
+// The transports of the networks, which can be of different types.
+trait Transport:
+    Stream<Item = Message, Error = Error> + Sink<SinkItem = Message, SinkError = Error>
+{
+    fn status(&self) -> NetworkStatus;
+}
+
+impl<T> Transport for Reconnect<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    fn status(&self) -> NetworkStatus {
+        if self.state().is_some() {
+            NetworkStatus::Connected
+        } else if self.is_waiting() {
+            NetworkStatus::Waiting {
+                attempt: self.control().attempt(),
+            }
+        } else {
+            NetworkStatus::Connecting
+        }
+    }
+}
+
+// A transport given to `MultiClient::add_with`, which is connected until it
+// ends.
+struct Added<T>(T);
+
+impl<T> Stream for Added<T>
+where
+    T: Stream<Item = Message, Error = Error>,
+{
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
+
+impl<T> Sink for Added<T>
+where
+    T: Sink<SinkItem = Message, SinkError = Error>,
+{
+    type SinkItem = Message;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.0.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.poll_complete()
+    }
+}
+
+impl<T> Transport for Added<T>
+where
+    T: Stream<Item = Message, Error = Error> + Sink<SinkItem = Message, SinkError = Error>,
+{
+    fn status(&self) -> NetworkStatus {
+        NetworkStatus::Connected
+    }
+}
+
+struct Network {
+    id: NetworkId,
+    transport: Box<dyn Transport>,
+    // Why the network failed, once its transport has ended.
+    failed: Option<String>,
+}
+
+impl Network {
+    // Polls the transport for the next message, failing the network once it
+    // ends.
+    fn poll(&mut self) -> Option<Message> {
+        if self.failed.is_some() {
+            return None;
+        }
+
+        let error: Error = match self.transport.poll() {
+            Ok(Async::Ready(Some(message))) => return Some(message),
+            Ok(Async::Ready(None)) => ErrorKind::ConnectionReset.into(),
+            Ok(Async::NotReady) => return None,
+            Err(err) => err,
+        };
+
+        self.failed = Some(error.to_string());
+
+        None
+    }
+
+    fn status(&self) -> NetworkStatus {
+        match self.failed {
+            Some(ref reason) => NetworkStatus::Failed(reason.clone()),
+            None => self.transport.status(),
+        }
+    }
+}
+
+/// A `Stream` of the messages received from several networks, tagged with
+/// the network they came from, which is also a `Sink` of messages to send
+/// to them. See the module documentation for details.
+///
+/// The stream never ends, as networks can be added at any time. Networks
+/// are polled in turn, starting after the one that last yielded a message,
+/// so a busy network can't keep the messages of the others waiting.
+pub struct MultiClient {
+    handle: Handle,
+    networks: Vec<Network>,
+    // The network polled first next time.
+    next: usize,
+    // The task polling the stream, woken when a network is added.
+    task: Option<Task>,
+}
+
+impl MultiClient {
+    /// Create a new `MultiClient` without any networks, connecting to them
+    /// on the reactor of the given handle.
+    pub fn new(handle: &Handle) -> MultiClient {
+        MultiClient {
+            handle: handle.clone(),
+            networks: Vec::new(),
+            next: 0,
+            task: None,
+        }
+    }
+
+    /// Add a network connected to with `client`, replacing any network with
+    /// the same id.
+    pub fn add<I: Into<NetworkId>>(&mut self, id: I, client: Client, policy: ReconnectPolicy) {
+        let client = client.reconnect_backoff(policy.backoff);
+        let reconnect = client.connect_reconnecting(&self.handle);
+
+        self.insert(id.into(), Box::new(reconnect));
+    }
+
+    /// Add a network connected to over TLS with `client`, replacing any
+    /// network with the same id. `domain` is the domain name of the server,
+    /// as for `Client::connect_tls`.
+    #[cfg(feature = "tls")]
+    pub fn add_tls<I, D>(&mut self, id: I, client: Client, domain: D, policy: ReconnectPolicy)
+    where
+        I: Into<NetworkId>,
+        D: Into<String>,
+    {
+        let client = client.reconnect_backoff(policy.backoff);
+        let reconnect = client.connect_tls_reconnecting(&self.handle, domain);
+
+        self.insert(id.into(), Box::new(reconnect));
+    }
+
+    /// Add a network with a transport of its own, such as a `Reconnect`
+    /// made by `Client::reconnecting_with` for connections made with
+    /// `Client::connect_websocket`, replacing any network with the same id.
+    /// The network fails once the transport ends.
+    pub fn add_with<I, T>(&mut self, id: I, transport: T)
+    where
+        I: Into<NetworkId>,
+        T: Stream<Item = Message, Error = Error>
+            + Sink<SinkItem = Message, SinkError = Error>
+            + 'static,
+    {
+        self.insert(id.into(), Box::new(Added(transport)));
+    }
+
+    fn insert(&mut self, id: NetworkId, transport: Box<dyn Transport>) {
+        self.remove(&id);
+        self.networks.push(Network {
+            id: id,
+            transport: transport,
+            failed: None,
+        });
+
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+
+    /// Remove a network, closing its connection. Returns whether there was
+    /// a network with the given id.
+    pub fn remove(&mut self, id: &NetworkId) -> bool {
+        match self.networks.iter().position(|network| network.id == *id) {
+            Some(index) => {
+                self.networks.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the ids of the networks, in the order they were added.
+    pub fn networks(&self) -> Vec<NetworkId> {
+        self.networks.iter().map(|network| network.id.clone()).collect()
+    }
+
+    /// Returns the state of the connection to a network, if there's a
+    /// network with the given id.
+    pub fn status(&self, id: &NetworkId) -> Option<NetworkStatus> {
+        self.networks
+            .iter()
+            .find(|network| network.id == *id)
+            .map(Network::status)
+    }
+}
+
+impl Stream for MultiClient {
+    type Item = (NetworkId, Message);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.task = Some(task::current());
+
+        let count = self.networks.len();
+
+        for offset in 0..count {
+            let index = (self.next + offset) % count;
+            let network = &mut self.networks[index];
+
+            if let Some(message) = network.poll() {
+                self.next = (index + 1) % count;
+                return Ok(Async::Ready(Some((network.id.clone(), message))));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl Sink for MultiClient {
+    type SinkItem = (NetworkId, Message);
+    type SinkError = Error;
+
+    /// Sends the message to the network it's tagged with. Fails for networks
+    /// which don't exist or have failed.
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let (id, message) = item;
+
+        let network = match self.networks.iter_mut().find(|network| network.id == id) {
+            Some(network) => network,
+            None => return Err(ErrorKind::UnknownNetwork(id.to_string()).into()),
+        };
+
+        if network.failed.is_some() {
+            return Err(ErrorKind::ConnectionReset.into());
+        }
+
+        match network.transport.start_send(message)? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(message) => Ok(AsyncSink::NotReady((id, message))),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let mut complete = true;
+
+        for network in &mut self.networks {
+            // Failures of the connection are handled when it's next polled
+            // for messages.
+            if network.failed.is_none() {
+                if let Ok(Async::NotReady) = network.transport.poll_complete() {
+                    complete = false;
+                }
+            }
+        }
+
+        if complete {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
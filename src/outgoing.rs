@@ -0,0 +1,352 @@
+//! The outgoing module contains `OutgoingMessage`, a builder for messages to
+//! send to the server which checks them against what the server accepts
+//! before they're built.
+//!
+//! Messages built from strings given by users, such as the text of a relayed
+//! message or a channel name typed into a bot command, can otherwise change
+//! the meaning of the line they're put in: a line break ends the message and
+//! starts another, and a space in a parameter splits it in two. Building
+//! with `OutgoingMessage::build` fails instead when:
+//!
+//! * any string contains CR, LF or NUL, with `InvalidParameter` or
+//!   `InvalidTarget`,
+//! * a parameter other than the trailing one is empty, contains a space or
+//!   starts with a colon, with `InvalidParameter`,
+//! * a target isn't a channel where one is needed, such as for JOIN, or is
+//!   one where a nickname is needed, such as for WHOIS, according to the
+//!   server's `CHANTYPES`, with `InvalidTarget`,
+//! * there are more targets than the server's `TARGMAX` allows, with
+//!   `TooManyTargets`,
+//! * there are more modes with parameters than the server's `MODES` allows,
+//!   with `TooManyModes`.
+//!
+//! ```no_run
+//! # extern crate tokio_irc_client;
+//! # use tokio_irc_client::state::State;
+//! use tokio_irc_client::mode::Mode;
+//! use tokio_irc_client::outgoing::OutgoingMessage;
+//!
+//! # fn main() {
+//! # let state = State::default();
+//! let greeting = OutgoingMessage::privmsg("Hello!")
+//!     .to("#rust")
+//!     .to("alice")
+//!     .build(&state);
+//!
+//! let voice = OutgoingMessage::mode()
+//!     .to("#rust")
+//!     .change(Mode::add('v', Some("alice")))
+//!     .build(&state);
+//! # }
+//! ```
+
+use error::{Error, ErrorKind, Result};
+use mode::{self, ChannelModes, Mode};
+use state::State;
+
+use pircolate::Message;
+
+// The commands which can only be sent to channels.
+const CHANNEL_COMMANDS: &[&str] = &["JOIN", "PART", "TOPIC", "NAMES", "KICK"];
+
+// The commands which can only be sent to users.
+const NICK_COMMANDS: &[&str] = &["WHOIS", "WHOWAS"];
+
+// The commands which can't be sent without a target.
+const TARGETED_COMMANDS: &[&str] = &["PRIVMSG", "NOTICE", "TAGMSG", "MODE", "INVITE"];
+
+// The commands which take several targets when the server doesn't advertise
+// TARGMAX.
+const MULTIPLE_TARGET_COMMANDS: &[&str] = &["JOIN", "PART"];
+
+// What a command can be sent to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TargetKind {
+    Any,
+    Channel,
+    Nick,
+}
+
+/// A builder of a message to send to the server, which is checked against
+/// what the server accepts when it's built. See the module documentation
+/// for details.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutgoingMessage {
+    command: String,
+    targets: Vec<String>,
+    params: Vec<String>,
+    changes: Vec<Mode>,
+    trailing: Option<String>,
+}
+
+impl OutgoingMessage {
+    /// Create a new `OutgoingMessage` with the given command, such as
+    /// `KICK`.
+    pub fn new(command: &str) -> OutgoingMessage {
+        OutgoingMessage {
+            command: command.to_ascii_uppercase(),
+            targets: Vec::new(),
+            params: Vec::new(),
+            changes: Vec::new(),
+            trailing: None,
+        }
+    }
+
+    /// Create a new PRIVMSG sending `text`.
+    pub fn privmsg(text: &str) -> OutgoingMessage {
+        OutgoingMessage::new("PRIVMSG").trailing(text)
+    }
+
+    /// Create a new NOTICE sending `text`.
+    pub fn notice(text: &str) -> OutgoingMessage {
+        OutgoingMessage::new("NOTICE").trailing(text)
+    }
+
+    /// Create a new JOIN, of the channels given with `to`.
+    pub fn join() -> OutgoingMessage {
+        OutgoingMessage::new("JOIN")
+    }
+
+    /// Create a new PART, leaving the channels given with `to`.
+    pub fn part() -> OutgoingMessage {
+        OutgoingMessage::new("PART")
+    }
+
+    /// Create a new MODE, making the changes given with `change` to the
+    /// target given with `to`.
+    pub fn mode() -> OutgoingMessage {
+        OutgoingMessage::new("MODE")
+    }
+
+    /// Add a target, which is a channel or a nickname. Several targets are
+    /// sent separated by commas.
+    pub fn to(mut self, target: &str) -> OutgoingMessage {
+        self.targets.push(target.to_owned());
+        self
+    }
+
+    /// Add a parameter, sent after the targets and any mode changes.
+    pub fn param(mut self, param: &str) -> OutgoingMessage {
+        self.params.push(param.to_owned());
+        self
+    }
+
+    /// Add a mode change, for a MODE message.
+    pub fn change(mut self, change: Mode) -> OutgoingMessage {
+        self.changes.push(change);
+        self
+    }
+
+    /// Set the trailing parameter, which is sent last and may contain
+    /// spaces, such as the text of a message or the reason for leaving a
+    /// channel.
+    pub fn trailing(mut self, text: &str) -> OutgoingMessage {
+        self.trailing = Some(text.to_owned());
+        self
+    }
+
+    /// Build the message, checking it against what the server has
+    /// advertised to the connection with the given `State`.
+    pub fn build(&self, state: &State) -> Result<Message> {
+        if self.command.is_empty() || !self.command.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid_param(&self.command, "commands are made of letters or digits"));
+        }
+
+        let kind = if CHANNEL_COMMANDS.contains(&self.command.as_str()) {
+            TargetKind::Channel
+        } else if NICK_COMMANDS.contains(&self.command.as_str()) {
+            TargetKind::Nick
+        } else {
+            TargetKind::Any
+        };
+
+        if self.targets.is_empty() && (kind != TargetKind::Any || self.is_targeted()) {
+            return Err(invalid_target("", "the command needs a target"));
+        }
+
+        for target in &self.targets {
+            check_target(target, kind, state)?;
+        }
+
+        if let Some(limit) = max_targets(state, &self.command) {
+            if self.targets.len() > limit {
+                return Err(ErrorKind::TooManyTargets(self.command.clone(), limit).into());
+            }
+        }
+
+        let mut raw = self.command.clone();
+
+        if !self.targets.is_empty() {
+            raw.push(' ');
+            raw.push_str(&self.targets.join(","));
+        }
+
+        if !self.changes.is_empty() {
+            let (modes, params) = self.mode_args(state)?;
+
+            raw.push(' ');
+            raw.push_str(&modes);
+            push_params(&mut raw, params)?;
+        }
+
+        push_params(&mut raw, self.params.iter().map(String::as_str))?;
+
+        if let Some(ref trailing) = self.trailing {
+            check_characters(trailing).map_err(|reason| invalid_param(trailing, reason))?;
+
+            raw.push_str(" :");
+            raw.push_str(trailing);
+        }
+
+        Ok(Message::try_from(raw)?)
+    }
+
+    fn is_targeted(&self) -> bool {
+        TARGETED_COMMANDS.contains(&self.command.as_str())
+    }
+
+    // The mode string and parameters of the changes, checked against the
+    // channel modes of the server when they're made to a channel.
+    fn mode_args(&self, state: &State) -> Result<(String, Vec<&str>)> {
+        let channel = self.targets.first().is_some_and(|target| state.is_channel(target));
+
+        // User modes don't take parameters.
+        let types = if channel {
+            state.channel_mode_types()
+        } else {
+            ChannelModes::new("", "", None)
+        };
+
+        let mut params = 0;
+
+        for change in &self.changes {
+            let takes_param = types.takes_param(change);
+
+            if takes_param && change.param.is_none() {
+                return Err(ErrorKind::InvalidMode(change.mode).into());
+            }
+
+            if takes_param {
+                params += 1;
+            }
+        }
+
+        if channel && params > types.max_params() {
+            return Err(ErrorKind::TooManyModes(types.max_params()).into());
+        }
+
+        let changes: Vec<&Mode> = self.changes.iter().collect();
+
+        Ok(mode::mode_args(&changes))
+    }
+}
+
+fn push_params<'a, I>(raw: &mut String, params: I) -> Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    for param in params {
+        check_param(param).map_err(|reason| invalid_param(param, reason))?;
+
+        raw.push(' ');
+        raw.push_str(param);
+    }
+
+    Ok(())
+}
+
+// The most targets the server takes for the command, if it's limited. Without
+// TARGMAX, only JOIN and PART are assumed to take more than one.
+fn max_targets(state: &State, command: &str) -> Option<usize> {
+    let targmax = match state.isupport("TARGMAX") {
+        Some(targmax) => targmax,
+        None if MULTIPLE_TARGET_COMMANDS.contains(&command) => return None,
+        None => return Some(1),
+    };
+
+    // "TARGMAX=PRIVMSG:4,NOTICE:4,JOIN:", where no value means no limit.
+    for entry in targmax.split(',') {
+        let mut parts = entry.splitn(2, ':');
+
+        if parts.next().is_some_and(|name| name.eq_ignore_ascii_case(command)) {
+            return parts.next().and_then(|limit| limit.parse().ok());
+        }
+    }
+
+    if MULTIPLE_TARGET_COMMANDS.contains(&command) {
+        None
+    } else {
+        Some(1)
+    }
+}
+
+fn check_target(target: &str, kind: TargetKind, state: &State) -> Result<()> {
+    let channel = state.is_channel(target);
+
+    let reason = check_characters(target).err().or_else(|| {
+        if target.is_empty() {
+            Some("targets can't be empty")
+        } else if target.contains(' ') || target.contains(',') {
+            Some("targets can't contain spaces or commas")
+        } else if target.starts_with(':') {
+            Some("targets can't start with a colon")
+        } else if channel && target.contains('\x07') {
+            Some("channel names can't contain BEL")
+        } else if kind == TargetKind::Channel && !channel {
+            Some("the command needs a channel")
+        } else if kind == TargetKind::Nick && channel {
+            Some("the command needs a nickname")
+        } else {
+            None
+        }
+    });
+
+    match reason {
+        Some(reason) => Err(invalid_target(target, reason)),
+        None => Ok(()),
+    }
+}
+
+// Checks a parameter other than the trailing one.
+fn check_param(param: &str) -> ::std::result::Result<(), &'static str> {
+    check_characters(param)?;
+
+    if param.is_empty() {
+        Err("only the trailing parameter can be empty")
+    } else if param.contains(' ') {
+        Err("only the trailing parameter can contain spaces")
+    } else if param.starts_with(':') {
+        Err("only the trailing parameter can start with a colon")
+    } else {
+        Ok(())
+    }
+}
+
+// Checks for the characters that would end the line early.
+fn check_characters(text: &str) -> ::std::result::Result<(), &'static str> {
+    if text.contains(['\r', '\n', '\0']) {
+        Err("it contains CR, LF or NUL")
+    } else {
+        Ok(())
+    }
+}
+
+// Checks text sent as the trailing parameter of a message built outside of
+// `OutgoingMessage`, such as by a `Conversation`.
+pub(crate) fn check_trailing(text: &str) -> Result<()> {
+    check_characters(text).map_err(|reason| invalid_param(text, reason))
+}
+
+// Checks a parameter other than the trailing one of a message built outside
+// of `OutgoingMessage`.
+pub(crate) fn check_middle(param: &str) -> Result<()> {
+    check_param(param).map_err(|reason| invalid_param(param, reason))
+}
+
+fn invalid_target(target: &str, reason: &str) -> Error {
+    ErrorKind::InvalidTarget(target.to_owned(), reason.to_owned()).into()
+}
+
+fn invalid_param(param: &str, reason: &str) -> Error {
+    ErrorKind::InvalidParameter(param.to_owned(), reason.to_owned()).into()
+}
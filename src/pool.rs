@@ -0,0 +1,302 @@
+//! The pool module contains `ClientPool`, which drives many connections
+//! together as a single `Stream`.
+//!
+//! Connections are polled in turn, and each may yield at most its budget of
+//! messages before the others get a turn, so a connection flooding the pool,
+//! such as one playing back a bouncer's buffer, can't hold up the messages
+//! of the rest. How often each connection had to give up its turn, and the
+//! longest it went without being polled, are available from
+//! `ClientPool::stats` to tell when the budget is too tight or the pool too
+//! large for a single task.
+
+use clock::{self, Clock};
+use error::Error;
+
+use futures::{Async, Poll, Stream};
+
+use pircolate::Message;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+// The number of messages a connection may yield in a turn by default.
+const DEFAULT_BUDGET: usize = 16;
+
+/// How a connection in a `ClientPool` has been scheduled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The id of the connection in the pool.
+    pub id: usize,
+    /// The number of messages the connection has yielded.
+    pub messages: u64,
+    /// The number of times the connection used up its budget and had to
+    /// give up its turn while it still had messages.
+    pub budget_exhausted: u64,
+    /// The longest time the connection went without being polled.
+    pub max_poll_gap: Duration,
+}
+
+struct Entry<S> {
+    stream: S,
+    // The messages yielded during the connection's current turn.
+    used: usize,
+    // A message received once the connection's budget was used up, which is
+    // yielded first on its next turn.
+    deferred: Option<Message>,
+    last_polled: Option<Duration>,
+    stats: ConnectionStats,
+}
+
+/// A `Stream` of the messages received on a set of connections, such as
+/// `IrcTransport`s or `Reconnect`s, each paired with the id of the
+/// connection it was received on.
+///
+/// A connection is removed from the pool once it ends. Errors are returned
+/// along with the id of the connection, which stays in the pool so that it
+/// may continue; remove it with `ClientPool::remove` if it won't. The pool
+/// ends once it's empty.
+pub struct ClientPool<S> {
+    connections: Vec<(usize, Entry<S>)>,
+    next_id: usize,
+    // The index of the connection whose turn it is.
+    turn: usize,
+    budget: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S> ClientPool<S>
+where
+    S: Stream<Item = Message, Error = Error>,
+{
+    /// Create a new, empty `ClientPool`, giving each connection a budget of
+    /// 16 messages per turn.
+    pub fn new() -> ClientPool<S> {
+        ClientPool {
+            connections: Vec::new(),
+            next_id: 0,
+            turn: 0,
+            budget: DEFAULT_BUDGET,
+            clock: clock::system(),
+        }
+    }
+
+    /// Let each connection yield up to `budget` messages per turn. A budget
+    /// of 0 is treated as 1.
+    pub fn budget(mut self, budget: usize) -> ClientPool<S> {
+        self.budget = budget.max(1);
+        self
+    }
+
+    /// Use the given `Clock` to measure how long connections go without
+    /// being polled, instead of the system's monotonic clock.
+    pub fn clock<C>(mut self, clock: C) -> ClientPool<S>
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Add a connection to the pool, returning its id.
+    pub fn insert(&mut self, stream: S) -> usize {
+        let id = self.next_id;
+
+        self.next_id += 1;
+        self.connections.push((id, Entry {
+            stream: stream,
+            used: 0,
+            deferred: None,
+            last_polled: None,
+            stats: ConnectionStats {
+                id: id,
+                ..ConnectionStats::default()
+            },
+        }));
+
+        id
+    }
+
+    /// Remove the connection with the given id from the pool, returning it.
+    pub fn remove(&mut self, id: usize) -> Option<S> {
+        let index = self.connections.iter().position(|&(other, _)| other == id)?;
+        let (_, entry) = self.connections.remove(index);
+
+        if self.turn > index {
+            self.turn -= 1;
+        }
+
+        Some(entry.stream)
+    }
+
+    /// Returns a reference to the connection with the given id.
+    pub fn get(&self, id: usize) -> Option<&S> {
+        self.connections
+            .iter()
+            .find(|&&(other, _)| other == id)
+            .map(|(_, entry)| &entry.stream)
+    }
+
+    /// The number of connections in the pool.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns true if there are no connections in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// How each connection in the pool has been scheduled.
+    pub fn stats(&self) -> Vec<ConnectionStats> {
+        self.connections
+            .iter()
+            .map(|(_, entry)| entry.stats)
+            .collect()
+    }
+
+    // Moves on to the next connection's turn.
+    fn next_turn(&mut self) {
+        if let Some(&mut (_, ref mut entry)) = self.connections.get_mut(self.turn) {
+            entry.used = 0;
+        }
+
+        self.turn += 1;
+    }
+}
+
+impl<S> Default for ClientPool<S>
+where
+    S: Stream<Item = Message, Error = Error>,
+{
+    fn default() -> ClientPool<S> {
+        ClientPool::new()
+    }
+}
+
+impl<S> Stream for ClientPool<S>
+where
+    S: Stream<Item = Message, Error = Error>,
+{
+    type Item = (usize, Message);
+    type Error = (usize, Error);
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // The number of connections in a row that had nothing to yield. Once
+        // every connection has been polled without yielding anything, each
+        // will wake the task when it does.
+        let mut idle = 0;
+
+        while idle < self.connections.len() {
+            if self.turn >= self.connections.len() {
+                self.turn = 0;
+            }
+
+            let now = self.clock.now();
+            let budget = self.budget;
+            let turn = self.turn;
+            let (id, ref mut entry) = self.connections[turn];
+
+            if entry.used < budget {
+                if let Some(message) = entry.deferred.take() {
+                    entry.used += 1;
+                    entry.stats.messages += 1;
+
+                    return Ok(Async::Ready(Some((id, message))));
+                }
+            }
+
+            if let Some(last_polled) = entry.last_polled {
+                let gap = now.saturating_sub(last_polled);
+                entry.stats.max_poll_gap = entry.stats.max_poll_gap.max(gap);
+            }
+
+            entry.last_polled = Some(now);
+
+            // A connection which has used up its budget is still polled, to
+            // tell whether it had to give up its turn with messages left.
+            match entry.stream.poll() {
+                Ok(Async::Ready(Some(message))) if entry.used >= budget => {
+                    entry.deferred = Some(message);
+                    entry.stats.budget_exhausted += 1;
+                    idle = 0;
+                    self.next_turn();
+                }
+                Ok(Async::Ready(Some(message))) => {
+                    entry.used += 1;
+                    entry.stats.messages += 1;
+
+                    return Ok(Async::Ready(Some((id, message))));
+                }
+                Ok(Async::Ready(None)) => {
+                    self.connections.remove(turn);
+                }
+                Ok(Async::NotReady) => {
+                    idle += 1;
+                    self.next_turn();
+                }
+                Err(err) => {
+                    entry.used += 1;
+
+                    return Err((id, err));
+                }
+            }
+        }
+
+        if self.connections.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream;
+
+    type Connection = Box<dyn Stream<Item = Message, Error = Error>>;
+
+    // A connection which receives `count` messages and then waits for more.
+    fn connection(count: usize) -> Connection {
+        let messages = (0..count).map(|_| Message::try_from("PING :x".to_owned()).unwrap());
+        let waiting = stream::poll_fn(|| Ok(Async::NotReady));
+
+        Box::new(stream::iter_ok(messages.collect::<Vec<_>>()).chain(waiting))
+    }
+
+    // Polls the pool until it has nothing to yield, returning the ids of the
+    // connections messages were yielded from.
+    fn drain(pool: &mut ClientPool<Connection>) -> Vec<usize> {
+        let mut ids = Vec::new();
+
+        while let Ok(Async::Ready(Some((id, _)))) = pool.poll() {
+            ids.push(id);
+        }
+
+        ids
+    }
+
+    #[test]
+    fn connections_take_turns_within_their_budget() {
+        let mut pool = ClientPool::new().budget(2);
+        pool.insert(connection(5));
+        pool.insert(connection(1));
+
+        assert_eq!(drain(&mut pool), vec![0, 0, 1, 0, 0, 0]);
+
+        let exhausted: Vec<u64> = pool.stats().iter().map(|stats| stats.budget_exhausted).collect();
+        assert_eq!(exhausted, vec![2, 0]);
+    }
+
+    #[test]
+    fn using_exactly_the_budget_is_not_exhaustion() {
+        let mut pool = ClientPool::new().budget(2);
+        pool.insert(connection(2));
+        pool.insert(connection(2));
+
+        assert_eq!(drain(&mut pool), vec![0, 0, 1, 1]);
+        assert!(pool.stats().iter().all(|stats| stats.budget_exhausted == 0));
+    }
+}
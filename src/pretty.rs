@@ -0,0 +1,404 @@
+//! The pretty module contains readable formatting of messages and events for
+//! logs and debugging output.
+//!
+//! `Message` is defined by pircolate, so it's formatted through the `Pretty`
+//! wrapper returned by `pretty`. Its `Display` form prints the prefix, the
+//! command padded so that arguments line up, and the arguments, with tags
+//! summarized by their names and control codes, such as those used for
+//! formatting, escaped. Its `Debug` form shows every part of the message
+//! separately, including the values of tags.
+//!
+//! `ToLogString` produces a line suitable for logging, which includes the
+//! values of tags, and hides secrets such as passwords as configured by a
+//! `Redaction`.
+
+use event::Event;
+
+use pircolate::Message;
+
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+
+// The width commands are padded to, which fits PRIVMSG and most numerics.
+const COMMAND_WIDTH: usize = 7;
+
+const REDACTED: &str = "<redacted>";
+
+// The commands sent to services which carry a password.
+const SERVICE_COMMANDS: &[&str] = &["IDENTIFY", "REGISTER", "GHOST", "RECOVER", "REGAIN", "RELEASE"];
+
+/// Returns a wrapper of `message` with readable `Display` and `Debug`
+/// implementations.
+pub fn pretty(message: &Message) -> Pretty<'_> {
+    Pretty { message: message }
+}
+
+/// A `Message` with readable `Display` and `Debug` implementations, returned
+/// by `pretty`.
+pub struct Pretty<'a> {
+    message: &'a Message,
+}
+
+impl<'a> fmt::Display for Pretty<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_message(f, self.message, false, None)
+    }
+}
+
+impl<'a> fmt::Debug for Pretty<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tags: Vec<(String, Option<String>)> = self.message
+            .raw_tags()
+            .map(|(key, value)| (key.to_owned(), value.map(escape)))
+            .collect();
+        let args: Vec<String> = self.message.raw_args().map(escape).collect();
+
+        f.debug_struct("Message")
+            .field("tags", &tags)
+            .field("prefix", &self.message.raw_prefix())
+            .field("command", &self.message.raw_command())
+            .field("args", &args)
+            .finish()
+    }
+}
+
+/// What `ToLogString` hides from the lines it produces.
+///
+/// By default, the arguments of PASS, OPER, AUTHENTICATE and WEBIRC are
+/// hidden, as are passwords sent to services such as NickServ.
+#[derive(Clone, Debug)]
+pub struct Redaction {
+    commands: Vec<String>,
+    tags: Vec<String>,
+    services: bool,
+}
+
+impl Default for Redaction {
+    fn default() -> Redaction {
+        Redaction {
+            commands: vec![
+                "PASS".into(),
+                "OPER".into(),
+                "AUTHENTICATE".into(),
+                "WEBIRC".into(),
+            ],
+            tags: Vec::new(),
+            services: true,
+        }
+    }
+}
+
+impl Redaction {
+    /// Create a new `Redaction` hiding the default secrets.
+    pub fn new() -> Redaction {
+        Redaction::default()
+    }
+
+    /// Create a new `Redaction` which doesn't hide anything.
+    pub fn none() -> Redaction {
+        Redaction {
+            commands: Vec::new(),
+            tags: Vec::new(),
+            services: false,
+        }
+    }
+
+    /// Hide the arguments of the given command.
+    pub fn command(mut self, command: &str) -> Redaction {
+        self.commands.push(command.to_ascii_uppercase());
+        self
+    }
+
+    /// Hide the value of the given tag.
+    pub fn tag(mut self, tag: &str) -> Redaction {
+        self.tags.push(tag.to_owned());
+        self
+    }
+
+    /// Whether to hide passwords sent to services, which are the arguments
+    /// following commands such as IDENTIFY in a PRIVMSG to a services bot.
+    pub fn services(mut self, services: bool) -> Redaction {
+        self.services = services;
+        self
+    }
+
+    fn hides_command(&self, command: &str) -> bool {
+        self.commands.iter().any(|hidden| hidden.eq_ignore_ascii_case(command))
+    }
+
+    fn hides_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|hidden| hidden == tag)
+    }
+}
+
+/// Types which can be formatted as a single line for logging, with secrets
+/// hidden.
+pub trait ToLogString {
+    /// Format `self` as a line for logging, hiding what `redaction` selects.
+    fn to_log_string(&self, redaction: &Redaction) -> String;
+}
+
+impl ToLogString for Message {
+    fn to_log_string(&self, redaction: &Redaction) -> String {
+        let mut line = String::new();
+
+        write_message(&mut line, self, true, Some(redaction)).expect("writing to a String failed");
+
+        line
+    }
+}
+
+impl ToLogString for Event {
+    fn to_log_string(&self, _: &Redaction) -> String {
+        // Events don't carry secrets.
+        self.to_string()
+    }
+}
+
+fn write_message<W: Write>(
+    w: &mut W,
+    message: &Message,
+    tag_values: bool,
+    redaction: Option<&Redaction>,
+) -> fmt::Result {
+    let mut tags = message.raw_tags().peekable();
+
+    if tags.peek().is_some() {
+        w.write_char('[')?;
+
+        for (i, (key, value)) in tags.enumerate() {
+            if i > 0 {
+                w.write_char(',')?;
+            }
+
+            w.write_str(key)?;
+
+            match value {
+                Some(_) if tag_values && redaction.is_some_and(|r| r.hides_tag(key)) => {
+                    write!(w, "={}", REDACTED)?
+                }
+                Some(value) if tag_values => write!(w, "={}", escape(value))?,
+                _ => (),
+            }
+        }
+
+        w.write_str("] ")?;
+    }
+
+    if let Some(prefix) = message.raw_prefix() {
+        write!(w, "{} ", escape(prefix))?;
+    }
+
+    let command = message.raw_command();
+    let args: Vec<&str> = message.raw_args().collect();
+
+    write!(w, "{:<width$}", command, width = COMMAND_WIDTH)?;
+
+    let hidden = match redaction {
+        Some(redaction) if redaction.hides_command(command) => 0,
+        Some(redaction) if redaction.services => service_password(command, &args),
+        _ => args.len(),
+    };
+
+    for (i, arg) in args.iter().enumerate() {
+        w.write_char(' ')?;
+
+        if i >= hidden {
+            w.write_str(REDACTED)?;
+            continue;
+        }
+
+        let trailing = i + 1 == args.len()
+            && (arg.is_empty() || arg.starts_with(':') || arg.contains(' '));
+
+        if trailing {
+            w.write_char(':')?;
+        }
+
+        w.write_str(&escape(arg))?;
+    }
+
+    Ok(())
+}
+
+// Hides what `redaction` selects in a raw line, leaving the rest of it as it
+// was sent or received.
+pub(crate) fn redact_line<'a>(line: &'a [u8], redaction: &Redaction) -> Cow<'a, [u8]> {
+    let mut redacted = Vec::with_capacity(line.len());
+    let mut rest = line;
+    let mut hidden = false;
+
+    if rest.first() == Some(&b'@') {
+        let (tags, spaces, after) = split_word(&rest[1..]);
+
+        redacted.push(b'@');
+
+        for (i, tag) in tags.split(|&b| b == b';').enumerate() {
+            if i > 0 {
+                redacted.push(b';');
+            }
+
+            let mut parts = tag.splitn(2, |&b| b == b'=');
+            let key = parts.next().unwrap_or_default();
+
+            match parts.next() {
+                Some(_) if redaction.hides_tag(&String::from_utf8_lossy(key)) => {
+                    redacted.extend_from_slice(key);
+                    redacted.push(b'=');
+                    redacted.extend_from_slice(REDACTED.as_bytes());
+                    hidden = true;
+                }
+                _ => redacted.extend_from_slice(tag),
+            }
+        }
+
+        redacted.extend_from_slice(spaces);
+        rest = after;
+    }
+
+    if rest.first() == Some(&b':') {
+        let (prefix, spaces, after) = split_word(rest);
+
+        redacted.extend_from_slice(prefix);
+        redacted.extend_from_slice(spaces);
+        rest = after;
+    }
+
+    let (command, spaces, args) = split_word(rest);
+    let name = String::from_utf8_lossy(command);
+
+    redacted.extend_from_slice(command);
+    redacted.extend_from_slice(spaces);
+
+    let secret = if args.is_empty() {
+        false
+    } else if redaction.hides_command(&name) {
+        true
+    } else if redaction.services && name.eq_ignore_ascii_case("PRIVMSG") {
+        let (target, spaces, text) = split_word(args);
+        let text = String::from_utf8_lossy(text);
+        let text = text.strip_prefix(':').unwrap_or(&text);
+        let secret = service_password(&name, &[&String::from_utf8_lossy(target), text]) == 1;
+
+        // The target is kept, and the text hidden.
+        if secret {
+            redacted.extend_from_slice(target);
+            redacted.extend_from_slice(spaces);
+            redacted.push(b':');
+        }
+
+        secret
+    } else {
+        false
+    };
+
+    if secret {
+        redacted.extend_from_slice(REDACTED.as_bytes());
+    } else if hidden {
+        redacted.extend_from_slice(args);
+    } else {
+        return Cow::Borrowed(line);
+    }
+
+    Cow::Owned(redacted)
+}
+
+// Splits the word at the start of a raw line from the spaces following it and
+// the rest of the line.
+fn split_word(line: &[u8]) -> (&[u8], &[u8], &[u8]) {
+    let end = line.iter().position(|&b| b == b' ').unwrap_or(line.len());
+    let rest = line[end..]
+        .iter()
+        .position(|&b| b != b' ')
+        .map_or(line.len(), |i| end + i);
+
+    (&line[..end], &line[end..rest], &line[rest..])
+}
+
+// The number of arguments that can be shown before a password sent to
+// services, or the number of arguments if there isn't one.
+fn service_password(command: &str, args: &[&str]) -> usize {
+    if !command.eq_ignore_ascii_case("PRIVMSG") || args.len() != 2 {
+        return args.len();
+    }
+
+    let target = args[0].split('@').next().unwrap_or_default();
+    let is_service = target.len() > 4 && target.to_ascii_lowercase().ends_with("serv");
+
+    let mut words = args[1].split_whitespace();
+    let is_secret = match words.next() {
+        Some(word) => SERVICE_COMMANDS.iter().any(|c| c.eq_ignore_ascii_case(word)),
+        None => false,
+    };
+
+    // The password can't be hidden separately from the text it's part of,
+    // so the whole text is hidden.
+    if is_service && is_secret {
+        1
+    } else {
+        args.len()
+    }
+}
+
+// Escapes control codes, such as the ones used for text formatting, so they
+// don't affect the terminal the text is printed to.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(line: &str) -> String {
+        let redacted = redact_line(line.as_bytes(), &Redaction::default());
+
+        String::from_utf8(redacted.into_owned()).unwrap()
+    }
+
+    #[test]
+    fn lines_without_secrets_are_kept() {
+        assert_eq!(redact("PRIVMSG #rust :PASS the salt"), "PRIVMSG #rust :PASS the salt");
+        assert_eq!(redact(":irc.example.org 001 bot :Hi"), ":irc.example.org 001 bot :Hi");
+        assert_eq!(redact("QUIT"), "QUIT");
+    }
+
+    #[test]
+    fn arguments_of_secret_commands_are_hidden() {
+        assert_eq!(redact("PASS hunter2"), "PASS <redacted>");
+        assert_eq!(redact("OPER admin hunter2"), "OPER <redacted>");
+        assert_eq!(redact("AUTHENTICATE dXNlcgB1c2VyAHB3"), "AUTHENTICATE <redacted>");
+        assert_eq!(redact("WEBIRC hunter2 gateway host 192.0.2.1"), "WEBIRC <redacted>");
+        assert_eq!(redact("@label=1 pass  hunter2"), "@label=1 pass  <redacted>");
+    }
+
+    #[test]
+    fn passwords_sent_to_services_are_hidden() {
+        assert_eq!(redact("PRIVMSG NickServ :IDENTIFY bot pw"), "PRIVMSG NickServ :<redacted>");
+        assert_eq!(
+            redact(":bot!bot@host PRIVMSG nickserv@services. :identify hunter2"),
+            ":bot!bot@host PRIVMSG nickserv@services. :<redacted>"
+        );
+        assert_eq!(redact("PRIVMSG NickServ :INFO bot"), "PRIVMSG NickServ :INFO bot");
+    }
+
+    #[test]
+    fn hidden_tags_are_hidden() {
+        let redaction = Redaction::none().tag("secret");
+        let redacted = redact_line(b"@secret=abc;msgid=1 PRIVMSG #rust :hi", &redaction);
+
+        assert_eq!(&*redacted, &b"@secret=<redacted>;msgid=1 PRIVMSG #rust :hi"[..]);
+    }
+}
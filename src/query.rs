@@ -0,0 +1,186 @@
+//! The query module contains `Query`, which represents a private
+//! conversation with another user.
+//!
+//! A `Query` is obtained from `IrcTransport::query`. As a `Stream` it yields
+//! the PRIVMSG and NOTICE messages the user sends directly to the client, and
+//! as a `Sink` it sends each line of text given to it to the user as a
+//! PRIVMSG, refusing text containing CR, LF or NUL with `InvalidParameter`.
+//! The conversation follows the user when they change their nickname, so it
+//! isn't interrupted by it.
+//!
+//! Messages only reach a `Query` while the transport it was obtained from,
+//! or the `Stream` half of it, is being polled. They are still yielded by
+//! the transport as well.
+
+use error::Error;
+use outgoing;
+use request::Requester;
+use state::State;
+
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use pircolate::Message;
+use pircolate::message;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A private conversation with another user. See the module documentation
+/// for details.
+pub struct Query {
+    peer: Arc<Mutex<String>>,
+    messages: UnboundedReceiver<Message>,
+    requester: Requester,
+}
+
+impl Query {
+    /// The current nickname of the other user.
+    pub fn nick(&self) -> String {
+        self.peer.lock().expect("Query lock poisoned").clone()
+    }
+}
+
+impl Stream for Query {
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.messages.poll() {
+            Ok(ready) => Ok(ready),
+            // Polling an unbounded receiver never fails.
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl Sink for Query {
+    type SinkItem = String;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        outgoing::check_trailing(&item)?;
+
+        let privmsg = message::client::priv_msg(&self.nick(), &item)?;
+
+        self.requester.send(privmsg)?;
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Subscriber {
+    // The channel or the current nickname of the user the conversation is
+    // with.
+    peer: Arc<Mutex<String>>,
+    is_channel: bool,
+    sender: UnboundedSender<Message>,
+}
+
+// The queries and conversations of a connection, which are given the
+// messages received from their users or sent to their channels.
+#[derive(Clone, Default)]
+pub(crate) struct Queries {
+    inner: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Queries {
+    pub fn open(&self, nick: &str, requester: Requester) -> Query {
+        let (peer, receiver) = self.subscribe(nick, false);
+
+        Query {
+            peer: peer,
+            messages: receiver,
+            requester: requester,
+        }
+    }
+
+    // Starts passing on the messages of the conversation with `target`,
+    // returning its current name along with the messages.
+    pub fn subscribe(
+        &self,
+        target: &str,
+        is_channel: bool,
+    ) -> (Arc<Mutex<String>>, UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let peer = Arc::new(Mutex::new(target.to_owned()));
+
+        self.lock().push(Subscriber {
+            peer: peer.clone(),
+            is_channel: is_channel,
+            sender: sender,
+        });
+
+        (peer, receiver)
+    }
+
+    // Handles a message received from the server, which has already been
+    // applied to `state`.
+    pub fn handle_incoming(&self, message: &Message, state: &State) {
+        let mut subscribers = self.lock();
+
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let source = match message.prefix() {
+            Some((nick, _, _)) => nick,
+            None => return,
+        };
+
+        let command = message.raw_command();
+        let target = message.raw_args().next().unwrap_or_default();
+
+        if command == "NICK" {
+            for subscriber in subscribers.iter().filter(|subscriber| !subscriber.is_channel) {
+                let mut peer = subscriber.peer.lock().expect("Query lock poisoned");
+
+                if state.same_name(&peer, source) {
+                    *peer = target.to_owned();
+                }
+            }
+
+            return;
+        }
+
+        // Conversations that have been dropped are forgotten.
+        subscribers.retain(|subscriber| {
+            let belongs = {
+                let peer = subscriber.peer.lock().expect("Query lock poisoned");
+                belongs(message, &peer, subscriber.is_channel, state)
+            };
+
+            !belongs || subscriber.sender.unbounded_send(message.clone()).is_ok()
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        self.inner.lock().expect("Queries lock poisoned")
+    }
+}
+
+// Returns true if `message` is part of the conversation in the channel, or
+// with the user, named `peer`.
+pub(crate) fn belongs(message: &Message, peer: &str, is_channel: bool, state: &State) -> bool {
+    let command = message.raw_command();
+    let target = message.raw_args().next().unwrap_or_default();
+
+    if is_channel {
+        // Replies from the server name the client as their first argument.
+        let is_reply = command.bytes().all(|b| b.is_ascii_digit());
+
+        return !is_reply && state.same_name(peer, target);
+    }
+
+    let source = match message.prefix() {
+        Some((nick, _, _)) => nick,
+        None => return false,
+    };
+
+    (command == "PRIVMSG" || command == "NOTICE")
+        && state.is_self(target)
+        && state.same_name(peer, source)
+}
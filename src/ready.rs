@@ -0,0 +1,139 @@
+//! The ready module contains `OnReady`, a set of actions performed as soon as
+//! a connection has registered with the server.
+//!
+//! Announcing the client in a channel, setting user modes and the like have
+//! to wait until registration completes, which is easy to get wrong when done
+//! by watching the incoming messages by hand. An `OnReady` given to
+//! `Client::on_ready` runs its actions, in the order they were added, once
+//! the server confirms registration with RPL_WELCOME.
+//!
+//! By default the actions run only for the first connection made by the
+//! `Client` to register, so reconnecting doesn't repeat them. Use
+//! `OnReady::every_connection` to run them on each one.
+
+use error::Result;
+use event::{Automation, EventBus};
+use outgoing;
+use state::State;
+
+use pircolate::Message;
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The actions performed once a connection has registered.
+#[derive(Clone, Default)]
+pub struct OnReady {
+    actions: Vec<Action>,
+    every_connection: bool,
+    // Shared by every connection made by the same `Client`.
+    done: Arc<AtomicBool>,
+}
+
+type Run = dyn Fn(&State) -> Vec<Message> + Send + Sync;
+
+#[derive(Clone)]
+enum Action {
+    Send(Message),
+    Message(String, String),
+    Modes(String),
+    Run(Arc<Run>),
+}
+
+impl OnReady {
+    /// Create a new `OnReady` without any actions.
+    pub fn new() -> OnReady {
+        OnReady::default()
+    }
+
+    /// Send a PRIVMSG with the given text to a channel or user, such as an
+    /// admin to notify of the client starting up. A target or text that
+    /// would break the PRIVMSG line, such as with CR or LF, is reported as a
+    /// failure on the event stream instead of being sent.
+    pub fn message(mut self, target: &str, text: &str) -> OnReady {
+        self.actions
+            .push(Action::Message(target.to_owned(), text.to_owned()));
+        self
+    }
+
+    /// Change the client's own user modes, such as `+B` to mark it as a bot.
+    pub fn modes(mut self, modes: &str) -> OnReady {
+        self.actions.push(Action::Modes(modes.to_owned()));
+        self
+    }
+
+    /// Send the given message.
+    pub fn send(mut self, message: Message) -> OnReady {
+        self.actions.push(Action::Send(message));
+        self
+    }
+
+    /// Run the given closure, sending the messages it returns. The closure
+    /// is given the state of the connection, which has just registered.
+    pub fn run<F>(mut self, f: F) -> OnReady
+    where
+        F: Fn(&State) -> Vec<Message> + Send + Sync + 'static,
+    {
+        self.actions.push(Action::Run(Arc::new(f)));
+        self
+    }
+
+    /// Whether the actions run each time a connection registers, rather than
+    /// only the first time. This is disabled by default.
+    pub fn every_connection(mut self, every_connection: bool) -> OnReady {
+        self.every_connection = every_connection;
+        self
+    }
+
+    // Queues the messages produced by the actions once the connection has
+    // registered, which happens when RPL_WELCOME is received. Messages that
+    // can't be built are reported on `events`.
+    pub(crate) fn handle_incoming(
+        &self,
+        message: &Message,
+        state: &State,
+        outgoing: &mut VecDeque<Message>,
+        events: &EventBus,
+    ) {
+        if message.raw_command() != "001" {
+            return;
+        }
+
+        if !self.every_connection && self.done.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        for action in &self.actions {
+            let message = match *action {
+                Action::Send(ref message) => {
+                    outgoing.push_back(message.clone());
+                    continue;
+                }
+                Action::Run(ref f) => {
+                    outgoing.extend(f(state));
+                    continue;
+                }
+                Action::Message(ref target, ref text) => privmsg(target, text),
+                // The client's nickname isn't known until registration
+                // completes.
+                Action::Modes(ref modes) => {
+                    let raw = format!("MODE {} {}", state.nick().unwrap_or_default(), modes);
+                    Message::try_from(raw).map_err(Into::into)
+                }
+            };
+
+            match message {
+                Ok(message) => outgoing.push_back(message),
+                Err(err) => events.emit_failure(Automation::OnReady, err),
+            }
+        }
+    }
+}
+
+fn privmsg(target: &str, text: &str) -> Result<Message> {
+    outgoing::check_middle(target)?;
+    outgoing::check_trailing(text)?;
+
+    Ok(Message::try_from(format!("PRIVMSG {} :{}", target, text))?)
+}
@@ -0,0 +1,705 @@
+//! The reconnect module contains `Reconnect`, which keeps a connection to
+//! the server open by reconnecting whenever it's lost.
+//!
+//! A `Reconnect` is created with `Client::connect_reconnecting` or
+//! `Client::connect_tls_reconnecting`, and is used like an `IrcTransport`.
+//! When the connection is lost, or an attempt to connect fails in a way that
+//! may not happen again, another attempt is made after a delay chosen by the
+//! client's `Backoff`, and each attempt is reported with
+//! `Event::Reconnecting`. Failures that retrying can't fix, such as the
+//! server rejecting the client's credentials, end the stream with the error.
+//!
+//! The schedule can be observed and controlled with the `ReconnectControl`
+//! returned by `Reconnect::control`, which remains valid after the
+//! `Reconnect` has been `split`. This allows an operator to hold off
+//! reconnecting during a server's maintenance window, and to reconnect as
+//! soon as it's over rather than waiting for the next attempt.
+//!
+//! Each new connection registers with the client's `Registration`, logging
+//! in with SASL again if it's configured. By default the session is then
+//! restored from the state tracked for the connection that was lost: once
+//! registered, the client rejoins its channels, using their keys when
+//! they're known, marks itself away again if it was, and sets its user
+//! modes again. Rejoins that fail are reported with `Event::AutomationFailed`.
+//! Restoring can be disabled with `Client::restore_session`.
+//!
+//! What happens to messages given to the `Sink` while there's no connection
+//! is decided by the client's `DisconnectPolicy`. They can fail, be dropped,
+//! or be held in a bounded outbox and sent, in order, once the next
+//! connection has registered and restored the session, which is when the
+//! server has answered each of the requests restoring it. A message that
+//! can't be sent because the connection has just been lost is handled the
+//! same way, and reconnecting starts straight away.
+
+use client::IrcTransport;
+use clock::{Clock, Delay, Timer};
+use error::{Error, ErrorKind};
+use event::{Automation, Event, EventBus, Events};
+use request::{Requester, Response};
+use state::State;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::task::{self, Task};
+
+use pircolate::Message;
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// User modes granted by the server, which the client can't set itself:
+// operator status, being logged in and using a secure connection.
+const SERVER_SET_MODES: &str = "oOrzZ";
+
+/// How long to wait between attempts to reconnect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: Option<usize>,
+}
+
+impl Backoff {
+    /// Wait `initial` before the first attempt to reconnect, doubling the
+    /// delay after each failed attempt up to `max`.
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial: initial,
+            max: max,
+            max_attempts: None,
+        }
+    }
+
+    /// Give up after `attempts` consecutive failed attempts, ending the
+    /// stream with the last error. By default attempts are made forever.
+    pub fn max_attempts(mut self, attempts: usize) -> Backoff {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    // The delay before the given attempt, starting from 1.
+    fn delay(&self, attempt: usize) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31) as u32;
+
+        self.initial
+            .checked_mul(1 << doublings)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+impl Default for Backoff {
+    /// Waits one second before the first attempt, up to five minutes.
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(5 * 60))
+    }
+}
+
+/// What a `Reconnect` does with the messages given to its `Sink` while
+/// there's no connection to send them on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisconnectPolicy {
+    /// Sending fails with `ConnectionReset`.
+    #[default]
+    Fail,
+    /// Messages are dropped without being sent.
+    Drop,
+    /// Up to the given number of messages are held until the next connection
+    /// has registered and restored the session, and then sent in order.
+    /// Messages registering the connection, such as NICK and USER, are always
+    /// sent straight away. Once the outbox is full the `Sink` stops accepting
+    /// messages until there's room again. The outbox is discarded if
+    /// reconnecting is given up.
+    Buffer(usize),
+}
+
+/// A future resolving to a new connection to the server.
+pub type Connecting<T> = Box<dyn Future<Item = IrcTransport<T>, Error = Error>>;
+
+type Connect<T> = dyn FnMut() -> Connecting<T>;
+
+/// A handle for observing and controlling when a `Reconnect` reconnects.
+#[derive(Clone)]
+pub struct ReconnectControl {
+    inner: Arc<Mutex<ControlData>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct ControlData {
+    // The number of the attempt being made or waited for, which is 0 while
+    // connected.
+    attempt: usize,
+    // When the next attempt is due, according to the clock.
+    next_attempt: Option<Duration>,
+    suspended: bool,
+    reconnect_now: bool,
+    // The task polling the `Reconnect`, woken when it's told to reconnect.
+    task: Option<Task>,
+}
+
+impl ReconnectControl {
+    /// The number of the attempt to reconnect being made or waited for,
+    /// starting from 1, or 0 while connected.
+    pub fn attempt(&self) -> usize {
+        self.lock().attempt
+    }
+
+    /// How long until the next attempt to reconnect is made, or `None` if no
+    /// attempt is waiting, such as while connected or while reconnecting is
+    /// suspended.
+    pub fn next_attempt(&self) -> Option<Duration> {
+        let data = self.lock();
+
+        match data.next_attempt {
+            Some(_) if data.suspended => None,
+            Some(at) => Some(at.saturating_sub(self.clock.now())),
+            None => None,
+        }
+    }
+
+    /// Reconnect immediately, rather than waiting for the next attempt. This
+    /// also resumes reconnecting if it was suspended. If the client is
+    /// connected, the connection is closed and a new one is made.
+    pub fn reconnect_now(&self) {
+        let mut data = self.lock();
+
+        data.suspended = false;
+        data.reconnect_now = true;
+
+        if let Some(ref task) = data.task {
+            task.notify();
+        }
+    }
+
+    /// Stop making attempts to reconnect until `resume_reconnects` or
+    /// `reconnect_now` is called. A connection that's open isn't affected.
+    pub fn suspend_reconnects(&self) {
+        self.lock().suspended = true;
+    }
+
+    /// Resume making attempts to reconnect after `suspend_reconnects`. An
+    /// attempt that came due while suspended is made immediately.
+    pub fn resume_reconnects(&self) {
+        let mut data = self.lock();
+
+        data.suspended = false;
+
+        if let Some(ref task) = data.task {
+            task.notify();
+        }
+    }
+
+    /// Returns true if reconnecting is suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.lock().suspended
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ControlData> {
+        self.inner.lock().expect("ReconnectControl lock poisoned")
+    }
+}
+
+// What a connection had set up, restored once the next one registers.
+#[derive(Default)]
+struct Session {
+    channels: Vec<(String, Option<String>)>,
+    away: Option<String>,
+    modes: Vec<char>,
+}
+
+impl Session {
+    fn capture(state: &State) -> Session {
+        Session {
+            channels: state
+                .channels()
+                .into_iter()
+                .map(|channel| {
+                    let key = state.channel_key(&channel);
+                    (channel, key)
+                })
+                .collect(),
+            away: state.away(),
+            modes: state
+                .modes()
+                .into_iter()
+                .filter(|&mode| !SERVER_SET_MODES.contains(mode))
+                .collect(),
+        }
+    }
+
+    // Queues the requests restoring the session on a connection that has
+    // just registered, returning the responses to wait for.
+    fn restore(
+        &self,
+        requester: &Requester,
+        state: &State,
+        events: &EventBus,
+    ) -> Vec<Response<()>> {
+        let mut restoring: Vec<Response<()>> = self.channels
+            .iter()
+            .map(|(channel, key)| requester.join(channel, key.as_deref()))
+            .collect();
+
+        if let Some(ref away) = self.away {
+            restoring.push(requester.set_away(away));
+        }
+
+        if let (false, Some(nick)) = (self.modes.is_empty(), state.nick()) {
+            let modes: String = self.modes.iter().collect();
+            let sent = Message::try_from(format!("MODE {} +{}", nick, modes))
+                .map_err(Error::from)
+                .and_then(|mode| requester.send(mode));
+
+            if let Err(err) = sent {
+                events.emit_failure(Automation::RestoreSession, err);
+            }
+        }
+
+        restoring
+    }
+}
+
+enum Connection<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    Connecting(Connecting<T>),
+    Connected(Box<IrcTransport<T>>),
+    Waiting(Delay),
+    Closed,
+}
+
+/// A `Stream` and `Sink` of messages like `IrcTransport`, which reconnects
+/// to the server whenever the connection is lost. See the module
+/// documentation for details.
+pub struct Reconnect<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    connect: Box<Connect<T>>,
+    backoff: Backoff,
+    timer: Box<dyn Timer>,
+    events: EventBus,
+    control: ReconnectControl,
+    connection: Connection<T>,
+    restore: bool,
+    // The session of the last connection that registered, restored on each
+    // new connection until one has restored it.
+    session: Option<Session>,
+    // The requests restoring the session, waiting to be confirmed.
+    restoring: Vec<Response<()>>,
+    disconnect_policy: DisconnectPolicy,
+    // The messages held by `DisconnectPolicy::Buffer`, and the task waiting
+    // to send more once the outbox has room.
+    outbox: VecDeque<Message>,
+    sending_task: Option<Task>,
+    // The delay waking the task when the watchdog of the connection is due,
+    // and when it's due according to the clock.
+    watchdog: Option<(Duration, Delay)>,
+}
+
+impl<T> Reconnect<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub(crate) fn new<F, M>(
+        mut connect: F,
+        backoff: Backoff,
+        clock: Arc<dyn Clock>,
+        timer: M,
+        events: EventBus,
+        restore: bool,
+        disconnect_policy: DisconnectPolicy,
+    ) -> Reconnect<T>
+    where
+        F: FnMut() -> Connecting<T> + 'static,
+        M: Timer + 'static,
+    {
+        let connecting = connect();
+
+        Reconnect {
+            connect: Box::new(connect),
+            backoff: backoff,
+            timer: Box::new(timer),
+            events: events,
+            control: ReconnectControl {
+                inner: Arc::new(Mutex::new(ControlData::default())),
+                clock: clock,
+            },
+            connection: Connection::Connecting(connecting),
+            restore: restore,
+            session: None,
+            restoring: Vec::new(),
+            disconnect_policy: disconnect_policy,
+            outbox: VecDeque::new(),
+            sending_task: None,
+            watchdog: None,
+        }
+    }
+
+    /// Returns a handle for observing and controlling when this reconnects.
+    pub fn control(&self) -> ReconnectControl {
+        self.control.clone()
+    }
+
+    /// Returns a `Stream` of the events observed on every connection made by
+    /// this `Reconnect`.
+    pub fn events(&self) -> Events {
+        self.events.subscribe()
+    }
+
+    /// Returns a handle to the state tracked for the current connection, if
+    /// there is one. Each new connection has a `State` of its own.
+    pub fn state(&self) -> Option<State> {
+        match self.connection {
+            Connection::Connected(ref transport) => Some(transport.state()),
+            _ => None,
+        }
+    }
+
+    // Whether it's waiting to make the next attempt to connect.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn is_waiting(&self) -> bool {
+        matches!(self.connection, Connection::Waiting(_))
+    }
+
+    // Remembers the session of the current connection, if it registered,
+    // before it's replaced. A session that's still being restored is kept
+    // instead, as the connection doesn't have all of it yet.
+    fn save_session(&mut self) {
+        if let Connection::Connected(ref transport) = self.connection {
+            let state = transport.state();
+
+            if self.restore && self.restoring.is_empty() && state.is_registered() {
+                self.session = Some(Session::capture(&state));
+            }
+        }
+
+        self.restoring.clear();
+    }
+
+    // Reports the requests restoring the session that failed.
+    fn poll_restoring(&mut self) {
+        let events = &self.events;
+
+        self.restoring.retain_mut(|restoring| match restoring.poll() {
+            Ok(Async::NotReady) => true,
+            Ok(Async::Ready(())) => false,
+            Err(err) => {
+                events.emit_failure(Automation::RestoreSession, err);
+                false
+            }
+        });
+    }
+
+    // Sends the messages held in the outbox once the connection has
+    // registered and restored the session. Messages the transport refuses
+    // are reported and dropped, other than those it couldn't send because
+    // the connection was lost, which wait for the next one.
+    fn flush_outbox(&mut self) {
+        let restored = self.restoring.is_empty();
+        let transport = match self.connection {
+            Connection::Connected(ref mut transport)
+                if restored && transport.state().is_registered() =>
+            {
+                transport
+            }
+            _ => return,
+        };
+
+        while let Some(message) = self.outbox.pop_front() {
+            match transport.start_send(message.clone()) {
+                Ok(AsyncSink::Ready) => (),
+                Ok(AsyncSink::NotReady(message)) => {
+                    self.outbox.push_front(message);
+                    break;
+                }
+                Err(ref err) if err.kind().is_retryable() => {
+                    self.outbox.push_front(message);
+                    break;
+                }
+                Err(err) => self.events.emit_failure(Automation::Outbox, err),
+            }
+        }
+
+        if let Some(task) = self.sending_task.take() {
+            task.notify();
+        }
+    }
+
+    // Drops the messages held in the outbox once reconnecting has been given
+    // up, so a task waiting for room finds out.
+    fn discard_outbox(&mut self) {
+        self.outbox.clear();
+
+        if let Some(task) = self.sending_task.take() {
+            task.notify();
+        }
+    }
+
+    // Holds a message in the outbox, until the connection has registered.
+    fn buffer(&mut self, item: Message, capacity: usize) -> StartSend<Message, Error> {
+        if self.outbox.len() >= capacity {
+            self.sending_task = Some(task::current());
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        self.outbox.push_back(item);
+        self.flush_outbox();
+
+        Ok(AsyncSink::Ready)
+    }
+
+    // Waits before the next attempt, or gives up with `error` once there
+    // have been as many attempts as the backoff allows.
+    fn retry(&mut self, error: Error) -> Result<(), Error> {
+        let mut data = self.control.lock();
+
+        data.attempt += 1;
+
+        if let Some(max_attempts) = self.backoff.max_attempts {
+            if data.attempt > max_attempts {
+                data.attempt = 0;
+                data.next_attempt = None;
+                drop(data);
+                self.connection = Connection::Closed;
+                self.discard_outbox();
+
+                return Err(error);
+            }
+        }
+
+        let delay = self.backoff.delay(data.attempt);
+
+        data.next_attempt = Some(self.control.clock.now() + delay);
+        self.connection = Connection::Waiting(self.timer.delay(delay));
+
+        Ok(())
+    }
+
+    // Wakes the task when the watchdog of the connection is due, since
+    // nothing else will if the server has gone quiet. Gives whether it
+    // already is.
+    fn arm_watchdog(&mut self, due: Duration) -> bool {
+        let at = self.control.clock.now() + due;
+        let mut delay = match self.watchdog.take() {
+            Some((armed, delay)) if armed == at => delay,
+            _ => self.timer.delay(due),
+        };
+
+        match delay.poll() {
+            Ok(Async::Ready(())) => true,
+            Ok(Async::NotReady) => {
+                self.watchdog = Some((at, delay));
+                false
+            }
+            // Without a timer the watchdog is only checked when the
+            // connection is polled for another reason.
+            Err(_) => false,
+        }
+    }
+
+    // Gives up on the current connection once sending on it has failed with
+    // `error`, reconnecting as the backoff allows. The task polling the
+    // stream is woken to wait for the next attempt.
+    fn lose_connection(&mut self, error: Error) -> Result<(), Error> {
+        self.save_session();
+        self.retry(error)?;
+
+        if let Some(ref task) = self.control.lock().task {
+            task.notify();
+        }
+
+        Ok(())
+    }
+
+    fn start_attempt(&mut self) {
+        let attempt = {
+            let mut data = self.control.lock();
+
+            data.next_attempt = None;
+            data.attempt.max(1)
+        };
+
+        self.events.emit(Event::Reconnecting { attempt: attempt });
+        self.connection = Connection::Connecting((self.connect)());
+    }
+}
+
+// Whether the message is part of registering a connection, and so is sent
+// before the server has accepted it.
+fn is_registering(message: &Message) -> bool {
+    matches!(
+        message.raw_command(),
+        "PASS" | "NICK" | "USER" | "CAP" | "AUTHENTICATE" | "WEBIRC" | "PONG"
+    )
+}
+
+impl<T> Stream for Reconnect<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let reconnect_now = {
+            let mut data = self.control.lock();
+
+            data.task = Some(task::current());
+
+            let reconnect_now = data.reconnect_now;
+            data.reconnect_now = false;
+            reconnect_now
+        };
+
+        self.poll_restoring();
+        self.flush_outbox();
+
+        if reconnect_now {
+            match self.connection {
+                Connection::Connected(_) => {
+                    self.save_session();
+                    self.control.lock().attempt = 1;
+                    self.start_attempt();
+                }
+                Connection::Waiting(_) => self.start_attempt(),
+                _ => (),
+            }
+        }
+
+        loop {
+            let lost = match self.connection {
+                Connection::Connected(ref mut transport) => match transport.poll() {
+                    Ok(Async::Ready(Some(message))) => {
+                        // RPL_WELCOME
+                        if message.raw_command() == "001" {
+                            if let Some(ref session) = self.session {
+                                let requester = transport.requester();
+                                let state = transport.state();
+
+                                self.restoring = session.restore(&requester, &state, &self.events);
+                                self.poll_restoring();
+                            }
+
+                            self.flush_outbox();
+                        }
+
+                        return Ok(Async::Ready(Some(message)));
+                    }
+                    Ok(Async::Ready(None)) => ErrorKind::ConnectionReset.into(),
+                    Ok(Async::NotReady) => match transport.watchdog_due() {
+                        Some(due) if self.arm_watchdog(due) => continue,
+                        _ => return Ok(Async::NotReady),
+                    },
+                    Err(err) => {
+                        if !err.kind().is_retryable() {
+                            return Err(err);
+                        }
+
+                        err
+                    }
+                },
+
+                Connection::Connecting(ref mut connecting) => match connecting.poll() {
+                    Ok(Async::Ready(transport)) => {
+                        let mut data = self.control.lock();
+
+                        data.attempt = 0;
+                        data.next_attempt = None;
+                        drop(data);
+
+                        self.connection = Connection::Connected(Box::new(transport));
+                        continue;
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => {
+                        if !err.kind().is_retryable() {
+                            self.connection = Connection::Closed;
+                            self.discard_outbox();
+                            return Err(err);
+                        }
+
+                        err
+                    }
+                },
+
+                Connection::Waiting(ref mut delay) => {
+                    if self.control.is_suspended() {
+                        return Ok(Async::NotReady);
+                    }
+
+                    try_ready!(delay.poll());
+                    self.start_attempt();
+                    continue;
+                }
+
+                Connection::Closed => return Ok(Async::Ready(None)),
+            };
+
+            self.save_session();
+            self.retry(lost)?;
+        }
+    }
+}
+
+impl<T> Sink for Reconnect<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type SinkItem = Message;
+    type SinkError = Error;
+
+    // Messages are sent on the current connection, or handled as the
+    // `DisconnectPolicy` says while there's none, including when sending
+    // shows the connection to have been lost.
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let buffering = matches!(self.disconnect_policy, DisconnectPolicy::Buffer(_));
+        let restoring = !self.restoring.is_empty();
+
+        let lost = match self.connection {
+            Connection::Connected(ref mut transport) => {
+                // Buffered messages wait for the session to be restored,
+                // and are sent ahead of later ones. The messages
+                // registering the connection can't wait for it.
+                let waiting = !transport.state().is_registered()
+                    || restoring
+                    || !self.outbox.is_empty();
+
+                if buffering && waiting && !is_registering(&item) {
+                    None
+                } else {
+                    match transport.start_send(item.clone()) {
+                        Err(err) if err.kind().is_retryable() => Some(err),
+                        sent => return sent,
+                    }
+                }
+            }
+            Connection::Closed => return Err(ErrorKind::ConnectionReset.into()),
+            _ => None,
+        };
+
+        if let Some(err) = lost {
+            self.lose_connection(err)?;
+        }
+
+        match self.disconnect_policy {
+            DisconnectPolicy::Fail => Err(ErrorKind::ConnectionReset.into()),
+            DisconnectPolicy::Drop => Ok(AsyncSink::Ready),
+            DisconnectPolicy::Buffer(capacity) => self.buffer(item, capacity),
+        }
+    }
+
+    // Messages held in the outbox count as having been handled, as they
+    // can't be sent any sooner by waiting.
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.flush_outbox();
+
+        match self.connection {
+            Connection::Connected(ref mut transport) => transport.poll_complete(),
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
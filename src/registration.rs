@@ -0,0 +1,815 @@
+//! The registration module contains the types used to configure how a
+//! connection registers itself with the server once it has been established.
+//!
+//! When a `Registration` is given to a `Client`, the resulting `IrcTransport`
+//! sends the registration sequence as soon as the connection is made and
+//! takes care of choosing an alternate nickname, via a `NickStrategy`, when
+//! the requested one is unavailable.
+//!
+//! Clients acting as gateways for other users can also identify the user
+//! they're connecting on behalf of with a `Gateway`, which is sent before
+//! anything else.
+//!
+//! Registration also negotiates IRCv3 capabilities with the server. Each
+//! capability in the registration's list is requested if, and only if, the
+//! server advertises it. With `cap-notify`, capabilities from the list that
+//! the server only starts offering later on are requested as soon as they're
+//! advertised.
+//!
+//! A connection can also log in to an account with SASL before registration
+//! completes. See the `sasl` module for details.
+
+use account;
+use chathistory;
+use error::{ErrorKind, Result};
+use event::{Automation, EventBus};
+use outgoing;
+use sasl::{Authenticator, Sasl};
+use state::State;
+use znc;
+
+use getrandom;
+
+use pircolate::Message;
+use pircolate::message;
+
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// A `NickStrategy` decides which nickname to try next when the server
+/// rejects the one the client asked for, both during registration and when
+/// attempting to reclaim the preferred nickname afterwards.
+pub trait NickStrategy: Send + Sync {
+    /// Returns the nickname to try for the given `attempt`, where `nick` is
+    /// the preferred nickname and `attempt` starts at 1 for the first
+    /// alternate. `nick_len` is the maximum nickname length advertised by the
+    /// server, if it is known yet, which it usually isn't during registration
+    /// since servers advertise it with RPL_ISUPPORT after RPL_WELCOME.
+    ///
+    /// Returning `None` gives up on finding a nickname.
+    fn next_nick(&self, nick: &str, attempt: usize, nick_len: Option<usize>) -> Option<String>;
+}
+
+/// Tries each of a fixed list of nicknames in order.
+#[derive(Clone, Debug)]
+pub struct FixedList(pub Vec<String>);
+
+impl NickStrategy for FixedList {
+    fn next_nick(&self, _: &str, attempt: usize, _: Option<usize>) -> Option<String> {
+        self.0.get(attempt - 1).cloned()
+    }
+}
+
+/// Appends an increasing number to the preferred nickname, giving up after
+/// the given number of attempts.
+#[derive(Clone, Debug)]
+pub struct NumericSuffix(pub usize);
+
+impl NickStrategy for NumericSuffix {
+    fn next_nick(&self, nick: &str, attempt: usize, _: Option<usize>) -> Option<String> {
+        if attempt > self.0 {
+            return None;
+        }
+
+        Some(format!("{}{}", nick, attempt))
+    }
+}
+
+/// Appends a random suffix of the given number of digits to the preferred
+/// nickname, giving up after the given number of attempts.
+///
+/// A `RandomSuffix` created with `RandomSuffix::new` picks a fresh suffix for
+/// every attempt, so each connection made with it, or with a clone of it,
+/// tries different nicknames. Use `RandomSuffix::with_seed` to make the
+/// sequence reproducible across runs instead, in which case every connection
+/// made with it tries the same one.
+#[derive(Clone, Debug)]
+pub struct RandomSuffix {
+    seed: Option<u64>,
+    digits: u32,
+    attempts: usize,
+}
+
+impl RandomSuffix {
+    /// Create a new `RandomSuffix` which picks each suffix at random.
+    pub fn new(digits: u32, attempts: usize) -> RandomSuffix {
+        RandomSuffix {
+            seed: None,
+            digits: digits,
+            attempts: attempts,
+        }
+    }
+
+    /// Create a new `RandomSuffix` which derives its suffixes from the given
+    /// seed.
+    pub fn with_seed(seed: u64, digits: u32, attempts: usize) -> RandomSuffix {
+        RandomSuffix {
+            seed: Some(seed),
+            digits: digits,
+            attempts: attempts,
+        }
+    }
+}
+
+impl NickStrategy for RandomSuffix {
+    fn next_nick(&self, nick: &str, attempt: usize, _: Option<usize>) -> Option<String> {
+        if attempt > self.attempts {
+            return None;
+        }
+
+        // A SplitMix64 step keyed by the attempt number, which is plenty for
+        // picking a nickname suffix.
+        let mut value = match self.seed {
+            Some(seed) => seed.wrapping_add((attempt as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)),
+            None => random(),
+        };
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        value ^= value >> 31;
+
+        let modulus = 10u64.saturating_pow(self.digits.min(19));
+
+        Some(format!(
+            "{}{:0width$}",
+            nick,
+            value % modulus,
+            width = self.digits as usize
+        ))
+    }
+}
+
+// A random value, falling back to the randomly keyed hasher used by the
+// standard library if the operating system can't provide one.
+fn random() -> u64 {
+    let mut bytes = [0; 8];
+
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => u64::from_le_bytes(bytes),
+        Err(_) => RandomState::new().build_hasher().finish(),
+    }
+}
+
+// The maximum nickname length given by RFC 1459, which is assumed until the
+// server advertises its own.
+const DEFAULT_NICK_LEN: usize = 9;
+
+/// Wraps another `NickStrategy` and truncates the nicknames it produces to
+/// the maximum length advertised by the server. The preferred nickname is
+/// shortened to make room for the suffix added by the wrapped strategy rather
+/// than cutting the suffix off.
+///
+/// Servers only advertise the length once registration has completed, so
+/// until then the limit of 9 characters from RFC 1459 is assumed. That keeps
+/// the alternates tried during registration short enough for any server, at
+/// the cost of shortening them on servers which would accept longer ones.
+#[derive(Clone, Debug)]
+pub struct Truncate<S>(pub S);
+
+impl<S> NickStrategy for Truncate<S>
+where
+    S: NickStrategy,
+{
+    fn next_nick(&self, nick: &str, attempt: usize, nick_len: Option<usize>) -> Option<String> {
+        let candidate = self.0.next_nick(nick, attempt, nick_len)?;
+
+        let nick_len = nick_len.unwrap_or(DEFAULT_NICK_LEN);
+
+        if candidate.chars().count() <= nick_len {
+            return Some(candidate);
+        }
+
+        // Keep whatever the wrapped strategy added to the end of the
+        // preferred nickname, and shorten the preferred nickname instead.
+        if let Some(suffix) = candidate.strip_prefix(nick) {
+            let keep = nick_len.saturating_sub(suffix.chars().count());
+            let prefix: String = nick.chars().take(keep).collect();
+
+            if !prefix.is_empty() {
+                return Some(prefix + suffix);
+            }
+        }
+
+        Some(candidate.chars().take(nick_len).collect())
+    }
+}
+
+/// The WEBIRC details sent by gateways connecting on behalf of another user.
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    password: String,
+    gateway: String,
+    hostname: String,
+    ip: IpAddr,
+    proxy: Option<(SocketAddr, SocketAddr)>,
+}
+
+impl Gateway {
+    /// Create a new `Gateway` that identifies the connection as coming from
+    /// the user at `hostname` and `ip`, using the WEBIRC `password` and the
+    /// `gateway` name configured on the server.
+    ///
+    /// Registration fails with `InvalidParameter` if any of these is empty
+    /// or contains a space, CR, LF or NUL, which would let it change or add
+    /// to the WEBIRC line.
+    pub fn new<P, G, H>(password: P, gateway: G, hostname: H, ip: IpAddr) -> Gateway
+    where
+        P: Into<String>,
+        G: Into<String>,
+        H: Into<String>,
+    {
+        Gateway {
+            password: password.into(),
+            gateway: gateway.into(),
+            hostname: hostname.into(),
+            ip: ip,
+            proxy: None,
+        }
+    }
+
+    /// Also send a PROXY protocol (version 1) header describing a connection
+    /// from `source` to `destination`, ahead of WEBIRC. This is only needed
+    /// when the server expects connections to arrive through a proxy.
+    ///
+    /// The header is written to the socket as soon as it's connected, before
+    /// the TLS handshake, so it's only sent on connections the `Client` makes
+    /// itself. Streams given to `Client::connect_stream` are expected to have
+    /// had it written already.
+    pub fn proxy_header(mut self, source: SocketAddr, destination: SocketAddr) -> Gateway {
+        self.proxy = Some((source, destination));
+        self
+    }
+
+    // The PROXY protocol header, which is written to the connection before
+    // anything else, including the TLS handshake.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn proxy_line(&self) -> Option<Vec<u8>> {
+        let (source, destination) = self.proxy?;
+
+        let protocol = match (source, destination) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+            (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+            _ => return Some(b"PROXY UNKNOWN\r\n".to_vec()),
+        };
+
+        let line = format!(
+            "PROXY {} {} {} {} {}\r\n",
+            protocol,
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        );
+
+        Some(line.into_bytes())
+    }
+
+    fn message(&self) -> Result<Message> {
+        // An address starting with ':' can't be sent as a parameter, so it
+        // is prefixed with a zero, which leaves its meaning unchanged.
+        let mut ip = self.ip.to_string();
+
+        if ip.starts_with(':') {
+            ip.insert(0, '0');
+        }
+
+        outgoing::check_middle(&self.password)?;
+        outgoing::check_middle(&self.gateway)?;
+        outgoing::check_middle(&self.hostname)?;
+
+        Ok(Message::try_from(format!(
+            "WEBIRC {} {} {} {}",
+            self.password, self.gateway, self.hostname, ip
+        ))?)
+    }
+}
+
+/// The information used to register a connection with the server.
+#[derive(Clone)]
+pub struct Registration {
+    nick: String,
+    user: String,
+    real_name: String,
+    password: Option<String>,
+    gateway: Option<Gateway>,
+    strategy: Arc<dyn NickStrategy>,
+    reclaim: bool,
+    capabilities: Vec<String>,
+    sasl: Option<Sasl>,
+}
+
+// The capabilities requested by default, all of which are handled by the
+// state tracking done by `IrcTransport`.
+const DEFAULT_CAPABILITIES: &[&str] = &[
+    "away-notify",
+    "batch",
+    "cap-notify",
+    "chghost",
+    "message-tags",
+    "setname",
+    znc::SELF_MESSAGE,
+];
+
+impl Registration {
+    /// Create a new `Registration` that registers with the given nickname,
+    /// username and real name. By default, alternate nicknames are chosen
+    /// by appending up to 9 numeric suffixes to the nickname, and the
+    /// `away-notify`, `batch`, `cap-notify`, `chghost`, `message-tags`,
+    /// `setname` and `znc.in/self-message` capabilities are requested.
+    pub fn new<N, U, R>(nick: N, user: U, real_name: R) -> Registration
+    where
+        N: Into<String>,
+        U: Into<String>,
+        R: Into<String>,
+    {
+        Registration {
+            nick: nick.into(),
+            user: user.into(),
+            real_name: real_name.into(),
+            password: None,
+            gateway: None,
+            strategy: Arc::new(Truncate(NumericSuffix(9))),
+            reclaim: false,
+            capabilities: DEFAULT_CAPABILITIES
+                .iter()
+                .map(|&cap| cap.to_owned())
+                .collect(),
+            sasl: None,
+        }
+    }
+
+    /// Send the given server password with PASS before registering. Bouncers
+    /// such as ZNC expect this to be in the form `user:password`.
+    pub fn password<P: Into<String>>(mut self, password: P) -> Registration {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Identify the connection as being made on behalf of another user with
+    /// WEBIRC, which is sent before any other message.
+    pub fn gateway(mut self, gateway: Gateway) -> Registration {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    /// Use the given `NickStrategy` to choose alternate nicknames.
+    pub fn nick_strategy<S>(mut self, strategy: S) -> Registration
+    where
+        S: NickStrategy + 'static,
+    {
+        self.strategy = Arc::new(strategy);
+        self
+    }
+
+    /// When enabled and the client ends up registered with an alternate
+    /// nickname, the preferred nickname will be requested again as soon as
+    /// the user holding it changes their nickname or quits. The same goes
+    /// for the alternates the `NickStrategy` chose before the one the client
+    /// ended up with, so it moves to the best of them that becomes free.
+    pub fn reclaim_nick(mut self, reclaim: bool) -> Registration {
+        self.reclaim = reclaim;
+        self
+    }
+
+    /// Request the given IRCv3 capability during registration, if the
+    /// server supports it.
+    pub fn capability<C: Into<String>>(mut self, cap: C) -> Registration {
+        let cap = cap.into();
+
+        if !self.capabilities.contains(&cap) {
+            self.capabilities.push(cap);
+        }
+
+        self
+    }
+
+    /// Log in with SASL before registration completes, which fails if the
+    /// server doesn't support it. The `sasl` capability is requested even if
+    /// capabilities are otherwise disabled.
+    pub fn sasl(mut self, sasl: Sasl) -> Registration {
+        self.sasl = Some(sasl);
+        self
+    }
+
+    /// Request the `znc.in/playback` capability, along with `server-time` so
+    /// played back messages carry the time they were originally sent. Once
+    /// it's enabled, ZNC only plays back its buffers when asked to with
+    /// `znc::play`. See the `znc` module for details.
+    pub fn znc_playback(self) -> Registration {
+        self.capability("server-time").capability(znc::PLAYBACK)
+    }
+
+    /// Request the `draft/chathistory` capability, along with `server-time`
+    /// so fetched messages carry the time they were originally sent. Once
+    /// it's enabled, history can be fetched with `Requester::latest_history`
+    /// and similar. See the `chathistory` module for details.
+    pub fn chathistory(self) -> Registration {
+        self.capability("server-time").capability(chathistory::CAPABILITY)
+    }
+
+    /// Request the `draft/account-registration` capability, allowing
+    /// accounts to be registered with `Requester::register_account`. See
+    /// the `account` module for details.
+    pub fn account_registration(self) -> Registration {
+        self.capability(account::CAPABILITY)
+    }
+
+    /// Don't negotiate any IRCv3 capabilities during registration.
+    pub fn without_capabilities(mut self) -> Registration {
+        self.capabilities.clear();
+        self
+    }
+
+    /// The preferred nickname.
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    // The bytes written to the connection before the TLS handshake and the
+    // registration sequence, if any.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn preamble(&self) -> Option<Vec<u8>> {
+        self.gateway.as_ref().and_then(Gateway::proxy_line)
+    }
+}
+
+// Drives the registration of a single connection.
+pub(crate) struct Registrar {
+    registration: Registration,
+    attempt: usize,
+    // The nicknames requested during registration, the preferred one first.
+    tried: Vec<String>,
+    negotiating: bool,
+    // The nickname requested to reclaim one, until the server answers.
+    reclaiming: Option<String>,
+    authenticator: Option<Authenticator>,
+    authenticating: bool,
+}
+
+impl Registrar {
+    pub fn new(registration: Registration) -> Registrar {
+        Registrar {
+            authenticator: registration.sasl.clone().map(Authenticator::new),
+            registration: registration,
+            attempt: 0,
+            tried: Vec::new(),
+            negotiating: false,
+            reclaiming: None,
+            authenticating: false,
+        }
+    }
+
+    // Queues the registration sequence to be sent to the server.
+    pub fn start(&mut self, outgoing: &mut VecDeque<Message>) -> Result<()> {
+        let registration = &self.registration;
+
+        if let Some(ref gateway) = registration.gateway {
+            outgoing.push_back(gateway.message()?);
+        }
+
+        // PASS has to be sent before any of NICK or USER are.
+        if let Some(ref password) = registration.password {
+            outgoing.push_back(message::client::pass(password)?);
+        }
+
+        // Sending CAP LS suspends registration until CAP END is sent,
+        // giving capability negotiation a chance to complete.
+        if !registration.capabilities.is_empty() || registration.sasl.is_some() {
+            self.negotiating = true;
+            outgoing.push_back(Message::try_from("CAP LS 302".to_owned())?);
+        }
+
+        outgoing.push_back(message::client::nick(&registration.nick)?);
+        self.tried = vec![registration.nick.clone()];
+        outgoing.push_back(message::client::user(
+            &registration.user,
+            &registration.real_name,
+        )?);
+
+        Ok(())
+    }
+
+    // Handles a message received from the server, which has already been
+    // applied to `state`. Failures that don't prevent registration are
+    // reported on `events` rather than returned.
+    pub fn handle_incoming(
+        &mut self,
+        message: &Message,
+        state: &State,
+        outgoing: &mut VecDeque<Message>,
+        events: &EventBus,
+    ) -> Result<()> {
+        match message.raw_command() {
+            "CAP" => {
+                let args: Vec<&str> = message.raw_args().collect();
+
+                match args.get(1).cloned() {
+                    // A "*" before the list of capabilities marks a reply
+                    // that continues on the following line.
+                    Some("LS") if args.len() > 3 && args[2] == "*" => (),
+
+                    Some("LS") if self.negotiating => {
+                        let sasl = self.authenticator.as_ref().map(|_| "sasl");
+                        let wanted: Vec<&str> = self.registration
+                            .capabilities
+                            .iter()
+                            .map(|cap| cap.as_str())
+                            .chain(sasl)
+                            .filter(|cap| state.available_capability(cap).is_some())
+                            .collect();
+
+                        if wanted.is_empty() {
+                            self.requested(state, outgoing)?;
+                        } else {
+                            outgoing.push_back(message::client::cap_req(&wanted.join(" "))?);
+                        }
+                    }
+
+                    Some("ACK") if self.negotiating && !self.authenticating => {
+                        self.requested(state, outgoing)?
+                    }
+
+                    Some("NAK") => {
+                        let rejected = args.get(2).cloned().unwrap_or_default();
+
+                        events.emit_failure(
+                            Automation::CapabilityNegotiation,
+                            format!("capabilities rejected by the server: {}", rejected),
+                        );
+
+                        if self.negotiating && !self.authenticating {
+                            self.requested(state, outgoing)?;
+                        }
+                    }
+
+                    // With cap-notify, the server started offering more
+                    // capabilities, of which the wanted ones are requested.
+                    Some("NEW") => {
+                        let offered: Vec<&str> = args.get(2)
+                            .cloned()
+                            .unwrap_or_default()
+                            .split_whitespace()
+                            .map(|cap| cap.split('=').next().unwrap_or_default())
+                            .collect();
+
+                        let wanted: Vec<&str> = self.registration
+                            .capabilities
+                            .iter()
+                            .map(|cap| cap.as_str())
+                            .filter(|cap| offered.contains(cap) && !state.has_capability(cap))
+                            .collect();
+
+                        if !wanted.is_empty() {
+                            outgoing.push_back(message::client::cap_req(&wanted.join(" "))?);
+                        }
+                    }
+
+                    _ => (),
+                }
+            }
+
+            "AUTHENTICATE" | "902" | "903" | "904" | "905" | "906" | "907" | "908"
+                if self.authenticating =>
+            {
+                let done = match self.authenticator {
+                    Some(ref mut authenticator) => authenticator.handle_incoming(message, outgoing)?,
+                    None => true,
+                };
+
+                if done {
+                    self.authenticating = false;
+                    self.end_negotiation(outgoing)?;
+                }
+            }
+
+            // ERR_ERRONEUSNICKNAME, ERR_NICKNAMEINUSE, ERR_NICKCOLLISION
+            "432" | "433" | "436" if !state.is_registered() => {
+                self.attempt += 1;
+
+                let nick = self.registration.strategy.next_nick(
+                    &self.registration.nick,
+                    self.attempt,
+                    state.nick_len(),
+                );
+
+                match nick {
+                    Some(nick) => {
+                        outgoing.push_back(message::client::nick(&nick)?);
+                        self.tried.push(nick);
+                    }
+                    None => {
+                        return Err(
+                            ErrorKind::NicknameUnavailable(self.registration.nick.clone()).into(),
+                        )
+                    }
+                }
+            }
+
+            // ERR_PASSWDMISMATCH
+            "464" if !state.is_registered() => return Err(ErrorKind::PasswordMismatch.into()),
+
+            // ERR_YOUREBANNEDCREEP
+            "465" => {
+                let reason = message.raw_args().next_back().unwrap_or_default();
+
+                return Err(ErrorKind::Banned(reason.to_owned()).into());
+            }
+
+            "NICK" | "QUIT" if self.registration.reclaim && state.is_registered() => {
+                let released = match message.prefix() {
+                    Some((source, _, _)) => self.rank(state, source),
+                    None => None,
+                };
+
+                // A nickname that wasn't tried during registration, such as
+                // one the client changed to since, ranks below all of them.
+                let held = state
+                    .nick()
+                    .and_then(|nick| self.rank(state, &nick))
+                    .unwrap_or(self.tried.len());
+
+                // The client itself giving up the nickname isn't a reason
+                // to take it back.
+                let renamed_self = match message.raw_args().next() {
+                    Some(new_nick) => message.raw_command() == "NICK" && state.is_self(new_nick),
+                    None => false,
+                };
+
+                match released {
+                    Some(rank) if rank < held && !renamed_self => {
+                        let nick = self.tried[rank].clone();
+
+                        match message::client::nick(&nick) {
+                            Ok(reclaim) => {
+                                self.reclaiming = Some(nick);
+                                outgoing.push_back(reclaim);
+                            }
+                            Err(err) => events.emit_failure(Automation::NickReclaim, err),
+                        }
+                    }
+                    _ => if self.reclaiming.as_ref().is_some_and(|nick| state.is_self(nick)) {
+                        self.reclaiming = None;
+                    },
+                }
+            }
+
+            // Someone else took the nickname before we could.
+            "432" | "433" | "436" | "437" if self.reclaiming.is_some() => {
+                self.reclaiming = None;
+
+                let reason = message.raw_args().next_back().unwrap_or_default();
+                events.emit_failure(Automation::NickReclaim, reason);
+            }
+
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    // The position of `nick` among the nicknames tried during registration.
+    fn rank(&self, state: &State, nick: &str) -> Option<usize> {
+        self.tried.iter().position(|tried| state.same_name(tried, nick))
+    }
+
+    // Moves on once the server has answered the request for capabilities,
+    // logging in with SASL if it's configured.
+    fn requested(&mut self, state: &State, outgoing: &mut VecDeque<Message>) -> Result<()> {
+        let authenticator = match self.authenticator {
+            Some(ref mut authenticator) => authenticator,
+            None => return self.end_negotiation(outgoing),
+        };
+
+        if !state.has_capability("sasl") {
+            return Err(ErrorKind::SaslFailed("the server doesn't support SASL".to_owned()).into());
+        }
+
+        self.authenticating = true;
+        authenticator.start(state.available_capability("sasl").as_deref(), outgoing)
+    }
+
+    fn end_negotiation(&mut self, outgoing: &mut VecDeque<Message>) -> Result<()> {
+        self.negotiating = false;
+        outgoing.push_back(Message::try_from("CAP END".to_owned())?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Server {
+        registrar: Registrar,
+        state: State,
+        events: EventBus,
+    }
+
+    impl Server {
+        fn new(registration: Registration) -> Server {
+            let mut registrar = Registrar::new(registration);
+            let mut outgoing = VecDeque::new();
+
+            registrar.start(&mut outgoing).unwrap();
+
+            Server {
+                registrar: registrar,
+                state: State::default(),
+                events: EventBus::default(),
+            }
+        }
+
+        // Handles a line from the server, returning the lines sent in reply.
+        fn receive(&mut self, line: &str) -> Result<Vec<String>> {
+            let message = Message::try_from(line.to_owned()).unwrap();
+            let mut outgoing = VecDeque::new();
+
+            self.state.handle_incoming(&message);
+            self.registrar
+                .handle_incoming(&message, &self.state, &mut outgoing, &self.events)?;
+
+            Ok(outgoing
+                .iter()
+                .map(|message| message.raw_message().to_owned())
+                .collect())
+        }
+    }
+
+    fn nick_in_use(nick: &str) -> String {
+        format!("433 * {} :Nickname is already in use", nick)
+    }
+
+    #[test]
+    fn nicknames_in_use_are_replaced_until_the_strategy_gives_up() {
+        let registration = Registration::new("bot", "bot", "Bot").nick_strategy(NumericSuffix(2));
+        let mut server = Server::new(registration);
+
+        assert_eq!(server.receive(&nick_in_use("bot")).unwrap(), vec!["NICK bot1"]);
+        assert_eq!(server.receive(&nick_in_use("bot1")).unwrap(), vec!["NICK bot2"]);
+
+        match *server.receive(&nick_in_use("bot2")).unwrap_err().kind() {
+            ErrorKind::NicknameUnavailable(ref nick) => assert_eq!(nick, "bot"),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn alternates_are_truncated_before_the_server_advertises_a_length() {
+        let mut server = Server::new(Registration::new("abcdefghijk", "bot", "Bot"));
+
+        assert_eq!(
+            server.receive(&nick_in_use("abcdefghijk")).unwrap(),
+            vec!["NICK abcdefgh1"]
+        );
+    }
+
+    #[test]
+    fn the_advertised_length_replaces_the_default() {
+        let strategy = Truncate(NumericSuffix(1));
+
+        assert_eq!(strategy.next_nick("abcdefghijk", 1, None).unwrap(), "abcdefgh1");
+        assert_eq!(strategy.next_nick("abcdefghijk", 1, Some(30)).unwrap(), "abcdefghijk1");
+        assert_eq!(strategy.next_nick("abcdefghijk", 1, Some(5)).unwrap(), "abcd1");
+    }
+
+    #[test]
+    fn reclaiming_moves_to_the_best_nickname_the_strategy_chose() {
+        let registration = Registration::new("bot", "bot", "Bot")
+            .nick_strategy(FixedList(vec!["robot".to_owned(), "automaton".to_owned()]))
+            .reclaim_nick(true);
+        let mut server = Server::new(registration);
+
+        assert_eq!(server.receive(&nick_in_use("bot")).unwrap(), vec!["NICK robot"]);
+        assert_eq!(server.receive(&nick_in_use("robot")).unwrap(), vec!["NICK automaton"]);
+        assert!(server.receive("001 automaton :Welcome").unwrap().is_empty());
+
+        // Nobody who isn't holding a better nickname is worth waiting for.
+        assert!(server.receive(":someone!u@h QUIT :Bye").unwrap().is_empty());
+
+        assert_eq!(server.receive(":Robot!u@h QUIT :Bye").unwrap(), vec!["NICK robot"]);
+        assert!(server.receive(":automaton!u@h NICK robot").unwrap().is_empty());
+
+        // Another nickname of the same rank isn't an improvement.
+        assert!(server.receive(":robot2!u@h NICK robot3").unwrap().is_empty());
+
+        assert_eq!(server.receive(":bot!u@h NICK bot_").unwrap(), vec!["NICK bot"]);
+        assert!(server.receive(&nick_in_use("bot")).unwrap().is_empty());
+        assert!(server.registrar.reclaiming.is_none());
+    }
+
+    #[test]
+    fn random_suffixes_differ_unless_seeded() {
+        let strategy = RandomSuffix::new(18, 1);
+        let clone = strategy.clone();
+
+        assert_ne!(strategy.next_nick("bot", 1, None), clone.next_nick("bot", 1, None));
+
+        let seeded = RandomSuffix::with_seed(7, 18, 1);
+
+        assert_eq!(seeded.next_nick("bot", 1, None), seeded.clone().next_nick("bot", 1, None));
+        assert_eq!(RandomSuffix::new(4, 1).next_nick("bot", 2, None), None);
+    }
+}
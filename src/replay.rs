@@ -0,0 +1,91 @@
+//! The replay module contains `Replay`, a bounded buffer of the messages most
+//! recently received on a connection.
+//!
+//! Keeping the last few messages around is useful to consumers that attach
+//! to a connection late, to bots that need a little context, and for finding
+//! out what happened right before a connection failed. The buffer is enabled
+//! with `Client::replay_buffer`, and is read through the handle returned by
+//! `IrcTransport::replay`, which can be kept after the transport has failed
+//! or been dropped.
+
+use pircolate::Message;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A handle to the buffer of messages recently received on a connection.
+#[derive(Clone)]
+pub struct Replay {
+    inner: Arc<Mutex<ReplayData>>,
+}
+
+struct ReplayData {
+    capacity: usize,
+    messages: VecDeque<Message>,
+}
+
+impl Replay {
+    pub(crate) fn new(capacity: usize) -> Replay {
+        Replay {
+            inner: Arc::new(Mutex::new(ReplayData {
+                capacity: capacity,
+                messages: VecDeque::with_capacity(capacity),
+            })),
+        }
+    }
+
+    /// The messages in the buffer, from the oldest to the most recent.
+    pub fn messages(&self) -> Vec<Message> {
+        self.lock().messages.iter().cloned().collect()
+    }
+
+    /// The most recent `count` messages in the buffer, from the oldest to the
+    /// most recent.
+    pub fn last(&self, count: usize) -> Vec<Message> {
+        let data = self.lock();
+        let skip = data.messages.len().saturating_sub(count);
+
+        data.messages.iter().skip(skip).cloned().collect()
+    }
+
+    /// The number of messages in the buffer.
+    pub fn len(&self) -> usize {
+        self.lock().messages.len()
+    }
+
+    /// Returns true if the buffer holds no messages.
+    pub fn is_empty(&self) -> bool {
+        self.lock().messages.is_empty()
+    }
+
+    /// The greatest number of messages kept by the buffer. A capacity of 0
+    /// means the buffer is disabled.
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Discard every message in the buffer.
+    pub fn clear(&self) {
+        self.lock().messages.clear();
+    }
+
+    // Adds a message received from the server, discarding the oldest message
+    // if the buffer is full.
+    pub(crate) fn record(&self, message: &Message) {
+        let mut data = self.lock();
+
+        if data.capacity == 0 {
+            return;
+        }
+
+        if data.messages.len() == data.capacity {
+            data.messages.pop_front();
+        }
+
+        data.messages.push_back(message.clone());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReplayData> {
+        self.inner.lock().expect("Replay lock poisoned")
+    }
+}
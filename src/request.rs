@@ -0,0 +1,873 @@
+//! The request module contains `Requester`, which sends commands to the
+//! server and provides futures that resolve once the server has responded
+//! to them, rather than leaving the application to pick the responses out
+//! of the stream of incoming messages.
+//!
+//! Responses are observed by the `IrcTransport` as it's polled, so the
+//! futures only make progress while the transport (or the `Stream` half of
+//! it, once split) continues to be polled. If the connection closes before
+//! a response is received, the future fails with `RequestAborted`.
+
+use account::{self, AccountRequest, RegisteredAccount};
+use chathistory::{self, HistoricalMessage, HistoryRequest, Reference};
+use error::{Error, ErrorKind, Result};
+use outgoing;
+use state::State;
+use who::{self, Listing, Name, NamesRequest, WhoEntry, WhoRequest};
+
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot::{self, Receiver, Sender};
+use futures::task::{self, Task};
+
+use pircolate::Message;
+use pircolate::message;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The topic of a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topic {
+    /// The text of the topic, or `None` if no topic is set.
+    pub text: Option<String>,
+    /// Who set the topic, if the server said.
+    pub set_by: Option<String>,
+    /// When the topic was set, in seconds since the Unix epoch, if the
+    /// server said.
+    pub set_at: Option<u64>,
+}
+
+/// A future resolving to the server's response to a request.
+pub struct Response<T> {
+    inner: Receiver<Result<T>>,
+}
+
+impl<T> Future for Response<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(ErrorKind::RequestAborted.into()),
+        }
+    }
+}
+
+// A request waiting for the server to respond.
+pub(crate) trait Pending: Send {
+    // Handles a message received from the server, which has already been
+    // applied to `state`, returning true once the request has completed.
+    fn handle(&mut self, message: &Message, state: &State) -> bool;
+
+    // The channel being joined, if this is a request to join one.
+    fn joining(&self) -> Option<&str> {
+        None
+    }
+
+    // Messages to send again after handling a message, such as when the
+    // server asked for the request to be retried.
+    fn resend(&mut self) -> Vec<Message> {
+        Vec::new()
+    }
+}
+
+/// A handle for sending requests on a connection, obtained from
+/// `IrcTransport::requester`. The handle remains valid after the transport
+/// has been `split`.
+#[derive(Clone)]
+pub struct Requester {
+    inner: Arc<Mutex<RequesterData>>,
+    state: State,
+    // The account registered last, while it waits to be verified.
+    unverified: Arc<Mutex<Option<String>>>,
+}
+
+#[derive(Default)]
+struct RequesterData {
+    // Messages waiting to be picked up by the transport.
+    queued: VecDeque<Message>,
+    pending: Vec<Box<dyn Pending>>,
+    // The task polling the transport, woken when messages are queued.
+    task: Option<Task>,
+    closed: bool,
+    // The token of the last WHOX query, telling its replies apart.
+    whox_token: u16,
+}
+
+impl Requester {
+    pub(crate) fn new(state: State) -> Requester {
+        Requester {
+            inner: Arc::new(Mutex::new(RequesterData::default())),
+            state: state,
+            unverified: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Join `channel`, using `key` if the channel requires one. The future
+    /// resolves once the server has confirmed the join and sent the list of
+    /// the channel's members.
+    ///
+    /// The future fails with `CannotJoin` if the server refuses the join,
+    /// such as when the client is banned, the channel is invite only or the
+    /// key is wrong.
+    pub fn join(&self, channel: &str, key: Option<&str>) -> Response<()> {
+        let (sender, response) = channel_pair();
+
+        match message::client::join(channel, key) {
+            Ok(join) => self.start(vec![join], JoinRequest {
+                channel: channel.to_owned(),
+                joined: false,
+                sender: Some(sender),
+            }),
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    /// Leave `channel`, giving `reason` if there is one. The future resolves
+    /// once the server has confirmed the client left the channel, at which
+    /// point it's no longer tracked by the `State`.
+    ///
+    /// Any joins of the channel still waiting to be confirmed fail with
+    /// `RequestAborted`, so that they don't undo leaving it. The future
+    /// fails with `NotOnChannel` or `NoSuchChannel` if the server refuses
+    /// the request, and with `InvalidParameter` without anything being sent
+    /// if the channel or the reason would break the PART line, such as with
+    /// CR or LF.
+    pub fn part(&self, channel: &str, reason: Option<&str>) -> Response<()> {
+        let checked = outgoing::check_middle(channel)
+            .and(reason.map_or(Ok(()), outgoing::check_trailing));
+
+        if let Err(err) = checked {
+            return failed(err);
+        }
+
+        let (sender, response) = channel_pair();
+
+        let part = match reason {
+            Some(reason) => Message::try_from(format!("PART {} :{}", channel, reason)),
+            None => Message::try_from(format!("PART {}", channel)),
+        };
+
+        match part {
+            Ok(part) => {
+                {
+                    let state = &self.state;
+                    let mut data = self.lock();
+
+                    data.pending.retain(|pending| match pending.joining() {
+                        Some(joining) => !state.same_name(joining, channel),
+                        None => true,
+                    });
+                }
+
+                self.start(vec![part], PartRequest {
+                    channel: channel.to_owned(),
+                    sender: Some(sender),
+                })
+            }
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    /// Query the topic of `channel`. The future resolves once the server has
+    /// said who set the topic, or has sent another reply about the channel
+    /// if it doesn't say.
+    ///
+    /// The future fails with `NoSuchChannel` or `NotOnChannel` if the server
+    /// refuses to give the topic.
+    pub fn topic(&self, channel: &str) -> Response<Topic> {
+        let (sender, response) = channel_pair();
+
+        match Message::try_from(format!("TOPIC {}", channel)) {
+            Ok(query) => self.start(vec![query], TopicRequest {
+                channel: channel.to_owned(),
+                topic: None,
+                sender: Some(sender),
+            }),
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    /// Set the topic of `channel` to `text`. The future resolves once the
+    /// server has confirmed the change.
+    ///
+    /// The future fails with `ChanOpPrivsNeeded` if the client isn't allowed
+    /// to change the topic, or with `NoSuchChannel` or `NotOnChannel`. It
+    /// fails with `InvalidParameter` without anything being sent if the
+    /// channel or the text would break the TOPIC line, such as with CR or LF.
+    pub fn set_topic(&self, channel: &str, text: &str) -> Response<()> {
+        if let Err(err) = outgoing::check_middle(channel).and(outgoing::check_trailing(text)) {
+            return failed(err);
+        }
+
+        let (sender, response) = channel_pair();
+
+        match Message::try_from(format!("TOPIC {} :{}", channel, text)) {
+            Ok(topic) => self.start(vec![topic], SetTopicRequest {
+                channel: channel.to_owned(),
+                sender: Some(sender),
+            }),
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    /// Mark the client as away with the given away message. The future
+    /// resolves once the server has confirmed it with RPL_NOWAWAY, at which
+    /// point it's reflected by `State::away`.
+    ///
+    /// The future fails with `InvalidParameter` if the message is empty,
+    /// since servers take that to mean the client is back, or if it would
+    /// break the AWAY line, such as with CR or LF. Use `set_back` for the
+    /// former instead.
+    pub fn set_away(&self, reason: &str) -> Response<()> {
+        if reason.is_empty() {
+            let reason = "the away message can't be empty".to_owned();
+            return failed(ErrorKind::InvalidParameter(String::new(), reason).into());
+        }
+
+        if let Err(err) = outgoing::check_trailing(reason) {
+            return failed(err);
+        }
+
+        self.away(format!("AWAY :{}", reason), true)
+    }
+
+    /// Mark the client as no longer away. The future resolves once the
+    /// server has confirmed it with RPL_UNAWAY.
+    pub fn set_back(&self) -> Response<()> {
+        self.away("AWAY".to_owned(), false)
+    }
+
+    /// Fetch up to `limit` of the most recent messages sent to `target`, a
+    /// channel or nickname, or only those sent after `since` if it's given.
+    /// The future resolves to the messages, oldest first, once the server
+    /// has sent them all. See the `chathistory` module for details.
+    ///
+    /// The future fails with `CapabilityNotEnabled` unless the
+    /// `draft/chathistory` capability is enabled, and with
+    /// `HistoryUnavailable` if the server refuses the request.
+    pub fn latest_history(
+        &self,
+        target: &str,
+        since: Option<Reference>,
+        limit: usize,
+    ) -> Response<Vec<HistoricalMessage>> {
+        let since = since.map_or("*".to_owned(), |since| since.to_string());
+
+        self.history("LATEST", target, since, limit)
+    }
+
+    /// Fetch up to `limit` of the messages sent to `target` right before
+    /// `before`, as with `latest_history`.
+    pub fn history_before(
+        &self,
+        target: &str,
+        before: Reference,
+        limit: usize,
+    ) -> Response<Vec<HistoricalMessage>> {
+        self.history("BEFORE", target, before.to_string(), limit)
+    }
+
+    /// Fetch up to `limit` of the messages sent to `target` right after
+    /// `after`, as with `latest_history`.
+    pub fn history_after(
+        &self,
+        target: &str,
+        after: Reference,
+        limit: usize,
+    ) -> Response<Vec<HistoricalMessage>> {
+        self.history("AFTER", target, after.to_string(), limit)
+    }
+
+    fn history(
+        &self,
+        subcommand: &str,
+        target: &str,
+        reference: String,
+        limit: usize,
+    ) -> Response<Vec<HistoricalMessage>> {
+        let (sender, response) = channel_pair();
+
+        if !self.state.has_capability(chathistory::CAPABILITY) {
+            let cap = chathistory::CAPABILITY.to_owned();
+            let _ = sender.send(Err(ErrorKind::CapabilityNotEnabled(cap).into()));
+
+            return response;
+        }
+
+        // The server advertises the most messages it returns at once, with
+        // zero meaning there's no limit.
+        let limit = match self.state.isupport("CHATHISTORY").and_then(|max| max.parse().ok()) {
+            Some(max) if max > 0 => ::std::cmp::min(limit, max),
+            _ => limit,
+        };
+
+        let raw = format!("CHATHISTORY {} {} {} {}", subcommand, target, reference, limit);
+
+        match Message::try_from(raw) {
+            Ok(request) => self.start(vec![request], HistoryRequest::new(target, sender)),
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    /// List the members of `channel` with NAMES. The future resolves once
+    /// the server has sent the whole list. See the `who` module for details.
+    pub fn names(&self, channel: &str) -> Response<Listing<Name>> {
+        let (sender, response) = channel_pair();
+
+        match Message::try_from(format!("NAMES {}", channel)) {
+            Ok(query) => {
+                let pending = NamesRequest::new(channel, query.clone(), sender);
+
+                self.start(vec![query], pending)
+            }
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    /// Query the users matching `mask`, a channel, nickname or hostmask,
+    /// with WHO. The future resolves once the server has sent every reply.
+    /// See the `who` module for details.
+    ///
+    /// The future fails with `ServerBusy` if the server keeps asking for the
+    /// query to be tried again.
+    pub fn who(&self, mask: &str) -> Response<Listing<WhoEntry>> {
+        self.who_query(mask, None)
+    }
+
+    /// Query the users matching `mask` with WHOX, asking for the fields
+    /// given by their letters in `fields`, such as `"nfa"` for nicknames,
+    /// flags and accounts. See `who::WHOX_FIELDS` for the fields that can be
+    /// requested, and the `who` module for details.
+    ///
+    /// Servers which don't advertise WHOX are sent a plain WHO instead,
+    /// whose replies give the fields they always include.
+    pub fn whox(&self, mask: &str, fields: &str) -> Response<Listing<WhoEntry>> {
+        if self.state.isupport("WHOX").is_none() {
+            return self.who_query(mask, None);
+        }
+
+        let mut fields: String = fields
+            .chars()
+            .filter(|field| who::WHOX_FIELDS.contains(*field) && *field != 't')
+            .collect();
+        fields.insert(0, 't');
+
+        let token = {
+            let mut data = self.lock();
+
+            data.whox_token = data.whox_token % 999 + 1;
+            data.whox_token.to_string()
+        };
+
+        self.who_query(mask, Some((token, fields)))
+    }
+
+    fn who_query(
+        &self,
+        mask: &str,
+        whox: Option<(String, String)>,
+    ) -> Response<Listing<WhoEntry>> {
+        let (sender, response) = channel_pair();
+
+        let raw = match whox {
+            Some((ref token, ref fields)) => format!("WHO {} %{},{}", mask, fields, token),
+            None => format!("WHO {}", mask),
+        };
+
+        match Message::try_from(raw) {
+            Ok(query) => {
+                let pending = WhoRequest::new(mask, whox, query.clone(), sender);
+
+                self.start(vec![query], pending)
+            }
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    /// Register the account `account`, or one named after the current
+    /// nickname if it's `*`, with the given password and email address. The
+    /// future resolves once the server has registered it, which may need it
+    /// to be verified with `verify_account` before it can be used. See the
+    /// `account` module for details.
+    ///
+    /// The future fails with `CapabilityNotEnabled` unless the server offers
+    /// the `draft/account-registration` capability, and with
+    /// `AccountRegistrationFailed` if the server refuses the registration.
+    /// It fails with `InvalidParameter` without anything being sent if the
+    /// account, email address or password is empty or contains a space, CR,
+    /// LF or NUL, since not every server accepts a password with spaces in it.
+    pub fn register_account(
+        &self,
+        account: &str,
+        email: Option<&str>,
+        password: &str,
+    ) -> Response<RegisteredAccount> {
+        let checked = outgoing::check_middle(account)
+            .and(email.map_or(Ok(()), outgoing::check_middle))
+            .and(outgoing::check_middle(password));
+
+        if let Err(err) = checked {
+            return failed(err);
+        }
+
+        let raw = format!("REGISTER {} {} :{}", account, email.unwrap_or("*"), password);
+
+        self.account_request(raw)
+    }
+
+    /// Verify the account registered last with the code the network sent,
+    /// or the account named after the current nickname if none is waiting to
+    /// be verified. The future fails as with `register_account`, including
+    /// for a code which isn't a single parameter.
+    pub fn verify_account(&self, code: &str) -> Response<RegisteredAccount> {
+        let account = self.unverified
+            .lock()
+            .expect("Account lock poisoned")
+            .clone()
+            .or_else(|| self.state.nick())
+            .unwrap_or_else(|| "*".to_owned());
+
+        if let Err(err) = outgoing::check_middle(code) {
+            return failed(err);
+        }
+
+        self.account_request(format!("VERIFY {} {}", account, code))
+    }
+
+    // Sends a REGISTER or VERIFY, requesting the capability first if the
+    // server offers it but it isn't enabled.
+    fn account_request(&self, raw: String) -> Response<RegisteredAccount> {
+        let (sender, response) = channel_pair();
+
+        let enabled = self.state.has_capability(account::CAPABILITY);
+
+        if !enabled && self.state.available_capability(account::CAPABILITY).is_none() {
+            let cap = account::CAPABILITY.to_owned();
+            let _ = sender.send(Err(ErrorKind::CapabilityNotEnabled(cap).into()));
+
+            return response;
+        }
+
+        let messages = Message::try_from(raw).and_then(|command| {
+            if enabled {
+                return Ok((vec![command.clone()], command));
+            }
+
+            let cap_req = message::client::cap_req(account::CAPABILITY)?;
+
+            Ok((vec![cap_req], command))
+        });
+
+        match messages {
+            Ok((messages, command)) => {
+                let unverified = self.unverified.clone();
+
+                self.start(messages, AccountRequest::new(command, !enabled, unverified, sender))
+            }
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    fn away(&self, raw: String, marking_away: bool) -> Response<()> {
+        let (sender, response) = channel_pair();
+
+        match Message::try_from(raw) {
+            Ok(away) => self.start(vec![away], AwayRequest {
+                marking_away: marking_away,
+                sender: Some(sender),
+            }),
+            Err(err) => {
+                let _ = sender.send(Err(err.into()));
+            }
+        }
+
+        response
+    }
+
+    // Queues the messages for a request to be sent, and waits for the
+    // server's response to them.
+    pub(crate) fn start<P>(&self, messages: Vec<Message>, pending: P)
+    where
+        P: Pending + 'static,
+    {
+        let mut data = self.lock();
+
+        // Dropping the request fails its future.
+        if data.closed {
+            return;
+        }
+
+        data.queued.extend(messages);
+        data.pending.push(Box::new(pending));
+
+        if let Some(ref task) = data.task {
+            task.notify();
+        }
+    }
+
+    // Queues a message to be sent without waiting for a response, failing if
+    // the connection has closed.
+    pub(crate) fn send(&self, message: Message) -> Result<()> {
+        let mut data = self.lock();
+
+        if data.closed {
+            return Err(ErrorKind::ConnectionReset.into());
+        }
+
+        data.queued.push_back(message);
+
+        if let Some(ref task) = data.task {
+            task.notify();
+        }
+
+        Ok(())
+    }
+
+    // Records the task that's polling the transport, so that it can be woken
+    // when new requests are made.
+    pub(crate) fn register(&self) {
+        self.lock().task = Some(task::current());
+    }
+
+    // Takes the messages of any new requests, to be sent.
+    pub(crate) fn take_queued(&self) -> Vec<Message> {
+        self.lock().queued.drain(..).collect()
+    }
+
+    pub(crate) fn handle_incoming(&self, message: &Message, state: &State) {
+        let mut data = self.lock();
+        let RequesterData { ref mut pending, ref mut queued, ref task, .. } = *data;
+
+        pending.retain_mut(|pending| {
+            let done = pending.handle(message, state);
+            let resent = pending.resend();
+
+            if !resent.is_empty() {
+                queued.extend(resent);
+
+                if let Some(ref task) = *task {
+                    task.notify();
+                }
+            }
+
+            !done
+        });
+    }
+
+    // Fails every outstanding request, as the connection has closed.
+    pub(crate) fn abort(&self) {
+        let mut data = self.lock();
+
+        data.closed = true;
+        data.queued.clear();
+        data.pending.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RequesterData> {
+        self.inner.lock().expect("Requester lock poisoned")
+    }
+}
+
+pub(crate) fn channel_pair<T>() -> (Sender<Result<T>>, Response<T>) {
+    let (sender, receiver) = oneshot::channel();
+
+    (sender, Response { inner: receiver })
+}
+
+// A response which has already failed, for a request that couldn't be made.
+pub(crate) fn failed<T>(err: Error) -> Response<T> {
+    let (sender, response) = channel_pair();
+    let _ = sender.send(Err(err));
+
+    response
+}
+
+struct JoinRequest {
+    channel: String,
+    joined: bool,
+    sender: Option<Sender<Result<()>>>,
+}
+
+impl Pending for JoinRequest {
+    fn handle(&mut self, message: &Message, state: &State) -> bool {
+        let mut args = message.raw_args();
+
+        let result = match message.raw_command() {
+            "JOIN" => {
+                let from_self = match message.prefix() {
+                    Some((nick, _, _)) => state.is_self(nick),
+                    None => false,
+                };
+
+                let same_channel = match args.next() {
+                    Some(channel) => state.same_name(channel, &self.channel),
+                    None => false,
+                };
+
+                if from_self && same_channel {
+                    self.joined = true;
+                }
+
+                return false;
+            }
+
+            // RPL_ENDOFNAMES
+            "366" if self.joined => Ok(()),
+
+            // ERR_NOSUCHCHANNEL, ERR_TOOMANYCHANNELS, ERR_CHANNELISFULL,
+            // ERR_INVITEONLYCHAN, ERR_BANNEDFROMCHAN, ERR_BADCHANNELKEY,
+            // ERR_NEEDREGGEDNICK
+            "403" | "405" | "471" | "473" | "474" | "475" | "477" => {
+                let reason = args.nth(2).unwrap_or_default().to_owned();
+
+                Err(ErrorKind::CannotJoin(self.channel.clone(), reason).into())
+            }
+
+            _ => return false,
+        };
+
+        // Replies to other requests name a different channel.
+        let channel = message.raw_args().nth(1).unwrap_or_default();
+
+        if !state.same_name(channel, &self.channel) {
+            return false;
+        }
+
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(result);
+        }
+
+        true
+    }
+
+    fn joining(&self) -> Option<&str> {
+        Some(&self.channel)
+    }
+}
+
+struct PartRequest {
+    channel: String,
+    sender: Option<Sender<Result<()>>>,
+}
+
+impl Pending for PartRequest {
+    fn handle(&mut self, message: &Message, state: &State) -> bool {
+        let result = match channel_error(message, &self.channel, state) {
+            Some(err) => Err(err),
+            None if message.raw_command() == "PART" => {
+                let from_self = match message.prefix() {
+                    Some((nick, _, _)) => state.is_self(nick),
+                    None => false,
+                };
+
+                let channel = message.raw_args().next().unwrap_or_default();
+
+                if !from_self || !state.same_name(channel, &self.channel) {
+                    return false;
+                }
+
+                Ok(())
+            }
+            None => return false,
+        };
+
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(result);
+        }
+
+        true
+    }
+}
+
+struct AwayRequest {
+    marking_away: bool,
+    sender: Option<Sender<Result<()>>>,
+}
+
+impl Pending for AwayRequest {
+    fn handle(&mut self, message: &Message, _: &State) -> bool {
+        // RPL_NOWAWAY or RPL_UNAWAY
+        let expected = if self.marking_away { "306" } else { "305" };
+
+        if message.raw_command() != expected {
+            return false;
+        }
+
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Ok(()));
+        }
+
+        true
+    }
+}
+
+// The error described by a reply refusing a command naming `channel`, if the
+// message is one.
+fn channel_error(message: &Message, channel: &str, state: &State) -> Option<Error> {
+    let replied = message.raw_args().nth(1).unwrap_or_default();
+
+    if !state.same_name(replied, channel) {
+        return None;
+    }
+
+    let channel = channel.to_owned();
+
+    match message.raw_command() {
+        "403" => Some(ErrorKind::NoSuchChannel(channel).into()),
+        "442" => Some(ErrorKind::NotOnChannel(channel).into()),
+        "482" => Some(ErrorKind::ChanOpPrivsNeeded(channel).into()),
+        _ => None,
+    }
+}
+
+struct TopicRequest {
+    channel: String,
+    // The topic received so far, waiting for who set it.
+    topic: Option<Topic>,
+    sender: Option<Sender<Result<Topic>>>,
+}
+
+impl TopicRequest {
+    fn complete(&mut self, result: Result<Topic>) -> bool {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(result);
+        }
+
+        true
+    }
+}
+
+impl Pending for TopicRequest {
+    fn handle(&mut self, message: &Message, state: &State) -> bool {
+        if let Some(err) = channel_error(message, &self.channel, state) {
+            return self.complete(Err(err));
+        }
+
+        let args: Vec<&str> = message.raw_args().collect();
+        let for_channel = args.len() > 2 && state.same_name(args[1], &self.channel);
+
+        match message.raw_command() {
+            // RPL_NOTOPIC
+            "331" if for_channel => self.complete(Ok(Topic {
+                text: None,
+                set_by: None,
+                set_at: None,
+            })),
+
+            // RPL_TOPIC
+            "332" if for_channel => {
+                self.topic = Some(Topic {
+                    text: Some(args[2].to_owned()),
+                    set_by: None,
+                    set_at: None,
+                });
+
+                false
+            }
+
+            // RPL_TOPICWHOTIME
+            "333" if for_channel && self.topic.is_some() => {
+                let mut topic = self.topic.take().expect("topic was checked");
+
+                topic.set_by = Some(args[2].to_owned());
+                topic.set_at = args.get(3).and_then(|time| time.parse().ok());
+
+                self.complete(Ok(topic))
+            }
+
+            // Not every server sends RPL_TOPICWHOTIME, so the topic is also
+            // complete once the server replies with anything else about the
+            // channel, such as the NAMES reply sent after joining it.
+            command if self.topic.is_some() && is_numeric(command) => {
+                let about_channel = args
+                    .iter()
+                    .skip(1)
+                    .any(|arg| state.same_name(arg, &self.channel));
+
+                if !about_channel {
+                    return false;
+                }
+
+                let topic = self.topic.take().expect("topic was checked");
+
+                self.complete(Ok(topic))
+            }
+
+            _ => false,
+        }
+    }
+}
+
+// Whether the command is a numeric reply.
+fn is_numeric(command: &str) -> bool {
+    command.len() == 3 && command.bytes().all(|b| b.is_ascii_digit())
+}
+
+struct SetTopicRequest {
+    channel: String,
+    sender: Option<Sender<Result<()>>>,
+}
+
+impl Pending for SetTopicRequest {
+    fn handle(&mut self, message: &Message, state: &State) -> bool {
+        let result = match channel_error(message, &self.channel, state) {
+            Some(err) => Err(err),
+            None if message.raw_command() == "TOPIC" => {
+                let from_self = match message.prefix() {
+                    Some((nick, _, _)) => state.is_self(nick),
+                    None => false,
+                };
+
+                let channel = message.raw_args().next().unwrap_or_default();
+
+                if !from_self || !state.same_name(channel, &self.channel) {
+                    return false;
+                }
+
+                Ok(())
+            }
+            None => return false,
+        };
+
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(result);
+        }
+
+        true
+    }
+}
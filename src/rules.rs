@@ -0,0 +1,400 @@
+//! The rules module contains a small rules engine for routing messages
+//! according to a table loaded from configuration, allowing simple relay and
+//! responder bots to change their behaviour without changing any code.
+//!
+//! Rules are written in TOML as a list of `[[rule]]` tables, each optionally
+//! matching on the `channel` a PRIVMSG was sent to, a regular expression
+//! matched against the `sender`'s `nick!user@host`, and a regular expression
+//! `pattern` matched against the text of the message. The first rule that
+//! matches decides the outcome:
+//!
+//! ```toml
+//! [[rule]]
+//! sender = "^spammer!"
+//! action = "ignore"
+//!
+//! [[rule]]
+//! channel = "#rust"
+//! pattern = "^!hello (?P<name>\\w+)"
+//! action = "reply"
+//! template = "Hello, {name}!"
+//! cooldown = 30
+//!
+//! [[rule]]
+//! channel = "#announcements"
+//! action = "relay"
+//! target = "#general"
+//! template = "<{nick}> {message}"
+//!
+//! [[rule]]
+//! pattern = "(?i)help"
+//! action = "webhook"
+//! url = "https://example.org/hooks/irc"
+//! ```
+//!
+//! Templates may refer to `{nick}`, `{channel}`, `{target}` (where a reply
+//! would be sent), `{message}`, and to the numbered or named groups captured
+//! by `pattern`. Values are sanitized as described by the `template`
+//! module, so a message can't inject formatting or protocol lines into the
+//! reply.
+//!
+//! A rule with a `cooldown` doesn't match again until that many seconds have
+//! passed since it last matched, so a busy channel can't make a bot flood it
+//! with the same reply.
+//!
+//! Rules can be evaluated by the application with `Rules::evaluate`, or
+//! given to `Client::responder` to have each connection send the replies and
+//! relayed messages itself, without any plumbing of its own. Channels are
+//! told apart from users, and compared with the `channel` of each rule, the
+//! way the server does when the rules are evaluated with
+//! `Rules::evaluate_on` or by a responder.
+//!
+//! This module is only available with the `rules` feature.
+
+use clock::{self, Clock};
+use error::{ErrorKind, Result};
+use event::{Automation, EventBus};
+use state::State;
+
+use pircolate::Message;
+use pircolate::command::PrivMsg;
+use pircolate::message;
+
+use template::Template;
+
+use regex::{Captures, Regex};
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The template used by relays and webhooks that don't specify one.
+const DEFAULT_TEMPLATE: &str = "<{nick}> {message}";
+
+#[derive(Deserialize)]
+struct RulesConfig {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    channel: Option<String>,
+    sender: Option<String>,
+    pattern: Option<String>,
+    action: String,
+    template: Option<String>,
+    target: Option<String>,
+    url: Option<String>,
+    cooldown: Option<u64>,
+}
+
+enum Action {
+    Reply(Template),
+    Relay(String, Template),
+    Webhook(String, Template),
+    Ignore,
+}
+
+struct Rule {
+    channel: Option<String>,
+    sender: Option<Regex>,
+    pattern: Option<Regex>,
+    action: Action,
+    cooldown: Option<Duration>,
+    // When the rule last matched, according to the clock of the `Rules`.
+    last_matched: Mutex<Option<Duration>>,
+}
+
+/// The outcome of a message matching a rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// A message, either a reply or a relayed message, to be sent to the
+    /// server.
+    Send(Message),
+    /// A request to be delivered to a webhook, containing the rendered
+    /// template as its body. Delivering it is left to the application.
+    Webhook {
+        /// The URL of the webhook.
+        url: String,
+        /// The body of the request.
+        body: String,
+    },
+    /// The message should be ignored by the application.
+    Ignore,
+}
+
+/// A table of rules, evaluated in order against incoming messages.
+pub struct Rules {
+    rules: Vec<Rule>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Rules {
+    /// Load a table of rules from the given TOML source.
+    pub fn from_toml(source: &str) -> Result<Rules> {
+        let config: RulesConfig = ::toml::from_str(source)
+            .map_err(|err| ErrorKind::InvalidRules(err.to_string()))?;
+
+        let rules = config
+            .rule
+            .into_iter()
+            .map(Rule::compile)
+            .collect::<Result<Vec<Rule>>>()?;
+
+        Ok(Rules {
+            rules: rules,
+            clock: clock::system(),
+        })
+    }
+
+    /// Load a table of rules from the TOML file at the given path.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Rules> {
+        Rules::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Use the given `Clock` to time the cooldowns of the rules, instead of
+    /// the system's monotonic clock.
+    pub fn clock<C>(mut self, clock: C) -> Rules
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Evaluate the rules against a message received from the server,
+    /// returning the outcome of the first rule to match, if any. Only
+    /// PRIVMSG messages are matched.
+    ///
+    /// Without the state of the connection, the channel types and
+    /// casemapping servers assume when they don't advertise their own are
+    /// used.
+    pub fn evaluate(&self, message: &Message) -> Result<Option<Outcome>> {
+        self.evaluate_on(&State::default(), message)
+    }
+
+    /// The same as `evaluate`, but recognizing channels and comparing their
+    /// names the way the server tracked by `state` does.
+    pub fn evaluate_on(&self, state: &State, message: &Message) -> Result<Option<Outcome>> {
+        let PrivMsg(target, text) = match message.command::<PrivMsg>() {
+            Some(privmsg) => privmsg,
+            None => return Ok(None),
+        };
+
+        let (nick, sender) = match message.prefix() {
+            Some((nick, _, _)) => (nick, message.raw_prefix().unwrap_or(nick)),
+            None => return Ok(None),
+        };
+
+        // Messages sent directly to the client are replied to privately.
+        let is_channel = state.is_channel(target);
+        let channel = if is_channel { target } else { "" };
+        let reply_to = if is_channel { target } else { nick };
+
+        for rule in &self.rules {
+            if let Some(ref expected) = rule.channel {
+                if !state.same_name(expected, channel) {
+                    continue;
+                }
+            }
+
+            if let Some(ref sender_pattern) = rule.sender {
+                if !sender_pattern.is_match(sender) {
+                    continue;
+                }
+            }
+
+            let captures = match rule.pattern {
+                Some(ref pattern) => match pattern.captures(text) {
+                    Some(captures) => Some(captures),
+                    None => continue,
+                },
+                None => None,
+            };
+
+            if !rule.start_cooldown(self.clock.now()) {
+                continue;
+            }
+
+            let context = Context {
+                nick: nick,
+                channel: channel,
+                target: reply_to,
+                message: text,
+                captures: captures.as_ref(),
+            };
+
+            let outcome = match rule.action {
+                Action::Reply(ref template) => {
+                    Outcome::Send(message::client::priv_msg(reply_to, &context.render(template))?)
+                }
+                Action::Relay(ref relay_to, ref template) => {
+                    Outcome::Send(message::client::priv_msg(relay_to, &context.render(template))?)
+                }
+                Action::Webhook(ref url, ref template) => Outcome::Webhook {
+                    url: url.clone(),
+                    body: context.render(template),
+                },
+                Action::Ignore => Outcome::Ignore,
+            };
+
+            return Ok(Some(outcome));
+        }
+
+        Ok(None)
+    }
+
+    // Queues the messages sent by the rule matching a message received from
+    // the server, for connections made by a `Client` given these rules as
+    // its responder. Other outcomes are left to the application.
+    pub(crate) fn handle_incoming(
+        &self,
+        message: &Message,
+        state: &State,
+        outgoing: &mut VecDeque<Message>,
+        events: &EventBus,
+    ) {
+        match self.evaluate_on(state, message) {
+            Ok(Some(Outcome::Send(message))) => outgoing.push_back(message),
+            Ok(_) => (),
+            Err(err) => events.emit_failure(Automation::Responder, err),
+        }
+    }
+}
+
+impl Rule {
+    fn compile(config: RuleConfig) -> Result<Rule> {
+        let template = config.template.as_ref().map(|template| Template::new(template));
+        let default_template = || Template::new(DEFAULT_TEMPLATE);
+
+        let action = match config.action.as_str() {
+            "reply" => Action::Reply(template.ok_or_else(|| invalid("reply rules need a template"))?),
+            "relay" => Action::Relay(
+                config.target.ok_or_else(|| invalid("relay rules need a target"))?,
+                template.unwrap_or_else(default_template),
+            ),
+            "webhook" => Action::Webhook(
+                config.url.ok_or_else(|| invalid("webhook rules need a url"))?,
+                template.unwrap_or_else(default_template),
+            ),
+            "ignore" => Action::Ignore,
+            other => return Err(invalid(&format!("unknown action '{}'", other))),
+        };
+
+        Ok(Rule {
+            channel: config.channel,
+            sender: compile_regex(config.sender)?,
+            pattern: compile_regex(config.pattern)?,
+            action: action,
+            cooldown: config.cooldown.map(Duration::from_secs),
+            last_matched: Mutex::new(None),
+        })
+    }
+
+    // Returns false if the rule is cooling down, otherwise starting its
+    // cooldown from `now`.
+    fn start_cooldown(&self, now: Duration) -> bool {
+        let cooldown = match self.cooldown {
+            Some(cooldown) => cooldown,
+            None => return true,
+        };
+
+        let mut last_matched = self.last_matched.lock().expect("Rules lock poisoned");
+
+        match *last_matched {
+            Some(last) if now.saturating_sub(last) < cooldown => false,
+            _ => {
+                *last_matched = Some(now);
+                true
+            }
+        }
+    }
+}
+
+struct Context<'a> {
+    nick: &'a str,
+    channel: &'a str,
+    target: &'a str,
+    message: &'a str,
+    captures: Option<&'a Captures<'a>>,
+}
+
+impl<'a> Context<'a> {
+    fn render(&self, template: &Template) -> String {
+        template.render_with(|name| self.lookup(name))
+    }
+
+    fn lookup(&self, name: &str) -> Option<&'a str> {
+        match name {
+            "nick" => Some(self.nick),
+            "channel" => Some(self.channel),
+            "target" => Some(self.target),
+            "message" => Some(self.message),
+            name => {
+                let captures = self.captures?;
+                let group = match name.parse::<usize>() {
+                    Ok(index) => captures.get(index),
+                    Err(_) => captures.name(name),
+                };
+
+                group.map(|group| group.as_str())
+            }
+        }
+    }
+}
+
+fn compile_regex(pattern: Option<String>) -> Result<Option<Regex>> {
+    match pattern {
+        Some(pattern) => Regex::new(&pattern)
+            .map(Some)
+            .map_err(|err| invalid(&err.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn invalid(reason: &str) -> ::error::Error {
+    ErrorKind::InvalidRules(reason.to_owned()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r##"
+        [[rule]]
+        channel = "#Rust[1]"
+        action = "ignore"
+
+        [[rule]]
+        channel = "!ops"
+        action = "ignore"
+    "##;
+
+    fn privmsg(target: &str) -> Message {
+        Message::try_from(format!(":alice!u@h PRIVMSG {} :hi", target)).unwrap()
+    }
+
+    #[test]
+    fn channels_are_compared_by_casemapping() {
+        let rules = Rules::from_toml(RULES).unwrap();
+
+        assert_eq!(rules.evaluate(&privmsg("#rust{1}")).unwrap(), Some(Outcome::Ignore));
+        assert_eq!(rules.evaluate(&privmsg("#rust{2}")).unwrap(), None);
+    }
+
+    #[test]
+    fn channel_types_are_those_the_server_advertises() {
+        let rules = Rules::from_toml(RULES).unwrap();
+        let state = State::default();
+
+        assert_eq!(rules.evaluate_on(&state, &privmsg("!OPS")).unwrap(), None);
+
+        let isupport = ":irc.example.org 005 bot CHANTYPES=#! :are supported by this server";
+        state.handle_incoming(&Message::try_from(isupport.to_owned()).unwrap());
+
+        assert_eq!(rules.evaluate_on(&state, &privmsg("!OPS")).unwrap(), Some(Outcome::Ignore));
+    }
+}
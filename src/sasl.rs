@@ -0,0 +1,529 @@
+//! The sasl module contains `Sasl`, which configures how a connection
+//! authenticates to an account with SASL while registering.
+//!
+//! When a `Sasl` is given to `Registration::sasl`, the `sasl` capability is
+//! requested and the client logs in before registration completes. The
+//! mechanisms the client allows are tried in order of preference, skipping
+//! those the server doesn't advertise: by default these are SCRAM-SHA-256
+//! and SCRAM-SHA-1, which prove knowledge of the password without sending
+//! it, falling back to PLAIN when the server supports neither.
+//!
+//! Registration fails with `SaslFailed` if the server doesn't support SASL
+//! or any of the allowed mechanisms, or if it rejects the credentials, so
+//! the client never ends up connected without being logged in.
+
+use digest::Algorithm;
+use error::{Error, ErrorKind, Result};
+
+use getrandom;
+
+use pircolate::Message;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str;
+
+// The longest chunk of an AUTHENTICATE payload sent in one message.
+const CHUNK_LENGTH: usize = 400;
+
+// The length of the nonce generated for SCRAM, in bytes before encoding.
+const NONCE_LENGTH: usize = 24;
+
+// The gs2 header of SCRAM messages, which asks for no channel binding and
+// no authorization identity, and its base64 encoding.
+const GS2_HEADER: &str = "n,,";
+const GS2_HEADER_BASE64: &str = "biws";
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A SASL mechanism supported by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mechanism {
+    /// PLAIN, which sends the password to the server.
+    Plain,
+    /// SCRAM-SHA-1, as defined by RFC 5802.
+    ScramSha1,
+    /// SCRAM-SHA-256, as defined by RFC 7677.
+    ScramSha256,
+}
+
+impl Mechanism {
+    /// The name of the mechanism, as sent with AUTHENTICATE.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::ScramSha1 => "SCRAM-SHA-1",
+            Mechanism::ScramSha256 => "SCRAM-SHA-256",
+        }
+    }
+}
+
+impl fmt::Display for Mechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The credentials and mechanisms used to log in with SASL.
+#[derive(Clone)]
+pub struct Sasl {
+    account: String,
+    password: String,
+    mechanisms: Vec<Mechanism>,
+}
+
+impl Sasl {
+    /// Log in to `account` with `password`, preferring SCRAM-SHA-256, then
+    /// SCRAM-SHA-1, then PLAIN.
+    pub fn new<A, P>(account: A, password: P) -> Sasl
+    where
+        A: Into<String>,
+        P: Into<String>,
+    {
+        Sasl {
+            account: account.into(),
+            password: password.into(),
+            mechanisms: vec![Mechanism::ScramSha256, Mechanism::ScramSha1, Mechanism::Plain],
+        }
+    }
+
+    /// Only allow the given mechanisms, tried in the given order. Leaving out
+    /// `Mechanism::Plain` ensures the password is never sent to the server.
+    pub fn mechanisms(mut self, mechanisms: Vec<Mechanism>) -> Sasl {
+        self.mechanisms = mechanisms;
+        self
+    }
+
+    /// The account to log in to.
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+}
+
+impl fmt::Debug for Sasl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sasl")
+            .field("account", &self.account)
+            .field("mechanisms", &self.mechanisms)
+            .finish()
+    }
+}
+
+// The progress of a SCRAM exchange.
+enum Scram {
+    // The client-first-message is about to be sent.
+    Start,
+    // The client-first-message was sent, and isn't repeated by the server.
+    ClientFirst { bare: String, nonce: String },
+    // The proof was sent, and the server is expected to prove it knows the
+    // password too.
+    ClientFinal { server_signature: Vec<u8> },
+    Done,
+}
+
+// The exchange of the mechanism being tried.
+enum Exchange {
+    Plain,
+    Scram(Algorithm, Scram),
+}
+
+// Drives authentication for a single connection.
+pub(crate) struct Authenticator {
+    sasl: Sasl,
+    // The mechanisms still to be tried, in order of preference.
+    candidates: VecDeque<Mechanism>,
+    current: Option<(Mechanism, Exchange)>,
+    // Whether the server has accepted the mechanism being tried by sending
+    // a challenge, after which failing means the credentials were rejected.
+    accepted: bool,
+    // The chunks of the challenge received so far.
+    challenge: String,
+}
+
+impl Authenticator {
+    pub fn new(sasl: Sasl) -> Authenticator {
+        Authenticator {
+            candidates: sasl.mechanisms.iter().cloned().collect(),
+            sasl: sasl,
+            current: None,
+            accepted: false,
+            challenge: String::new(),
+        }
+    }
+
+    // Starts authenticating with the first allowed mechanism out of those
+    // advertised with the `sasl` capability, if the server lists them.
+    pub fn start(&mut self, advertised: Option<&str>, outgoing: &mut VecDeque<Message>) -> Result<()> {
+        if let Some(advertised) = advertised.filter(|advertised| !advertised.is_empty()) {
+            self.retain_advertised(advertised);
+        }
+
+        self.next_mechanism(outgoing)
+    }
+
+    // Handles a message received from the server while authenticating,
+    // returning true once the client has logged in.
+    pub fn handle_incoming(
+        &mut self,
+        message: &Message,
+        outgoing: &mut VecDeque<Message>,
+    ) -> Result<bool> {
+        let mut args = message.raw_args();
+
+        match message.raw_command() {
+            "AUTHENTICATE" => {
+                let chunk = args.next().unwrap_or_default();
+
+                self.accepted = true;
+
+                if chunk != "+" {
+                    self.challenge.push_str(chunk);
+                }
+
+                // A chunk of the longest length means more are coming.
+                if chunk.len() == CHUNK_LENGTH {
+                    return Ok(false);
+                }
+
+                let challenge = base64_decode(&self.challenge)
+                    .ok_or_else(|| failed("the server sent an invalid challenge"))?;
+                self.challenge.clear();
+
+                let response = self.respond(&challenge)?;
+
+                for chunk in chunks(&base64_encode(&response)) {
+                    outgoing.push_back(Message::try_from(format!("AUTHENTICATE {}", chunk))?);
+                }
+            }
+
+            // RPL_SASLSUCCESS or ERR_SASLALREADY
+            "903" | "907" => return Ok(true),
+
+            // RPL_SASLMECHS: "<nick> <mechanisms> :are available SASL mechanisms"
+            "908" => if let Some(advertised) = args.nth(1) {
+                self.retain_advertised(advertised);
+            },
+
+            // ERR_SASLFAIL or ERR_SASLTOOLONG, before the server accepted the
+            // mechanism, means the mechanism isn't supported.
+            "904" | "905" if !self.accepted => self.next_mechanism(outgoing)?,
+
+            // ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG or ERR_SASLABORTED
+            "902" | "904" | "905" | "906" => {
+                let reason = args.next_back().unwrap_or_default();
+
+                return Err(failed(reason));
+            }
+
+            _ => (),
+        }
+
+        Ok(false)
+    }
+
+    fn retain_advertised(&mut self, advertised: &str) {
+        let advertised: Vec<&str> = advertised.split(',').collect();
+
+        self.candidates
+            .retain(|mechanism| advertised.contains(&mechanism.name()));
+    }
+
+    fn next_mechanism(&mut self, outgoing: &mut VecDeque<Message>) -> Result<()> {
+        let mechanism = self.candidates
+            .pop_front()
+            .ok_or_else(|| failed("the server supports none of the allowed mechanisms"))?;
+
+        let exchange = match mechanism {
+            Mechanism::Plain => Exchange::Plain,
+            Mechanism::ScramSha1 => Exchange::Scram(Algorithm::Sha1, Scram::Start),
+            Mechanism::ScramSha256 => Exchange::Scram(Algorithm::Sha256, Scram::Start),
+        };
+
+        self.current = Some((mechanism, exchange));
+        self.accepted = false;
+        self.challenge.clear();
+
+        outgoing.push_back(Message::try_from(format!("AUTHENTICATE {}", mechanism))?);
+
+        Ok(())
+    }
+
+    // Produces the response to a challenge from the server.
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let sasl = &self.sasl;
+
+        match self.current {
+            Some((_, Exchange::Plain)) => {
+                Ok(format!("{0}\0{0}\0{1}", sasl.account, sasl.password).into_bytes())
+            }
+            Some((_, Exchange::Scram(algorithm, ref mut scram))) => {
+                let challenge = str::from_utf8(challenge)
+                    .map_err(|_| failed("the server sent an invalid challenge"))?;
+
+                scram_respond(algorithm, scram, sasl, challenge)
+            }
+            None => Err(failed("the server sent a challenge before a mechanism was chosen")),
+        }
+    }
+}
+
+// Advances a SCRAM exchange, as described by RFC 5802.
+fn scram_respond(algorithm: Algorithm, scram: &mut Scram, sasl: &Sasl, challenge: &str) -> Result<Vec<u8>> {
+    match *scram {
+        Scram::Start => {
+            let nonce = base64_encode(&nonce()?);
+            let bare = format!("n={},r={}", scram_name(&sasl.account), nonce);
+            let message = format!("{}{}", GS2_HEADER, bare);
+
+            *scram = Scram::ClientFirst {
+                bare: bare,
+                nonce: nonce,
+            };
+
+            Ok(message.into_bytes())
+        }
+
+        Scram::ClientFirst {
+            ref bare,
+            ref nonce,
+        } => {
+            let server_first = challenge;
+            let nonce = scram_attribute(server_first, 'r')
+                .filter(|server_nonce| server_nonce.starts_with(nonce.as_str()))
+                .ok_or_else(|| failed("the server sent an invalid nonce"))?;
+            let salt = scram_attribute(server_first, 's')
+                .and_then(base64_decode)
+                .ok_or_else(|| failed("the server sent an invalid salt"))?;
+            let iterations = scram_attribute(server_first, 'i')
+                .and_then(|iterations| iterations.parse::<u32>().ok())
+                .filter(|&iterations| iterations > 0)
+                .ok_or_else(|| failed("the server sent an invalid iteration count"))?;
+
+            let without_proof = format!("c={},r={}", GS2_HEADER_BASE64, nonce);
+            let auth_message = format!("{},{},{}", bare, server_first, without_proof);
+
+            let salted = algorithm.pbkdf2(sasl.password.as_bytes(), &salt, iterations);
+            let client_key = algorithm.hmac(&salted, b"Client Key");
+            let stored_key = algorithm.digest(&client_key);
+            let client_signature = algorithm.hmac(&stored_key, auth_message.as_bytes());
+            let server_key = algorithm.hmac(&salted, b"Server Key");
+
+            let proof: Vec<u8> = client_key
+                .iter()
+                .zip(&client_signature)
+                .map(|(key, signature)| key ^ signature)
+                .collect();
+
+            *scram = Scram::ClientFinal {
+                server_signature: algorithm.hmac(&server_key, auth_message.as_bytes()),
+            };
+
+            Ok(format!("{},p={}", without_proof, base64_encode(&proof)).into_bytes())
+        }
+
+        Scram::ClientFinal {
+            ref server_signature,
+        } => {
+            if let Some(error) = scram_attribute(challenge, 'e') {
+                return Err(failed(error));
+            }
+
+            let verifier = scram_attribute(challenge, 'v').and_then(base64_decode);
+
+            if verifier.as_ref() != Some(server_signature) {
+                return Err(failed("the server couldn't prove it knows the password"));
+            }
+
+            *scram = Scram::Done;
+
+            Ok(Vec::new())
+        }
+
+        Scram::Done => Err(failed("the server sent a challenge after the exchange completed")),
+    }
+}
+
+// The value of an attribute of a SCRAM message, such as `r=<nonce>`.
+fn scram_attribute(message: &str, name: char) -> Option<&str> {
+    message.split(',').find_map(|attribute| {
+        let mut chars = attribute.chars();
+
+        match (chars.next(), chars.next()) {
+            (Some(found), Some('=')) if found == name => Some(&attribute[2..]),
+            _ => None,
+        }
+    })
+}
+
+// Escapes a username for SCRAM, in which ',' and '=' are reserved.
+fn scram_name(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+// Random bytes unique to the exchange, drawn from the operating system's
+// secure random number generator.
+fn nonce() -> Result<Vec<u8>> {
+    let mut nonce = vec![0; NONCE_LENGTH];
+
+    getrandom::getrandom(&mut nonce)
+        .map_err(|err| failed(format!("a nonce couldn't be generated: {}", err)))?;
+
+    Ok(nonce)
+}
+
+// Splits an encoded AUTHENTICATE payload into the chunks it's sent in. An
+// empty payload, or one ending with a full chunk, is ended by a "+" chunk.
+fn chunks(payload: &str) -> Vec<&str> {
+    let mut chunks: Vec<&str> = payload
+        .as_bytes()
+        .chunks(CHUNK_LENGTH)
+        .map(|chunk| str::from_utf8(chunk).unwrap_or_default())
+        .collect();
+
+    if payload.len().is_multiple_of(CHUNK_LENGTH) {
+        chunks.push("+");
+    }
+
+    chunks
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for group in data.chunks(3) {
+        let bytes = [group[0], *group.get(1).unwrap_or(&0), *group.get(2).unwrap_or(&0)];
+        let bits = (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+
+        for i in 0..4 {
+            if i <= group.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+
+    for group in encoded.chunks(4) {
+        if group.len() == 1 {
+            return None;
+        }
+
+        let mut bits = 0u32;
+
+        for (i, &c) in group.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+
+        for i in 0..group.len() - 1 {
+            decoded.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+fn failed<R: Into<String>>(reason: R) -> Error {
+    ErrorKind::SaslFailed(reason.into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the rest of an exchange which sent `nonce` in its first message,
+    // returning what the client sent in reply to `server_first`.
+    fn exchange(
+        algorithm: Algorithm,
+        nonce: &str,
+        server_first: &str,
+        server_final: &str,
+    ) -> String {
+        let sasl = Sasl::new("user", "pencil");
+        let mut scram = Scram::ClientFirst {
+            bare: format!("n=user,r={}", nonce),
+            nonce: nonce.to_owned(),
+        };
+
+        let client_final = scram_respond(algorithm, &mut scram, &sasl, server_first).unwrap();
+
+        assert!(scram_respond(algorithm, &mut scram, &sasl, server_final)
+            .unwrap()
+            .is_empty());
+
+        String::from_utf8(client_final).unwrap()
+    }
+
+    // The example exchange of RFC 5802.
+    #[test]
+    fn scram_sha1_matches_rfc_5802() {
+        let client_final = exchange(
+            Algorithm::Sha1,
+            "fyko+d2lbbFgONRv9qkxdawL",
+            "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+            "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=",
+        );
+
+        assert_eq!(
+            client_final,
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts="
+        );
+    }
+
+    // The example exchange of RFC 7677.
+    #[test]
+    fn scram_sha256_matches_rfc_7677() {
+        let client_final = exchange(
+            Algorithm::Sha256,
+            "rOprNGfwEbeRWgbNEkqO",
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,\
+             i=4096",
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=",
+        );
+
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+    }
+
+    #[test]
+    fn scram_fails_when_the_server_cant_prove_the_password() {
+        let sasl = Sasl::new("user", "pencil");
+        let mut scram = Scram::ClientFirst {
+            bare: "n=user,r=fyko+d2lbbFgONRv9qkxdawL".to_owned(),
+            nonce: "fyko+d2lbbFgONRv9qkxdawL".to_owned(),
+        };
+        let server_first =
+            "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096";
+
+        scram_respond(Algorithm::Sha1, &mut scram, &sasl, server_first).unwrap();
+
+        assert!(scram_respond(Algorithm::Sha1, &mut scram, &sasl, "v=AAAA").is_err());
+    }
+
+    #[test]
+    fn scram_starts_with_a_fresh_nonce() {
+        let sasl = Sasl::new("user", "pencil");
+        let mut first = Scram::Start;
+        let mut second = Scram::Start;
+
+        let first_message = scram_respond(Algorithm::Sha256, &mut first, &sasl, "").unwrap();
+        let second_message = scram_respond(Algorithm::Sha256, &mut second, &sasl, "").unwrap();
+
+        let first_message = String::from_utf8(first_message).unwrap();
+
+        assert!(first_message.starts_with("n,,n=user,r="));
+        assert_eq!(first_message.len(), "n,,n=user,r=".len() + NONCE_LENGTH / 3 * 4);
+        assert_ne!(first_message.into_bytes(), second_message);
+    }
+}
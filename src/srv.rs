@@ -0,0 +1,612 @@
+//! The srv module locates the servers of an IRC network from the DNS SRV
+//! records it publishes, `_ircs._tcp.<domain>` for TLS and
+//! `_irc._tcp.<domain>` for plain text, as used by `Client::from_network`.
+//!
+//! Records are tried in the order RFC 2782 describes: lowest priority first,
+//! and in a random order weighted by their weights among records of the same
+//! priority. Networks which don't publish SRV records are connected to at
+//! the addresses the domain itself resolves to, on the usual port.
+//!
+//! Lookups are sent to the name servers listed in `/etc/resolv.conf`, and
+//! block the current thread until they complete, in the same way as
+//! `Client::resolve`.
+//!
+//! ```no_run
+//! # extern crate tokio_irc_client;
+//! use tokio_irc_client::srv;
+//!
+//! # fn main() {
+//! let service = srv::locate("example.org").unwrap();
+//!
+//! for server in &service.servers {
+//!     println!("{} at {}, TLS: {}", server.host, server.addr, service.tls);
+//! }
+//! # }
+//! ```
+
+use error::{Error, ErrorKind, Result};
+
+use getrandom;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// The port of plain text IRC, used when a network publishes no SRV records.
+pub const DEFAULT_PORT: u16 = 6667;
+
+/// The port of IRC over TLS, used when a network publishes no SRV records.
+pub const DEFAULT_TLS_PORT: u16 = 6697;
+
+// How long each name server is given to answer.
+const QUERY_TIMEOUT_IN_MILLISECONDS: u64 = 3000;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+// The most compression pointers followed while reading a name, which is
+// more than any valid name needs and stops maliciously looping ones.
+const MAX_POINTERS: usize = 64;
+
+/// An SRV record, naming a host and port providing a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Records with a lower priority are tried first.
+    pub priority: u16,
+    /// The relative chance of the record being tried before the others of
+    /// the same priority.
+    pub weight: u16,
+    /// The port the service is provided on.
+    pub port: u16,
+    /// The host providing the service.
+    pub target: String,
+}
+
+/// An address of a server of a network, as found by `locate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Server {
+    /// The host name the address was resolved from, which is the target of
+    /// its SRV record, or the domain of the network when it publishes none.
+    /// The certificate of a server over TLS names this host, rather than
+    /// the network.
+    pub host: String,
+    /// The address to connect to.
+    pub addr: SocketAddr,
+}
+
+/// The servers of a network, as found by `locate`.
+#[derive(Clone, Debug)]
+pub struct Service {
+    /// Whether the servers are for IRC over TLS, and so should be connected
+    /// to with `Client::connect_tls`.
+    pub tls: bool,
+    /// The addresses of the servers, in the order they should be tried.
+    pub servers: Vec<Server>,
+}
+
+/// Look up the SRV records of `name`, such as `_irc._tcp.example.org`,
+/// returning them in the order they should be tried.
+///
+/// No records are returned when the name doesn't exist, has no SRV records,
+/// or has the single record with the target `.` saying the service isn't
+/// provided.
+pub fn lookup(name: &str) -> Result<Vec<Record>> {
+    let query = build_query(name, random(name, 1)?[0] as u16)?;
+    let mut failure = None;
+
+    for server in name_servers() {
+        match ask(server, &query).and_then(|response| parse_response(&response, &query)) {
+            Ok(records) => {
+                let random = random(name, records.len())?;
+
+                return Ok(order(records, &random));
+            }
+            Err(err) => failure = Some(err),
+        }
+    }
+
+    Err(failure.unwrap_or_else(|| failed(name, "no name servers are configured")))
+}
+
+/// Locate the servers of the network at `domain` from its SRV records,
+/// preferring `_ircs._tcp` when TLS is supported.
+///
+/// When the domain has no records, or they can't be looked up, the
+/// addresses the domain itself resolves to are used, on `DEFAULT_TLS_PORT`
+/// when TLS is supported and `DEFAULT_PORT` otherwise.
+pub fn locate(domain: &str) -> Result<Service> {
+    let domain = domain.trim_end_matches('.');
+    let services = [
+        #[cfg(feature = "tls")]
+        ("_ircs._tcp", true),
+        ("_irc._tcp", false),
+    ];
+
+    for &(service, tls) in &services {
+        let records = match lookup(&format!("{}.{}", service, domain)) {
+            Ok(records) => records,
+            Err(_) => continue,
+        };
+
+        if records.is_empty() {
+            continue;
+        }
+
+        return Ok(Service {
+            tls: tls,
+            servers: resolve(&records)?,
+        });
+    }
+
+    let tls = cfg!(feature = "tls");
+    let port = if tls { DEFAULT_TLS_PORT } else { DEFAULT_PORT };
+
+    Ok(Service {
+        tls: tls,
+        servers: servers(domain, port)?,
+    })
+}
+
+// The servers at the targets of the records, in the order of the records.
+// Targets which can't be resolved are skipped, unless none can be.
+fn resolve(records: &[Record]) -> Result<Vec<Server>> {
+    let mut resolved = Vec::new();
+    let mut failure = None;
+
+    for record in records {
+        match servers(&record.target, record.port) {
+            Ok(servers) => resolved.extend(servers),
+            Err(err) => failure = Some(err),
+        }
+    }
+
+    match failure {
+        Some(err) if resolved.is_empty() => Err(err),
+        _ => Ok(resolved),
+    }
+}
+
+// The servers `host` resolves to.
+fn servers(host: &str, port: u16) -> Result<Vec<Server>> {
+    let addrs = (host, port).to_socket_addrs()?;
+
+    Ok(addrs
+        .map(|addr| Server {
+            host: host.to_owned(),
+            addr: addr,
+        })
+        .collect())
+}
+
+// The name servers of `/etc/resolv.conf`, or the local host when there are
+// none.
+fn name_servers() -> Vec<SocketAddr> {
+    let mut conf = String::new();
+
+    if let Ok(mut file) = File::open("/etc/resolv.conf") {
+        let _ = file.read_to_string(&mut conf);
+    }
+
+    let mut servers: Vec<SocketAddr> = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .map(|addr| SocketAddr::new(addr, 53))
+        .collect();
+
+    if servers.is_empty() {
+        servers.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53));
+    }
+
+    servers
+}
+
+// Sends the query to a name server over UDP, asking again over TCP when the
+// answer was too long for a datagram.
+fn ask(server: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let timeout = Some(Duration::from_millis(QUERY_TIMEOUT_IN_MILLISECONDS));
+    let local: SocketAddr = if server.is_ipv6() {
+        "[::]:0".parse().expect("valid address")
+    } else {
+        "0.0.0.0:0".parse().expect("valid address")
+    };
+
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(timeout)?;
+    socket.connect(server)?;
+    socket.send(query)?;
+
+    let mut response = vec![0; 4096];
+
+    // Datagrams which aren't the answer to this query are ignored.
+    let length = loop {
+        let length = socket.recv(&mut response)?;
+
+        if length >= 12 && response[..2] == query[..2] && response[2] & 0x80 != 0 {
+            break length;
+        }
+    };
+
+    response.truncate(length);
+
+    // The truncation flag.
+    if response[2] & 0x02 == 0 {
+        return Ok(response);
+    }
+
+    let mut stream = TcpStream::connect(server)?;
+    stream.set_read_timeout(timeout)?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+
+    let mut response = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut response)?;
+
+    Ok(response)
+}
+
+// A query for the SRV records of `name`, asking for recursion.
+fn build_query(name: &str, id: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(failed(name, "the name is invalid"));
+        }
+
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+// The SRV records answering the query, in the order the server gave them.
+fn parse_response(response: &[u8], query: &[u8]) -> Result<Vec<Record>> {
+    let (name, question_end) = read_name(query, 12).unwrap_or_default();
+
+    let truncated = || failed(&name, "the response is truncated");
+    let unrelated = || failed(&name, "the response doesn't answer the query");
+
+    if response.len() < 12 {
+        return Err(truncated());
+    }
+
+    if response[..2] != query[..2] {
+        return Err(unrelated());
+    }
+
+    match response[3] & 0x0f {
+        0 => (),
+        // The name doesn't exist.
+        3 => return Ok(Vec::new()),
+        code => return Err(failed(&name, &format!("the name server answered with code {}", code))),
+    }
+
+    let questions = read_u16(response, 4).ok_or_else(truncated)?;
+    let answers = read_u16(response, 6).ok_or_else(truncated)?;
+
+    // The question is echoed back, which has to be the one that was asked
+    // for the answers to be about the right name. Name servers may change
+    // the case of the name.
+    if questions != 1 {
+        return Err(unrelated());
+    }
+
+    let (question, mut offset) = read_name(response, 12).ok_or_else(truncated)?;
+    let kind_and_class = response.get(offset..offset + 4).ok_or_else(truncated)?;
+
+    if !question.eq_ignore_ascii_case(&name) || query.get(question_end..) != Some(kind_and_class) {
+        return Err(unrelated());
+    }
+
+    offset += 4;
+
+    let mut records = Vec::new();
+
+    for _ in 0..answers {
+        offset = read_name(response, offset).ok_or_else(truncated)?.1;
+
+        let kind = read_u16(response, offset).ok_or_else(truncated)?;
+        let class = read_u16(response, offset + 2).ok_or_else(truncated)?;
+        let length = read_u16(response, offset + 8).ok_or_else(truncated)? as usize;
+        let data = offset + 10;
+
+        offset = data + length;
+
+        if offset > response.len() {
+            return Err(truncated());
+        }
+
+        // Answers can include the CNAME records leading to the SRV ones.
+        if kind != TYPE_SRV || class != CLASS_IN {
+            continue;
+        }
+
+        let record = Record {
+            priority: read_u16(response, data).ok_or_else(truncated)?,
+            weight: read_u16(response, data + 2).ok_or_else(truncated)?,
+            port: read_u16(response, data + 4).ok_or_else(truncated)?,
+            target: read_name(response, data + 6).ok_or_else(truncated)?.0,
+        };
+
+        records.push(record);
+    }
+
+    // A single record with the root as its target says the service is
+    // decidedly not available.
+    if records.len() == 1 && records[0].target.is_empty() {
+        records.clear();
+    }
+
+    Ok(records)
+}
+
+// Orders the records by priority, and within each priority by the weighted
+// random selection of RFC 2782, using a value of `random` for each record.
+fn order(mut records: Vec<Record>, random: &[u64]) -> Vec<Record> {
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+
+    let mut ordered = Vec::with_capacity(records.len());
+
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let end = records.iter().position(|record| record.priority != priority);
+        let mut group: Vec<Record> = records.drain(..end.unwrap_or(records.len())).collect();
+
+        while !group.is_empty() {
+            let total: u64 = group.iter().map(|record| u64::from(record.weight)).sum();
+            let chosen = random[ordered.len()] % (total + 1);
+
+            let mut running = 0;
+            let index = group
+                .iter()
+                .position(|record| {
+                    running += u64::from(record.weight);
+                    running >= chosen
+                })
+                .unwrap_or(0);
+
+            ordered.push(group.remove(index));
+        }
+    }
+
+    ordered
+}
+
+// Reads the name starting at `offset`, following compression pointers. Gives
+// the name without its trailing dot, and the offset just past it.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let length = *message.get(offset)? as usize;
+
+        if length == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+
+        if length & 0xc0 == 0xc0 {
+            pointers += 1;
+
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+
+            end = end.or(Some(offset + 2));
+            offset = (read_u16(message, offset)? & 0x3fff) as usize;
+            continue;
+        }
+
+        let label = message.get(offset + 1..offset + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + length;
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    let bytes = message.get(offset..offset + 2)?;
+
+    Some(u16::from(bytes[0]) << 8 | u16::from(bytes[1]))
+}
+
+fn failed(name: &str, reason: &str) -> Error {
+    ErrorKind::Dns(name.to_owned(), reason.to_owned()).into()
+}
+
+// Random numbers, for query ids and choosing between records. Query ids
+// that could be guessed would let answers be forged, so nothing weaker than
+// the operating system's random number generator is used.
+fn random(name: &str, count: usize) -> Result<Vec<u64>> {
+    let mut bytes = vec![0; count * 8];
+
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| failed(name, &format!("random numbers couldn't be generated: {}", err)))?;
+
+    Ok(bytes
+        .chunks(8)
+        .map(|chunk| chunk.iter().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A response to `query` with an SRV record for each of `records`, given
+    // as priority, weight, port and target.
+    fn response(query: &[u8], records: &[(u16, u16, u16, &str)]) -> Vec<u8> {
+        let mut response = query.to_vec();
+
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = records.len() as u8;
+
+        for &(priority, weight, port, target) in records {
+            // The name of the target, without the header and question type
+            // of a query for it, or the empty name of the root.
+            let target = match target {
+                "" => vec![0],
+                target => {
+                    let query = build_query(target, 0).unwrap();
+                    query[12..query.len() - 4].to_vec()
+                }
+            };
+
+            // A pointer back to the name in the question.
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&[0, 0, 0x0e, 0x10]);
+            response.extend_from_slice(&(target.len() as u16 + 6).to_be_bytes());
+            response.extend_from_slice(&priority.to_be_bytes());
+            response.extend_from_slice(&weight.to_be_bytes());
+            response.extend_from_slice(&port.to_be_bytes());
+            response.extend_from_slice(&target);
+        }
+
+        response
+    }
+
+    fn record(priority: u16, weight: u16, target: &str) -> Record {
+        Record {
+            priority: priority,
+            weight: weight,
+            port: 6697,
+            target: target.to_owned(),
+        }
+    }
+
+    #[test]
+    fn records_are_read_from_the_answers() {
+        let query = build_query("_ircs._tcp.example.org", 0x1234).unwrap();
+        let response = response(
+            &query,
+            &[(10, 5, 6697, "irc1.example.org"), (20, 0, 7000, "irc2.example.org")],
+        );
+
+        assert_eq!(
+            parse_response(&response, &query).unwrap(),
+            vec![
+                Record {
+                    priority: 10,
+                    weight: 5,
+                    port: 6697,
+                    target: "irc1.example.org".to_owned(),
+                },
+                Record {
+                    priority: 20,
+                    weight: 0,
+                    port: 7000,
+                    target: "irc2.example.org".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_single_record_for_the_root_means_no_service() {
+        let query = build_query("_irc._tcp.example.org", 1).unwrap();
+        let response = response(&query, &[(0, 0, 0, "")]);
+
+        assert!(parse_response(&response, &query).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_responses_are_refused() {
+        let query = build_query("_irc._tcp.example.org", 1).unwrap();
+        let response = response(&query, &[(0, 0, 6667, "irc.example.org")]);
+
+        for length in &[0, 11, query.len() - 1, response.len() - 1] {
+            assert!(parse_response(&response[..*length], &query).is_err());
+        }
+    }
+
+    #[test]
+    fn responses_to_other_queries_are_refused() {
+        let query = build_query("_irc._tcp.example.org", 1).unwrap();
+        let records = [(0, 0, 6667, "irc.example.org")];
+
+        let other_id = build_query("_irc._tcp.example.org", 2).unwrap();
+        assert!(parse_response(&response(&other_id, &records), &query).is_err());
+
+        let other_name = build_query("_irc._tcp.example.com", 1).unwrap();
+        assert!(parse_response(&response(&other_name, &records), &query).is_err());
+
+        let mut other_type = response(&query, &records);
+        other_type[query.len() - 3] = 1;
+        assert!(parse_response(&other_type, &query).is_err());
+
+        let changed_case = build_query("_IRC._tcp.Example.org", 1).unwrap();
+        assert_eq!(parse_response(&response(&changed_case, &records), &query).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn names_follow_compression_pointers() {
+        let message = [3, b'i', b'r', b'c', 0xc0, 6, 3, b'o', b'r', b'g', 0];
+
+        assert_eq!(read_name(&message, 0), Some(("irc.org".to_owned(), 6)));
+        assert_eq!(read_name(&message, 6), Some(("org".to_owned(), 11)));
+    }
+
+    #[test]
+    fn malformed_names_are_refused() {
+        // A label running past the end of the message.
+        assert_eq!(read_name(&[5, b'i', b'r', b'c'], 0), None);
+        // A name without its terminating empty label.
+        assert_eq!(read_name(&[3, b'i', b'r', b'c'], 0), None);
+        // A pointer past the end of the message.
+        assert_eq!(read_name(&[0xc0, 10], 0), None);
+        // A pointer cut short.
+        assert_eq!(read_name(&[0xc0], 0), None);
+    }
+
+    #[test]
+    fn cyclic_pointers_are_refused() {
+        assert_eq!(read_name(&[0xc0, 0], 0), None);
+        assert_eq!(read_name(&[1, b'a', 0xc0, 4, 0xc0, 0], 0), None);
+    }
+
+    #[test]
+    fn records_are_ordered_by_priority_then_weight() {
+        let records = vec![
+            record(20, 0, "last"),
+            record(10, 1, "light"),
+            record(10, 0, "unweighted"),
+            record(10, 9, "heavy"),
+        ];
+
+        // The unweighted record sorts first. A draw of 0 picks it, a draw
+        // past the weight of "light" picks "heavy", after which "light" is
+        // the only choice.
+        let ordered = order(records.clone(), &[0, 5, 0, 0]);
+        let targets: Vec<&str> = ordered.iter().map(|record| &record.target[..]).collect();
+        assert_eq!(targets, vec!["unweighted", "heavy", "light", "last"]);
+
+        // A draw of 1 skips the unweighted record and lands on "light".
+        let ordered = order(records, &[1, 0, 0, 0]);
+        let targets: Vec<&str> = ordered.iter().map(|record| &record.target[..]).collect();
+        assert_eq!(targets, vec!["light", "unweighted", "heavy", "last"]);
+    }
+
+    #[test]
+    fn random_numbers_are_generated() {
+        let values = random("example.org", 4).unwrap();
+
+        assert_eq!(values.len(), 4);
+        assert!(values.iter().any(|&value| value != values[0]));
+    }
+}
@@ -0,0 +1,975 @@
+//! The state module contains the `State` type, which tracks information about
+//! the connection as it is observed by an `IrcTransport`.
+//!
+//! A `State` is a cheap handle that can be cloned and held on to after the
+//! transport has been `split`, allowing the currently tracked information to
+//! be queried at any time.
+//!
+//! Besides information about the client itself, the state tracks the
+//! channels the client is in, their members, and what is known about the
+//! users sharing those channels with the client.
+
+use casemap::{CaseMapping, IrcStr};
+use chathistory;
+use mode::{self, ChannelModes, ModeKind};
+use znc;
+
+use pircolate::Message;
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// The maximum length of an IRC line, including the trailing CR-LF.
+const MAX_LINE_LENGTH: usize = 512;
+
+// The channel types assumed when the server doesn't advertise CHANTYPES.
+const DEFAULT_CHANTYPES: &str = "#&";
+
+/// A handle to the state tracked for a connection.
+#[derive(Clone, Default)]
+pub struct State {
+    inner: Arc<RwLock<StateData>>,
+}
+
+/// What is known about a user sharing a channel with the client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    /// The nickname of the user.
+    pub nick: String,
+    /// The username of the user, if it is known.
+    pub user: Option<String>,
+    /// The host of the user, if it is known.
+    pub host: Option<String>,
+    /// The real name of the user, if it is known.
+    pub real_name: Option<String>,
+    /// The away message of the user if they are marked as away. Users known
+    /// to be away without a known message have an empty away message.
+    pub away: Option<String>,
+}
+
+/// A channel the client is in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    /// The name of the channel.
+    pub name: String,
+    /// The nicknames of the members of the channel, each paired with the
+    /// membership prefixes (such as `@` for operators) they hold.
+    pub members: Vec<(String, String)>,
+    /// The modes set on the channel, if they are known, not including list
+    /// modes such as bans.
+    pub modes: Option<Vec<char>>,
+}
+
+/// A batch of related messages, opened with BATCH by the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Batch {
+    /// The type of the batch, such as `netsplit` or `znc.in/playback`.
+    pub kind: String,
+    /// The parameters given with the type of the batch.
+    pub params: Vec<String>,
+}
+
+#[derive(Default)]
+struct StateData {
+    registered: bool,
+    nick: Option<String>,
+    user: Option<String>,
+    host: Option<String>,
+    real_name: Option<String>,
+    modes: BTreeSet<char>,
+    // The client's away message while it's marked as away.
+    away: Option<String>,
+    // The away message most recently sent with AWAY, which the server
+    // doesn't repeat when confirming it.
+    requested_away: Option<String>,
+    isupport: HashMap<String, String>,
+    // Capabilities offered by the server, along with their values.
+    available_caps: HashMap<String, String>,
+    enabled_caps: BTreeSet<String>,
+    channels: HashMap<String, ChannelData>,
+    // The modes of channels, keyed by folded name, learned from MODE changes
+    // and RPL_CHANNELMODEIS. This includes channels the client isn't in but
+    // has queried the modes of.
+    channel_modes: HashMap<String, BTreeSet<char>>,
+    // The keys of channels, keyed by folded name, learned from joining them
+    // with a key and from MODE changes setting one.
+    channel_keys: HashMap<String, String>,
+    users: HashMap<String, User>,
+    casemapping: CaseMapping,
+    // Open batches, keyed by reference tag.
+    batches: HashMap<String, Batch>,
+    // The batch closed by the most recently handled message, which is kept
+    // until the next message is handled.
+    closed_batch: Option<String>,
+}
+
+struct ChannelData {
+    name: String,
+    // Keyed by folded nickname, holding the member's nickname and prefixes.
+    members: HashMap<String, (String, String)>,
+}
+
+impl ChannelData {
+    fn new(name: &str) -> ChannelData {
+        ChannelData {
+            name: name.to_owned(),
+            members: HashMap::new(),
+        }
+    }
+}
+
+impl State {
+    /// The nickname currently used by the client, if it is known.
+    ///
+    /// Before registration completes this is the most recent nickname sent
+    /// to the server, afterwards it is the nickname confirmed by the server,
+    /// following any changes made to it with NICK, including forced changes.
+    pub fn nick(&self) -> Option<String> {
+        self.read().nick.clone()
+    }
+
+    /// The username portion of the client's hostmask, if it is known.
+    pub fn user(&self) -> Option<String> {
+        self.read().user.clone()
+    }
+
+    /// The host portion of the client's hostmask, if it is known.
+    pub fn host(&self) -> Option<String> {
+        self.read().host.clone()
+    }
+
+    /// The real name of the client, if it is known.
+    pub fn real_name(&self) -> Option<String> {
+        self.read().real_name.clone()
+    }
+
+    /// The full `nick!user@host` hostmask of the client, as seen by other
+    /// users, if all of its parts are known.
+    pub fn hostmask(&self) -> Option<String> {
+        let data = self.read();
+
+        match (data.nick.as_ref(), data.user.as_ref(), data.host.as_ref()) {
+            (Some(nick), Some(user), Some(host)) => Some(format!("{}!{}@{}", nick, user, host)),
+            _ => None,
+        }
+    }
+
+    /// The user modes currently set on the client.
+    pub fn modes(&self) -> Vec<char> {
+        self.read().modes.iter().cloned().collect()
+    }
+
+    /// Returns true if the given user mode is set on the client.
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.read().modes.contains(&mode)
+    }
+
+    /// The away message of the client while the server has it marked as
+    /// away, as confirmed by RPL_NOWAWAY and RPL_UNAWAY.
+    pub fn away(&self) -> Option<String> {
+        self.read().away.clone()
+    }
+
+    /// Returns true once the server has welcomed the client.
+    pub fn is_registered(&self) -> bool {
+        self.read().registered
+    }
+
+    /// The value of the given RPL_ISUPPORT (005) parameter advertised by the
+    /// server. Parameters advertised without a value are returned as an empty
+    /// string.
+    pub fn isupport(&self, key: &str) -> Option<String> {
+        self.read().isupport.get(key).cloned()
+    }
+
+    /// The maximum nickname length advertised by the server, if any.
+    pub fn nick_len(&self) -> Option<usize> {
+        self.isupport("NICKLEN").and_then(|value| value.parse().ok())
+    }
+
+    /// Returns true if the given IRCv3 capability is enabled.
+    pub fn has_capability(&self, cap: &str) -> bool {
+        self.read().enabled_caps.contains(cap)
+    }
+
+    /// The IRCv3 capabilities currently enabled.
+    pub fn capabilities(&self) -> Vec<String> {
+        self.read().enabled_caps.iter().cloned().collect()
+    }
+
+    /// The value the server advertised for the given IRCv3 capability, or
+    /// `None` if the server doesn't offer it. Capabilities advertised without
+    /// a value are returned as an empty string.
+    pub fn available_capability(&self, cap: &str) -> Option<String> {
+        self.read().available_caps.get(cap).cloned()
+    }
+
+    /// Returns true if the given nickname refers to the client itself.
+    pub fn is_self(&self, nick: &str) -> bool {
+        let data = self.read();
+
+        match data.nick {
+            Some(ref current) => data.casemapping.equal(current, nick),
+            None => false,
+        }
+    }
+
+    /// The names of the channels the client is in.
+    pub fn channels(&self) -> Vec<String> {
+        self.read()
+            .channels
+            .values()
+            .map(|channel| channel.name.clone())
+            .collect()
+    }
+
+    /// The channel with the given name, if the client is in it.
+    pub fn channel(&self, name: &str) -> Option<Channel> {
+        let data = self.read();
+
+        let name = data.casemapping.fold(name);
+
+        data.channels.get(&name).map(|channel| {
+            Channel {
+                name: channel.name.clone(),
+                members: channel.members.values().cloned().collect(),
+                modes: data.channel_modes
+                    .get(&name)
+                    .map(|modes| modes.iter().cloned().collect()),
+            }
+        })
+    }
+
+    /// The channel modes supported by the server, as advertised by the
+    /// `CHANMODES`, `PREFIX` and `MODES` ISUPPORT tokens, which are needed
+    /// to parse and build MODE messages.
+    pub fn channel_mode_types(&self) -> ChannelModes {
+        channel_mode_types(&self.read().isupport)
+    }
+
+    /// The modes set on the given channel, if they are known, not including
+    /// list modes such as bans. The modes of a channel are learned from MODE
+    /// changes and from the reply to querying them with MODE.
+    pub fn channel_modes(&self, name: &str) -> Option<Vec<char>> {
+        let data = self.read();
+
+        data.channel_modes
+            .get(&data.casemapping.fold(name))
+            .map(|modes| modes.iter().cloned().collect())
+    }
+
+    /// The key of the given channel, if it has one and it's known. Keys are
+    /// learned from joining a channel with one and from the channel's modes,
+    /// unless the server hides them.
+    pub fn channel_key(&self, name: &str) -> Option<String> {
+        let data = self.read();
+
+        data.channel_keys.get(&data.casemapping.fold(name)).cloned()
+    }
+
+    /// What is known about the user with the given nickname, if they share
+    /// a channel with the client.
+    pub fn user_info(&self, nick: &str) -> Option<User> {
+        let data = self.read();
+
+        data.users.get(&data.casemapping.fold(nick)).cloned()
+    }
+
+    /// Returns true if the user with the given nickname is known to be away.
+    /// Away status is learned from `away-notify`, WHO replies and RPL_AWAY,
+    /// and is forgotten if `away-notify` is disabled, as it can no longer be
+    /// kept up to date.
+    pub fn is_away(&self, nick: &str) -> bool {
+        let data = self.read();
+
+        match data.users.get(&data.casemapping.fold(nick)) {
+            Some(user) => user.away.is_some(),
+            None => false,
+        }
+    }
+
+    /// Returns true if the given target is a channel name, according to the
+    /// channel types advertised by the server.
+    pub fn is_channel(&self, target: &str) -> bool {
+        let data = self.read();
+        let chantypes = data.isupport
+            .get("CHANTYPES")
+            .map(|chantypes| chantypes.as_str())
+            .unwrap_or(DEFAULT_CHANTYPES);
+
+        match target.chars().next() {
+            Some(first) => chantypes.contains(first),
+            None => false,
+        }
+    }
+
+    /// The number of bytes of text that can be sent to `target` with `command`
+    /// (such as PRIVMSG or NOTICE) in a single message, taking into account
+    /// the prefix the server adds when relaying it to other clients.
+    ///
+    /// When the hostmask isn't fully known yet, the largest possible hostmask
+    /// is assumed so the result is never an overestimate.
+    pub fn message_length_limit(&self, command: &str, target: &str) -> usize {
+        // The longest prefix servers commonly allow: a 30 character nick,
+        // a 10 character username and a 63 character host.
+        const WORST_CASE_HOSTMASK: usize = 30 + 1 + 10 + 1 + 63;
+
+        let hostmask = self.hostmask()
+            .map(|hostmask| hostmask.len())
+            .unwrap_or(WORST_CASE_HOSTMASK);
+
+        // ":<hostmask> <command> <target> :<text>\r\n"
+        let overhead = 1 + hostmask + 1 + command.len() + 1 + target.len() + 2 + 2;
+
+        MAX_LINE_LENGTH.saturating_sub(overhead)
+    }
+
+    // The mode of `channel` known to prevent the client from sending to it,
+    // if any: +n when the client isn't in the channel, +m when it doesn't
+    // have voice or a higher prefix, or +R when it isn't identified.
+    pub(crate) fn send_restriction(&self, channel: &str) -> Option<char> {
+        let data = self.read();
+        let casemapping = data.casemapping;
+        let modes = data.channel_modes.get(&casemapping.fold(channel))?;
+
+        let prefixes = match (data.channels.get(&casemapping.fold(channel)), data.nick.as_ref()) {
+            (Some(joined), Some(nick)) => joined
+                .members
+                .get(&casemapping.fold(nick))
+                .map(|member| &member.1),
+            _ => None,
+        };
+
+        let privileged = match prefixes {
+            Some(prefixes) => !prefixes.is_empty(),
+            None => false,
+        };
+
+        if prefixes.is_none() && modes.contains(&'n') {
+            Some('n')
+        } else if !privileged && modes.contains(&'m') {
+            Some('m')
+        } else if !privileged && modes.contains(&'R') && !data.modes.contains(&'r') {
+            Some('R')
+        } else {
+            None
+        }
+    }
+
+    /// The batch with the given reference tag, if it is open. A batch is
+    /// still available while the BATCH message closing it is handled.
+    pub fn batch(&self, reference: &str) -> Option<Batch> {
+        self.read().batches.get(reference).cloned()
+    }
+
+    /// The batch the given message belongs to, if any, as named by its
+    /// `batch` tag.
+    pub fn batch_of(&self, message: &Message) -> Option<Batch> {
+        message
+            .raw_tags()
+            .find(|&(key, _)| key == "batch")
+            .and_then(|(_, reference)| reference)
+            .and_then(|reference| self.batch(reference))
+    }
+
+    /// Returns true if the given message is being played back from the
+    /// buffer of a ZNC bouncer, rather than being live traffic. See the
+    /// `znc` module.
+    pub fn is_playback(&self, message: &Message) -> bool {
+        match self.batch_of(message) {
+            Some(batch) => batch.kind == znc::PLAYBACK_BATCH,
+            None => false,
+        }
+    }
+
+    /// Returns true if the given message was sent in response to a history
+    /// request, rather than being live traffic. See the `chathistory`
+    /// module.
+    pub fn is_history(&self, message: &Message) -> bool {
+        match self.batch_of(message) {
+            Some(batch) => batch.kind == chathistory::BATCH,
+            None => false,
+        }
+    }
+
+    /// The case mapping used by the server to compare nicknames and channel
+    /// names, as advertised by the `CASEMAPPING` ISUPPORT token.
+    pub fn casemapping(&self) -> CaseMapping {
+        self.read().casemapping
+    }
+
+    /// Returns true if the two nicknames or channel names refer to the same
+    /// user or channel, according to the server's case mapping.
+    pub fn same_name(&self, a: &str, b: &str) -> bool {
+        self.read().casemapping.equal(a, b)
+    }
+
+    /// Wrap the given nickname or channel name in an `IrcStr`, which is
+    /// compared according to the server's case mapping.
+    pub fn irc_str<S: Into<String>>(&self, name: S) -> IrcStr {
+        IrcStr::new(name, self.casemapping())
+    }
+
+    // Folds a nickname or channel name, so that names referring to the same
+    // user or channel are equal.
+    pub(crate) fn fold_name(&self, name: &str) -> String {
+        self.read().casemapping.fold(name)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, StateData> {
+        self.inner.read().expect("State lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, StateData> {
+        self.inner.write().expect("State lock poisoned")
+    }
+
+    // Updates the tracked state from a message received from the server.
+    pub(crate) fn handle_incoming(&self, message: &Message) {
+        let mut data = self.write();
+        let data = &mut *data;
+        let casemapping = data.casemapping;
+        let mut args = message.raw_args();
+
+        if let Some(reference) = data.closed_batch.take() {
+            data.batches.remove(&reference);
+        }
+
+        let source = message.prefix();
+        let from_self = match (source, data.nick.as_ref()) {
+            (Some((nick, _, _)), Some(current)) => casemapping.equal(current, nick),
+            _ => false,
+        };
+
+        // Any message originating from a user carries their full hostmask,
+        // which is the most accurate source of it available.
+        if let Some((nick, Some(user), Some(host))) = source {
+            if from_self {
+                data.user = Some(user.to_owned());
+                data.host = Some(host.to_owned());
+            }
+
+            if let Some(known) = data.users.get_mut(&casemapping.fold(nick)) {
+                known.user = Some(user.to_owned());
+                known.host = Some(host.to_owned());
+            }
+        }
+
+        let source_nick = source.map(|(nick, _, _)| nick).unwrap_or_default();
+
+        match message.raw_command() {
+            // RPL_WELCOME: "<nick> :Welcome to the network, <nick>!<user>@<host>"
+            "001" => {
+                data.registered = true;
+
+                if let Some(nick) = args.next() {
+                    data.nick = Some(nick.to_owned());
+                }
+
+                let mask = args.next_back()
+                    .and_then(|text| text.split_whitespace().next_back());
+
+                if let Some((_, user, host)) = mask.and_then(parse_hostmask) {
+                    data.user = Some(user.to_owned());
+                    data.host = Some(host.to_owned());
+                }
+            }
+
+            // RPL_ISUPPORT: "<nick> <token>... :are supported by this server"
+            "005" => {
+                let tokens: Vec<&str> = args.skip(1).collect();
+                let tokens = &tokens[..tokens.len().saturating_sub(1)];
+
+                for token in tokens {
+                    if let Some(key) = token.strip_prefix('-') {
+                        data.isupport.remove(key);
+                        continue;
+                    }
+
+                    let mut parts = token.splitn(2, '=');
+                    let key = parts.next().unwrap_or_default();
+                    let value = parts.next().unwrap_or_default();
+
+                    data.isupport.insert(key.to_owned(), value.to_owned());
+                }
+
+                // ISUPPORT is sent right after RPL_WELCOME, before any
+                // channels are joined, so nothing has been folded with the
+                // mapping assumed until now.
+                let advertised = data.isupport.get("CASEMAPPING").map(|value| value.as_str());
+                data.casemapping = CaseMapping::from_isupport(advertised);
+            }
+
+            // BATCH: "+<reference> <type> <params>..." or "-<reference>"
+            "BATCH" => if let Some(reference) = args.next() {
+                if let Some(reference) = reference.strip_prefix('+') {
+                    let batch = Batch {
+                        kind: args.next().unwrap_or_default().to_owned(),
+                        params: args.map(str::to_owned).collect(),
+                    };
+
+                    data.batches.insert(reference.to_owned(), batch);
+                } else if let Some(reference) = reference.strip_prefix('-') {
+                    data.closed_batch = Some(reference.to_owned());
+                }
+            },
+
+            // RPL_UMODEIS: "<nick> <modes>"
+            "221" => if let Some(modes) = args.nth(1) {
+                data.modes.clear();
+                apply_modes(&mut data.modes, modes);
+            },
+
+            // RPL_CHANNELMODEIS: "<nick> <channel> <modes> <mode params>..."
+            "324" => if let Some(channel) = args.nth(1) {
+                let args: Vec<&str> = args.collect();
+
+                data.channel_modes.entry(casemapping.fold(channel)).or_default().clear();
+                data.apply_channel_modes(channel, &args);
+            },
+
+            // RPL_AWAY: "<nick> <target> :<away message>"
+            "301" => if let (Some(nick), Some(away)) = (args.nth(1), args.next()) {
+                if let Some(known) = data.users.get_mut(&casemapping.fold(nick)) {
+                    known.away = Some(away.to_owned());
+                }
+            },
+
+            // RPL_UNAWAY: "<nick> :<text>"
+            "305" => {
+                data.away = None;
+
+                if let Some(nick) = data.nick.clone() {
+                    if let Some(known) = data.users.get_mut(&casemapping.fold(&nick)) {
+                        known.away = None;
+                    }
+                }
+            }
+
+            // RPL_NOWAWAY: "<nick> :<text>"
+            "306" => {
+                let away = data.requested_away.clone().unwrap_or_default();
+
+                if let Some(nick) = data.nick.clone() {
+                    if let Some(known) = data.users.get_mut(&casemapping.fold(&nick)) {
+                        known.away = Some(away.clone());
+                    }
+                }
+
+                data.away = Some(away);
+            }
+
+            // RPL_WHOREPLY: "<nick> <channel> <user> <host> <server> <nick> <flags> :<hops> <real name>"
+            "352" => {
+                let reply: Vec<&str> = args.collect();
+
+                if reply.len() >= 8 {
+                    if let Some(known) = data.users.get_mut(&casemapping.fold(reply[5])) {
+                        known.user = Some(reply[2].to_owned());
+                        known.host = Some(reply[3].to_owned());
+
+                        if let Some(real_name) = reply[7].split_once(' ').map(|(_, real_name)| real_name) {
+                            known.real_name = Some(real_name.to_owned());
+                        }
+
+                        // "G" marks a user as gone and "H" as here.
+                        if reply[6].starts_with('G') {
+                            known.away = known.away.take().or_else(|| Some(String::new()));
+                        } else if reply[6].starts_with('H') {
+                            known.away = None;
+                        }
+                    }
+                }
+            }
+
+            // RPL_NAMREPLY: "<nick> <type> <channel> :<prefix><nick>..."
+            "353" => {
+                let names = args.next_back().unwrap_or_default();
+                let channel = args.next_back().unwrap_or_default();
+                let prefixes = prefix_symbols(&data.isupport);
+
+                if let Some(channel) = data.channels.get_mut(&casemapping.fold(channel)) {
+                    for name in names.split_whitespace() {
+                        let split = name.find(|c| !prefixes.contains(c)).unwrap_or(name.len());
+                        let (modes, mask) = name.split_at(split);
+
+                        // With userhost-in-names, each name is a full hostmask.
+                        let (nick, user, host) = match parse_hostmask(mask) {
+                            Some((nick, user, host)) => (nick, Some(user), Some(host)),
+                            None => (mask, None, None),
+                        };
+
+                        channel
+                            .members
+                            .insert(casemapping.fold(nick), (nick.to_owned(), modes.to_owned()));
+
+                        let known = data.users
+                            .entry(casemapping.fold(nick))
+                            .or_insert_with(|| new_user(nick));
+
+                        if let (Some(user), Some(host)) = (user, host) {
+                            known.user = Some(user.to_owned());
+                            known.host = Some(host.to_owned());
+                        }
+                    }
+                }
+            }
+
+            // RPL_HOSTHIDDEN: "<nick> <host> :is now your displayed host"
+            "396" => if let Some(host) = args.nth(1) {
+                match host.find('@') {
+                    Some(index) => {
+                        data.user = Some(host[..index].to_owned());
+                        data.host = Some(host[index + 1..].to_owned());
+                    }
+                    None => data.host = Some(host.to_owned()),
+                }
+            },
+
+            "CAP" => {
+                let subcommand = args.nth(1).unwrap_or_default();
+                let caps = args.next_back().unwrap_or_default().split_whitespace();
+
+                match subcommand {
+                    "LS" | "NEW" => for cap in caps {
+                        let mut parts = cap.splitn(2, '=');
+                        let name = parts.next().unwrap_or_default();
+                        let value = parts.next().unwrap_or_default();
+
+                        data.available_caps.insert(name.to_owned(), value.to_owned());
+                    },
+
+                    "ACK" => for cap in caps {
+                        match cap.strip_prefix('-') {
+                            Some(cap) => disable_capability(data, cap),
+                            None => {
+                                data.enabled_caps.insert(cap.to_owned());
+                            }
+                        }
+                    },
+
+                    // With cap-notify, the server withdrew the capabilities.
+                    "DEL" => for cap in caps {
+                        data.available_caps.remove(cap);
+                        disable_capability(data, cap);
+                    },
+
+                    _ => (),
+                }
+            }
+
+            "CHGHOST" => if let (Some(user), Some(host)) = (args.next(), args.next()) {
+                if from_self {
+                    data.user = Some(user.to_owned());
+                    data.host = Some(host.to_owned());
+                }
+
+                if let Some(known) = data.users.get_mut(&casemapping.fold(source_nick)) {
+                    known.user = Some(user.to_owned());
+                    known.host = Some(host.to_owned());
+                }
+            },
+
+            // With away-notify, "AWAY :<message>" or "AWAY" when returning.
+            "AWAY" => if let Some(known) = data.users.get_mut(&casemapping.fold(source_nick)) {
+                known.away = args.next().map(str::to_owned);
+            },
+
+            "SETNAME" => if let Some(real_name) = args.next() {
+                if from_self {
+                    data.real_name = Some(real_name.to_owned());
+                }
+
+                if let Some(known) = data.users.get_mut(&casemapping.fold(source_nick)) {
+                    known.real_name = Some(real_name.to_owned());
+                }
+            },
+
+            // With extended-join: "JOIN <channel> <account> :<real name>"
+            "JOIN" => if let Some(channel) = args.next() {
+                let real_name = args.nth(1);
+
+                if from_self {
+                    data.channels
+                        .insert(casemapping.fold(channel), ChannelData::new(channel));
+
+                    if real_name.is_some() {
+                        data.real_name = real_name.map(str::to_owned);
+                    }
+                }
+
+                if let Some(joined) = data.channels.get_mut(&casemapping.fold(channel)) {
+                    joined
+                        .members
+                        .insert(casemapping.fold(source_nick), (source_nick.to_owned(), String::new()));
+
+                    let known = data.users
+                        .entry(casemapping.fold(source_nick))
+                        .or_insert_with(|| new_user(source_nick));
+
+                    if let Some((_, Some(user), Some(host))) = source {
+                        known.user = Some(user.to_owned());
+                        known.host = Some(host.to_owned());
+                    }
+
+                    if real_name.is_some() {
+                        known.real_name = real_name.map(str::to_owned);
+                    }
+                }
+            },
+
+            "PART" => if let Some(channel) = args.next() {
+                data.leave(channel, source_nick, from_self);
+            },
+
+            "KICK" => if let (Some(channel), Some(nick)) = (args.next(), args.next()) {
+                let kicked_self = data.nick
+                    .as_ref()
+                    .map(|current| casemapping.equal(current, nick))
+                    .unwrap_or(false);
+
+                data.leave(channel, nick, kicked_self);
+            },
+
+            "QUIT" => {
+                for channel in data.channels.values_mut() {
+                    channel.members.remove(&casemapping.fold(source_nick));
+                }
+
+                data.users.remove(&casemapping.fold(source_nick));
+            }
+
+            "NICK" => if let Some(nick) = args.next() {
+                if from_self {
+                    data.nick = Some(nick.to_owned());
+                }
+
+                for channel in data.channels.values_mut() {
+                    if let Some((_, modes)) = channel.members.remove(&casemapping.fold(source_nick)) {
+                        channel
+                            .members
+                            .insert(casemapping.fold(nick), (nick.to_owned(), modes));
+                    }
+                }
+
+                if let Some(mut known) = data.users.remove(&casemapping.fold(source_nick)) {
+                    known.nick = nick.to_owned();
+                    data.users.insert(casemapping.fold(nick), known);
+                }
+            },
+
+            "MODE" => {
+                let target = args.next();
+                let is_self = match (target, data.nick.as_ref()) {
+                    (Some(target), Some(current)) => casemapping.equal(current, target),
+                    _ => false,
+                };
+
+                if is_self {
+                    for modes in args {
+                        apply_modes(&mut data.modes, modes);
+                    }
+                } else if let Some(channel) = target {
+                    let args: Vec<&str> = args.collect();
+                    data.apply_channel_modes(channel, &args);
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    // Updates the tracked state from a message sent to the server.
+    pub(crate) fn handle_outgoing(&self, message: &Message) {
+        let mut data = self.write();
+
+        // Until the server confirms a nickname with RPL_WELCOME, the last
+        // nickname that was requested is the best guess available.
+        if !data.registered && message.raw_command() == "NICK" {
+            if let Some(nick) = message.raw_args().next() {
+                data.nick = Some(nick.to_owned());
+            }
+        }
+
+        if message.raw_command() == "AWAY" {
+            data.requested_away = message.raw_args().next().map(str::to_owned);
+        }
+
+        // "JOIN <channel>{,<channel>} [<key>{,<key>}]"
+        if message.raw_command() == "JOIN" {
+            let mut args = message.raw_args();
+
+            if let (Some(channels), Some(keys)) = (args.next(), args.next()) {
+                for (channel, key) in channels.split(',').zip(keys.split(',')) {
+                    if !key.is_empty() {
+                        let channel = data.casemapping.fold(channel);
+                        data.channel_keys.insert(channel, key.to_owned());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl StateData {
+    // Removes `nick` from `channel`, or forgets the channel entirely when
+    // it's the client that left.
+    fn leave(&mut self, channel: &str, nick: &str, is_self: bool) {
+        let casemapping = self.casemapping;
+
+        if is_self {
+            self.channels.remove(&casemapping.fold(channel));
+            self.channel_modes.remove(&casemapping.fold(channel));
+            self.channel_keys.remove(&casemapping.fold(channel));
+        } else if let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) {
+            channel.members.remove(&casemapping.fold(nick));
+        }
+
+        self.forget_users();
+    }
+
+    // Applies a MODE change to a channel, given the arguments following the
+    // target, using CHANMODES and PREFIX to know which modes take a
+    // parameter.
+    fn apply_channel_modes(&mut self, channel: &str, args: &[&str]) {
+        let casemapping = self.casemapping;
+        let types = channel_mode_types(&self.isupport);
+        let known = self.channel_modes.entry(casemapping.fold(channel)).or_default();
+
+        for change in types.parse(args) {
+            match types.kind(change.mode) {
+                ModeKind::Prefix => {
+                    let symbol = types.prefix_symbol(change.mode);
+                    let nick = change.param.as_ref().map(|nick| casemapping.fold(nick));
+
+                    let joined = self.channels.get_mut(&casemapping.fold(channel));
+                    let member = match (joined, nick) {
+                        (Some(joined), Some(nick)) => joined.members.get_mut(&nick),
+                        _ => None,
+                    };
+
+                    if let (Some(member), Some(symbol)) = (member, symbol) {
+                        // Prefixes are kept in order of rank.
+                        member.1 = types
+                            .prefix_symbols()
+                            .chars()
+                            .filter(|&held| if held == symbol { change.adding } else { member.1.contains(held) })
+                            .collect();
+                    }
+                }
+                ModeKind::List => (),
+                _ => {
+                    if change.adding {
+                        known.insert(change.mode);
+                    } else {
+                        known.remove(&change.mode);
+                    }
+
+                    // Servers show the key as "*" to those who may not see it.
+                    if change.mode == 'k' {
+                        let folded = casemapping.fold(channel);
+
+                        match change.param {
+                            Some(ref key) if change.adding && key != "*" => {
+                                self.channel_keys.insert(folded, key.clone());
+                            }
+                            _ if !change.adding => {
+                                self.channel_keys.remove(&folded);
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Drops any users that no longer share a channel with the client.
+    fn forget_users(&mut self) {
+        let channels = &self.channels;
+
+        self.users.retain(|nick, _| {
+            channels
+                .values()
+                .any(|channel| channel.members.contains_key(nick))
+        });
+    }
+}
+
+fn new_user(nick: &str) -> User {
+    User {
+        nick: nick.to_owned(),
+        user: None,
+        host: None,
+        real_name: None,
+        away: None,
+    }
+}
+
+// Disables a capability, forgetting what was tracked with its help that can
+// no longer be kept up to date without it.
+fn disable_capability(data: &mut StateData, cap: &str) {
+    if !data.enabled_caps.remove(cap) {
+        return;
+    }
+
+    match cap {
+        // The server won't close the batches that are open.
+        "batch" => data.batches.clear(),
+
+        // The client's own away status is still confirmed by the server.
+        "away-notify" => {
+            let casemapping = data.casemapping;
+            let own = data.nick.as_ref().map(|nick| casemapping.fold(nick));
+
+            for (folded, user) in &mut data.users {
+                if Some(folded) != own.as_ref() {
+                    user.away = None;
+                }
+            }
+        }
+
+        _ => (),
+    }
+}
+
+// The symbols used for membership prefixes, such as `@` and `+`.
+fn prefix_symbols(isupport: &HashMap<String, String>) -> String {
+    channel_mode_types(isupport).prefix_symbols().to_owned()
+}
+
+fn channel_mode_types(isupport: &HashMap<String, String>) -> ChannelModes {
+    let token = |key: &str, default: &'static str| {
+        isupport.get(key).map(|value| value.as_str()).unwrap_or(default)
+    };
+
+    ChannelModes::new(
+        token("CHANMODES", mode::DEFAULT_CHANMODES),
+        token("PREFIX", mode::DEFAULT_PREFIX),
+        isupport.get("MODES").map(|modes| modes.as_str()),
+    )
+}
+
+fn apply_modes(modes: &mut BTreeSet<char>, changes: &str) {
+    let mut adding = true;
+
+    for mode in changes.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            mode if adding => {
+                modes.insert(mode);
+            }
+            mode => {
+                modes.remove(&mode);
+            }
+        }
+    }
+}
+
+pub(crate) fn parse_hostmask(mask: &str) -> Option<(&str, &str, &str)> {
+    let bang = mask.find('!')?;
+    let at = mask[bang..].find('@')? + bang;
+
+    Some((&mask[..bang], &mask[bang + 1..at], &mask[at + 1..]))
+}
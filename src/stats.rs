@@ -0,0 +1,392 @@
+//! The stats module contains `ChannelStats`, which keeps rolling statistics
+//! about the messages sent to each channel, such as how busy it is and who
+//! talks the most.
+//!
+//! Statistics cover the messages received during a window of time ending
+//! now, and are enabled with `Client::channel_stats`. They're read through
+//! the handle returned by `IrcTransport::stats`, for example to answer a
+//! `!stats` command. The crate has no metrics integration of its own, but
+//! `summaries` gives everything as plain data, ready to be handed to
+//! whichever metrics system an application uses.
+//!
+//! Speakers are told apart the way the server compares nicknames, and a
+//! user who changes their nickname keeps the messages they sent under the
+//! old one.
+
+use clock::Clock;
+use state::State;
+
+use pircolate::Message;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// A summary of the messages sent to a channel during the window of a
+/// `ChannelStats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    /// The name of the channel.
+    pub channel: String,
+    /// The number of messages sent to the channel.
+    pub messages: usize,
+    /// The rate of messages, in messages per hour, over the part of the
+    /// window during which statistics were kept.
+    pub messages_per_hour: f64,
+    /// The number of users who sent messages to the channel.
+    pub active_users: usize,
+    /// The users who sent the most messages, with the number each sent, from
+    /// the most to the least.
+    pub top_speakers: Vec<(String, usize)>,
+}
+
+/// A handle to the statistics kept about the channels of a connection.
+#[derive(Clone)]
+pub struct ChannelStats {
+    inner: Arc<Mutex<StatsData>>,
+    clock: Arc<dyn Clock>,
+    state: State,
+}
+
+struct StatsData {
+    window: Duration,
+    // When statistics started being kept, which is when they were last
+    // cleared.
+    started: Duration,
+    // Keyed by folded channel name.
+    channels: HashMap<String, ChannelData>,
+}
+
+struct ChannelData {
+    name: String,
+    // The time each message was received and the folded nickname that sent
+    // it, from the oldest to the most recent.
+    messages: VecDeque<(Duration, String)>,
+    // The latest nickname and the number of messages of each folded
+    // nickname in `messages`.
+    speakers: HashMap<String, (String, usize)>,
+}
+
+impl ChannelData {
+    fn push(&mut self, received: Duration, folded: String, nick: &str) {
+        let speaker = self.speakers
+            .entry(folded.clone())
+            .or_insert_with(|| (String::new(), 0));
+
+        speaker.0 = nick.to_owned();
+        speaker.1 += 1;
+
+        self.messages.push_back((received, folded));
+    }
+
+    fn pop(&mut self) {
+        let folded = match self.messages.pop_front() {
+            Some((_, folded)) => folded,
+            None => return,
+        };
+
+        let gone = match self.speakers.get_mut(&folded) {
+            Some(speaker) => {
+                speaker.1 -= 1;
+                speaker.1 == 0
+            }
+            None => false,
+        };
+
+        if gone {
+            self.speakers.remove(&folded);
+        }
+    }
+
+    // Attributes the messages sent by `old` to `new`, who is the same user
+    // under a new nickname.
+    fn rename(&mut self, old: &str, new: &str, nick: &str) {
+        let count = match self.speakers.remove(old) {
+            Some((_, count)) => count,
+            None => return,
+        };
+
+        for message in &mut self.messages {
+            if message.1 == old {
+                message.1 = new.to_owned();
+            }
+        }
+
+        let speaker = self.speakers
+            .entry(new.to_owned())
+            .or_insert_with(|| (String::new(), 0));
+
+        speaker.0 = nick.to_owned();
+        speaker.1 += count;
+    }
+}
+
+impl ChannelStats {
+    pub(crate) fn new(window: Duration, clock: Arc<dyn Clock>, state: State) -> ChannelStats {
+        ChannelStats {
+            inner: Arc::new(Mutex::new(StatsData {
+                window: window,
+                started: clock.now(),
+                channels: HashMap::new(),
+            })),
+            clock: clock,
+            state: state,
+        }
+    }
+
+    /// The length of the window statistics are kept over. A window of 0
+    /// means statistics are disabled.
+    pub fn window(&self) -> Duration {
+        self.lock().window
+    }
+
+    /// The channels messages were sent to during the window.
+    pub fn channels(&self) -> Vec<String> {
+        let data = self.expire();
+
+        data.channels.values().map(|channel| channel.name.clone()).collect()
+    }
+
+    /// The number of messages sent to `channel` during the window.
+    pub fn messages(&self, channel: &str) -> usize {
+        self.with_channel(channel, |channel| channel.messages.len())
+            .unwrap_or(0)
+    }
+
+    /// The rate of messages sent to `channel` during the window, in messages
+    /// per hour. Until statistics have been kept for a whole window, such as
+    /// just after connecting, the rate is over the time they've been kept.
+    pub fn messages_per_hour(&self, channel: &str) -> f64 {
+        let observed = {
+            let data = self.lock();
+            self.clock.now().saturating_sub(data.started).min(data.window)
+        };
+
+        if observed == Duration::from_secs(0) {
+            return 0.0;
+        }
+
+        self.messages(channel) as f64 * 3600.0 / observed.as_secs_f64()
+    }
+
+    /// The number of users who sent messages to `channel` during the window.
+    pub fn active_users(&self, channel: &str) -> usize {
+        self.with_channel(channel, |channel| channel.speakers.len())
+            .unwrap_or(0)
+    }
+
+    /// The `count` users who sent the most messages to `channel` during the
+    /// window, with the number each sent, from the most to the least.
+    pub fn top_speakers(&self, channel: &str, count: usize) -> Vec<(String, usize)> {
+        self.with_channel(channel, |channel| {
+            let mut speakers = speakers(channel);
+            speakers.truncate(count);
+            speakers
+        }).unwrap_or_default()
+    }
+
+    /// A `Summary` of `channel`, including its `count` top speakers, if any
+    /// messages were sent to it during the window.
+    pub fn summary(&self, channel: &str, count: usize) -> Option<Summary> {
+        let name = self.with_channel(channel, |channel| channel.name.clone())?;
+
+        Some(Summary {
+            channel: name,
+            messages: self.messages(channel),
+            messages_per_hour: self.messages_per_hour(channel),
+            active_users: self.active_users(channel),
+            top_speakers: self.top_speakers(channel, count),
+        })
+    }
+
+    /// A `Summary` of every channel messages were sent to during the window,
+    /// each including its `count` top speakers.
+    pub fn summaries(&self, count: usize) -> Vec<Summary> {
+        self.channels()
+            .iter()
+            .filter_map(|channel| self.summary(channel, count))
+            .collect()
+    }
+
+    /// Discard the statistics of every channel, starting to keep them anew.
+    pub fn clear(&self) {
+        let mut data = self.lock();
+
+        data.started = self.clock.now();
+        data.channels.clear();
+    }
+
+    // Counts a message received from the server, if it was sent to a
+    // channel, and follows the nickname changes of those who sent them.
+    pub(crate) fn record(&self, message: &Message) {
+        if message.raw_command() == "NICK" {
+            return self.rename(message);
+        }
+
+        if message.raw_command() != "PRIVMSG" {
+            return;
+        }
+
+        let (nick, channel) = match (message.prefix(), message.raw_args().next()) {
+            (Some((nick, _, _)), Some(channel)) if self.state.is_channel(channel) => (nick, channel),
+            _ => return,
+        };
+
+        let now = self.clock.now();
+        let mut data = self.expire();
+
+        if data.window == Duration::from_secs(0) {
+            return;
+        }
+
+        data.channels
+            .entry(self.state.fold_name(channel))
+            .or_insert_with(|| ChannelData {
+                name: channel.to_owned(),
+                messages: VecDeque::new(),
+                speakers: HashMap::new(),
+            })
+            .push(now, self.state.fold_name(nick), nick);
+    }
+
+    fn rename(&self, message: &Message) {
+        let (old, new) = match (message.prefix(), message.raw_args().next()) {
+            (Some((old, _, _)), Some(new)) => (self.state.fold_name(old), new),
+            _ => return,
+        };
+
+        let folded = self.state.fold_name(new);
+
+        for channel in self.lock().channels.values_mut() {
+            channel.rename(&old, &folded, new);
+        }
+    }
+
+    fn with_channel<F, R>(&self, channel: &str, f: F) -> Option<R>
+    where
+        F: FnOnce(&ChannelData) -> R,
+    {
+        let data = self.expire();
+
+        data.channels.get(&self.state.fold_name(channel)).map(f)
+    }
+
+    // Locks the statistics, first discarding the messages that have left
+    // the window.
+    fn expire(&self) -> MutexGuard<'_, StatsData> {
+        let now = self.clock.now();
+        let mut data = self.lock();
+        let window = data.window;
+
+        data.channels.retain(|_, channel| {
+            while let Some(&(received, _)) = channel.messages.front() {
+                if now.saturating_sub(received) < window {
+                    break;
+                }
+
+                channel.pop();
+            }
+
+            !channel.messages.is_empty()
+        });
+
+        data
+    }
+
+    fn lock(&self) -> MutexGuard<'_, StatsData> {
+        self.inner.lock().expect("ChannelStats lock poisoned")
+    }
+}
+
+// The users who sent messages to a channel, with the number each sent, from
+// the most to the least.
+fn speakers(channel: &ChannelData) -> Vec<(String, usize)> {
+    let mut speakers: Vec<(String, usize)> = channel.speakers.values().cloned().collect();
+
+    speakers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    speakers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn stats(window: u64) -> (ChannelStats, Arc<AtomicU64>) {
+        let now = Arc::new(AtomicU64::new(100));
+        let clock = {
+            let now = now.clone();
+            move || Duration::from_secs(now.load(Ordering::SeqCst))
+        };
+
+        let window = Duration::from_secs(window);
+        let stats = ChannelStats::new(window, Arc::new(clock), State::default());
+
+        (stats, now)
+    }
+
+    fn receive(stats: &ChannelStats, line: &str) {
+        stats.record(&Message::try_from(line.to_owned()).unwrap());
+    }
+
+    #[test]
+    fn speakers_are_folded_and_followed_across_nickname_changes() {
+        let (stats, _) = stats(3600);
+
+        receive(&stats, ":Bob[away]!u@h PRIVMSG #chan :hi");
+        receive(&stats, ":bob{AWAY}!u@h PRIVMSG #Chan :hello");
+        receive(&stats, ":alice!u@h PRIVMSG #chan :hey");
+        receive(&stats, ":alice!u@h PRIVMSG #chan :there");
+        receive(&stats, ":alice!u@h PRIVMSG #chan :bob");
+        receive(&stats, ":bob{away}!u@h NICK Bob");
+        receive(&stats, ":Bob!u@h PRIVMSG #chan :back");
+
+        assert_eq!(stats.active_users("#chan"), 2);
+        assert_eq!(
+            stats.top_speakers("#CHAN", 5),
+            vec![("Bob".to_owned(), 3), ("alice".to_owned(), 3)]
+        );
+    }
+
+    #[test]
+    fn messages_leave_the_window() {
+        let (stats, now) = stats(60);
+
+        receive(&stats, ":bob!u@h PRIVMSG #chan :hi");
+        now.store(130, Ordering::SeqCst);
+        receive(&stats, ":alice!u@h PRIVMSG #chan :hi");
+
+        assert_eq!(stats.messages("#chan"), 2);
+
+        now.store(160, Ordering::SeqCst);
+
+        assert_eq!(stats.messages("#chan"), 1);
+        assert_eq!(stats.top_speakers("#chan", 5), vec![("alice".to_owned(), 1)]);
+
+        now.store(200, Ordering::SeqCst);
+
+        assert!(stats.channels().is_empty());
+    }
+
+    #[test]
+    fn the_rate_is_over_the_time_observed() {
+        let (stats, now) = stats(3600);
+
+        for _ in 0..10 {
+            receive(&stats, ":bob!u@h PRIVMSG #chan :hi");
+        }
+
+        // Ten messages in the first ten minutes are sixty an hour, rather
+        // than ten over the whole window.
+        now.store(100 + 600, Ordering::SeqCst);
+        assert_eq!(stats.messages_per_hour("#chan"), 60.0);
+
+        // Once a whole window has been observed, the rate is over it.
+        now.store(100 + 3599, Ordering::SeqCst);
+        assert!((stats.messages_per_hour("#chan") - 10.0).abs() < 0.01);
+
+        stats.clear();
+        assert_eq!(stats.messages_per_hour("#chan"), 0.0);
+    }
+}
@@ -0,0 +1,186 @@
+//! The tagmsg module contains `TagMsg`, a message carrying only IRCv3 client
+//! tags, and functions building the TAGMSG messages sent by clients such as
+//! typing notifications and reactions.
+//!
+//! Client tags, whose names start with `+`, are only relayed by servers
+//! that have the `message-tags` capability enabled, which `Registration`
+//! requests by default. Received TAGMSG messages can be picked out of a
+//! stream with `MessageStreamExt::tagmsgs`.
+//!
+//! ```no_run
+//! # extern crate tokio_irc_client;
+//! use tokio_irc_client::tagmsg::{self, Typing};
+//!
+//! # fn main() {
+//! let typing = tagmsg::typing("#rust", Typing::Active).unwrap();
+//! let reaction = tagmsg::react("#rust", "abc123", "👍").unwrap();
+//! # }
+//! ```
+
+use error::Result;
+
+use pircolate::Message;
+
+use std::fmt;
+
+/// Whether the client is typing in a conversation, as shown to other users
+/// with the `+typing` client tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Typing {
+    /// The client is typing.
+    Active,
+    /// The client has typed something but stopped typing for now.
+    Paused,
+    /// The client has stopped typing, without sending anything.
+    Done,
+}
+
+impl Typing {
+    /// The `Typing` described by the value of a `+typing` tag.
+    pub fn from_tag(value: &str) -> Option<Typing> {
+        match value {
+            "active" => Some(Typing::Active),
+            "paused" => Some(Typing::Paused),
+            "done" => Some(Typing::Done),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Typing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Typing::Active => "active",
+            Typing::Paused => "paused",
+            Typing::Done => "done",
+        })
+    }
+}
+
+/// A TAGMSG received from the server.
+#[derive(Clone, Debug)]
+pub struct TagMsg {
+    /// The nickname of the sender, if the message has a source.
+    pub nick: Option<String>,
+    /// The channel or nickname the message was sent to.
+    pub target: String,
+    /// The tags of the message, with their values unescaped.
+    pub tags: Vec<(String, Option<String>)>,
+    /// The message itself.
+    pub message: Message,
+}
+
+impl TagMsg {
+    /// Returns the `TagMsg` held by `message`, if it is a TAGMSG.
+    pub fn from_message(message: Message) -> Option<TagMsg> {
+        if message.raw_command() != "TAGMSG" {
+            return None;
+        }
+
+        let target = message.raw_args().next()?.to_owned();
+        let tags = message
+            .raw_tags()
+            .map(|(key, value)| (key.to_owned(), value.map(unescape)))
+            .collect();
+
+        Some(TagMsg {
+            nick: message.prefix().map(|(nick, _, _)| nick.to_owned()),
+            target: target,
+            tags: tags,
+            message: message,
+        })
+    }
+
+    /// The value of the tag with the given name, or an empty string for a
+    /// tag without a value, if the message has it.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_ref().map_or("", |value| value.as_str()))
+    }
+
+    /// Whether the sender is typing, if this is a typing notification.
+    pub fn typing(&self) -> Option<Typing> {
+        self.tag("+typing").and_then(Typing::from_tag)
+    }
+
+    /// The reaction, such as an emoji, if this is a reaction to a message.
+    pub fn reaction(&self) -> Option<&str> {
+        self.tag("+draft/react")
+    }
+
+    /// The id of the message this reacts or replies to, if any.
+    pub fn reply_to(&self) -> Option<&str> {
+        self.tag("+draft/reply")
+    }
+}
+
+/// Build a TAGMSG to `target` carrying the given tags, whose values are
+/// escaped as needed.
+pub fn tagmsg(target: &str, tags: &[(&str, Option<&str>)]) -> Result<Message> {
+    let tags: Vec<String> = tags.iter()
+        .map(|&(key, value)| match value {
+            Some(value) => format!("{}={}", key, escape(value)),
+            // An empty value means the same as none, and unlike a bare key
+            // can also be parsed as the last tag.
+            None => format!("{}=", key),
+        })
+        .collect();
+
+    Ok(Message::try_from(format!("@{} TAGMSG {}", tags.join(";"), target))?)
+}
+
+/// Build a TAGMSG telling `target` whether the client is typing.
+pub fn typing(target: &str, typing: Typing) -> Result<Message> {
+    tagmsg(target, &[("+typing", Some(&typing.to_string()))])
+}
+
+/// Build a TAGMSG reacting to the message with the id `msgid` sent to
+/// `target`, such as with an emoji.
+pub fn react(target: &str, msgid: &str, reaction: &str) -> Result<Message> {
+    tagmsg(target, &[("+draft/reply", Some(msgid)), ("+draft/react", Some(reaction))])
+}
+
+// Escapes a tag value so it can't end the tag or the tags early.
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// Reverses `escape`. Unknown escapes drop the backslash, as does a trailing
+// one.
+pub(crate) fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => (),
+        }
+    }
+
+    unescaped
+}
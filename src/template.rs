@@ -0,0 +1,277 @@
+//! The template module contains `Template`, which builds the text of
+//! outgoing messages from a template with named placeholders, such as
+//! `Hello, {nick}!`.
+//!
+//! The values filled in are often chosen by other users, so they're passed
+//! through `sanitize` first: line breaks can't start a new protocol line,
+//! CTCP delimiters can't turn a reply into a CTCP request, and formatting
+//! codes can't change the appearance of the rest of the message. Formatting
+//! codes written in the template itself are kept.
+//!
+//! A template given a maximum length shortens the values filled in, longest
+//! first, so that the rendered text fits without cutting off the template's
+//! own text. Shortened values end with an ellipsis.
+//!
+//! ```
+//! # extern crate tokio_irc_client;
+//! use tokio_irc_client::template::{Template, Values};
+//!
+//! # fn main() {
+//! let template = Template::new("\x02{nick}\x02 said: {message}").max_len(30);
+//! let values = Values::new()
+//!     .set("nick", "alice")
+//!     .set("message", "hello\nQUIT :gotcha");
+//!
+//! assert_eq!(template.render(&values), "\x02alice\x02 said: hello QUIT :g…");
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+
+const ELLIPSIS: &str = "…";
+
+/// A template for the text of a message, with `{name}` placeholders for
+/// the values filled in when it's rendered. Placeholders without a value are
+/// left untouched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+    max_len: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Placeholder(String),
+}
+
+impl Template {
+    /// Parse a template from its source. Line breaks in the source are
+    /// replaced with spaces, as a message is sent as a single line.
+    pub fn new(source: &str) -> Template {
+        let source = source.replace(&['\r', '\n'][..], " ");
+        let mut parts = Vec::new();
+        let mut rest = source.as_str();
+
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_owned()));
+            }
+
+            parts.push(Part::Placeholder(rest[start + 1..end].to_owned()));
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+
+        Template {
+            parts: parts,
+            max_len: None,
+        }
+    }
+
+    /// Limit the rendered text to `max_len` bytes, shortening the values
+    /// filled in as needed. The text of the template is only cut off when
+    /// it doesn't fit by itself.
+    pub fn max_len(mut self, max_len: usize) -> Template {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Render the template with the given values.
+    pub fn render(&self, values: &Values) -> String {
+        self.render_with(|name| values.get(name))
+    }
+
+    /// Render the template, looking up the value of each placeholder with
+    /// the given closure.
+    pub fn render_with<F, V>(&self, lookup: F) -> String
+    where
+        F: Fn(&str) -> Option<V>,
+        V: AsRef<str>,
+    {
+        // Each part of the rendered text, along with whether it's a value
+        // that can be shortened.
+        let mut rendered: Vec<(String, bool)> = self.parts
+            .iter()
+            .map(|part| match *part {
+                Part::Text(ref text) => (text.clone(), false),
+                Part::Placeholder(ref name) => match lookup(name) {
+                    Some(value) => (sanitize(value.as_ref()), true),
+                    None => (format!("{{{}}}", name), false),
+                },
+            })
+            .collect();
+
+        let max_len = match self.max_len {
+            Some(max_len) => max_len,
+            None => return rendered.into_iter().map(|(part, _)| part).collect(),
+        };
+
+        let len: usize = rendered.iter().map(|(part, _)| part.len()).sum();
+
+        if len > max_len {
+            let text_len: usize = rendered
+                .iter()
+                .filter(|&&(_, is_value)| !is_value)
+                .map(|(part, _)| part.len())
+                .sum();
+
+            let value_lens = rendered
+                .iter()
+                .filter(|&&(_, is_value)| is_value)
+                .map(|(part, _)| part.len())
+                .collect();
+
+            let cap = fair_share(value_lens, max_len.saturating_sub(text_len));
+
+            for &mut (ref mut part, is_value) in &mut rendered {
+                if is_value && part.len() > cap {
+                    shorten(part, cap);
+                }
+            }
+        }
+
+        let mut text: String = rendered.into_iter().map(|(part, _)| part).collect();
+
+        if text.len() > max_len {
+            let end = floor_char_boundary(&text, max_len);
+            text.truncate(end);
+        }
+
+        text
+    }
+}
+
+/// The values filled in when rendering a `Template`.
+#[derive(Clone, Debug, Default)]
+pub struct Values {
+    values: HashMap<String, String>,
+}
+
+impl Values {
+    /// Create a new, empty set of values.
+    pub fn new() -> Values {
+        Values::default()
+    }
+
+    /// Set the value of the placeholder `name`.
+    pub fn set<N, V>(mut self, name: N, value: V) -> Values
+    where
+        N: Into<String>,
+        V: fmt::Display,
+    {
+        self.values.insert(name.into(), value.to_string());
+        self
+    }
+
+    /// The value of the placeholder `name`, if it has one.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+/// Makes a value safe to include in the text of a message, by removing
+/// formatting codes and control characters and replacing line breaks and
+/// tabs with spaces.
+pub fn sanitize(value: &str) -> String {
+    let mut sanitized = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' | '\n' | '\t' => sanitized.push(' '),
+
+            // Colors: "\x03<fg>[,<bg>]" with up to two digits each.
+            '\x03' => skip_color(&mut chars, 2, |c| c.is_ascii_digit()),
+
+            // Hex colors: "\x04<rrggbb>[,<rrggbb>]".
+            '\x04' => skip_color(&mut chars, 6, |c| c.is_ascii_hexdigit()),
+
+            c if c.is_control() => (),
+            c => sanitized.push(c),
+        }
+    }
+
+    sanitized
+}
+
+// Skips the foreground and optional background of a color code, which are
+// each up to `digits` characters matching `is_digit`.
+fn skip_color<I, F>(chars: &mut Peekable<I>, digits: usize, is_digit: F)
+where
+    I: Iterator<Item = char> + Clone,
+    F: Fn(char) -> bool,
+{
+    let skip_digits = |chars: &mut Peekable<I>| {
+        let mut skipped = 0;
+
+        while skipped < digits && chars.peek().map(|&c| is_digit(c)) == Some(true) {
+            chars.next();
+            skipped += 1;
+        }
+
+        skipped
+    };
+
+    if skip_digits(chars) == 0 {
+        return;
+    }
+
+    // The comma is only part of the code when a background follows it.
+    let mut lookahead = chars.clone();
+
+    if lookahead.next() == Some(',') && lookahead.next().map(&is_digit) == Some(true) {
+        chars.next();
+        skip_digits(chars);
+    }
+}
+
+// The largest length which, when each value is cut down to it, makes the
+// values fit in `budget` bytes together.
+fn fair_share(mut lens: Vec<usize>, budget: usize) -> usize {
+    lens.sort_unstable();
+
+    let mut remaining = budget;
+    let mut left = lens.len();
+
+    for len in lens {
+        if len > remaining / left {
+            return remaining / left;
+        }
+
+        remaining -= len;
+        left -= 1;
+    }
+
+    usize::MAX
+}
+
+// Shortens `value` to at most `len` bytes, ending it with an ellipsis when
+// there's room for one.
+fn shorten(value: &mut String, len: usize) {
+    if len < ELLIPSIS.len() {
+        let end = floor_char_boundary(value, len);
+        value.truncate(end);
+    } else {
+        let end = floor_char_boundary(value, len - ELLIPSIS.len());
+        value.truncate(end);
+        value.push_str(ELLIPSIS);
+    }
+}
+
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    (0..=index.min(text.len()))
+        .rev()
+        .find(|&index| text.is_char_boundary(index))
+        .unwrap_or(0)
+}
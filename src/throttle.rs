@@ -0,0 +1,264 @@
+//! The throttle module contains `Throttle`, which paces the messages sent to
+//! a `Sink` according to a `SendPolicy`.
+//!
+//! Servers penalize flooding differently depending on the command, so a
+//! single global rate is either too slow for cheap commands or too fast for
+//! expensive ones. A `SendPolicy` is a small table giving commands their own
+//! `Budget`, with the default budget shared by every command not in the
+//! table:
+//!
+//! ```no_run
+//! # extern crate tokio_irc_client;
+//! use std::time::Duration;
+//! use tokio_irc_client::throttle::{Budget, SendPolicy};
+//!
+//! # fn main() {
+//! let policy = SendPolicy::new()
+//!     .default_budget(Budget::new(5, Duration::from_secs(2)))
+//!     .command("WHO", Budget::new(1, Duration::from_secs(10)))
+//!     .command("JOIN", Budget::new(4, Duration::from_secs(5)))
+//!     .unlimited("PONG");
+//! # }
+//! ```
+//!
+//! Messages are sent in the order they're given. When the next message is
+//! over its budget, `Throttle` stops accepting messages until it can be sent.
+//! Messages generated by the transport itself, such as PONG replies and the
+//! registration sequence, don't go through the `Sink` and are never delayed.
+
+use clock::{Clock, Delay, Timer};
+#[cfg(not(target_arch = "wasm32"))]
+use clock;
+use error::Error;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend};
+
+use pircolate::Message;
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_core::reactor::Handle;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How many messages of a kind may be sent, as a bucket holding up to
+/// `burst` messages which refills by one every `interval`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Budget {
+    burst: u32,
+    interval: Duration,
+}
+
+impl Budget {
+    /// Allow `burst` messages to be sent at once, and then one more each time
+    /// `interval` passes. A `burst` of 0 is treated as 1.
+    pub fn new(burst: u32, interval: Duration) -> Budget {
+        Budget {
+            burst: burst.max(1),
+            interval: interval,
+        }
+    }
+}
+
+/// The budgets applied to the messages sent through a `Throttle`.
+#[derive(Clone, Debug, Default)]
+pub struct SendPolicy {
+    default: Option<Budget>,
+    commands: HashMap<String, Option<Budget>>,
+}
+
+impl SendPolicy {
+    /// Create a new `SendPolicy` which doesn't limit any command.
+    pub fn new() -> SendPolicy {
+        SendPolicy::default()
+    }
+
+    /// Limit the commands that aren't given their own budget by `budget`,
+    /// which all of them share.
+    pub fn default_budget(mut self, budget: Budget) -> SendPolicy {
+        self.default = Some(budget);
+        self
+    }
+
+    /// Limit `command`, such as `WHO`, by its own `budget`. Commands are
+    /// compared ignoring case.
+    pub fn command(mut self, command: &str, budget: Budget) -> SendPolicy {
+        self.commands
+            .insert(command.to_ascii_uppercase(), Some(budget));
+        self
+    }
+
+    /// Never limit `command`, even when there is a default budget.
+    pub fn unlimited(mut self, command: &str) -> SendPolicy {
+        self.commands.insert(command.to_ascii_uppercase(), None);
+        self
+    }
+
+    // The name of the bucket a command draws from and its budget, if it's
+    // limited at all.
+    fn budget(&self, command: &str) -> Option<(String, Budget)> {
+        let command = command.to_ascii_uppercase();
+
+        match self.commands.get(&command) {
+            Some(&Some(budget)) => Some((command, budget)),
+            Some(&None) => None,
+            // The default budget is shared, so it has a bucket of its own.
+            None => self.default.map(|budget| (String::new(), budget)),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: u32,
+    refilled_at: Duration,
+}
+
+impl Bucket {
+    fn refill(&mut self, budget: Budget, now: Duration) {
+        if self.tokens >= budget.burst {
+            self.refilled_at = now;
+            return;
+        }
+
+        let elapsed = now.saturating_sub(self.refilled_at);
+        let refills = if budget.interval == Duration::from_secs(0) {
+            budget.burst
+        } else {
+            (elapsed.as_nanos() / budget.interval.as_nanos()).min(u128::from(budget.burst)) as u32
+        };
+
+        self.tokens = (self.tokens + refills).min(budget.burst);
+
+        if self.tokens >= budget.burst {
+            self.refilled_at = now;
+        } else {
+            self.refilled_at += budget.interval * refills;
+        }
+    }
+
+    // How long until another message may be sent, if one can't be sent now.
+    fn wait(&self, budget: Budget, now: Duration) -> Option<Duration> {
+        if self.tokens > 0 {
+            None
+        } else {
+            Some((self.refilled_at + budget.interval).saturating_sub(now))
+        }
+    }
+}
+
+/// A `Sink` which passes messages on to another one, such as the `Sink` half
+/// of an `IrcTransport`, no faster than a `SendPolicy` allows.
+pub struct Throttle<S> {
+    inner: S,
+    policy: SendPolicy,
+    buckets: HashMap<String, Bucket>,
+    clock: Arc<dyn Clock>,
+    timer: Box<dyn Timer>,
+    // The delay until the next message may be sent, which wakes the task
+    // that tried to send it.
+    delay: Option<Delay>,
+}
+
+impl<S> Throttle<S>
+where
+    S: Sink<SinkItem = Message, SinkError = Error>,
+{
+    /// Create a new `Throttle` sending to `inner`, timed by the system's
+    /// monotonic clock and tokio's reactor.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(inner: S, policy: SendPolicy, handle: &Handle) -> Throttle<S> {
+        Throttle::with_clock(inner, policy, clock::system(), handle.clone())
+    }
+
+    /// Create a new `Throttle` sending to `inner`, timed by the given `Clock`
+    /// and `Timer`.
+    pub fn with_clock<T>(inner: S, policy: SendPolicy, clock: Arc<dyn Clock>, timer: T) -> Throttle<S>
+    where
+        T: Timer + 'static,
+    {
+        Throttle {
+            inner: inner,
+            policy: policy,
+            buckets: HashMap::new(),
+            clock: clock,
+            timer: Box::new(timer),
+            delay: None,
+        }
+    }
+
+    /// Returns a reference to the wrapped `Sink`.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the wrapped `Sink`.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    // How long until `message` may be sent, if it can't be sent now.
+    fn wait(&mut self, message: &Message) -> Option<Duration> {
+        let (name, budget) = self.policy.budget(message.raw_command())?;
+        let now = self.clock.now();
+
+        let bucket = self.buckets.entry(name).or_insert_with(|| Bucket {
+            tokens: budget.burst,
+            refilled_at: now,
+        });
+
+        bucket.refill(budget, now);
+        bucket.wait(budget, now)
+    }
+
+    // Takes a message out of the named bucket once it has been sent.
+    fn spend(&mut self, name: &str) {
+        if let Some(bucket) = self.buckets.get_mut(name) {
+            bucket.tokens = bucket.tokens.saturating_sub(1);
+        }
+    }
+}
+
+impl<S> Sink for Throttle<S>
+where
+    S: Sink<SinkItem = Message, SinkError = Error>,
+{
+    type SinkItem = Message;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        while let Some(wait) = self.wait(&item) {
+            let mut delay = self.timer.delay(wait);
+
+            if let Async::NotReady = delay.poll()? {
+                self.delay = Some(delay);
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        self.delay = None;
+
+        let bucket = self.policy
+            .budget(item.raw_command())
+            .map(|(name, _)| name);
+
+        match self.inner.start_send(item)? {
+            AsyncSink::Ready => {
+                if let Some(name) = bucket {
+                    self.spend(&name);
+                }
+
+                Ok(AsyncSink::Ready)
+            }
+            not_ready => Ok(not_ready),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.close()
+    }
+}
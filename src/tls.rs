@@ -0,0 +1,277 @@
+//! The tls module contains hooks for customizing how the certificates
+//! presented by a remote server are validated.
+//!
+//! By default, connections made with `Client::connect_tls` are validated by
+//! the platform's TLS implementation using the system trust store and the
+//! domain name given to `connect_tls`, which is also sent to the server
+//! with SNI. Installing a `CertificateVerifier` on a `Client` adds a user
+//! provided check of the certificates once they've been validated, which is
+//! useful for accepting only a rotating set of known certificates. A
+//! verifier can instead replace the platform's validation by returning
+//! `true` from `CertificateVerifier::replaces_validation`, as
+//! `PinnedCertificate` does to accept servers with self-signed certificates
+//! that have a known fingerprint.
+//!
+//! Verifiers need the certificates presented by the server, which are only
+//! exposed by the OpenSSL backend of the platform's TLS implementation, so
+//! `Client::certificate_verifier` isn't available on macOS, iOS or Windows.
+//!
+//! Once the handshake completes, the protocol version and cipher suite that
+//! were negotiated are checked, where the platform's TLS implementation
+//! exposes them. Obsolete protocols and ciphers offering little or no
+//! protection are reported with `Event::WeakTls`, or cause the connection to
+//! be refused, according to the `WeakTlsPolicy` set on the `Client`.
+
+use digest::Algorithm;
+use error::{ErrorKind, Result};
+
+use native_tls::{self, TlsConnector};
+
+use tokio_tls::TlsStream;
+
+/// How connections negotiating an obsolete protocol version or a weak cipher
+/// suite are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeakTlsPolicy {
+    /// Connections are made without any warning.
+    Allow,
+    /// Connections are made, but are reported with `Event::WeakTls`.
+    #[default]
+    Warn,
+    /// Connections are refused with `WeakTls`, after being reported with
+    /// `Event::WeakTls`.
+    Refuse,
+}
+
+/// The protocol version and cipher suite negotiated for a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    /// The protocol version, such as `TLSv1.2`.
+    pub protocol: String,
+    /// The name of the cipher suite, such as `ECDHE-RSA-AES128-GCM-SHA256`.
+    pub cipher: String,
+    /// The strength of the cipher's key, in bits.
+    pub secret_bits: u32,
+}
+
+// The smallest key strength not considered weak.
+const MIN_SECRET_BITS: u32 = 128;
+
+// Parts of cipher suite names marking ciphers that offer little or no
+// protection: no encryption, export grade or broken ciphers, broken
+// digests, and key exchanges without authentication.
+const WEAK_CIPHERS: &[&str] = &["NULL", "EXP", "RC4", "DES", "MD5", "ADH", "AECDH", "anon"];
+
+impl Negotiated {
+    /// Why the negotiated parameters are weak, if they are.
+    pub fn weakness(&self) -> Option<String> {
+        match self.protocol.as_str() {
+            "SSLv2" | "SSLv3" | "TLSv1" | "TLSv1.1" => {
+                return Some(format!("{} is obsolete", self.protocol))
+            }
+            _ => (),
+        }
+
+        if let Some(weak) = WEAK_CIPHERS.iter().find(|weak| self.cipher.contains(*weak)) {
+            return Some(format!("the cipher {} uses {}", self.cipher, weak));
+        }
+
+        if self.secret_bits < MIN_SECRET_BITS {
+            return Some(format!(
+                "the cipher {} only has {} bit keys",
+                self.cipher, self.secret_bits
+            ));
+        }
+
+        None
+    }
+}
+
+/// A `CertificateVerifier` decides whether or not the certificate chain
+/// presented by a remote server should be trusted.
+///
+/// The `chain` is provided as a list of DER encoded certificates, starting
+/// with the certificate of the remote server itself.  The `domain` is the
+/// domain name that was passed to `Client::connect_tls`.  Returning `true`
+/// accepts the connection, returning `false` rejects it.
+///
+/// By default the verifier is only consulted once the platform has validated
+/// the chain against the system trust store and `domain`, so it can reject
+/// certificates the platform accepts but can't accept ones it rejects. If the
+/// presented certificates cannot be retrieved from the TLS implementation,
+/// the connection is always rejected.
+pub trait CertificateVerifier: Send + Sync {
+    /// Inspect the certificate chain presented by the server for `domain`
+    /// and return whether or not it should be trusted.
+    fn verify(&self, chain: &[Vec<u8>], domain: &str) -> bool;
+
+    /// Whether the platform's own validation of the chain and the domain is
+    /// skipped, leaving the verifier entirely responsible for the security
+    /// of the connection. The domain is still sent to the server with SNI.
+    /// By default it isn't skipped.
+    fn replaces_validation(&self) -> bool {
+        false
+    }
+}
+
+impl<F> CertificateVerifier for F
+where
+    F: Fn(&[Vec<u8>], &str) -> bool + Send + Sync,
+{
+    fn verify(&self, chain: &[Vec<u8>], domain: &str) -> bool {
+        self(chain, domain)
+    }
+}
+
+/// A `CertificateVerifier` accepting only servers presenting a certificate
+/// whose SHA-256 fingerprint is one of the pinned ones, regardless of who
+/// signed it or which domain it's for. Pinning more than one fingerprint
+/// allows a certificate to be replaced without losing the connection.
+///
+/// The platform's own validation is skipped, so self-signed certificates are
+/// accepted as long as they're pinned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinnedCertificate {
+    fingerprints: Vec<Vec<u8>>,
+}
+
+impl PinnedCertificate {
+    /// Pin the certificate with the given SHA-256 fingerprint, written in
+    /// hex with or without colons between bytes, as printed by
+    /// `openssl x509 -fingerprint -sha256`.
+    pub fn new(fingerprint: &str) -> Result<PinnedCertificate> {
+        PinnedCertificate {
+            fingerprints: Vec::new(),
+        }
+        .pin(fingerprint)
+    }
+
+    /// Also accept the certificate with the given SHA-256 fingerprint.
+    pub fn pin(mut self, fingerprint: &str) -> Result<PinnedCertificate> {
+        let invalid = || ErrorKind::InvalidFingerprint(fingerprint.to_owned());
+        let digits: Vec<u8> = fingerprint.bytes().filter(|&b| b != b':').collect();
+
+        if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(invalid().into());
+        }
+
+        let bytes = digits
+            .chunks(2)
+            .map(|pair| (hex_value(pair[0]) << 4) | hex_value(pair[1]))
+            .collect();
+
+        self.fingerprints.push(bytes);
+        Ok(self)
+    }
+}
+
+impl CertificateVerifier for PinnedCertificate {
+    fn verify(&self, chain: &[Vec<u8>], _: &str) -> bool {
+        match chain.first() {
+            Some(certificate) => {
+                let fingerprint = Algorithm::Sha256.digest(certificate);
+                self.fingerprints.contains(&fingerprint)
+            }
+            None => false,
+        }
+    }
+
+    fn replaces_validation(&self) -> bool {
+        true
+    }
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// The SHA-256 fingerprint of a DER encoded certificate, in uppercase hex
+/// with colons between bytes, as accepted by `PinnedCertificate`.
+pub fn fingerprint(certificate: &[u8]) -> String {
+    Algorithm::Sha256
+        .digest(certificate)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Creates the connector for a connection checked by the given verifier,
+// which skips the platform's validation if the verifier replaces it.
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+pub(crate) fn connector(
+    verifier: Option<&dyn CertificateVerifier>,
+) -> native_tls::Result<TlsConnector> {
+    use native_tls::backend::openssl::TlsConnectorBuilderExt;
+    use openssl::ssl::SSL_VERIFY_NONE;
+
+    let mut builder = TlsConnector::builder()?;
+
+    if verifier.is_some_and(|verifier| verifier.replaces_validation()) {
+        builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    }
+
+    builder.build()
+}
+
+// Verifiers can't be installed where certificates aren't exposed.
+#[cfg(any(target_os = "macos", target_os = "windows", target_os = "ios"))]
+pub(crate) fn connector(_: Option<&dyn CertificateVerifier>) -> native_tls::Result<TlsConnector> {
+    TlsConnector::builder()?.build()
+}
+
+// Retrieves the DER encoded certificate chain presented by the remote end of
+// the given stream. `native-tls` doesn't expose peer certificates in its
+// portable API, so this is implemented per backend where it's possible to do so.
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+pub(crate) fn peer_certificates<S>(stream: &TlsStream<S>) -> Result<Vec<Vec<u8>>> {
+    use native_tls::backend::openssl::TlsStreamExt;
+
+    let ssl = stream.get_ref().raw_stream().ssl();
+    let chain = match ssl.peer_cert_chain() {
+        Some(chain) => chain,
+        None => return Err(ErrorKind::CertificateUnavailable.into()),
+    };
+
+    let mut certificates = Vec::new();
+
+    for certificate in chain {
+        match certificate.to_der() {
+            Ok(der) => certificates.push(der),
+            Err(_) => return Err(ErrorKind::CertificateUnavailable.into()),
+        }
+    }
+
+    Ok(certificates)
+}
+
+// This is never reached, as verifiers can't be installed on these backends.
+#[cfg(any(target_os = "macos", target_os = "windows", target_os = "ios"))]
+pub(crate) fn peer_certificates<S>(_: &TlsStream<S>) -> Result<Vec<Vec<u8>>> {
+    Err(ErrorKind::CertificateUnavailable.into())
+}
+
+// Retrieves the protocol version and cipher suite negotiated for the given
+// stream, where the backend exposes them.
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+pub(crate) fn negotiated<S>(stream: &TlsStream<S>) -> Option<Negotiated> {
+    use native_tls::backend::openssl::TlsStreamExt;
+
+    let ssl = stream.get_ref().raw_stream().ssl();
+    let cipher = ssl.current_cipher()?;
+
+    Some(Negotiated {
+        protocol: ssl.version().to_owned(),
+        cipher: cipher.name().to_owned(),
+        secret_bits: cipher.bits().secret.max(0) as u32,
+    })
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows", target_os = "ios"))]
+pub(crate) fn negotiated<S>(_: &TlsStream<S>) -> Option<Negotiated> {
+    None
+}
@@ -0,0 +1,313 @@
+//! The traffic module contains `TrafficLog`, which records the raw lines
+//! sent and received on a connection to a file, for audit trails and for
+//! debugging problems with the protocol.
+//!
+//! Each line of the log holds the time, in UTC, the line was received (`<-`)
+//! or sent (`->`), followed by the line itself:
+//!
+//! ```text
+//! 2017-06-01T09:30:00.000Z <- :irc.example.org 001 bot :Welcome
+//! 2017-06-01T09:30:01.250Z -> JOIN #rust
+//! ```
+//!
+//! A log is set on a `Client` with `Client::traffic_log`, and records every
+//! connection the client makes, including each one made by a `Reconnect`.
+//! Logs are appended to, and can be rotated once they reach a size or age
+//! with `Rotation`: the log is renamed to `<path>.1`, older logs are renamed
+//! to `<path>.2` and so on up to the number kept, and a new log is started.
+//!
+//! Lines are handed to a thread of the log's own as they're sent and
+//! received, which writes each of them straight away, so that a slow disk
+//! doesn't hold up the connections being logged. Lines the thread hasn't
+//! written yet when the process exits are lost. Failures to write the log
+//! are reported with `Event::AutomationFailed` by the next connection to
+//! record a line, and don't affect the connection.
+//!
+//! Passwords are hidden from the log by default, as described by
+//! `Redaction`: the arguments of PASS, OPER, AUTHENTICATE and WEBIRC, and
+//! the text of messages such as IDENTIFY sent to services, are replaced
+//! with `<redacted>`. On Unix, new logs are only readable by their owner.
+//!
+//! ```no_run
+//! # extern crate tokio_irc_client;
+//! # use std::net::SocketAddr;
+//! use tokio_irc_client::Client;
+//! use tokio_irc_client::traffic::{Rotation, TrafficLog};
+//!
+//! # fn main() {
+//! # let addr: SocketAddr = "127.0.0.1:6667".parse().unwrap();
+//! let log = TrafficLog::new("/var/log/bot/traffic.log")
+//!     .rotation(Rotation::Size(10 * 1024 * 1024))
+//!     .keep(3);
+//!
+//! let client = Client::new(addr).traffic_log(log);
+//! # }
+//! ```
+
+use chathistory::format_time;
+use error::{Error, Result};
+use pretty::{self, Redaction};
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// How many rotated logs are kept when not configured.
+const DEFAULT_KEEP: usize = 5;
+
+/// When a `TrafficLog` is rotated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    /// Never rotate the log.
+    #[default]
+    Never,
+    /// Rotate the log before it would grow beyond the given number of bytes.
+    Size(u64),
+    /// Rotate the log once it's been written to for the given time.
+    Interval(Duration),
+}
+
+// The direction a line travelled in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Received,
+    Sent,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Direction::Received => "<-",
+            Direction::Sent => "->",
+        })
+    }
+}
+
+/// A log of the raw lines sent and received on connections. See the module
+/// documentation for details.
+///
+/// Clones of a `TrafficLog` write to the same file.
+#[derive(Clone, Debug)]
+pub struct TrafficLog {
+    config: Config,
+    redaction: Redaction,
+    writer: Arc<Mutex<Option<Writer>>>,
+}
+
+// Where and how the log is written.
+#[derive(Clone, Debug)]
+struct Config {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+}
+
+// The thread writing the log, started when the first line is recorded, to
+// which entries are sent along with the time they were recorded.
+#[derive(Debug)]
+struct Writer {
+    entries: Sender<(SystemTime, Vec<u8>)>,
+    failures: Receiver<Error>,
+}
+
+// The log currently being written, opened when the first line is written.
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    size: u64,
+    started: SystemTime,
+}
+
+impl TrafficLog {
+    /// Create a new `TrafficLog` writing to the file at `path`, which isn't
+    /// rotated by default.
+    pub fn new<P: AsRef<Path>>(path: P) -> TrafficLog {
+        TrafficLog {
+            config: Config {
+                path: path.as_ref().to_owned(),
+                rotation: Rotation::default(),
+                keep: DEFAULT_KEEP,
+            },
+            redaction: Redaction::default(),
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Set when the log is rotated.
+    pub fn rotation(mut self, rotation: Rotation) -> TrafficLog {
+        self.config.rotation = rotation;
+        self
+    }
+
+    /// Set how many rotated logs are kept, with older ones being deleted. By
+    /// default 5 are kept, and with none the log is started again each time
+    /// it's rotated.
+    pub fn keep(mut self, keep: usize) -> TrafficLog {
+        self.config.keep = keep;
+        self
+    }
+
+    /// Set what's hidden from the lines logged. By default passwords are,
+    /// and with `Redaction::none()` lines are logged exactly as they were
+    /// sent and received.
+    pub fn redaction(mut self, redaction: Redaction) -> TrafficLog {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Returns the path of the log being written.
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    // Hands the line to the writing thread, failing if an earlier line
+    // couldn't be written.
+    pub(crate) fn record(&self, direction: Direction, line: &[u8]) -> Result<()> {
+        let now = SystemTime::now();
+        let mut entry = format!("{} {} ", format_time(now), direction).into_bytes();
+
+        entry.extend_from_slice(&pretty::redact_line(line, &self.redaction));
+        entry.push(b'\n');
+
+        let mut writer = self.writer.lock().expect("Traffic log lock poisoned");
+
+        if writer.is_none() {
+            *writer = Some(self.config.clone().spawn()?);
+        }
+
+        let sent = writer
+            .as_ref()
+            .map(|writer| writer.entries.send((now, entry)).is_ok());
+
+        // The thread only stops if it panicked, in which case another is
+        // started for the next line.
+        if sent == Some(false) {
+            *writer = None;
+            return Err(io::Error::other("the traffic log's thread stopped").into());
+        }
+
+        match writer.as_ref().and_then(|writer| writer.failures.try_recv().ok()) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Config {
+    // Starts a thread writing the entries sent to it, which stops once every
+    // clone of the log has been dropped.
+    fn spawn(self) -> Result<Writer> {
+        let (entries, received) = mpsc::channel::<(SystemTime, Vec<u8>)>();
+        let (failed, failures) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("traffic-log".to_owned())
+            .spawn(move || {
+                let mut file = None;
+
+                for (time, entry) in received {
+                    if let Err(err) = self.write(&mut file, time, &entry) {
+                        let _ = failed.send(err);
+                    }
+                }
+            })?;
+
+        Ok(Writer {
+            entries: entries,
+            failures: failures,
+        })
+    }
+
+    fn write(&self, file: &mut Option<LogFile>, now: SystemTime, entry: &[u8]) -> Result<()> {
+        let due = file
+            .as_ref()
+            .is_some_and(|log| self.is_due(log, entry.len() as u64, now));
+
+        if due {
+            *file = None;
+            self.rotate()?;
+        }
+
+        if file.is_none() {
+            *file = Some(self.open(now)?);
+        }
+
+        let log = file.as_mut().expect("log was opened");
+
+        if let Err(err) = log.file.write_all(entry) {
+            // The log is opened again for the next line, in case it was
+            // removed or its disk was remounted.
+            *file = None;
+            return Err(err.into());
+        }
+
+        log.size += entry.len() as u64;
+
+        Ok(())
+    }
+
+    fn is_due(&self, log: &LogFile, length: u64, now: SystemTime) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => log.size > 0 && log.size + length > max,
+            Rotation::Interval(interval) => match now.duration_since(log.started) {
+                Ok(age) => age >= interval,
+                Err(_) => false,
+            },
+        }
+    }
+
+    fn open(&self, now: SystemTime) -> Result<LogFile> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+
+        // The log holds private conversations, so only its owner can read
+        // it.
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let file = options.open(&self.path)?;
+        let metadata = file.metadata()?;
+
+        Ok(LogFile {
+            size: metadata.len(),
+            // A log left by an earlier run is as old as its first line.
+            started: metadata.created().unwrap_or(now).min(now),
+            file: file,
+        })
+    }
+
+    // Renames the log and those already rotated to make way for a new one,
+    // deleting the oldest.
+    fn rotate(&self) -> Result<()> {
+        if self.keep == 0 {
+            return ignore_missing(fs::remove_file(&self.path));
+        }
+
+        for number in (1..self.keep).rev() {
+            ignore_missing(fs::rename(self.rotated(number), self.rotated(number + 1)))?;
+        }
+
+        ignore_missing(fs::rename(&self.path, self.rotated(1)))
+    }
+
+    fn rotated(&self, number: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", number));
+        PathBuf::from(path)
+    }
+}
+
+fn ignore_missing(result: io::Result<()>) -> Result<()> {
+    match result {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
@@ -0,0 +1,206 @@
+//! The transform module contains the `Transform` trait, used to rewrite the
+//! text of PRIVMSG and NOTICE messages as they're received from and sent to
+//! the server, such as for decryption, profanity masking or machine
+//! translation.
+//!
+//! Transforms are installed on a `Client` with `inbound_transform` and
+//! `outbound_transform`, and are run in the order they were installed. Each
+//! message is transformed in turn: an incoming message isn't yielded, and no
+//! later message is, until its transforms have completed, and outgoing
+//! messages are likewise sent in the order they were given to the `Sink`.
+//! CTCP messages aren't transformed.
+//!
+//! An outbound transform can return text with line breaks in it to send the
+//! message as several, one for each line, such as when the transformed text
+//! is too long for a single message. Line breaks in the text of an incoming
+//! message are replaced with spaces.
+//!
+//! Messages sent by handles, such as a `Query`, a `Conversation` or a `Bot`
+//! replying to a command, and by an `OnReady` or a responder, are
+//! transformed the same way, with failures reported by
+//! `Event::AutomationFailed` rather than returned. The messages the
+//! transport sends itself to run the protocol aren't transformed: PONG
+//! replies, the watchdog's PING, registration and SASL, the MODE queries of
+//! `send_preflight`, CTCP replies, and messages held for away users, which
+//! were transformed before being held.
+//!
+//! Incoming messages are transformed before anything else sees them, so the
+//! replay buffer, `Requester`, bots and events all get the transformed text,
+//! and messages a transform drops aren't seen by them at all.
+
+use error::{Error, Result};
+use state::State;
+
+use futures::{future, Async, Future, Poll};
+
+use pircolate::Message;
+
+use std::sync::Arc;
+
+/// A future resolving to the transformed text of a message, or to `None` if
+/// the message should be dropped.
+pub type TransformFuture = Box<dyn Future<Item = Option<String>, Error = Error> + Send>;
+
+/// A `Transform` rewrites the text of a message.
+///
+/// The `peer` is the other end of the conversation the message belongs to,
+/// which is the channel for messages sent to a channel and otherwise the
+/// nickname of the other user.
+pub trait Transform: Send + Sync {
+    /// Transform the `text` of a message exchanged with `peer`.
+    fn transform(&self, peer: &str, text: String) -> TransformFuture;
+
+    /// Transform the `text` of a message exchanged with `peer` on the
+    /// connection tracked by `state`, such as to compare `peer` with other
+    /// names the way the server does. By default this calls `transform`.
+    fn transform_on(&self, state: &State, peer: &str, text: String) -> TransformFuture {
+        let _ = state;
+        self.transform(peer, text)
+    }
+}
+
+impl<F> Transform for F
+where
+    F: Fn(&str, String) -> TransformFuture + Send + Sync,
+{
+    fn transform(&self, peer: &str, text: String) -> TransformFuture {
+        self(peer, text)
+    }
+}
+
+/// What happens to a message when one of its transforms fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// The error is returned from the `Stream` or `Sink`, or reported for
+    /// messages sent by handles.
+    #[default]
+    Fail,
+    /// The message is dropped.
+    Drop,
+    /// The original, untransformed message is used.
+    Passthrough,
+}
+
+// The transforms applied to messages travelling in one direction.
+#[derive(Clone, Default)]
+pub(crate) struct Pipeline {
+    transforms: Vec<Arc<dyn Transform>>,
+    policy: ErrorPolicy,
+    // Whether transformed text is split into a message for each line.
+    split_lines: bool,
+}
+
+impl Pipeline {
+    // The transforms applied to outgoing messages.
+    pub fn outbound() -> Pipeline {
+        Pipeline {
+            split_lines: true,
+            ..Pipeline::default()
+        }
+    }
+
+    pub fn push(&mut self, transform: Arc<dyn Transform>) {
+        self.transforms.push(transform);
+    }
+
+    pub fn set_policy(&mut self, policy: ErrorPolicy) {
+        self.policy = policy;
+    }
+
+    // Starts transforming the message exchanged with `peer`, if it's one
+    // that should be transformed.
+    pub fn start(&self, message: &Message, peer: &str, state: &State) -> Option<Transforming> {
+        if self.transforms.is_empty() || !matches!(message.raw_command(), "PRIVMSG" | "NOTICE") {
+            return None;
+        }
+
+        let text = match message.raw_args().nth(1) {
+            Some(text) if !text.starts_with('\x01') => text.to_owned(),
+            _ => return None,
+        };
+
+        let mut transformed: TransformFuture = Box::new(future::ok(Some(text)));
+
+        for transform in &self.transforms {
+            let transform = transform.clone();
+            let peer = peer.to_owned();
+            let state = state.clone();
+
+            transformed = Box::new(transformed.and_then(move |text| match text {
+                Some(text) => transform.transform_on(&state, &peer, text),
+                None => Box::new(future::ok(None)),
+            }));
+        }
+
+        Some(Transforming {
+            message: Some(message.clone()),
+            future: transformed,
+            policy: self.policy,
+            split_lines: self.split_lines,
+        })
+    }
+}
+
+// A future resolving to the transformed messages, of which there are none if
+// the message should be dropped.
+pub(crate) struct Transforming {
+    message: Option<Message>,
+    future: TransformFuture,
+    policy: ErrorPolicy,
+    split_lines: bool,
+}
+
+impl Future for Transforming {
+    type Item = Vec<Message>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match self.future.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(text)) => Ok(text),
+            Err(err) => Err(err),
+        };
+
+        let message = self.message.take().expect("Transforming polled after completion");
+
+        match result {
+            Ok(Some(text)) => {
+                if !self.split_lines {
+                    return Ok(Async::Ready(vec![with_text(&message, &text)?]));
+                }
+
+                // Empty lines, such as between CR and LF, aren't sent.
+                let lines = text
+                    .split(&['\r', '\n'][..])
+                    .filter(|line| !line.is_empty() || text.is_empty())
+                    .map(|line| with_text(&message, line))
+                    .collect::<Result<_>>()?;
+
+                Ok(Async::Ready(lines))
+            }
+            Ok(None) => Ok(Async::Ready(Vec::new())),
+            Err(err) => match self.policy {
+                ErrorPolicy::Fail => Err(err),
+                ErrorPolicy::Drop => Ok(Async::Ready(Vec::new())),
+                ErrorPolicy::Passthrough => Ok(Async::Ready(vec![message])),
+            },
+        }
+    }
+}
+
+// Rebuilds a message with the given text in place of its last argument,
+// keeping its tags, prefix and other arguments.
+fn with_text(message: &Message, text: &str) -> Result<Message> {
+    let raw = message.raw_message();
+    let old_text = message.raw_args().next_back().unwrap_or_default();
+    let head = &raw[..raw.len() - old_text.len()];
+
+    // The original text may not have been given as a trailing argument.
+    let raw = if head.ends_with(':') {
+        format!("{}{}", head, text)
+    } else {
+        format!("{}:{}", head, text)
+    };
+
+    Ok(Message::try_from(raw.replace(&['\r', '\n'][..], " "))?)
+}
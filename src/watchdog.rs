@@ -0,0 +1,106 @@
+//! The watchdog module contains the types used to notice connections that
+//! have gone quiet.
+//!
+//! The PING timeout only notices servers that stop sending PING requests,
+//! which many only send every few minutes. Setting a `Watchdog` on a
+//! `Client` makes the `IrcTransport` watch for any traffic at all, and act
+//! as its `StallAction` says once nothing has been received for the
+//! watchdog's duration. `Event::Stalled` is emitted each time, whatever the
+//! action.
+//!
+//! The transport checks the watchdog whenever it's polled. Connections made
+//! with `Client::connect_reconnecting` and `connect_tls_reconnecting` are
+//! also woken when the watchdog is due, while a transport used on its own is
+//! only checked when its task is woken for another reason, such as a message
+//! being sent on it.
+
+use std::time::Duration;
+
+/// What a `Watchdog` does once nothing has been received for its duration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StallAction {
+    /// Send a PING to the server, and close the connection with `Stalled` if
+    /// nothing has been received after the watchdog's duration again.
+    #[default]
+    Probe,
+    /// Close the connection with `Stalled` straight away, which makes a
+    /// `Reconnect` reconnect.
+    Reconnect,
+    /// Only emit `Event::Stalled`, leaving the connection as it is.
+    Report,
+}
+
+/// Watches for connections which have received nothing for a while.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchdog {
+    idle: Duration,
+    action: StallAction,
+}
+
+impl Watchdog {
+    /// Create a new `Watchdog` acting as `action` says once nothing has been
+    /// received for `idle`.
+    pub fn new(idle: Duration, action: StallAction) -> Watchdog {
+        Watchdog {
+            idle: idle,
+            action: action,
+        }
+    }
+}
+
+// What the transport should do about a connection's silence.
+pub(crate) enum Verdict {
+    Fine,
+    // Nothing has been received for the given time, so the action is due.
+    Stalled(Duration, StallAction),
+    // The probe went unanswered.
+    Unanswered,
+}
+
+// Applies a `Watchdog` to the traffic received on a connection, with times
+// measured by the transport's clock.
+pub(crate) struct Watch {
+    watchdog: Watchdog,
+    last_received: Duration,
+    stalled: bool,
+}
+
+impl Watch {
+    pub fn new(watchdog: Watchdog, now: Duration) -> Watch {
+        Watch {
+            watchdog: watchdog,
+            last_received: now,
+            stalled: false,
+        }
+    }
+
+    pub fn received(&mut self, now: Duration) {
+        self.last_received = now;
+        self.stalled = false;
+    }
+
+    // When the watchdog is next due, if it will be before more traffic is
+    // received.
+    pub fn deadline(&self) -> Option<Duration> {
+        match (self.stalled, self.watchdog.action) {
+            (false, _) => Some(self.last_received + self.watchdog.idle),
+            (true, StallAction::Probe) => Some(self.last_received + self.watchdog.idle * 2),
+            (true, _) => None,
+        }
+    }
+
+    pub fn check(&mut self, now: Duration) -> Verdict {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => (),
+            _ => return Verdict::Fine,
+        }
+
+        if self.stalled {
+            return Verdict::Unanswered;
+        }
+
+        self.stalled = true;
+
+        Verdict::Stalled(now.saturating_sub(self.last_received), self.watchdog.action)
+    }
+}
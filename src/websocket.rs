@@ -0,0 +1,258 @@
+//! The websocket module allows the protocol handling of an `IrcTransport` to
+//! run over a WebSocket, such as one opened by a browser, rather than over a
+//! TCP socket.
+//!
+//! Following the IRCv3 WebSocket specification, each IRC message is carried
+//! in its own frame without the trailing CRLF. `WebSocketStream` adapts any
+//! `Stream` and `Sink` of frames into the byte stream expected by
+//! `IrcTransport`, leaving the WebSocket implementation itself, whether
+//! that's `web_sys::WebSocket` behind `wasm-bindgen` or a native library, up
+//! to the application. A transport is created from a connected socket with
+//! `Client::connect_websocket`.
+//!
+//! On `wasm32`, where the crate builds without tokio's reactor and TCP
+//! support, this is how a client connects. There the client needs a `Clock`,
+//! given with `Client::clock`, and reconnecting needs a `Timer`, given to
+//! `Client::reconnecting_with_timer`; see the `clock` module.
+//!
+//! This module is only available with the `websocket` feature.
+
+use bytes::BytesMut;
+
+use futures::{Async, AsyncSink, Sink, Stream};
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+/// Adapts a WebSocket, represented as a `Stream` and `Sink` of frames, into
+/// a byte stream that can be used by an `IrcTransport`.
+pub struct WebSocketStream<S> {
+    inner: S,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+    // A frame which the socket wasn't ready to accept yet.
+    pending: Option<Vec<u8>>,
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: Stream<Item = Vec<u8>, Error = io::Error> + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+{
+    /// Wrap the given connected WebSocket.
+    pub fn new(socket: S) -> WebSocketStream<S> {
+        WebSocketStream {
+            inner: socket,
+            read_buffer: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+            pending: None,
+        }
+    }
+
+    // Sends every complete line that has been written as a frame, returning
+    // false if the socket isn't ready to accept all of them.
+    fn send_frames(&mut self) -> io::Result<bool> {
+        loop {
+            let frame = match self.pending.take() {
+                Some(frame) => frame,
+                None => match self.write_buffer.iter().position(|&b| b == b'\n') {
+                    Some(index) => {
+                        let line = self.write_buffer.split_to(index + 1);
+                        let end = if line.ends_with(b"\r\n") { index - 1 } else { index };
+
+                        line[..end].to_vec()
+                    }
+                    None => return Ok(true),
+                },
+            };
+
+            if let AsyncSink::NotReady(frame) = self.inner.start_send(frame)? {
+                self.pending = Some(frame);
+                return Ok(false);
+            }
+        }
+    }
+}
+
+impl<S> Read for WebSocketStream<S>
+where
+    S: Stream<Item = Vec<u8>, Error = io::Error> + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+{
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.read_buffer.is_empty() {
+            match self.inner.poll()? {
+                Async::Ready(Some(frame)) => {
+                    self.read_buffer.extend(frame);
+                    self.read_buffer.extend(b"\r\n");
+                }
+                Async::Ready(None) => return Ok(0),
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+
+        let length = cmp::min(buffer.len(), self.read_buffer.len());
+        buffer[..length].copy_from_slice(&self.read_buffer.split_to(length));
+
+        Ok(length)
+    }
+}
+
+impl<S> Write for WebSocketStream<S>
+where
+    S: Stream<Item = Vec<u8>, Error = io::Error> + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+{
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        // Nothing more is accepted until the socket takes the frame it
+        // previously refused.
+        if self.pending.is_some() && !self.send_frames()? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        self.write_buffer.extend(buffer);
+        self.send_frames()?;
+
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.send_frames()? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        match self.inner.poll_complete()? {
+            Async::Ready(()) => Ok(()),
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: Stream<Item = Vec<u8>, Error = io::Error> + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+{
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: Stream<Item = Vec<u8>, Error = io::Error> + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+{
+    fn shutdown(&mut self) -> ::futures::Poll<(), io::Error> {
+        if !self.send_frames()? {
+            return Ok(Async::NotReady);
+        }
+
+        self.inner.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{Poll, StartSend};
+
+    use std::collections::VecDeque;
+
+    // A socket whose frames are received from `incoming`, which accepts up to
+    // `room` frames into `sent`.
+    struct Socket {
+        incoming: VecDeque<Vec<u8>>,
+        sent: Vec<Vec<u8>>,
+        room: usize,
+    }
+
+    impl Socket {
+        fn new(incoming: &[&str], room: usize) -> Socket {
+            Socket {
+                incoming: incoming.iter().map(|frame| frame.as_bytes().to_vec()).collect(),
+                sent: Vec::new(),
+                room: room,
+            }
+        }
+    }
+
+    impl Stream for Socket {
+        type Item = Vec<u8>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+            match self.incoming.pop_front() {
+                Some(frame) => Ok(Async::Ready(Some(frame))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl Sink for Socket {
+        type SinkItem = Vec<u8>;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, frame: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+            if self.sent.len() == self.room {
+                return Ok(AsyncSink::NotReady(frame));
+            }
+
+            self.sent.push(frame);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn sent(stream: &WebSocketStream<Socket>) -> Vec<String> {
+        stream
+            .inner
+            .sent
+            .iter()
+            .map(|frame| String::from_utf8(frame.clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_read_as_lines() {
+        let mut stream = WebSocketStream::new(Socket::new(&["PING :a", "PING :b"], 0));
+        let mut buffer = [0; 64];
+        let mut read = Vec::new();
+
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(length) => read.extend_from_slice(&buffer[..length]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+
+        assert_eq!(read, b"PING :a\r\nPING :b\r\n");
+    }
+
+    #[test]
+    fn lines_are_written_as_frames() {
+        let mut stream = WebSocketStream::new(Socket::new(&[], 10));
+
+        stream.write_all(b"NICK bot\r\nUSER bot 0 * ").unwrap();
+        assert_eq!(sent(&stream), ["NICK bot"]);
+
+        stream.write_all(b":bot\r\nPONG :a\n").unwrap();
+        assert_eq!(sent(&stream), ["NICK bot", "USER bot 0 * :bot", "PONG :a"]);
+    }
+
+    #[test]
+    fn writes_wait_for_a_refused_frame() {
+        let mut stream = WebSocketStream::new(Socket::new(&[], 1));
+
+        stream.write_all(b"PONG :a\r\nPONG :b\r\n").unwrap();
+        assert_eq!(sent(&stream), ["PONG :a"]);
+
+        let err = stream.write(b"PONG :c\r\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(stream.flush().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        stream.inner.room = 3;
+        stream.write_all(b"PONG :c\r\n").unwrap();
+        stream.flush().unwrap();
+        assert_eq!(sent(&stream), ["PONG :a", "PONG :b", "PONG :c"]);
+    }
+}
@@ -0,0 +1,394 @@
+//! The who module contains the listings returned by `Requester::names`,
+//! `Requester::who` and `Requester::whox`, which collect the many replies
+//! the server sends to NAMES and WHO into a single list once the reply
+//! ending them arrives.
+//!
+//! Replies to WHOX queries carry a token identifying the query, so they're
+//! never mixed up with the replies to other queries. Plain WHO replies only
+//! say which channel they concern, so those for a query of a nickname or
+//! mask are told apart from the replies to other such queries sent at the
+//! same time only by the order the server answers in.
+//!
+//! Servers which limit how many replies they send say so with
+//! ERR_TOOMANYMATCHES, in which case the listing holds the replies given so
+//! far and is marked as truncated. Queries the server asks to be tried
+//! again with RPL_TRYAGAIN are sent again a few times before failing with
+//! `ServerBusy`. As RPL_TRYAGAIN doesn't say which query it concerns, every
+//! query of the same command still waiting for its first reply is sent
+//! again.
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate tokio_irc_client;
+//! # use futures::Future;
+//! # use tokio_irc_client::request::Requester;
+//! # fn example(requester: Requester) {
+//! let accounts = requester
+//!     .whox("#rust", "nfa")
+//!     .map(|listing| for entry in listing.entries {
+//!         println!("{:?} is logged in as {:?}", entry.nick, entry.account);
+//!     });
+//! # }
+//! # fn main() {}
+//! ```
+
+use error::{ErrorKind, Result};
+use request::Pending;
+use state::{self, State};
+
+use futures::sync::oneshot::Sender;
+
+use pircolate::Message;
+
+/// The WHOX fields that can be requested, in the order the server sends
+/// them.
+pub const WHOX_FIELDS: &str = "tcuihsnfdlaor";
+
+// How many times a query is sent before giving up on a busy server.
+const MAX_ATTEMPTS: usize = 3;
+
+/// The complete list of replies to a NAMES or WHO query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listing<T> {
+    /// The replies, in the order the server sent them.
+    pub entries: Vec<T>,
+    /// Whether the server stopped sending replies before giving them all.
+    pub truncated: bool,
+}
+
+/// A member of a channel, as given in reply to NAMES.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Name {
+    /// The member's nickname.
+    pub nick: String,
+    /// The member's prefixes in the channel, such as `@` for operators,
+    /// highest first. More than one is only given with the `multi-prefix`
+    /// capability.
+    pub prefixes: String,
+    /// The member's username, given with the `userhost-in-names` capability.
+    pub user: Option<String>,
+    /// The member's host, given with the `userhost-in-names` capability.
+    pub host: Option<String>,
+}
+
+/// A user, as given in reply to WHO. Fields the reply didn't include, such
+/// as those not requested from WHOX, are left empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WhoEntry {
+    /// The user's nickname.
+    pub nick: Option<String>,
+    /// A channel the user is in, if the query was of a channel or the
+    /// server chose to name one.
+    pub channel: Option<String>,
+    /// The user's username.
+    pub user: Option<String>,
+    /// The user's host.
+    pub host: Option<String>,
+    /// The user's IP address, if the server doesn't hide it.
+    pub ip: Option<String>,
+    /// The server the user is connected to.
+    pub server: Option<String>,
+    /// Whether the user is away, if the flags were given.
+    pub away: Option<bool>,
+    /// Whether the user is an IRC operator.
+    pub operator: bool,
+    /// The user's prefixes in `channel`, highest first.
+    pub prefixes: String,
+    /// How many servers away the user is.
+    pub hops: Option<u32>,
+    /// How many seconds the user has been idle.
+    pub idle: Option<u64>,
+    /// The account the user is logged in to, if they're logged in.
+    pub account: Option<String>,
+    /// The user's operator level in `channel`.
+    pub op_level: Option<String>,
+    /// The user's real name.
+    pub real_name: Option<String>,
+}
+
+impl WhoEntry {
+    // Fills in the away status, operator status and prefixes given by the
+    // flags of a reply, such as "H*@".
+    fn apply_flags(&mut self, flags: &str, state: &State) {
+        let symbols = state.channel_mode_types().prefix_symbols().to_owned();
+
+        self.away = match flags.chars().next() {
+            Some('G') => Some(true),
+            Some('H') => Some(false),
+            _ => None,
+        };
+        self.operator = flags.contains('*');
+        self.prefixes = flags.chars().filter(|c| symbols.contains(*c)).collect();
+    }
+}
+
+// The state shared by the requests collecting NAMES and WHO replies.
+struct Collecting<T> {
+    // The query, sent again if the server asks for it to be retried.
+    query: Message,
+    command: &'static str,
+    entries: Vec<T>,
+    attempts: usize,
+    resend: bool,
+    sender: Option<Sender<Result<Listing<T>>>>,
+}
+
+impl<T> Collecting<T> {
+    fn new(query: Message, sender: Sender<Result<Listing<T>>>) -> Collecting<T> {
+        let command = if query.raw_command() == "NAMES" { "NAMES" } else { "WHO" };
+
+        Collecting {
+            query: query,
+            command: command,
+            entries: Vec::new(),
+            attempts: 1,
+            resend: false,
+            sender: Some(sender),
+        }
+    }
+
+    fn complete(&mut self, truncated: bool) -> bool {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Ok(Listing {
+                entries: ::std::mem::take(&mut self.entries),
+                truncated: truncated,
+            }));
+        }
+
+        true
+    }
+
+    // Handles the replies saying the query can't be answered in full, given
+    // the name the query was of.
+    fn handle_refusal(&mut self, message: &Message, name: &str, state: &State) -> Option<bool> {
+        let args: Vec<&str> = message.raw_args().collect();
+
+        if args.get(1) != Some(&self.command) {
+            return None;
+        }
+
+        match message.raw_command() {
+            // ERR_TOOMANYMATCHES: "<client> <command> [<mask>] :<info>"
+            "416" if args.len() < 4 || state.same_name(args[2], name) => Some(self.complete(true)),
+
+            // RPL_TRYAGAIN: "<client> <command> :<info>"
+            // Queries already being answered aren't the ones refused.
+            "263" if !self.entries.is_empty() => Some(false),
+            "263" if self.attempts >= MAX_ATTEMPTS => {
+                if let Some(sender) = self.sender.take() {
+                    let _ = sender.send(Err(ErrorKind::ServerBusy(self.command.to_owned()).into()));
+                }
+
+                Some(true)
+            }
+            "263" => {
+                self.attempts += 1;
+                self.resend = true;
+
+                Some(false)
+            }
+
+            _ => None,
+        }
+    }
+
+    fn resend(&mut self) -> Vec<Message> {
+        if ::std::mem::take(&mut self.resend) {
+            vec![self.query.clone()]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// A NAMES query waiting for the end of the names of its channel.
+pub(crate) struct NamesRequest {
+    channel: String,
+    collecting: Collecting<Name>,
+}
+
+impl NamesRequest {
+    pub(crate) fn new(
+        channel: &str,
+        query: Message,
+        sender: Sender<Result<Listing<Name>>>,
+    ) -> NamesRequest {
+        NamesRequest {
+            channel: channel.to_owned(),
+            collecting: Collecting::new(query, sender),
+        }
+    }
+}
+
+impl Pending for NamesRequest {
+    fn handle(&mut self, message: &Message, state: &State) -> bool {
+        if let Some(done) = self.collecting.handle_refusal(message, &self.channel, state) {
+            return done;
+        }
+
+        let args: Vec<&str> = message.raw_args().collect();
+
+        match message.raw_command() {
+            // RPL_NAMREPLY: "<client> <symbol> <channel> :<prefix><nick>..."
+            "353" if args.len() >= 4 && state.same_name(args[2], &self.channel) => {
+                let symbols = state.channel_mode_types().prefix_symbols().to_owned();
+
+                for name in args[3].split_whitespace() {
+                    let split = name.find(|c| !symbols.contains(c)).unwrap_or(name.len());
+                    let (prefixes, mask) = name.split_at(split);
+
+                    let name = match state::parse_hostmask(mask) {
+                        Some((nick, user, host)) => Name {
+                            nick: nick.to_owned(),
+                            prefixes: prefixes.to_owned(),
+                            user: Some(user.to_owned()),
+                            host: Some(host.to_owned()),
+                        },
+                        None => Name {
+                            nick: mask.to_owned(),
+                            prefixes: prefixes.to_owned(),
+                            user: None,
+                            host: None,
+                        },
+                    };
+
+                    self.collecting.entries.push(name);
+                }
+
+                false
+            }
+
+            // RPL_ENDOFNAMES: "<client> <channel> :End of /NAMES list"
+            "366" if args.len() >= 2 && state.same_name(args[1], &self.channel) => {
+                self.collecting.complete(false)
+            }
+
+            _ => false,
+        }
+    }
+
+    fn resend(&mut self) -> Vec<Message> {
+        self.collecting.resend()
+    }
+}
+
+// A WHO query waiting for the end of the replies to its mask. WHOX queries
+// know their token and the fields they asked for.
+pub(crate) struct WhoRequest {
+    mask: String,
+    whox: Option<(String, String)>,
+    collecting: Collecting<WhoEntry>,
+}
+
+impl WhoRequest {
+    pub(crate) fn new(
+        mask: &str,
+        whox: Option<(String, String)>,
+        query: Message,
+        sender: Sender<Result<Listing<WhoEntry>>>,
+    ) -> WhoRequest {
+        WhoRequest {
+            mask: mask.to_owned(),
+            whox: whox,
+            collecting: Collecting::new(query, sender),
+        }
+    }
+
+    // The entry of a RPL_WHOREPLY, if it's a reply to this query.
+    fn who_reply(&self, args: &[&str], state: &State) -> Option<WhoEntry> {
+        // "<client> <channel> <user> <host> <server> <nick> <flags> :<hops> <real name>"
+        if args.len() < 8 || self.whox.is_some() {
+            return None;
+        }
+
+        if state.is_channel(&self.mask) && !state.same_name(args[1], &self.mask) {
+            return None;
+        }
+
+        let (hops, real_name) = args[7].split_once(' ').unwrap_or((args[7], ""));
+
+        let mut entry = WhoEntry {
+            nick: Some(args[5].to_owned()),
+            channel: Some(args[1].to_owned()).filter(|channel| channel != "*"),
+            user: Some(args[2].to_owned()),
+            host: Some(args[3].to_owned()),
+            server: Some(args[4].to_owned()),
+            hops: hops.parse().ok(),
+            real_name: Some(real_name.to_owned()),
+            ..WhoEntry::default()
+        };
+
+        entry.apply_flags(args[6], state);
+
+        Some(entry)
+    }
+
+    // The entry of a RPL_WHOSPCRPL, if it's a reply to this query.
+    fn whox_reply(&self, args: &[&str], state: &State) -> Option<WhoEntry> {
+        // "<client> <token> <fields>..."
+        let (ref token, ref fields) = *self.whox.as_ref()?;
+
+        if args.get(1) != Some(&token.as_str()) {
+            return None;
+        }
+
+        let mut values = args[2..].iter().cloned();
+        let mut entry = WhoEntry::default();
+
+        let present = |value: &str| Some(value.to_owned()).filter(|value| value != "*");
+
+        for field in WHOX_FIELDS[1..].chars().filter(|field| fields.contains(*field)) {
+            let value = values.next()?;
+
+            match field {
+                'c' => entry.channel = present(value),
+                'u' => entry.user = present(value),
+                'i' => entry.ip = present(value).filter(|ip| ip != "255.255.255.255"),
+                'h' => entry.host = present(value),
+                's' => entry.server = present(value),
+                'n' => entry.nick = present(value),
+                'f' => entry.apply_flags(value, state),
+                'd' => entry.hops = value.parse().ok(),
+                'l' => entry.idle = value.parse().ok(),
+                'a' => entry.account = present(value).filter(|account| account != "0"),
+                'o' => entry.op_level = present(value).filter(|level| level != "n/a"),
+                'r' => entry.real_name = Some(value.to_owned()),
+                _ => (),
+            }
+        }
+
+        Some(entry)
+    }
+}
+
+impl Pending for WhoRequest {
+    fn handle(&mut self, message: &Message, state: &State) -> bool {
+        if let Some(done) = self.collecting.handle_refusal(message, &self.mask, state) {
+            return done;
+        }
+
+        let args: Vec<&str> = message.raw_args().collect();
+
+        let entry = match message.raw_command() {
+            "352" => self.who_reply(&args, state),
+            "354" => self.whox_reply(&args, state),
+
+            // RPL_ENDOFWHO: "<client> <mask> :End of WHO list"
+            "315" if args.len() >= 2 && state.same_name(args[1], &self.mask) => {
+                return self.collecting.complete(false);
+            }
+
+            _ => None,
+        };
+
+        if let Some(entry) = entry {
+            self.collecting.entries.push(entry);
+        }
+
+        false
+    }
+
+    fn resend(&mut self) -> Vec<Message> {
+        self.collecting.resend()
+    }
+}
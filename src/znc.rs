@@ -0,0 +1,53 @@
+//! The znc module contains support for the extensions offered by the ZNC
+//! bouncer.
+//!
+//! With the `znc.in/playback` capability, ZNC no longer plays back its
+//! buffers as soon as a client connects, and instead waits for the client to
+//! ask for the messages it missed with a `play` message. Requesting the
+//! capability is enabled with `Registration::znc_playback`. Played back
+//! messages arrive in a batch, so they can be told apart from live traffic
+//! with `State::is_playback`, and the start and end of each batch are
+//! reported by `Event::PlaybackStarted` and `Event::PlaybackFinished`.
+//!
+//! The `znc.in/self-message` capability, which is requested by default,
+//! makes ZNC forward the messages sent by other clients attached to the same
+//! user. They can be recognized with `State::is_self` on their source.
+
+use error::Result;
+
+use pircolate::Message;
+
+/// The name of the capability deferring buffer playback until requested.
+pub const PLAYBACK: &str = "znc.in/playback";
+
+/// The name of the capability forwarding messages sent by other clients.
+pub const SELF_MESSAGE: &str = "znc.in/self-message";
+
+/// The type of the batches holding played back messages.
+pub const PLAYBACK_BATCH: &str = "znc.in/playback";
+
+/// Build a message asking ZNC to play back the messages in `buffer` received
+/// after `since`, given in seconds since the Unix epoch. `buffer` is a
+/// channel or nickname, and may contain `*` wildcards, so `"*"` plays back
+/// every buffer.
+pub fn play(buffer: &str, since: u64) -> Result<Message> {
+    Ok(Message::try_from(format!("PRIVMSG *playback :PLAY {} {}", buffer, since))?)
+}
+
+/// Build a message asking ZNC to play back the messages in `buffer` received
+/// after `since` and before `until`, both given in seconds since the Unix
+/// epoch.
+pub fn play_range(buffer: &str, since: u64, until: u64) -> Result<Message> {
+    Ok(Message::try_from(format!(
+        "PRIVMSG *playback :PLAY {} {} {}",
+        buffer,
+        since,
+        until
+    ))?)
+}
+
+/// Build a message asking ZNC to discard the buffered messages in `buffer`,
+/// which may contain `*` wildcards.
+pub fn clear(buffer: &str) -> Result<Message> {
+    Ok(Message::try_from(format!("PRIVMSG *playback :CLEAR {}", buffer))?)
+}